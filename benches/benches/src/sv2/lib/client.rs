@@ -7,7 +7,9 @@ use async_std::channel::unbounded;
 use binary_sv2::u256_from_int;
 use codec_sv2::{buffer_sv2::Slice, StandardEitherFrame, StandardSv2Frame};
 use roles_logic_sv2::{
-    common_messages_sv2::{Protocol, SetupConnection, SetupConnectionSuccess},
+    common_messages_sv2::{
+        MiningFlags, Protocol, ProtocolVersion, SetupConnection, SetupConnectionSuccess,
+    },
     common_properties::{IsMiningUpstream, IsUpstream},
     errors::Error,
     handlers::{
@@ -144,11 +146,11 @@ impl Device {
 }
 
 impl IsUpstream<(), NullDownstreamMiningSelector> for Device {
-    fn get_version(&self) -> u16 {
+    fn get_version(&self) -> ProtocolVersion {
         todo!()
     }
 
-    fn get_flags(&self) -> u32 {
+    fn get_flags(&self) -> MiningFlags {
         todo!()
     }
 
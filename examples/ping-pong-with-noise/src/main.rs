@@ -26,7 +26,7 @@ async fn server_pool_listen(listener: TcpListener) {
         let k_pub: Secp256k1PublicKey = AUTHORITY_PUBLIC_K.to_string().try_into().unwrap();
         let k_priv: Secp256k1SecretKey = AUTHORITY_PRIVATE_K.to_string().try_into().unwrap();
         let responder =
-            Responder::from_authority_kp(&k_pub.into_bytes(), &k_priv.into_bytes(), CERT_VALIDITY)
+            Responder::from_authority_kp(&k_pub.into_bytes(), k_priv.into_bytes(), CERT_VALIDITY)
                 .unwrap();
         let _server = node::Node::new(
             "server".to_string(),
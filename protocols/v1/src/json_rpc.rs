@@ -21,6 +21,22 @@ impl Message {
         }
     }
 
+    /// Parses a line received from a client, that can be either a single message or a batch
+    /// (a JSON array of messages). The messages are returned in the order in which they appear in
+    /// the batch. An empty batch, or a batch with an element that is not a valid message, is an
+    /// error.
+    pub fn from_batch(s: &str) -> Result<Vec<Message>, serde_json::Error> {
+        match serde_json::from_str(s)? {
+            serde_json::Value::Array(messages) => {
+                if messages.is_empty() {
+                    return Err(serde::de::Error::invalid_length(0, &"a non empty batch"));
+                }
+                messages.into_iter().map(serde_json::from_value).collect()
+            }
+            message => Ok(vec![serde_json::from_value(message)?]),
+        }
+    }
+
     //pub fn error(&self) -> Option<JsonRpcError> {
     //    match self {
     //        Message::Response(r) => r.error.clone(),
@@ -77,3 +93,63 @@ impl From<Notification> for Message {
         Message::Notification(n)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_message() {
+        let messages = Message::from_batch(
+            r#"{"id": 1, "method": "mining.subscribe", "params": ["cpuminer/1.0"]}"#,
+        )
+        .unwrap();
+        match &messages[..] {
+            [Message::StandardRequest(request)] => {
+                assert_eq!(request.id, 1);
+                assert_eq!(request.method, "mining.subscribe");
+            }
+            _ => panic!("Expected a single request, got {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn parses_batch_in_order() {
+        let messages = Message::from_batch(
+            r#"[
+                {"id": 1, "method": "mining.configure", "params": [[], {}]},
+                {"id": 2, "method": "mining.subscribe", "params": []},
+                {"id": 3, "method": "mining.authorize", "params": ["user", "password"]}
+            ]"#,
+        )
+        .unwrap();
+        let methods: Vec<(u64, &str)> = messages
+            .iter()
+            .map(|message| match message {
+                Message::StandardRequest(request) => (request.id, request.method.as_str()),
+                _ => panic!("Expected a request, got {:?}", message),
+            })
+            .collect();
+        assert_eq!(
+            methods,
+            vec![
+                (1, "mining.configure"),
+                (2, "mining.subscribe"),
+                (3, "mining.authorize")
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_batch() {
+        // Not valid json
+        assert!(Message::from_batch(r#"[{"id": 1, "method": "mining.subscribe""#).is_err());
+        // An element that is not a message
+        assert!(Message::from_batch(
+            r#"[{"id": 1, "method": "mining.subscribe", "params": []}, 2]"#
+        )
+        .is_err());
+        // Empty batch
+        assert!(Message::from_batch("[]").is_err());
+    }
+}
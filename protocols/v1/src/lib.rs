@@ -48,7 +48,7 @@ use tracing::debug;
 use error::Error;
 pub use json_rpc::Message;
 pub use methods::{client_to_server, server_to_client, Method, MethodError, ParsingMethodError};
-use utils::{Extranonce, HexU32Be};
+use utils::{Extranonce, HexBytes, HexU32Be};
 
 /// json_rpc Response are not handled cause stratum v1 does not have any request from a server to a
 /// client
@@ -88,7 +88,10 @@ pub trait IsServer<'a> {
         Self: std::marker::Sized,
     {
         match request {
-            methods::Client2Server::SuggestDifficulty() => Ok(None),
+            methods::Client2Server::SuggestDifficulty(suggest_difficulty) => {
+                self.handle_suggest_difficulty(&suggest_difficulty);
+                Ok(None)
+            }
             methods::Client2Server::Authorize(authorize) => {
                 let authorized = self.handle_authorize(&authorize);
                 if authorized {
@@ -107,6 +110,10 @@ pub trait IsServer<'a> {
                 self.handle_extranonce_subscribe();
                 Ok(None)
             }
+            methods::Client2Server::GetTransactions(get_transactions) => {
+                let transactions = self.handle_get_transactions(&get_transactions);
+                Ok(Some(get_transactions.respond(transactions)))
+            }
             methods::Client2Server::Submit(submit) => {
                 let has_valid_version_bits = match &submit.version_bits {
                     Some(a) => {
@@ -143,6 +150,16 @@ pub trait IsServer<'a> {
         }
     }
 
+    /// Called when the client suggests a share difficulty with `mining.suggest_difficulty`.
+    /// Returns the difficulty the server is going to use, if it honors the suggestion. The
+    /// default implementation ignores it.
+    fn handle_suggest_difficulty(
+        &mut self,
+        _request: &client_to_server::SuggestDifficulty,
+    ) -> Option<f64> {
+        None
+    }
+
     /// This message (JSON RPC Request) SHOULD be the first message sent by the miner after the
     /// connection with the server is established.
     fn handle_configure(
@@ -187,6 +204,16 @@ pub trait IsServer<'a> {
     /// Indicates to the server that the client supports the mining.set_extranonce method.
     fn handle_extranonce_subscribe(&self);
 
+    /// Called when the client asks for the transactions of a job with `mining.get_transactions`.
+    /// Returns the hex-encoded transactions of the job's block template. The default
+    /// implementation does not expose them and returns an empty list.
+    fn handle_get_transactions(
+        &self,
+        _request: &client_to_server::GetTransactions,
+    ) -> Vec<HexBytes> {
+        Vec::new()
+    }
+
     fn is_authorized(&self, name: &str) -> bool;
 
     fn authorize(&mut self, name: &str);
@@ -221,8 +248,14 @@ pub trait IsServer<'a> {
         }
         .into())
     }
-    // {"params":["00003000"], "id":null, "method": "mining.set_version_mask"}
-    // fn update_version_rolling_mask
+
+    /// Set the version rolling mask to `version_mask` and return the `mining.set_version_mask`
+    /// notification that tells the client about it. Submits are checked against the new mask from
+    /// now on.
+    fn update_version_rolling_mask(&mut self, version_mask: HexU32Be) -> json_rpc::Message {
+        self.set_version_rolling_mask(Some(version_mask.clone()));
+        server_to_client::SetVersionMask { version_mask }.into()
+    }
 
     fn notify(&mut self) -> Result<json_rpc::Message, Error>;
 
@@ -311,6 +344,10 @@ pub trait IsClient<'a> {
                 self.handle_set_version_mask(&mut set_version_mask)?;
                 Ok(None)
             }
+            methods::Server2Client::Reconnect(reconnect) => {
+                self.handle_reconnect(&reconnect)?;
+                Ok(None)
+            }
         }
     }
 
@@ -392,6 +429,12 @@ pub trait IsClient<'a> {
         m: &mut server_to_client::SetVersionMask,
     ) -> Result<(), Error<'a>>;
 
+    /// Called when the server asks the client to reconnect with `client.reconnect`. The default
+    /// implementation ignores it.
+    fn handle_reconnect(&mut self, _m: &server_to_client::Reconnect) -> Result<(), Error<'a>> {
+        Ok(())
+    }
+
     fn handle_subscribe(
         &mut self,
         subscribe: &server_to_client::Subscribe<'a>,
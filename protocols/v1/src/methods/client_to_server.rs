@@ -9,7 +9,7 @@ use crate::{
     error::Error,
     json_rpc::{Message, Response, StandardRequest},
     methods::ParsingMethodError,
-    utils::{Extranonce, HexU32Be},
+    utils::{Extranonce, HexBytes, HexU32Be},
 };
 
 #[cfg(test)]
@@ -100,7 +100,54 @@ fn from_to_json_rpc(auth: Authorize) -> bool {
 #[derive(Debug, Clone, Copy)]
 pub struct ExtranonceSubscribe();
 
-// mining.get_transactions
+/// _mining.get_transactions(job_id)_
+///
+/// Asks the server for the transactions of the block template that `job_id` was built from, so
+/// that the miner can check what it is mining on. The result is the list of the hex-encoded
+/// transactions, see [GetTransactionsResult](crate::server_to_client::GetTransactionsResult).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetTransactions {
+    pub id: u64,
+    pub job_id: String,
+}
+
+impl GetTransactions {
+    pub fn respond(self, transactions: Vec<HexBytes>) -> Response {
+        let transactions: Vec<Value> = transactions.into_iter().map(Value::from).collect();
+        Response {
+            id: self.id,
+            result: transactions.into(),
+            error: None,
+        }
+    }
+}
+
+impl From<GetTransactions> for Message {
+    fn from(get_transactions: GetTransactions) -> Self {
+        Message::StandardRequest(StandardRequest {
+            id: get_transactions.id,
+            method: "mining.get_transactions".into(),
+            params: (&[get_transactions.job_id][..]).into(),
+        })
+    }
+}
+
+impl TryFrom<StandardRequest> for GetTransactions {
+    type Error = ParsingMethodError;
+
+    fn try_from(msg: StandardRequest) -> Result<Self, Self::Error> {
+        match msg.params.as_array() {
+            Some(params) => {
+                let job_id = match &params[..] {
+                    [JString(a)] => a.clone(),
+                    _ => return Err(ParsingMethodError::wrong_args_from_value(msg.params)),
+                };
+                Ok(Self { id: msg.id, job_id })
+            }
+            None => Err(ParsingMethodError::not_array_from_value(msg.params)),
+        }
+    }
+}
 
 /// _mining.submit("username", "job id", "ExtraNonce2", "nTime", "nOnce")_
 ///
@@ -654,7 +701,44 @@ impl From<InfoParams> for serde_json::Map<String, Value> {
     }
 }
 
-// mining.suggest_difficulty
+/// _mining.suggest_difficulty(difficulty)_
+///
+/// Sent by the client, usually right after connecting, to ask the server for a specific share
+/// difficulty. The server MAY ignore it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuggestDifficulty {
+    pub id: u64,
+    pub value: f64,
+}
+
+impl From<SuggestDifficulty> for Message {
+    fn from(suggest: SuggestDifficulty) -> Self {
+        Message::StandardRequest(StandardRequest {
+            id: suggest.id,
+            method: "mining.suggest_difficulty".into(),
+            params: (&[suggest.value][..]).into(),
+        })
+    }
+}
+
+impl TryFrom<StandardRequest> for SuggestDifficulty {
+    type Error = ParsingMethodError;
+
+    fn try_from(msg: StandardRequest) -> Result<Self, Self::Error> {
+        match msg.params.as_array() {
+            Some(params) => {
+                let value = match &params[..] {
+                    [JNumber(a)] => a.as_f64().ok_or_else(|| {
+                        ParsingMethodError::not_float_from_value(msg.params.clone())
+                    })?,
+                    _ => return Err(ParsingMethodError::wrong_args_from_value(msg.params)),
+                };
+                Ok(Self { id: msg.id, value })
+            }
+            None => Err(ParsingMethodError::not_array_from_value(msg.params)),
+        }
+    }
+}
 
 // mining.suggest_target
 
@@ -716,3 +800,69 @@ fn test_version_extension_with_no_bit_count() {
         _ => panic!(),
     };
 }
+
+#[test]
+fn test_suggest_difficulty_from_to_json_rpc() {
+    let suggest = SuggestDifficulty {
+        id: 3,
+        value: 512.5,
+    };
+    let request = match Message::from(suggest.clone()) {
+        Message::StandardRequest(s) => s,
+        _ => panic!(),
+    };
+    assert_eq!(request.method, "mining.suggest_difficulty");
+    assert_eq!(suggest, SuggestDifficulty::try_from(request).unwrap());
+
+    let client_message = r#"{"id":4, "method": "mining.suggest_difficulty", "params":[1024]}"#;
+    let client_message: StandardRequest = serde_json::from_str(client_message).unwrap();
+    let suggest = SuggestDifficulty::try_from(client_message).unwrap();
+    assert_eq!(
+        suggest,
+        SuggestDifficulty {
+            id: 4,
+            value: 1024.0
+        }
+    );
+}
+
+#[test]
+fn test_suggest_difficulty_with_wrong_params() {
+    let client_message = r#"{"id":4, "method": "mining.suggest_difficulty", "params":["1024"]}"#;
+    let client_message: StandardRequest = serde_json::from_str(client_message).unwrap();
+    assert!(SuggestDifficulty::try_from(client_message).is_err());
+}
+
+#[test]
+fn test_get_transactions_from_to_json_rpc() {
+    let get_transactions = GetTransactions {
+        id: 7,
+        job_id: "bf".to_string(),
+    };
+    let request = match Message::from(get_transactions.clone()) {
+        Message::StandardRequest(s) => s,
+        _ => panic!(),
+    };
+    assert_eq!(request.method, "mining.get_transactions");
+    assert_eq!(
+        get_transactions,
+        GetTransactions::try_from(request).unwrap()
+    );
+
+    let client_message = r#"{"id":8, "method": "mining.get_transactions", "params":["1a"]}"#;
+    let client_message: StandardRequest = serde_json::from_str(client_message).unwrap();
+    assert_eq!(
+        GetTransactions::try_from(client_message).unwrap(),
+        GetTransactions {
+            id: 8,
+            job_id: "1a".to_string()
+        }
+    );
+}
+
+#[test]
+fn test_get_transactions_with_wrong_params() {
+    let client_message = r#"{"id":8, "method": "mining.get_transactions", "params":[26]}"#;
+    let client_message: StandardRequest = serde_json::from_str(client_message).unwrap();
+    assert!(GetTransactions::try_from(client_message).is_err());
+}
@@ -125,10 +125,11 @@ pub enum Method<'a> {
 
 #[derive(Debug, Clone)]
 pub enum Client2Server<'a> {
-    SuggestDifficulty(),
+    SuggestDifficulty(client_to_server::SuggestDifficulty),
     Subscribe(client_to_server::Subscribe<'a>),
     Authorize(client_to_server::Authorize),
     ExtranonceSubscribe(client_to_server::ExtranonceSubscribe),
+    GetTransactions(client_to_server::GetTransactions),
     Submit(client_to_server::Submit<'a>),
     Configure(client_to_server::Configure),
 }
@@ -159,6 +160,7 @@ pub enum Server2Client<'a> {
     SetDifficulty(server_to_client::SetDifficulty),
     SetExtranonce(server_to_client::SetExtranonce<'a>),
     SetVersionMask(server_to_client::SetVersionMask),
+    Reconnect(server_to_client::Reconnect),
 }
 
 impl<'a> From<Server2Client<'a>> for Method<'a> {
@@ -218,7 +220,13 @@ impl<'a> TryFrom<Message> for Method<'a> {
         match &msg {
             Message::StandardRequest(request) => match &request.method[..] {
                 "mining.suggest_difficulty" => {
-                    Ok(Method::Client2Server(Client2Server::SuggestDifficulty()))
+                    let method = request
+                        .clone()
+                        .try_into()
+                        .map_err(|e: ParsingMethodError| e.as_method_error(msg))?;
+                    Ok(Method::Client2Server(Client2Server::SuggestDifficulty(
+                        method,
+                    )))
                 }
                 "mining.subscribe" => {
                     let method = request
@@ -237,6 +245,15 @@ impl<'a> TryFrom<Message> for Method<'a> {
                 "mining.extranonce.subscribe" => Ok(Method::Client2Server(
                     Client2Server::ExtranonceSubscribe(client_to_server::ExtranonceSubscribe()),
                 )),
+                "mining.get_transactions" => {
+                    let method = request
+                        .clone()
+                        .try_into()
+                        .map_err(|e: ParsingMethodError| e.as_method_error(msg))?;
+                    Ok(Method::Client2Server(Client2Server::GetTransactions(
+                        method,
+                    )))
+                }
                 "mining.submit" => {
                     let method = request
                         .clone()
@@ -261,7 +278,8 @@ impl<'a> TryFrom<Message> for Method<'a> {
                         .map_err(|e: ParsingMethodError| e.as_method_error(msg))?;
                     Ok(Method::Server2Client(Server2Client::Notify(method)))
                 }
-                "mining.set_version_mask" => {
+                // some servers use the non standard `mining.set_version` name
+                "mining.set_version_mask" | "mining.set_version" => {
                     let method = notification
                         .clone()
                         .try_into()
@@ -282,6 +300,13 @@ impl<'a> TryFrom<Message> for Method<'a> {
                         .map_err(|e: ParsingMethodError| e.as_method_error(msg))?;
                     Ok(Method::Server2Client(Server2Client::SetExtranonce(method)))
                 }
+                "client.reconnect" => {
+                    let method = notification
+                        .clone()
+                        .try_into()
+                        .map_err(|e: ParsingMethodError| e.as_method_error(msg))?;
+                    Ok(Method::Server2Client(Server2Client::Reconnect(method)))
+                }
                 _ => Err(MethodError::MethodNotFound(notification.clone().method)),
             },
             Message::OkResponse(response) => response
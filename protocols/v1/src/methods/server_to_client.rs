@@ -13,7 +13,77 @@ use crate::{
 
 // client.get_version()
 
-// client.reconnect
+/// _client.reconnect(hostname, port, wait)_
+///
+/// The server asks the client to reconnect, to `host` and `port` if given, otherwise to the same
+/// server, after waiting `wait` seconds. Every parameter is optional, a missing parameter is sent
+/// as `null` unless all the following ones are missing too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reconnect {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub wait: Option<u32>,
+}
+
+impl From<Reconnect> for Message {
+    fn from(r: Reconnect) -> Self {
+        let mut params: Vec<Value> = vec![
+            r.host.map(Value::from).unwrap_or(Value::Null),
+            r.port.map(Value::from).unwrap_or(Value::Null),
+            r.wait.map(Value::from).unwrap_or(Value::Null),
+        ];
+        while params.last() == Some(&Value::Null) {
+            params.pop();
+        }
+        Message::Notification(Notification {
+            method: "client.reconnect".to_string(),
+            params: params.into(),
+        })
+    }
+}
+
+impl TryFrom<Notification> for Reconnect {
+    type Error = ParsingMethodError;
+
+    fn try_from(msg: Notification) -> Result<Self, Self::Error> {
+        let params = msg
+            .params
+            .as_array()
+            .ok_or_else(|| ParsingMethodError::not_array_from_value(msg.params.clone()))?;
+        if params.len() > 3 {
+            return Err(ParsingMethodError::wrong_args_from_value(msg.params));
+        }
+        let host = match params.first() {
+            None | Some(Value::Null) => None,
+            Some(JString(a)) => Some(a.clone()),
+            Some(_) => return Err(ParsingMethodError::wrong_args_from_value(msg.params)),
+        };
+        // some pools send the port as a string
+        let port = match params.get(1) {
+            None | Some(Value::Null) => None,
+            Some(JNumber(a)) => Some(
+                a.as_u64()
+                    .and_then(|p| u16::try_from(p).ok())
+                    .ok_or_else(|| ParsingMethodError::not_unsigned_from_value(a.clone()))?,
+            ),
+            Some(JString(a)) => Some(
+                a.parse::<u16>()
+                    .map_err(|_| ParsingMethodError::wrong_args_from_value(msg.params.clone()))?,
+            ),
+            Some(_) => return Err(ParsingMethodError::wrong_args_from_value(msg.params)),
+        };
+        let wait = match params.get(2) {
+            None | Some(Value::Null) => None,
+            Some(JNumber(a)) => Some(
+                a.as_u64()
+                    .and_then(|w| u32::try_from(w).ok())
+                    .ok_or_else(|| ParsingMethodError::not_unsigned_from_value(a.clone()))?,
+            ),
+            Some(_) => return Err(ParsingMethodError::wrong_args_from_value(msg.params)),
+        };
+        Ok(Reconnect { host, port, wait })
+    }
+}
 
 // client.show_message
 
@@ -238,14 +308,14 @@ impl<'a> TryFrom<Notification> for SetExtranonce<'a> {
 #[derive(Debug, Clone)]
 /// Server may arbitrarily adjust version mask
 pub struct SetVersionMask {
-    version_mask: HexU32Be,
+    pub version_mask: HexU32Be,
 }
 
 impl From<SetVersionMask> for Message {
     fn from(sv: SetVersionMask) -> Self {
         let version_mask: Value = sv.version_mask.into();
         Message::Notification(Notification {
-            method: "mining.set_version".to_string(),
+            method: "mining.set_version_mask".to_string(),
             params: (&[version_mask][..]).into(),
         })
     }
@@ -423,6 +493,46 @@ impl<'a> TryFrom<&Response> for Subscribe<'a> {
     }
 }
 
+/// Result of a [mining.get_transactions][a] request: the hex-encoded transactions of the block
+/// template the requested job was built from.
+///
+/// [a]: crate::client_to_server::GetTransactions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetTransactionsResult {
+    pub id: u64,
+    pub transactions: Vec<HexBytes>,
+}
+
+impl From<GetTransactionsResult> for Message {
+    fn from(result: GetTransactionsResult) -> Self {
+        let transactions: Vec<Value> = result.transactions.into_iter().map(Value::from).collect();
+        Message::OkResponse(Response {
+            id: result.id,
+            error: None,
+            result: transactions.into(),
+        })
+    }
+}
+
+impl TryFrom<&Response> for GetTransactionsResult {
+    type Error = ParsingMethodError;
+
+    fn try_from(msg: &Response) -> Result<Self, Self::Error> {
+        let id = msg.id;
+        let params = msg.result.as_array().ok_or_else(|| {
+            ParsingMethodError::ImpossibleToParseResultField(Box::new(msg.clone()))
+        })?;
+        let mut transactions = Vec::with_capacity(params.len());
+        for transaction in params {
+            let transaction = transaction
+                .as_str()
+                .ok_or_else(|| ParsingMethodError::UnexpectedArrayParams(params.clone()))?;
+            transactions.push(HexBytes::try_from(transaction)?);
+        }
+        Ok(GetTransactionsResult { id, transactions })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Configure {
     pub id: u64,
@@ -635,3 +745,92 @@ impl From<VersionRollingParams> for serde_json::Map<String, Value> {
         params
     }
 }
+
+#[test]
+fn reconnect_from_to_json_rpc_all_params() {
+    let reconnect = Reconnect {
+        host: Some("pool.example.com".to_string()),
+        port: Some(3333),
+        wait: Some(10),
+    };
+    let notification = match Message::from(reconnect.clone()) {
+        Message::Notification(n) => n,
+        _ => panic!(),
+    };
+    assert_eq!(notification.method, "client.reconnect");
+    assert_eq!(
+        notification.params,
+        serde_json::json!(["pool.example.com", 3333, 10])
+    );
+    assert_eq!(reconnect, Reconnect::try_from(notification).unwrap());
+}
+
+#[test]
+fn reconnect_from_to_json_rpc_no_params() {
+    let reconnect = Reconnect {
+        host: None,
+        port: None,
+        wait: None,
+    };
+    let notification = match Message::from(reconnect.clone()) {
+        Message::Notification(n) => n,
+        _ => panic!(),
+    };
+    assert_eq!(notification.params, serde_json::json!([]));
+    assert_eq!(reconnect, Reconnect::try_from(notification).unwrap());
+}
+
+#[test]
+fn reconnect_with_string_port() {
+    let notification: Notification = serde_json::from_str(
+        r#"{"method": "client.reconnect", "params": ["pool.example.com", "3333", 0]}"#,
+    )
+    .unwrap();
+    let reconnect = Reconnect::try_from(notification).unwrap();
+    assert_eq!(reconnect.port, Some(3333));
+    assert_eq!(reconnect.wait, Some(0));
+}
+
+#[test]
+fn get_transactions_result_from_to_json_rpc() {
+    let result = GetTransactionsResult {
+        id: 7,
+        transactions: vec![vec![0x01, 0x02, 0xab].into(), vec![0xff; 40].into()],
+    };
+    let response = match Message::from(result.clone()) {
+        Message::OkResponse(r) => r,
+        _ => panic!(),
+    };
+    assert_eq!(result, GetTransactionsResult::try_from(&response).unwrap());
+
+    let server_message = r#"{"id":8, "error":null, "result":["0102", "abcdef"]}"#;
+    let server_message: Response = serde_json::from_str(server_message).unwrap();
+    assert_eq!(
+        GetTransactionsResult::try_from(&server_message).unwrap(),
+        GetTransactionsResult {
+            id: 8,
+            transactions: vec![vec![0x01, 0x02].into(), vec![0xab, 0xcd, 0xef].into()],
+        }
+    );
+}
+
+#[test]
+fn get_transactions_result_with_non_hex_transaction() {
+    let server_message = r#"{"id":8, "error":null, "result":["0102", "zz"]}"#;
+    let server_message: Response = serde_json::from_str(server_message).unwrap();
+    assert!(GetTransactionsResult::try_from(&server_message).is_err());
+}
+
+#[test]
+fn set_version_mask_from_to_json_rpc() {
+    let set_version_mask = SetVersionMask {
+        version_mask: HexU32Be(0x1fff_e000),
+    };
+    let notification = match Message::from(set_version_mask) {
+        Message::Notification(n) => n,
+        _ => panic!(),
+    };
+    assert_eq!(notification.method, "mining.set_version_mask");
+    let parsed = SetVersionMask::try_from(notification).unwrap();
+    assert_eq!(parsed.version_mask, HexU32Be(0x1fff_e000));
+}
@@ -154,6 +154,23 @@ impl<'de> Deserialize<'de> for HexU32Be {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PrevHash<'a>(pub U256<'a>);
 
+impl PrevHash<'static> {
+    /// Prev hash of a SV2 `SetNewPrevHash`, that is in the internal byte order of bitcoin. The
+    /// swap of every u32 word that SV1 miners expect is applied when the prev hash is serialized.
+    pub fn from_sv2(u256: &[u8; 32]) -> Self {
+        PrevHash(U256::from(*u256))
+    }
+}
+
+impl<'a> PrevHash<'a> {
+    /// Reverse of [`PrevHash::from_sv2`]: the prev hash in the internal byte order of bitcoin
+    pub fn to_sv2(&self) -> [u8; 32] {
+        let mut u256 = [0; 32];
+        u256.copy_from_slice(self.0.inner_as_ref());
+        u256
+    }
+}
+
 impl<'a> From<PrevHash<'a>> for Vec<u8> {
     fn from(p_hash: PrevHash<'a>) -> Self {
         p_hash.0.to_vec()
@@ -396,4 +413,22 @@ mod tests {
 
         be_hex == back_to_hex && be_hex == value_to_string
     }
+
+    #[test]
+    fn test_prev_hash_from_sv2() {
+        // Prev hash of the mining.notify example of the stratum V1 documentation, it is the block
+        // 00000000440b921e1b77c6c0487ae5616de67f788f44ae2a5af6e2194d16b6f8
+        let stratum = "4d16b6f85af6e2198f44ae2a6de67f78487ae5611b77c6c0440b921e00000000";
+        let mut sv2 = <[u8; 32]>::from_hex(
+            "00000000440b921e1b77c6c0487ae5616de67f788f44ae2a5af6e2194d16b6f8",
+        )
+        .unwrap();
+        // block hashes are displayed in the reverse of the internal byte order
+        sv2.reverse();
+
+        let prev_hash = PrevHash::from_sv2(&sv2);
+        assert_eq!(String::from(prev_hash.clone()), stratum);
+        assert_eq!(prev_hash.to_sv2(), sv2);
+        assert_eq!(PrevHash::try_from(stratum).unwrap().to_sv2(), sv2);
+    }
 }
@@ -1,8 +1,7 @@
-use std::ptr;
-
-use crate::aed_cipher::AeadCipher;
+use crate::{aed_cipher::AeadCipher, EncryptionAlgorithm};
 use aes_gcm::Aes256Gcm;
 use chacha20poly1305::{aead::Buffer, ChaCha20Poly1305};
+use zeroize::Zeroize;
 
 pub trait CipherState<Cipher_: AeadCipher>
 where
@@ -83,17 +82,22 @@ where
 #[allow(clippy::large_enum_variant)]
 pub enum GenericCipher {
     ChaCha20Poly1305(Cipher<ChaCha20Poly1305>),
-    #[allow(dead_code)]
     Aes256Gcm(Cipher<Aes256Gcm>),
 }
 
-impl Drop for GenericCipher {
-    fn drop(&mut self) {
-        self.erase_k();
+impl GenericCipher {
+    /// Cipher for the transport messages, the key is hidden in the AEAD
+    pub fn for_transport(algorithm: EncryptionAlgorithm, k: [u8; 32]) -> Self {
+        match algorithm {
+            EncryptionAlgorithm::ChaChaPoly => {
+                GenericCipher::ChaCha20Poly1305(Cipher::from_cipher(ChaCha20Poly1305::from_key(k)))
+            }
+            EncryptionAlgorithm::AesGcm => {
+                GenericCipher::Aes256Gcm(Cipher::from_cipher(Aes256Gcm::from_key(k)))
+            }
+        }
     }
-}
 
-impl GenericCipher {
     pub fn encrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
         match self {
             GenericCipher::ChaCha20Poly1305(c) => c.encrypt_with_ad(&[], msg),
@@ -106,21 +110,29 @@ impl GenericCipher {
             GenericCipher::Aes256Gcm(c) => c.decrypt_with_ad(&[], msg),
         }
     }
+    pub fn algorithm(&self) -> EncryptionAlgorithm {
+        match self {
+            GenericCipher::ChaCha20Poly1305(_) => EncryptionAlgorithm::ChaChaPoly,
+            GenericCipher::Aes256Gcm(_) => EncryptionAlgorithm::AesGcm,
+        }
+    }
+    pub fn rekey(&mut self) -> Result<(), aes_gcm::Error> {
+        match self {
+            GenericCipher::ChaCha20Poly1305(c) => c.rekey(),
+            GenericCipher::Aes256Gcm(c) => c.rekey(),
+        }
+    }
     pub fn erase_k(&mut self) {
         match self {
             GenericCipher::ChaCha20Poly1305(c) => {
                 if let Some(k) = c.k.as_mut() {
-                    for b in k {
-                        unsafe { ptr::write_volatile(b, 0) };
-                    }
+                    k.zeroize();
                     c.k = None;
                 }
             }
             GenericCipher::Aes256Gcm(c) => {
                 if let Some(k) = c.k.as_mut() {
-                    for b in k {
-                        unsafe { ptr::write_volatile(b, 0) };
-                    }
+                    k.zeroize();
                     c.k = None;
                 }
             }
@@ -194,6 +206,7 @@ pub struct Cipher<C: AeadCipher> {
 
 impl<C: AeadCipher> Cipher<C> {
     /// Internal use only, we need k for handshake
+    #[allow(dead_code)]
     pub fn from_key_and_cipher(k: [u8; 32], c: C) -> Self {
         Self {
             k: Some(k),
@@ -203,7 +216,6 @@ impl<C: AeadCipher> Cipher<C> {
     }
 
     /// At the end of the handshake we return a cipher with hidden key
-    #[allow(dead_code)]
     pub fn from_cipher(c: C) -> Self {
         Self {
             k: None,
@@ -211,6 +223,34 @@ impl<C: AeadCipher> Cipher<C> {
             cipher: Some(c),
         }
     }
+
+    /// Noise `REKEY(k)`: the new key is the first 32 bytes of `ENCRYPT(k, maxnonce, zerolen,
+    /// zeros)`. The nonce is not reset. If the key is kept it is overwritten by the new one.
+    pub fn rekey(&mut self) -> Result<(), aes_gcm::Error> {
+        // maxnonce (2^64 - 1) encoded as in nonce_to_bytes
+        let nonce = [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        let mut new_k = vec![0; 32];
+        if let Some(c) = self.cipher.as_mut() {
+            c.encrypt(&nonce, &[], &mut new_k)?;
+            let mut k = [0; 32];
+            k.copy_from_slice(&new_k[..32]);
+            self.cipher = Some(C::from_key(k));
+            if let Some(old_k) = self.k.as_mut() {
+                *old_k = k;
+            }
+            k.zeroize();
+        }
+        new_k.zeroize();
+        Ok(())
+    }
+}
+
+impl<C: AeadCipher> Drop for Cipher<C> {
+    fn drop(&mut self) {
+        if let Some(k) = self.k.as_mut() {
+            k.zeroize();
+        }
+    }
 }
 
 impl<C: AeadCipher> CipherState<C> for Cipher<C> {
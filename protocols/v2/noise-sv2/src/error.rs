@@ -9,11 +9,38 @@ pub enum Error {
     InvalidCipherChosed(Vec<u8>),
     AesGcm(AesGcm),
     InvalidCipherState,
-    InvalidCertificate([u8; 74]),
+    CertificateExpired,
+    CertificateNotYetValid,
+    CertificateBadSignature,
+    // The certificate is signed by the responder static key instead of the authority key
+    SelfSignedRejected,
+    // The responder static key is not the one the initiator expects, see
+    // `Initiator::with_expected_static_key`
+    StaticKeyMismatch,
     InvalidRawPublicKey,
     InvalidRawPrivateKey,
+    /// The validity of the certificate, in seconds, does not fit the u32 of the certificate
+    InvalidCertificateValidity(u64),
     ExpectedIncomingHandshakeMessage,
     InvalidMessageLength,
+    /// A handshake step has been called out of order, eg a step called twice or
+    /// `Initiator::step_2` called before `Initiator::step_0`. Steps are numbered as in the
+    /// handshake: 0 and 2 for the initiator, 1 for the responder. `expected` is
+    /// `handshake::HANDSHAKE_DONE` when the handshake is already over.
+    UnexpectedHandshakeStage {
+        expected: u8,
+        got: u8,
+    },
+    /// A handshake message is shorter than the message of its step
+    HandshakeMessageTooSmall {
+        expected: usize,
+        got: usize,
+    },
+    /// A handshake message is longer than the message of its step
+    HandshakeMessageTooLarge {
+        expected: usize,
+        got: usize,
+    },
 }
 
 impl From<AesGcm> for Error {
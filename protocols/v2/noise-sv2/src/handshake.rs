@@ -1,12 +1,61 @@
-use crate::{aed_cipher::AeadCipher, cipher_state::CipherState, NOISE_HASHED_PROTOCOL_NAME_CHACHA};
+//! Noise handshake primitives, and [`run_initiator`] and [`run_responder`] that drive a whole
+//! handshake over caller provided async send and receive functions.
+
+use crate::{
+    aed_cipher::AeadCipher, cipher_state::CipherState, error::Error, initiator::Initiator,
+    responder::Responder, NoiseCodec, SecretKeypair, NOISE_HASHED_PROTOCOL_NAME_CHACHA,
+};
 use chacha20poly1305::ChaCha20Poly1305;
+use const_sv2::{
+    INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE, RESPONDER_EXPECTED_HANDSHAKE_MESSAGE_SIZE,
+};
+use core::convert::TryInto;
 use secp256k1::{
     ecdh::SharedSecret,
     hashes::{sha256::Hash as Sha256Hash, Hash},
     rand, Keypair, Secp256k1, SecretKey, XOnlyPublicKey,
 };
+use zeroize::Zeroize;
+
+/// Stage of a role that has run all its handshake steps
+pub const HANDSHAKE_DONE: u8 = 3;
+
+/// Converts a handshake message received from the peer into the message of a step, `N` is the
+/// size of the message of the step: `RESPONDER_EXPECTED_HANDSHAKE_MESSAGE_SIZE` for
+/// `Responder::step_1`, `INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE` for `Initiator::step_2`.
+///
+/// Handshake messages have a fixed size, so the length is checked before anything is done with
+/// the message of a peer that is not authenticated yet.
+pub fn message_from_slice<const N: usize>(message: &[u8]) -> Result<[u8; N], Error> {
+    match message.len() {
+        len if len < N => Err(Error::HandshakeMessageTooSmall {
+            expected: N,
+            got: len,
+        }),
+        len if len > N => Err(Error::HandshakeMessageTooLarge {
+            expected: N,
+            got: len,
+        }),
+        // Infallible, the length has been checked above
+        _ => Ok(message.try_into().expect("message of the expected length")),
+    }
+}
+
+/// Checks that the step `got` is the next one of a role at `stage` and moves the role past it.
+/// The stage moves on before the step runs, so a step that fails can not be called again with
+/// the handshake state left half updated.
+pub(crate) fn enter_stage(stage: &mut u8, got: u8, next: u8) -> Result<(), Error> {
+    if *stage != got {
+        return Err(Error::UnexpectedHandshakeStage {
+            expected: *stage,
+            got,
+        });
+    }
+    *stage = next;
+    Ok(())
+}
 
-pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
+pub(crate) trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
     fn name(&self) -> String;
     fn get_h(&mut self) -> &mut [u8; 32];
 
@@ -24,13 +73,15 @@ pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
         *h = Sha256Hash::hash(&to_hash).to_byte_array();
     }
 
-    fn generate_key() -> Keypair {
+    fn generate_key() -> SecretKeypair {
         let secp = Secp256k1::new();
-        let (secret_key, _) = secp.generate_keypair(&mut rand::thread_rng());
-        let kp = Keypair::from_secret_key(&secp, &secret_key);
+        let (mut secret_key, _) = secp.generate_keypair(&mut rand::thread_rng());
+        let mut kp = Keypair::from_secret_key(&secp, &secret_key);
+        secret_key.non_secure_erase();
         if kp.x_only_public_key().1 == crate::PARITY {
-            kp
+            SecretKeypair::new(&mut kp)
         } else {
+            kp.non_secure_erase();
             Self::generate_key()
         }
     }
@@ -60,9 +111,10 @@ pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
     }
 
     fn hkdf_2(chaining_key: &[u8; 32], input_key_material: &[u8]) -> ([u8; 32], [u8; 32]) {
-        let temp_key = Self::hmac_hash(chaining_key, input_key_material);
+        let mut temp_key = Self::hmac_hash(chaining_key, input_key_material);
         let out_1 = Self::hmac_hash(&temp_key, &[0x1]);
         let out_2 = Self::hmac_hash(&temp_key, &[&out_1[..], &[0x2][..]].concat());
+        temp_key.zeroize();
         (out_1, out_2)
     }
 
@@ -70,18 +122,20 @@ pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
         chaining_key: &[u8; 32],
         input_key_material: &[u8],
     ) -> ([u8; 32], [u8; 32], [u8; 32]) {
-        let temp_key = Self::hmac_hash(chaining_key, input_key_material);
+        let mut temp_key = Self::hmac_hash(chaining_key, input_key_material);
         let out_1 = Self::hmac_hash(&temp_key, &[0x1]);
         let out_2 = Self::hmac_hash(&temp_key, &[&out_1[..], &[0x2][..]].concat());
         let out_3 = Self::hmac_hash(&temp_key, &[&out_2[..], &[0x3][..]].concat());
+        temp_key.zeroize();
         (out_1, out_2, out_3)
     }
 
     fn mix_key(&mut self, input_key_material: &[u8]) {
         let ck = self.get_ck();
-        let (ck, temp_k) = Self::hkdf_2(ck, input_key_material);
+        let (ck, mut temp_k) = Self::hkdf_2(ck, input_key_material);
         self.set_ck(ck);
         self.initialize_key(temp_k);
+        temp_k.zeroize();
     }
 
     fn encrypt_and_hash(&mut self, plaintext: &mut Vec<u8>) -> Result<(), aes_gcm::Error> {
@@ -138,6 +192,52 @@ pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
     fn set_handshake_cipher(&mut self, cipher: ChaCha20Poly1305);
 }
 
+/// Runs the handshake as initiator: sends the first message with `send`, receives the reply of
+/// the responder with `recv` and returns the codec to use in transport mode.
+///
+/// The errors of `send` and `recv` are returned as they are, the errors of the handshake are
+/// converted into `E`.
+pub async fn run_initiator<E, S, SF, R, RF>(
+    initiator: &mut Initiator,
+    send: S,
+    recv: R,
+) -> Result<NoiseCodec, E>
+where
+    E: From<Error>,
+    S: FnOnce([u8; RESPONDER_EXPECTED_HANDSHAKE_MESSAGE_SIZE]) -> SF,
+    SF: core::future::Future<Output = Result<(), E>>,
+    R: FnOnce() -> RF,
+    RF: core::future::Future<Output = Result<[u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE], E>>,
+{
+    let first_message = initiator.step_0()?;
+    send(first_message).await?;
+    let second_message = recv().await?;
+    Ok(initiator.step_2(second_message)?)
+}
+
+/// Runs the handshake as responder: receives the first message of the initiator with `recv`,
+/// sends the reply with `send` and returns the codec to use in transport mode.
+///
+/// The handshake is complete for the responder once the reply is built, so the codec can be used
+/// as soon as `send` returns.
+pub async fn run_responder<E, S, SF, R, RF>(
+    responder: &mut Responder,
+    send: S,
+    recv: R,
+) -> Result<NoiseCodec, E>
+where
+    E: From<Error>,
+    S: FnOnce([u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE]) -> SF,
+    SF: core::future::Future<Output = Result<(), E>>,
+    R: FnOnce() -> RF,
+    RF: core::future::Future<Output = Result<[u8; RESPONDER_EXPECTED_HANDSHAKE_MESSAGE_SIZE], E>>,
+{
+    let first_message = recv().await?;
+    let (second_message, codec) = responder.step_1(first_message)?;
+    send(second_message).await?;
+    Ok(codec)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1,13 +1,12 @@
-use std::{convert::TryInto, ptr};
+use std::{convert::TryInto, time::Duration};
 
 use crate::{
-    cipher_state::{Cipher, CipherState, GenericCipher},
+    cipher_state::{CipherState, GenericCipher},
     error::Error,
-    handshake::HandshakeOp,
+    handshake::{enter_stage, HandshakeOp, HANDSHAKE_DONE},
     signature_message::SignatureNoiseMessage,
-    NoiseCodec,
+    EncryptionAlgorithm, NoiseCodec, SecretKeypair,
 };
-use aes_gcm::KeyInit;
 use chacha20poly1305::ChaCha20Poly1305;
 use const_sv2::{
     ELLSWIFT_ENCODING_SIZE, ENCRYPTED_ELLSWIFT_ENCODING_SIZE,
@@ -16,8 +15,10 @@ use const_sv2::{
 };
 use secp256k1::{
     ellswift::{ElligatorSwift, ElligatorSwiftParty},
-    Keypair, PublicKey, XOnlyPublicKey,
+    PublicKey, XOnlyPublicKey,
 };
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
 pub struct Initiator {
     handshake_cipher: Option<ChaCha20Poly1305>,
@@ -28,12 +29,26 @@ pub struct Initiator {
     // Handshake hash
     h: [u8; 32],
     // ephemeral keypair
-    e: Keypair,
-    // upstream pub key
-    #[allow(unused)]
-    responder_authority_pk: Option<XOnlyPublicKey>,
+    e: SecretKeypair,
+    // upstream authority pub keys, the certificate must be signed by one of them. Empty when the
+    // responder is not authenticated
+    responder_authority_pks: Vec<XOnlyPublicKey>,
+    // verify the responder certificate against the responder static key
+    accept_self_signed: bool,
+    // pinned responder static key
+    expected_remote_static_key: Option<XOnlyPublicKey>,
     c1: Option<GenericCipher>,
     c2: Option<GenericCipher>,
+    // set when the handshake is completed
+    chosen_algorithm: Option<EncryptionAlgorithm>,
+    // responder static pub key, set when the handshake is completed
+    remote_static_key: Option<XOnlyPublicKey>,
+    // certificate of the responder static key, set when the handshake is completed
+    certificate: Option<SignatureNoiseMessage>,
+    // time allowed to the handshake I/O, see `handshake_timeout`
+    handshake_timeout: Option<Duration>,
+    // next handshake step that can be called
+    stage: u8,
 }
 
 impl std::fmt::Debug for Initiator {
@@ -111,14 +126,81 @@ impl Initiator {
             ck: [0; 32],
             h: [0; 32],
             e: Self::generate_key(),
-            responder_authority_pk: pk,
+            responder_authority_pks: pk.into_iter().collect(),
+            accept_self_signed: false,
             c1: None,
             c2: None,
+            chosen_algorithm: None,
+            remote_static_key: None,
+            expected_remote_static_key: None,
+            certificate: None,
+            handshake_timeout: None,
+            stage: 0,
         };
         self_.initialize_self();
         Box::new(self_)
     }
 
+    /// Like `new` but trusts several authority keys: the certificate of the responder is accepted
+    /// if it is signed by any of them. Lets a pool rotate its authority key while its downstreams
+    /// are configured with both the old and the new key. An empty list does not authenticate the
+    /// responder, as `new(None)`.
+    pub fn with_authority_keys(keys: Vec<XOnlyPublicKey>) -> Box<Self> {
+        let mut self_ = Self::new(None);
+        self_.responder_authority_pks = keys;
+        self_
+    }
+
+    /// Initiator that accepts any responder whose certificate is validly signed by its own static
+    /// key, see `Responder::new_self_signed`. The responder is not authenticated so this is only
+    /// meant for tests and development setups.
+    #[cfg(feature = "dangerous-accept-self-signed")]
+    pub fn new_accept_self_signed() -> Box<Self> {
+        let mut self_ = Self::new(None);
+        self_.accept_self_signed = true;
+        self_
+    }
+
+    /// Algorithm used by the `NoiseCodec` returned by the handshake, `None` until the handshake
+    /// is completed. The handshake does not negotiate the algorithm so the initiator always
+    /// uses `EncryptionAlgorithm::DEFAULT`.
+    pub fn chosen_algorithm(&self) -> Option<EncryptionAlgorithm> {
+        self.chosen_algorithm
+    }
+
+    /// Static key of the responder, `None` until the handshake is completed. The key has been
+    /// verified against the authority key (if any) so it can be pinned by the caller.
+    pub fn remote_static_key(&self) -> Option<XOnlyPublicKey> {
+        self.remote_static_key
+    }
+
+    /// Certificate of the responder static key, `None` until the handshake is completed. Can be
+    /// used to check how long the certificate is still valid.
+    pub fn certificate(&self) -> Option<&SignatureNoiseMessage> {
+        self.certificate.as_ref()
+    }
+
+    /// Time allowed to the whole handshake, from sending the first message to receiving the
+    /// reply of the responder. See `handshake_timeout`.
+    pub fn with_timeout(mut self: Box<Self>, timeout: Duration) -> Box<Self> {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Timeout set with `with_timeout`. The steps do not measure time, as a stalled responder
+    /// would never let `step_2` run: the timeout must be enforced by the code that sends and
+    /// receives the handshake messages.
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake_timeout
+    }
+
+    /// Fail the handshake with `Error::StaticKeyMismatch` if the responder static key is not
+    /// `key`, even when its certificate is valid.
+    pub fn with_expected_static_key(mut self: Box<Self>, key: XOnlyPublicKey) -> Box<Self> {
+        self.expected_remote_static_key = Some(key);
+        self
+    }
+
     /// #### 4.5.1.1 Initiator
     ///
     /// Initiator generates ephemeral keypair and sends the public key to the responder:
@@ -136,7 +218,8 @@ impl Initiator {
     /// | PUBKEY     | Initiator's ephemeral public key |
     ///
     /// Message length: 64 bytes
-    pub fn step_0(&mut self) -> Result<[u8; ELLSWIFT_ENCODING_SIZE], aes_gcm::Error> {
+    pub fn step_0(&mut self) -> Result<[u8; ELLSWIFT_ENCODING_SIZE], Error> {
+        enter_stage(&mut self.stage, 0, 2)?;
         let elliswift_enc_pubkey = ElligatorSwift::from_pubkey(self.e.public_key()).to_array();
         self.mix_hash(&elliswift_enc_pubkey);
         self.encrypt_and_hash(&mut vec![])?;
@@ -167,6 +250,8 @@ impl Initiator {
         &mut self,
         message: [u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE],
     ) -> Result<NoiseCodec, Error> {
+        enter_stage(&mut self.stage, 2, HANDSHAKE_DONE)?;
+
         // 2. interprets first 64 bytes as ElligatorSwift encoding of x-coordinate of public key
         // from this is derived the 32-bytes remote ephemeral public key `re.public_key`
         let mut elliswift_theirs_ephemeral_serialized: [u8; ELLSWIFT_ENCODING_SIZE] =
@@ -176,11 +261,11 @@ impl Initiator {
 
         // 3. calls `MixHash(re.public_key)`
         // 4. calls `MixKey(ECDH(e.private_key, re.public_key))`
-        let e_private_key = self.e.secret_key();
+        let mut e_private_key = self.e.secret_key();
         let elligatorswift_ours_ephemeral = ElligatorSwift::from_pubkey(self.e.public_key());
         let elligatorswift_theirs_ephemeral =
             ElligatorSwift::from_array(elliswift_theirs_ephemeral_serialized);
-        let ecdh_ephemeral: [u8; 32] = ElligatorSwift::shared_secret(
+        let mut ecdh_ephemeral: [u8; 32] = ElligatorSwift::shared_secret(
             elligatorswift_ours_ephemeral,
             elligatorswift_theirs_ephemeral,
             e_private_key,
@@ -189,6 +274,7 @@ impl Initiator {
         )
         .to_secret_bytes();
         self.mix_key(&ecdh_ephemeral);
+        ecdh_ephemeral.zeroize();
 
         // 5. decrypts next 80 bytes with `DecryptAndHash()` and stores the results as
         // `rs.public_key` which is **server's static public key** (note that 64 bytes is the
@@ -204,7 +290,7 @@ impl Initiator {
             .expect("slice with incorrect length");
         let elligatorswift_theirs_static =
             ElligatorSwift::from_array(elligatorswift_theirs_static_serialized);
-        let ecdh_static: [u8; 32] = ElligatorSwift::shared_secret(
+        let mut ecdh_static: [u8; 32] = ElligatorSwift::shared_secret(
             elligatorswift_ours_ephemeral,
             elligatorswift_theirs_static,
             e_private_key,
@@ -213,6 +299,8 @@ impl Initiator {
         )
         .to_secret_bytes();
         self.mix_key(&ecdh_static);
+        ecdh_static.zeroize();
+        e_private_key.non_secure_erase();
 
         // Decrypt and verify the SignatureNoiseMessage
         let mut to_decrypt = message[ELLSWIFT_ENCODING_SIZE + ENCRYPTED_ELLSWIFT_ENCODING_SIZE
@@ -230,47 +318,53 @@ impl Initiator {
             .0
             .serialize();
         let rs_pk_xonly = XOnlyPublicKey::from_slice(&rs_pub_key).unwrap();
-        if signature_message.verify(&rs_pk_xonly, &self.responder_authority_pk) {
-            let (temp_k1, temp_k2) = Self::hkdf_2(self.get_ck(), &[]);
-            let c1 = ChaCha20Poly1305::new(&temp_k1.into());
-            let c2 = ChaCha20Poly1305::new(&temp_k2.into());
-            let c1: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k1, c1);
-            let c2: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k2, c2);
-            self.c1 = None;
-            self.c2 = None;
-            let mut encryptor = GenericCipher::ChaCha20Poly1305(c1);
-            let mut decryptor = GenericCipher::ChaCha20Poly1305(c2);
-            encryptor.erase_k();
-            decryptor.erase_k();
-            let codec = crate::NoiseCodec {
-                encryptor,
-                decryptor,
-            };
-            Ok(codec)
+        if self.accept_self_signed {
+            signature_message.verify_self_signed(&rs_pk_xonly)?;
         } else {
-            Err(Error::InvalidCertificate(plaintext))
+            signature_message.verify_any(&rs_pk_xonly, &self.responder_authority_pks)?;
+        }
+        if let Some(expected) = self.expected_remote_static_key {
+            let is_expected: bool = expected.serialize()[..].ct_eq(&rs_pub_key[..]).into();
+            if !is_expected {
+                return Err(Error::StaticKeyMismatch);
+            }
         }
+        let (mut temp_k1, mut temp_k2) = Self::hkdf_2(self.get_ck(), &[]);
+        let algorithm = EncryptionAlgorithm::DEFAULT;
+        let encryptor = GenericCipher::for_transport(algorithm, temp_k1);
+        let decryptor = GenericCipher::for_transport(algorithm, temp_k2);
+        temp_k1.zeroize();
+        temp_k2.zeroize();
+        self.c1 = None;
+        self.c2 = None;
+        self.chosen_algorithm = Some(algorithm);
+        self.remote_static_key = Some(rs_pk_xonly);
+        self.certificate = Some(signature_message);
+        // The handshake is done, nothing left to do with the handshake secrets
+        self.erase();
+        let codec = crate::NoiseCodec {
+            encryptor,
+            decryptor,
+            sent_since_rekey: 0,
+            received_since_rekey: 0,
+            remote_static_key: Some(rs_pk_xonly),
+        };
+        Ok(codec)
     }
 
     fn erase(&mut self) {
         if let Some(k) = self.k.as_mut() {
-            for b in k {
-                unsafe { ptr::write_volatile(b, 0) };
-            }
-        }
-        for mut b in self.ck {
-            unsafe { ptr::write_volatile(&mut b, 0) };
-        }
-        for mut b in self.h {
-            unsafe { ptr::write_volatile(&mut b, 0) };
+            k.zeroize();
         }
+        self.ck.zeroize();
+        self.h.zeroize();
         if let Some(c1) = self.c1.as_mut() {
             c1.erase_k()
         }
         if let Some(c2) = self.c2.as_mut() {
             c2.erase_k()
         }
-        self.e.non_secure_erase();
+        self.e.zeroize();
     }
 }
 impl Drop for Initiator {
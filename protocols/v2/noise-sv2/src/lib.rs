@@ -5,12 +5,14 @@
 use aes_gcm::aead::Buffer;
 pub use aes_gcm::aead::Error as AeadError;
 use cipher_state::GenericCipher;
+use secp256k1::XOnlyPublicKey;
 mod aed_cipher;
 mod cipher_state;
 mod error;
-mod handshake;
+pub mod handshake;
 mod initiator;
 mod responder;
+mod secret_keypair;
 mod signature_message;
 #[cfg(test)]
 mod test;
@@ -19,9 +21,59 @@ pub use const_sv2::{NOISE_HASHED_PROTOCOL_NAME_CHACHA, NOISE_SUPPORTED_CIPHERS_M
 
 const PARITY: secp256k1::Parity = secp256k1::Parity::Even;
 
+/// AEAD algorithm used to encrypt transport messages after the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionAlgorithm {
+    ChaChaPoly,
+    AesGcm,
+}
+
+impl EncryptionAlgorithm {
+    /// Algorithms that the handshake can end on. The handshake messages are always encrypted
+    /// with ChaChaPoly, as fixed by the protocol name
+    /// (Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256), the algorithm only applies to the
+    /// transport messages.
+    pub const SUPPORTED: [EncryptionAlgorithm; 2] =
+        [EncryptionAlgorithm::ChaChaPoly, EncryptionAlgorithm::AesGcm];
+
+    /// Algorithm used by the initiators for the transport, the handshake does not negotiate it
+    pub const DEFAULT: EncryptionAlgorithm = EncryptionAlgorithm::ChaChaPoly;
+
+    /// Check a list of algorithms passed by the caller: it must be non empty, without duplicates
+    /// and contain at least one supported algorithm.
+    fn check_list(algorithms: &[EncryptionAlgorithm]) -> Result<(), Error> {
+        let tags = || algorithms.iter().flat_map(|a| a.tag()).collect::<Vec<u8>>();
+        if algorithms.is_empty() {
+            return Err(Error::CipherListMustBeNonEmpty);
+        }
+        for (i, algorithm) in algorithms.iter().enumerate() {
+            if algorithms[..i].contains(algorithm) {
+                return Err(Error::InvalidCipherList(tags()));
+            }
+        }
+        if !algorithms.iter().any(|a| Self::SUPPORTED.contains(a)) {
+            return Err(Error::UnsupportedCiphers(tags()));
+        }
+        Ok(())
+    }
+
+    /// 4 bytes ASCII tag of the algorithm
+    pub fn tag(&self) -> [u8; 4] {
+        match self {
+            EncryptionAlgorithm::ChaChaPoly => *b"CHCH",
+            EncryptionAlgorithm::AesGcm => *b"AESG",
+        }
+    }
+}
+
 pub struct NoiseCodec {
     encryptor: GenericCipher,
     decryptor: GenericCipher,
+    // messages encrypted since the last `rekey_outgoing`
+    sent_since_rekey: u64,
+    // messages decrypted since the last `rekey_incoming`
+    received_since_rekey: u64,
+    remote_static_key: Option<XOnlyPublicKey>,
 }
 
 impl std::fmt::Debug for NoiseCodec {
@@ -32,13 +84,87 @@ impl std::fmt::Debug for NoiseCodec {
 
 impl NoiseCodec {
     pub fn encrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
-        self.encryptor.encrypt(msg)
+        self.encryptor.encrypt(msg)?;
+        self.sent_since_rekey += 1;
+        Ok(())
     }
     pub fn decrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
-        self.decryptor.decrypt(msg)
+        self.decryptor.decrypt(msg)?;
+        self.received_since_rekey += 1;
+        Ok(())
+    }
+
+    /// Like `encrypt` but allocate and return a correctly sized buffer
+    pub fn encrypt_to_vec(&mut self, plain: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut msg = Vec::with_capacity(plain.len() + const_sv2::AEAD_MAC_LEN);
+        msg.extend_from_slice(plain);
+        self.encrypt(&mut msg)?;
+        Ok(msg)
+    }
+
+    /// Like `decrypt` but allocate and return a correctly sized buffer
+    pub fn decrypt_to_vec(&mut self, cipher: &[u8]) -> Result<Vec<u8>, Error> {
+        if cipher.len() < const_sv2::AEAD_MAC_LEN {
+            return Err(Error::InvalidMessageLength);
+        }
+        let mut msg = cipher.to_vec();
+        self.decrypt(&mut msg)?;
+        Ok(msg)
+    }
+
+    pub fn encryption_algorithm(&self) -> EncryptionAlgorithm {
+        self.encryptor.algorithm()
+    }
+
+    /// Static key of the remote peer, verified against the authority key during the handshake.
+    /// Only the initiator learns the remote static key so this is `None` on the responder side.
+    pub fn remote_static_key(&self) -> Option<XOnlyPublicKey> {
+        self.remote_static_key
+    }
+
+    /// Rotate the key used to encrypt outgoing messages.
+    ///
+    /// Rekeying is not signaled on the wire: the peer must call `rekey_incoming` exactly after
+    /// having decrypted the last message encrypted with the old key, and before decrypting the
+    /// first one encrypted with the new key. Usually each direction is rekeyed after an agreed
+    /// number of messages (see `should_rekey_outgoing` and `should_rekey_incoming`).
+    pub fn rekey_outgoing(&mut self) -> Result<(), aes_gcm::Error> {
+        self.encryptor.rekey()?;
+        self.sent_since_rekey = 0;
+        Ok(())
+    }
+
+    /// Rotate the key used to decrypt incoming messages. See `rekey_outgoing` for the ordering
+    /// requirements.
+    pub fn rekey_incoming(&mut self) -> Result<(), aes_gcm::Error> {
+        self.decryptor.rekey()?;
+        self.received_since_rekey = 0;
+        Ok(())
+    }
+
+    /// Number of messages encrypted since the last `rekey_outgoing`
+    pub fn messages_sent_since_rekey(&self) -> u64 {
+        self.sent_since_rekey
+    }
+
+    /// Number of messages decrypted since the last `rekey_incoming`
+    pub fn messages_received_since_rekey(&self) -> u64 {
+        self.received_since_rekey
+    }
+
+    /// True when `threshold` messages have been encrypted since the last `rekey_outgoing`
+    pub fn should_rekey_outgoing(&self, threshold: u64) -> bool {
+        self.sent_since_rekey >= threshold
+    }
+
+    /// True when `threshold` messages have been decrypted since the last `rekey_incoming`
+    pub fn should_rekey_incoming(&self, threshold: u64) -> bool {
+        self.received_since_rekey >= threshold
     }
 }
 
 pub use error::Error;
 pub use initiator::Initiator;
 pub use responder::Responder;
+pub use secret_keypair::SecretKeypair;
+pub use signature_message::SignatureNoiseMessage;
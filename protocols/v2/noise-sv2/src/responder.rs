@@ -1,19 +1,21 @@
-use std::{ptr, time::Duration};
+use std::{convert::TryFrom, time::Duration};
 
 use crate::{
-    cipher_state::{Cipher, CipherState, GenericCipher},
+    cipher_state::{CipherState, GenericCipher},
     error::Error,
-    handshake::HandshakeOp,
+    handshake::{enter_stage, HandshakeOp, HANDSHAKE_DONE},
     signature_message::SignatureNoiseMessage,
-    NoiseCodec,
+    EncryptionAlgorithm, NoiseCodec, SecretKeypair,
 };
-use aes_gcm::KeyInit;
 use chacha20poly1305::ChaCha20Poly1305;
 use const_sv2::{
     ELLSWIFT_ENCODING_SIZE, ENCRYPTED_ELLSWIFT_ENCODING_SIZE,
     ENCRYPTED_SIGNATURE_NOISE_MESSAGE_SIZE, INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE,
+    SIGNATURE_NOISE_MESSAGE_SIZE,
 };
-use secp256k1::{ellswift::ElligatorSwift, Keypair, Secp256k1, SecretKey};
+use secp256k1::{ellswift::ElligatorSwift, Keypair, Secp256k1, SecretKey, XOnlyPublicKey};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
 const VERSION: u16 = 0;
 
@@ -26,14 +28,25 @@ pub struct Responder {
     // Handshake hash
     h: [u8; 32],
     // ephemeral keypair
-    e: Keypair,
+    e: SecretKeypair,
     // Static pub keypair
-    s: Keypair,
+    s: SecretKeypair,
     // Authority pub keypair
-    a: Keypair,
+    a: SecretKeypair,
     c1: Option<GenericCipher>,
     c2: Option<GenericCipher>,
+    // algorithms accepted for the transport
+    algorithms: Vec<EncryptionAlgorithm>,
+    // set when the handshake is completed
+    chosen_algorithm: Option<EncryptionAlgorithm>,
     cert_validity: u32,
+    // certificate set by `refresh_certificate`, when `None` step_1 signs one valid for
+    // `cert_validity` from then
+    certificate: Option<[u8; SIGNATURE_NOISE_MESSAGE_SIZE]>,
+    // time allowed to the handshake I/O, see `handshake_timeout`
+    handshake_timeout: Option<Duration>,
+    // next handshake step that can be called
+    stage: u8,
 }
 
 impl std::fmt::Debug for Responder {
@@ -93,23 +106,29 @@ impl HandshakeOp<ChaCha20Poly1305> for Responder {
 }
 
 impl Responder {
+    /// Responder whose certificate is signed by the authority keypair `public`, `private`.
+    /// `private` is consumed: it is erased once the keypair is built.
     pub fn from_authority_kp(
         public: &[u8; 32],
-        private: &[u8; 32],
+        mut private: [u8; 32],
         cert_validity: Duration,
     ) -> Result<Box<Self>, Error> {
         let secp = Secp256k1::new();
-        let secret = SecretKey::from_slice(private).map_err(|_| Error::InvalidRawPrivateKey)?;
-        let kp = Keypair::from_secret_key(&secp, &secret);
+        let secret = SecretKey::from_slice(&private);
+        private.zeroize();
+        let mut secret = secret.map_err(|_| Error::InvalidRawPrivateKey)?;
+        let kp = SecretKeypair::new(&mut Keypair::from_secret_key(&secp, &secret));
+        secret.non_secure_erase();
         let pub_ = kp.x_only_public_key().0.serialize();
-        if public == &pub_[..] {
-            Ok(Self::new(kp, cert_validity.as_secs() as u32))
+        let cert_validity = Self::cert_validity_secs(cert_validity)?;
+        if public[..].ct_eq(&pub_[..]).into() {
+            Ok(Self::new(kp, cert_validity))
         } else {
             Err(Error::InvalidRawPublicKey)
         }
     }
 
-    pub fn new(a: Keypair, cert_validity: u32) -> Box<Self> {
+    pub fn new(a: SecretKeypair, cert_validity: u32) -> Box<Self> {
         let mut self_ = Self {
             handshake_cipher: None,
             k: None,
@@ -121,12 +140,101 @@ impl Responder {
             a,
             c1: None,
             c2: None,
+            algorithms: vec![EncryptionAlgorithm::DEFAULT],
+            chosen_algorithm: None,
             cert_validity,
+            certificate: None,
+            handshake_timeout: None,
+            stage: 1,
         };
         Self::initialize_self(&mut self_);
         Box::new(self_)
     }
 
+    /// Like `new` but let the caller list the algorithms it is willing to use for the transport.
+    /// The list must be non empty, without duplicates and contain at least one algorithm
+    /// supported by the handshake, so a responder can not be configured to only accept
+    /// algorithms that it can not use.
+    ///
+    /// The handshake does not negotiate the algorithm: initiators always use
+    /// `EncryptionAlgorithm::DEFAULT`, so `step_1` fails with `Error::InvalidCipherChosed` when
+    /// it is not in the list.
+    pub fn with_algorithms(
+        a: SecretKeypair,
+        cert_validity: u32,
+        algorithms: Vec<EncryptionAlgorithm>,
+    ) -> Result<Box<Self>, Error> {
+        EncryptionAlgorithm::check_list(&algorithms)?;
+        let mut self_ = Self::new(a, cert_validity);
+        self_.algorithms = algorithms;
+        Ok(self_)
+    }
+
+    /// Like `new` but the static key is also the key that signs the certificate, so the
+    /// responder does not need an authority. Only initiators built with
+    /// `Initiator::new_accept_self_signed` or without an authority key accept it.
+    pub fn new_self_signed(cert_validity: u32) -> Box<Self> {
+        let kp = Self::generate_key();
+        let mut self_ = Self::new(kp.clone(), cert_validity);
+        self_.s = kp;
+        self_
+    }
+
+    /// Algorithm used by the `NoiseCodec` returned by the handshake, `None` until the handshake
+    /// is completed.
+    pub fn chosen_algorithm(&self) -> Option<EncryptionAlgorithm> {
+        self.chosen_algorithm
+    }
+
+    /// Static key sent to the initiator and signed by the authority key
+    pub fn static_public_key(&self) -> XOnlyPublicKey {
+        self.s.x_only_public_key().0
+    }
+
+    /// Signs the static key with `authority` for `validity` from now and sends this certificate in
+    /// the handshake instead of the one signed by the previous authority key. The static key does
+    /// not change, so the responder does not need to be rebuilt when its certificate is renewed
+    /// or the authority key is rotated.
+    ///
+    /// The certificate is sent by `step_1`: a handshake that already went past it keeps using
+    /// the old certificate.
+    pub fn refresh_certificate(
+        &mut self,
+        authority: SecretKeypair,
+        validity: Duration,
+    ) -> Result<(), Error> {
+        let validity = Self::cert_validity_secs(validity)?;
+        let valid_from = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        self.a = authority;
+        self.cert_validity = validity;
+        self.certificate =
+            Some(self.get_signature(VERSION, valid_from, valid_from.saturating_add(validity)));
+        Ok(())
+    }
+
+    // The certificate carries its validity bounds as u32 seconds
+    fn cert_validity_secs(validity: Duration) -> Result<u32, Error> {
+        u32::try_from(validity.as_secs())
+            .map_err(|_| Error::InvalidCertificateValidity(validity.as_secs()))
+    }
+
+    /// Time allowed to the whole handshake, from the connection of the initiator to sending the
+    /// reply. See `handshake_timeout`.
+    pub fn with_timeout(mut self: Box<Self>, timeout: Duration) -> Box<Self> {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Timeout set with `with_timeout`. The responder has a single step, that can only run once
+    /// the first message of the initiator has been received: the timeout must be enforced by the
+    /// code that receives and sends the handshake messages.
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake_timeout
+    }
+
     /// #### 4.5.1.2 Responder
     ///
     /// 1. receives ephemeral public key message with ElligatorSwift encoding (64 bytes plaintext)
@@ -164,15 +272,20 @@ impl Responder {
     pub fn step_1(
         &mut self,
         elligatorswift_theirs_ephemeral_serialized: [u8; ELLSWIFT_ENCODING_SIZE],
-    ) -> Result<([u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE], NoiseCodec), aes_gcm::Error> {
+    ) -> Result<([u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE], NoiseCodec), Error> {
+        enter_stage(&mut self.stage, 1, HANDSHAKE_DONE)?;
+
         // 4.5.1.2 Responder
+        let algorithm = EncryptionAlgorithm::DEFAULT;
+        if !self.algorithms.contains(&algorithm) {
+            return Err(Error::InvalidCipherChosed(algorithm.tag().to_vec()));
+        }
         Self::mix_hash(self, &elligatorswift_theirs_ephemeral_serialized[..]);
         Self::decrypt_and_hash(self, &mut vec![])?;
 
         // 4.5.2.1 Responder
         let mut out = [0; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE];
-        let keypair = self.e;
-        let elligatorswitf_ours_ephemeral = ElligatorSwift::from_pubkey(keypair.public_key());
+        let elligatorswitf_ours_ephemeral = ElligatorSwift::from_pubkey(self.e.public_key());
         let elligatorswift_ours_ephemeral_serialized = elligatorswitf_ours_ephemeral.to_array();
         out[..ELLSWIFT_ENCODING_SIZE]
             .copy_from_slice(&elligatorswift_ours_ephemeral_serialized[..ELLSWIFT_ENCODING_SIZE]);
@@ -184,10 +297,10 @@ impl Responder {
         Self::mix_hash(self, &elligatorswift_ours_ephemeral_serialized);
 
         // 4. calls `MixKey(ECDH(e.private_key, re.public_key))`
-        let e_private_key = keypair.secret_key();
+        let mut e_private_key = self.e.secret_key();
         let elligatorswift_theirs_ephemeral =
             ElligatorSwift::from_array(elligatorswift_theirs_ephemeral_serialized);
-        let ecdh_ephemeral = ElligatorSwift::shared_secret(
+        let mut ecdh_ephemeral = ElligatorSwift::shared_secret(
             elligatorswift_theirs_ephemeral,
             elligatorswitf_ours_ephemeral,
             e_private_key,
@@ -196,6 +309,8 @@ impl Responder {
         )
         .to_secret_bytes();
         Self::mix_key(self, &ecdh_ephemeral);
+        ecdh_ephemeral.zeroize();
+        e_private_key.non_secure_erase();
 
         // 5. appends `EncryptAndHash(s.public_key)` (64 bytes encrypted elligatorswift  public key, 16 bytes MAC)
         let mut encrypted_static_pub_k = vec![0; ELLSWIFT_ENCODING_SIZE];
@@ -210,8 +325,8 @@ impl Responder {
         // note: 64+16+64 = 144
 
         // 6. calls `MixKey(ECDH(s.private_key, re.public_key))`
-        let s_private_key = self.s.secret_key();
-        let ecdh_static = ElligatorSwift::shared_secret(
+        let mut s_private_key = self.s.secret_key();
+        let mut ecdh_static = ElligatorSwift::shared_secret(
             elligatorswift_theirs_ephemeral,
             elligatorswift_ours_static,
             s_private_key,
@@ -220,15 +335,21 @@ impl Responder {
         )
         .to_secret_bytes();
         Self::mix_key(self, &ecdh_static[..]);
+        ecdh_static.zeroize();
+        s_private_key.non_secure_erase();
 
         // 7. appends `EncryptAndHash(SIGNATURE_NOISE_MESSAGE)` to the buffer
-        let valid_from = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let not_valid_after = valid_from as u32 + self.cert_validity;
-        let signature_noise_message =
-            self.get_signature(VERSION, valid_from as u32, not_valid_after);
+        let signature_noise_message = match self.certificate {
+            Some(certificate) => certificate,
+            None => {
+                let valid_from = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as u32;
+                let not_valid_after = valid_from.saturating_add(self.cert_validity);
+                self.get_signature(VERSION, valid_from, not_valid_after)
+            }
+        };
         let mut signature_part = Vec::with_capacity(ENCRYPTED_SIGNATURE_NOISE_MESSAGE_SIZE);
         signature_part.extend_from_slice(&signature_noise_message[..]);
         Self::encrypt_and_hash(self, &mut signature_part)?;
@@ -239,21 +360,24 @@ impl Responder {
 
         // 9. return pair of CipherState objects, the first for encrypting transport messages from initiator to responder, and the second for messages in the other direction:
         let ck = Self::get_ck(self);
-        let (temp_k1, temp_k2) = Self::hkdf_2(ck, &[]);
-        let c1 = ChaCha20Poly1305::new(&temp_k1.into());
-        let c2 = ChaCha20Poly1305::new(&temp_k2.into());
-        let c1: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k1, c1);
-        let c2: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k2, c2);
+        let (mut temp_k1, mut temp_k2) = Self::hkdf_2(ck, &[]);
+        let encryptor = GenericCipher::for_transport(algorithm, temp_k2);
+        let decryptor = GenericCipher::for_transport(algorithm, temp_k1);
+        temp_k1.zeroize();
+        temp_k2.zeroize();
         let to_send = out;
         self.c1 = None;
         self.c2 = None;
-        let mut encryptor = GenericCipher::ChaCha20Poly1305(c2);
-        let mut decryptor = GenericCipher::ChaCha20Poly1305(c1);
-        encryptor.erase_k();
-        decryptor.erase_k();
+        self.chosen_algorithm = Some(algorithm);
+        // The handshake is done, the static and authority keys are kept as they identify the
+        // responder but the handshake secrets are not needed anymore
+        self.erase_handshake_state();
         let codec = crate::NoiseCodec {
             encryptor,
             decryptor,
+            sent_since_rekey: 0,
+            received_since_rekey: 0,
+            remote_static_key: None,
         };
         Ok((to_send, codec))
     }
@@ -277,27 +401,25 @@ impl Responder {
         ret
     }
 
-    fn erase(&mut self) {
+    fn erase_handshake_state(&mut self) {
         if let Some(k) = self.k.as_mut() {
-            for b in k {
-                unsafe { ptr::write_volatile(b, 0) };
-            }
-        }
-        for mut b in self.ck {
-            unsafe { ptr::write_volatile(&mut b, 0) };
-        }
-        for mut b in self.h {
-            unsafe { ptr::write_volatile(&mut b, 0) };
+            k.zeroize();
         }
+        self.ck.zeroize();
+        self.h.zeroize();
         if let Some(c1) = self.c1.as_mut() {
             c1.erase_k()
         }
         if let Some(c2) = self.c2.as_mut() {
             c2.erase_k()
         }
-        self.e.non_secure_erase();
-        self.s.non_secure_erase();
-        self.a.non_secure_erase();
+        self.e.zeroize();
+    }
+
+    fn erase(&mut self) {
+        self.erase_handshake_state();
+        self.s.zeroize();
+        self.a.zeroize();
     }
 }
 
@@ -306,3 +428,60 @@ impl Drop for Responder {
         self.erase();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn erase_clears_secret_material() {
+        let mut responder = Responder::new(Responder::generate_key(), 31449600);
+        responder.k = Some([1; 32]);
+        responder.erase();
+
+        assert_eq!(responder.k, Some([0; 32]));
+        assert_eq!(responder.ck, [0; 32]);
+        assert_eq!(responder.h, [0; 32]);
+        // secp256k1 overwrites erased keys with the dummy secret key [1; 32]
+        assert_eq!(responder.e.secret_bytes(), [1; 32]);
+        assert_eq!(responder.s.secret_bytes(), [1; 32]);
+        assert_eq!(responder.a.secret_bytes(), [1; 32]);
+    }
+
+    #[test]
+    fn keys_are_erased_when_the_responder_is_dropped() {
+        let mut responder =
+            std::mem::MaybeUninit::new(*Responder::new(Responder::generate_key(), 31449600));
+        let ptr = responder.as_mut_ptr();
+        // SAFETY: the responder is initialized and not used after the drop, only the keys, that
+        // own no heap memory, are read back from the memory still owned by `responder`
+        let (e, s, a) = unsafe {
+            std::ptr::drop_in_place(ptr);
+            (
+                (*ptr).e.secret_bytes(),
+                (*ptr).s.secret_bytes(),
+                (*ptr).a.secret_bytes(),
+            )
+        };
+        assert_eq!(e, [1; 32]);
+        assert_eq!(s, [1; 32]);
+        assert_eq!(a, [1; 32]);
+    }
+
+    #[test]
+    fn handshake_secrets_are_erased_when_the_handshake_completes() {
+        let authority = Responder::generate_key();
+        let mut initiator = crate::Initiator::new(Some(authority.x_only_public_key().0));
+        let mut responder = Responder::new(authority, 31449600);
+        let first_message = initiator.step_0().unwrap();
+        let (second_message, _codec) = responder.step_1(first_message).unwrap();
+        initiator.step_2(second_message).unwrap();
+
+        assert_eq!(responder.k, Some([0; 32]));
+        assert_eq!(responder.ck, [0; 32]);
+        assert_eq!(responder.h, [0; 32]);
+        assert_eq!(responder.e.secret_bytes(), [1; 32]);
+        // The static key still identifies the responder
+        assert_ne!(responder.s.secret_bytes(), [1; 32]);
+    }
+}
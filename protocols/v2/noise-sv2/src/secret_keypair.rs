@@ -0,0 +1,79 @@
+use secp256k1::Keypair;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// Keypair whose secret key is erased when it is dropped. Used for the static, authority and
+/// ephemeral keys of the handshake so that they do not linger in memory.
+///
+/// Unlike `Keypair` it is not `Copy`: the secret is only duplicated by an explicit `clone`, and
+/// every copy is erased when dropped.
+#[derive(Clone)]
+pub struct SecretKeypair(Keypair);
+
+impl SecretKeypair {
+    /// Take the secret of `keypair`, that is erased so that the only copy left is the returned
+    /// one.
+    pub fn new(keypair: &mut Keypair) -> Self {
+        let self_ = Self(*keypair);
+        keypair.non_secure_erase();
+        self_
+    }
+}
+
+impl core::ops::Deref for SecretKeypair {
+    type Target = Keypair;
+
+    fn deref(&self) -> &Keypair {
+        &self.0
+    }
+}
+
+impl Zeroize for SecretKeypair {
+    /// secp256k1 overwrites the erased key with the dummy secret key `[1; 32]`
+    fn zeroize(&mut self) {
+        self.0.non_secure_erase();
+    }
+}
+
+impl Drop for SecretKeypair {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for SecretKeypair {}
+
+impl std::fmt::Debug for SecretKeypair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretKeypair").finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{handshake::HandshakeOp, responder::Responder};
+    use std::mem::MaybeUninit;
+
+    #[test]
+    fn secret_is_erased_on_drop() {
+        let mut keypair = MaybeUninit::new(Responder::generate_key());
+        let ptr = keypair.as_mut_ptr();
+        // SAFETY: the keypair is initialized and not used after the drop, only its memory that
+        // is still owned by `keypair` is read
+        let secret = unsafe {
+            assert_ne!((*ptr).0.secret_bytes(), [1; 32]);
+            std::ptr::drop_in_place(ptr);
+            (*ptr).0.secret_bytes()
+        };
+        assert_eq!(secret, [1; 32]);
+    }
+
+    #[test]
+    fn source_keypair_is_erased() {
+        let mut keypair = *Responder::generate_key();
+        let secret = keypair.secret_bytes();
+        let secret_keypair = SecretKeypair::new(&mut keypair);
+        assert_eq!(secret_keypair.secret_bytes(), secret);
+        assert_eq!(keypair.secret_bytes(), [1; 32]);
+    }
+}
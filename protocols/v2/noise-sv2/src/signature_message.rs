@@ -1,6 +1,12 @@
+use crate::error::Error;
 use secp256k1::{hashes::sha256, schnorr::Signature, Keypair, Message, Secp256k1, XOnlyPublicKey};
-use std::{convert::TryInto, time::SystemTime};
+use std::{
+    convert::TryInto,
+    time::{Duration, SystemTime},
+};
 
+/// Certificate sent by the responder: the responder static key signed by the authority key
+#[derive(Debug, Clone, Copy)]
 pub struct SignatureNoiseMessage {
     pub version: u16,
     pub valid_from: u32,
@@ -24,30 +30,76 @@ impl From<[u8; 74]> for SignatureNoiseMessage {
 }
 
 impl SignatureNoiseMessage {
-    pub fn verify(self, pk: &XOnlyPublicKey, authority_pk: &Option<XOnlyPublicKey>) -> bool {
+    pub fn verify(
+        &self,
+        pk: &XOnlyPublicKey,
+        authority_pk: &Option<XOnlyPublicKey>,
+    ) -> Result<(), Error> {
         if let Some(authority_pk) = authority_pk {
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as u32;
-            if self.valid_from <= now && self.not_valid_after >= now {
-                let secp = Secp256k1::verification_only();
-                let (m, s) = self.split();
-                // m = SHA-256(version || valid_from || not_valid_after || server_static_key)
-                let m = [&m[0..10], &pk.serialize()].concat();
-                let m = Message::from_hashed_data::<sha256::Hash>(&m);
-                let s = match Signature::from_slice(&s) {
-                    Ok(s) => s,
-                    _ => return false,
-                };
-                secp.verify_schnorr(&s, &m, authority_pk).is_ok()
-            } else {
-                false
+            let now = now();
+            match self.verify_at(pk, authority_pk, now) {
+                Err(Error::CertificateBadSignature) if self.verify_at(pk, pk, now).is_ok() => {
+                    Err(Error::SelfSignedRejected)
+                }
+                result => result,
             }
         } else {
-            true
+            Ok(())
         }
     }
+
+    /// Like `verify` but accepts a certificate signed by any of `authority_pks`. When no key
+    /// accepts it the error of the last key is returned. An empty list accepts any certificate.
+    pub fn verify_any(
+        &self,
+        pk: &XOnlyPublicKey,
+        authority_pks: &[XOnlyPublicKey],
+    ) -> Result<(), Error> {
+        let mut result = Ok(());
+        for authority_pk in authority_pks {
+            result = self.verify(pk, &Some(*authority_pk));
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Verify a certificate signed by the static key `pk` itself rather than by an authority key.
+    /// This only proves that the responder holds the static key, not who the responder is.
+    pub fn verify_self_signed(&self, pk: &XOnlyPublicKey) -> Result<(), Error> {
+        self.verify_at(pk, pk, now())
+    }
+
+    fn verify_at(
+        &self,
+        pk: &XOnlyPublicKey,
+        authority_pk: &XOnlyPublicKey,
+        now: u32,
+    ) -> Result<(), Error> {
+        if now < self.valid_from {
+            return Err(Error::CertificateNotYetValid);
+        }
+        if now > self.not_valid_after {
+            return Err(Error::CertificateExpired);
+        }
+        let secp = Secp256k1::verification_only();
+        let (m, s) = self.split();
+        // m = SHA-256(version || valid_from || not_valid_after || server_static_key)
+        let m = [&m[0..10], &pk.serialize()].concat();
+        let m = Message::from_hashed_data::<sha256::Hash>(&m);
+        let s = Signature::from_slice(&s).map_err(|_| Error::CertificateBadSignature)?;
+        secp.verify_schnorr(&s, &m, authority_pk)
+            .map_err(|_| Error::CertificateBadSignature)
+    }
+
+    /// Time left before the certificate expires, `None` if it is already expired
+    pub fn time_remaining(&self, now: SystemTime) -> Option<Duration> {
+        let not_valid_after =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(self.not_valid_after as u64);
+        not_valid_after.duration_since(now).ok()
+    }
+
     pub fn sign(msg: &mut [u8; 74], static_pk: &XOnlyPublicKey, kp: &Keypair) {
         let secp = Secp256k1::signing_only();
         let m = [&msg[0..10], &static_pk.serialize()].concat();
@@ -58,7 +110,7 @@ impl SignatureNoiseMessage {
         }
     }
 
-    fn split(self) -> ([u8; 10], [u8; 64]) {
+    fn split(&self) -> ([u8; 10], [u8; 64]) {
         let mut m = [0; 10];
         m[0] = self.version.to_le_bytes()[0];
         m[1] = self.version.to_le_bytes()[1];
@@ -73,3 +125,81 @@ impl SignatureNoiseMessage {
         (m, self.signature)
     }
 }
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{handshake::HandshakeOp, responder::Responder, SecretKeypair};
+
+    fn signed(
+        valid_from: u32,
+        not_valid_after: u32,
+        static_pk: &XOnlyPublicKey,
+    ) -> ([u8; 74], SecretKeypair) {
+        let authority = Responder::generate_key();
+        let mut msg = [0; 74];
+        msg[2..6].copy_from_slice(&valid_from.to_le_bytes());
+        msg[6..10].copy_from_slice(&not_valid_after.to_le_bytes());
+        SignatureNoiseMessage::sign(&mut msg, static_pk, &authority);
+        (msg, authority)
+    }
+
+    #[test]
+    fn certificate_validity() {
+        let static_pk = Responder::generate_key().x_only_public_key().0;
+        let now = 1_000_000;
+        let (msg, authority) = signed(now - 100, now + 100, &static_pk);
+        let authority_pk = authority.x_only_public_key().0;
+        let cert: SignatureNoiseMessage = msg.into();
+
+        assert_eq!(cert.verify_at(&static_pk, &authority_pk, now), Ok(()));
+        assert_eq!(
+            cert.verify_at(&static_pk, &authority_pk, now - 101),
+            Err(Error::CertificateNotYetValid)
+        );
+        assert_eq!(
+            cert.verify_at(&static_pk, &authority_pk, now + 101),
+            Err(Error::CertificateExpired)
+        );
+
+        let other_pk = Responder::generate_key().x_only_public_key().0;
+        assert_eq!(
+            cert.verify_at(&other_pk, &authority_pk, now),
+            Err(Error::CertificateBadSignature)
+        );
+    }
+
+    #[test]
+    fn certificate_for_another_static_key_is_rejected() {
+        let static_pk = Responder::generate_key().x_only_public_key().0;
+        let presented_pk = Responder::generate_key().x_only_public_key().0;
+        let (msg, authority) = signed(0, u32::MAX, &static_pk);
+        let authority_pk = authority.x_only_public_key().0;
+        let cert: SignatureNoiseMessage = msg.into();
+
+        assert_eq!(cert.verify(&static_pk, &Some(authority_pk)), Ok(()));
+        assert_eq!(
+            cert.verify(&presented_pk, &Some(authority_pk)),
+            Err(Error::CertificateBadSignature)
+        );
+    }
+
+    #[test]
+    fn certificate_time_remaining() {
+        let static_pk = Responder::generate_key().x_only_public_key().0;
+        let (msg, _) = signed(0, 1_000_100, &static_pk);
+        let cert: SignatureNoiseMessage = msg.into();
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert_eq!(cert.time_remaining(now), Some(Duration::from_secs(100)));
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_200);
+        assert_eq!(cert.time_remaining(now), None);
+    }
+}
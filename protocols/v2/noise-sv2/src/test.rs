@@ -1,4 +1,14 @@
-use crate::{handshake::HandshakeOp, initiator::Initiator, responder::Responder};
+use crate::{
+    aed_cipher::AeadCipher,
+    cipher_state::{Cipher, CipherState},
+    handshake::{self, HandshakeOp},
+    initiator::Initiator,
+    responder::Responder,
+    EncryptionAlgorithm, Error,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver as Receiver};
 
 #[test]
 fn test_1() {
@@ -16,3 +26,618 @@ fn test_1() {
 
     assert!(message == "ciao".as_bytes().to_vec());
 }
+
+#[test]
+fn handshake_timeout_is_exposed_to_the_caller() {
+    let key_pair = Responder::generate_key();
+
+    let mut initiator =
+        Initiator::new(Some(key_pair.public_key().into())).with_timeout(Duration::from_secs(10));
+    let mut responder =
+        Responder::new(key_pair.clone(), 31449600).with_timeout(Duration::from_secs(5));
+    assert_eq!(initiator.handshake_timeout(), Some(Duration::from_secs(10)));
+    assert_eq!(responder.handshake_timeout(), Some(Duration::from_secs(5)));
+    assert_eq!(Responder::new(key_pair, 31449600).handshake_timeout(), None);
+
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    assert!(initiator.step_2(second_message).is_ok());
+}
+
+#[test]
+fn rekey_both_sides() {
+    let key_pair = Responder::generate_key();
+
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, mut codec_responder) = responder.step_1(first_message).unwrap();
+    let mut codec_initiator = initiator.step_2(second_message).unwrap();
+
+    for _ in 0..3 {
+        let mut message = "ciao".as_bytes().to_vec();
+        codec_initiator.encrypt(&mut message).unwrap();
+        codec_responder.decrypt(&mut message).unwrap();
+        assert!(message == "ciao".as_bytes().to_vec());
+    }
+    assert_eq!(codec_initiator.messages_sent_since_rekey(), 3);
+    assert_eq!(codec_initiator.messages_received_since_rekey(), 0);
+    assert!(codec_responder.should_rekey_incoming(3));
+    assert!(!codec_responder.should_rekey_incoming(4));
+    assert!(!codec_responder.should_rekey_outgoing(1));
+
+    codec_initiator.rekey_outgoing().unwrap();
+    codec_initiator.rekey_incoming().unwrap();
+    codec_responder.rekey_outgoing().unwrap();
+    codec_responder.rekey_incoming().unwrap();
+    assert_eq!(codec_initiator.messages_sent_since_rekey(), 0);
+
+    let mut message = "ciao".as_bytes().to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    codec_responder.decrypt(&mut message).unwrap();
+    assert!(message == "ciao".as_bytes().to_vec());
+
+    let mut message = "hola".as_bytes().to_vec();
+    codec_responder.encrypt(&mut message).unwrap();
+    codec_initiator.decrypt(&mut message).unwrap();
+    assert!(message == "hola".as_bytes().to_vec());
+}
+
+#[test]
+fn rekey_counters_are_per_direction() {
+    let key_pair = Responder::generate_key();
+
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, mut codec_responder) = responder.step_1(first_message).unwrap();
+    let mut codec_initiator = initiator.step_2(second_message).unwrap();
+
+    for _ in 0..2 {
+        let mut message = "ciao".as_bytes().to_vec();
+        codec_initiator.encrypt(&mut message).unwrap();
+        codec_responder.decrypt(&mut message).unwrap();
+    }
+    let mut message = "hola".as_bytes().to_vec();
+    codec_responder.encrypt(&mut message).unwrap();
+    codec_initiator.decrypt(&mut message).unwrap();
+
+    // Rekeying one direction does not reset the counter of the other one
+    codec_initiator.rekey_incoming().unwrap();
+    codec_responder.rekey_outgoing().unwrap();
+    assert_eq!(codec_initiator.messages_sent_since_rekey(), 2);
+    assert_eq!(codec_initiator.messages_received_since_rekey(), 0);
+    assert_eq!(codec_responder.messages_sent_since_rekey(), 0);
+    assert_eq!(codec_responder.messages_received_since_rekey(), 2);
+}
+
+#[test]
+fn rekey_overwrites_the_kept_key() {
+    let old_k = [7; 32];
+    let mut cipher = Cipher::from_key_and_cipher(old_k, ChaCha20Poly1305::from_key(old_k));
+    cipher.rekey().unwrap();
+    let new_k = cipher.get_k().expect("the key is kept");
+    assert_ne!(new_k, old_k);
+
+    // The kept key is the one the cipher now uses
+    let mut other = Cipher::from_key_and_cipher(new_k, ChaCha20Poly1305::from_key(new_k));
+    let mut message = "ciao".as_bytes().to_vec();
+    cipher.encrypt_with_ad(&[], &mut message).unwrap();
+    other.decrypt_with_ad(&[], &mut message).unwrap();
+    assert_eq!(message, "ciao".as_bytes().to_vec());
+}
+
+#[test]
+fn rekey_only_one_side_fails() {
+    let key_pair = Responder::generate_key();
+
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, mut codec_responder) = responder.step_1(first_message).unwrap();
+    let mut codec_initiator = initiator.step_2(second_message).unwrap();
+
+    codec_initiator.rekey_outgoing().unwrap();
+    let mut message = "ciao".as_bytes().to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    assert!(codec_responder.decrypt(&mut message).is_err());
+}
+
+#[test]
+fn both_sides_report_chosen_algorithm() {
+    let key_pair = Responder::generate_key();
+
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+    assert_eq!(initiator.chosen_algorithm(), None);
+    assert_eq!(responder.chosen_algorithm(), None);
+
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, codec_responder) = responder.step_1(first_message).unwrap();
+    let codec_initiator = initiator.step_2(second_message).unwrap();
+
+    assert_eq!(
+        initiator.chosen_algorithm(),
+        Some(EncryptionAlgorithm::ChaChaPoly)
+    );
+    assert_eq!(
+        responder.chosen_algorithm(),
+        Some(EncryptionAlgorithm::ChaChaPoly)
+    );
+    assert_eq!(
+        codec_initiator.encryption_algorithm(),
+        codec_responder.encryption_algorithm()
+    );
+}
+
+#[test]
+fn responder_with_algorithms() {
+    let key_pair = Responder::generate_key();
+    let pk = Some(key_pair.public_key().into());
+
+    assert_eq!(
+        Responder::with_algorithms(key_pair.clone(), 31449600, vec![]).unwrap_err(),
+        Error::CipherListMustBeNonEmpty
+    );
+    assert_eq!(
+        Responder::with_algorithms(
+            key_pair.clone(),
+            31449600,
+            vec![
+                EncryptionAlgorithm::ChaChaPoly,
+                EncryptionAlgorithm::ChaChaPoly
+            ]
+        )
+        .unwrap_err(),
+        Error::InvalidCipherList(b"CHCHCHCH".to_vec())
+    );
+
+    let mut responder =
+        Responder::with_algorithms(key_pair, 31449600, vec![EncryptionAlgorithm::ChaChaPoly])
+            .unwrap();
+    let mut initiator = Initiator::new(pk);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    initiator.step_2(second_message).unwrap();
+    assert_eq!(
+        responder.chosen_algorithm(),
+        Some(EncryptionAlgorithm::ChaChaPoly)
+    );
+}
+
+#[test]
+fn responder_preferring_another_algorithm_uses_the_default_one() {
+    let key_pair = Responder::generate_key();
+    let pk = Some(key_pair.public_key().into());
+
+    let mut responder = Responder::with_algorithms(
+        key_pair,
+        31449600,
+        vec![EncryptionAlgorithm::AesGcm, EncryptionAlgorithm::ChaChaPoly],
+    )
+    .unwrap();
+    let mut initiator = Initiator::new(pk);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, mut codec_responder) = responder.step_1(first_message).unwrap();
+    let mut codec_initiator = initiator.step_2(second_message).unwrap();
+
+    assert_eq!(
+        responder.chosen_algorithm(),
+        Some(EncryptionAlgorithm::ChaChaPoly)
+    );
+    let mut message = "ciao".as_bytes().to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    codec_responder.decrypt(&mut message).unwrap();
+    assert_eq!(message, "ciao".as_bytes().to_vec());
+}
+
+#[test]
+fn responder_without_the_default_algorithm_rejects_the_handshake() {
+    let key_pair = Responder::generate_key();
+    let pk = Some(key_pair.public_key().into());
+
+    let mut responder =
+        Responder::with_algorithms(key_pair, 31449600, vec![EncryptionAlgorithm::AesGcm]).unwrap();
+    let mut initiator = Initiator::new(pk);
+    let first_message = initiator.step_0().unwrap();
+    assert_eq!(
+        responder.step_1(first_message).unwrap_err(),
+        Error::InvalidCipherChosed(b"CHCH".to_vec())
+    );
+    assert_eq!(responder.chosen_algorithm(), None);
+}
+
+#[test]
+fn initiator_learns_remote_static_key() {
+    let key_pair = Responder::generate_key();
+
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+    assert_eq!(initiator.remote_static_key(), None);
+
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, codec_responder) = responder.step_1(first_message).unwrap();
+    let codec_initiator = initiator.step_2(second_message).unwrap();
+
+    assert_eq!(
+        initiator.remote_static_key(),
+        Some(responder.static_public_key())
+    );
+    assert_eq!(
+        codec_initiator.remote_static_key(),
+        Some(responder.static_public_key())
+    );
+    assert_eq!(codec_responder.remote_static_key(), None);
+}
+
+#[test]
+fn pinned_static_key_must_match_the_responder_one() {
+    let authority = Responder::generate_key();
+    let mut responder = Responder::new(authority.clone(), 31449600);
+    let mut initiator = Initiator::new(Some(authority.x_only_public_key().0))
+        .with_expected_static_key(responder.static_public_key());
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    assert!(initiator.step_2(second_message).is_ok());
+
+    // Validly certified by the authority but not the pinned key
+    let mut responder = Responder::new(authority, 31449600);
+    let pinned = Responder::generate_key().x_only_public_key().0;
+    let mut initiator =
+        Initiator::new(Some(authority.x_only_public_key().0)).with_expected_static_key(pinned);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    assert_eq!(
+        initiator.step_2(second_message).unwrap_err(),
+        Error::StaticKeyMismatch
+    );
+    assert_eq!(initiator.remote_static_key(), None);
+}
+
+#[test]
+fn initiator_exposes_certificate() {
+    let key_pair = Responder::generate_key();
+
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 3600);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    initiator.step_2(second_message).unwrap();
+
+    let remaining = initiator
+        .certificate()
+        .unwrap()
+        .time_remaining(std::time::SystemTime::now())
+        .unwrap();
+    assert!(remaining <= Duration::from_secs(3600));
+    assert!(remaining > Duration::from_secs(3500));
+}
+
+#[test]
+fn owned_buffer_round_trip() {
+    let key_pair = Responder::generate_key();
+
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, mut codec_responder) = responder.step_1(first_message).unwrap();
+    let mut codec_initiator = initiator.step_2(second_message).unwrap();
+
+    let max_payload = const_sv2::NOISE_FRAME_MAX_SIZE - const_sv2::AEAD_MAC_LEN;
+    for plain in [vec![], vec![7], vec![42; max_payload]] {
+        let cipher = codec_initiator.encrypt_to_vec(&plain).unwrap();
+        assert_eq!(cipher.len(), plain.len() + const_sv2::AEAD_MAC_LEN);
+        assert_eq!(codec_responder.decrypt_to_vec(&cipher).unwrap(), plain);
+    }
+
+    assert_eq!(
+        codec_responder.decrypt_to_vec(&[]).unwrap_err(),
+        Error::InvalidMessageLength
+    );
+}
+
+#[test]
+fn pinned_initiator_rejects_self_signed_responder() {
+    let authority = Responder::generate_key();
+
+    let mut initiator = Initiator::new(Some(authority.public_key().into()));
+    let mut responder = Responder::new_self_signed(31449600);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    assert_eq!(
+        initiator.step_2(second_message).unwrap_err(),
+        Error::SelfSignedRejected
+    );
+}
+
+#[cfg(feature = "dangerous-accept-self-signed")]
+#[test]
+fn self_signed_handshake() {
+    let mut initiator = Initiator::new_accept_self_signed();
+    let mut responder = Responder::new_self_signed(31449600);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, mut codec_responder) = responder.step_1(first_message).unwrap();
+    let mut codec_initiator = initiator.step_2(second_message).unwrap();
+    assert_eq!(
+        initiator.remote_static_key(),
+        Some(responder.static_public_key())
+    );
+
+    let mut message = "ciao".as_bytes().to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    codec_responder.decrypt(&mut message).unwrap();
+    assert_eq!(message, "ciao".as_bytes().to_vec());
+
+    // A certificate signed by an authority key is not self signed
+    let mut initiator = Initiator::new_accept_self_signed();
+    let mut responder = Responder::new(Responder::generate_key(), 31449600);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    assert_eq!(
+        initiator.step_2(second_message).unwrap_err(),
+        Error::CertificateBadSignature
+    );
+}
+
+#[test]
+fn initiator_rejects_steps_out_of_order() {
+    let key_pair = Responder::generate_key();
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair.clone(), 31449600);
+
+    // the reply of the responder before the initiator sent its ephemeral key
+    let mut other_initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let first_message = other_initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    assert_eq!(
+        initiator.step_2(second_message).unwrap_err(),
+        Error::UnexpectedHandshakeStage {
+            expected: 0,
+            got: 2
+        }
+    );
+
+    // a second ephemeral key while the reply of the responder is expected
+    initiator.step_0().unwrap();
+    assert_eq!(
+        initiator.step_0().unwrap_err(),
+        Error::UnexpectedHandshakeStage {
+            expected: 2,
+            got: 0
+        }
+    );
+}
+
+#[test]
+fn responder_rejects_a_replayed_first_message() {
+    let key_pair = Responder::generate_key();
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+    let first_message = initiator.step_0().unwrap();
+    responder.step_1(first_message).unwrap();
+
+    assert_eq!(
+        responder.step_1(first_message).unwrap_err(),
+        Error::UnexpectedHandshakeStage {
+            expected: handshake::HANDSHAKE_DONE,
+            got: 1
+        }
+    );
+}
+
+#[test]
+fn handshake_messages_of_the_wrong_size_are_rejected() {
+    use const_sv2::{
+        INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE as SECOND,
+        RESPONDER_EXPECTED_HANDSHAKE_MESSAGE_SIZE as FIRST,
+    };
+
+    for expected in [FIRST, SECOND] {
+        let check = |len: usize| match expected {
+            FIRST => handshake::message_from_slice::<FIRST>(&vec![0; len]).map(|_| ()),
+            _ => handshake::message_from_slice::<SECOND>(&vec![0; len]).map(|_| ()),
+        };
+        assert_eq!(
+            check(expected - 1).unwrap_err(),
+            Error::HandshakeMessageTooSmall {
+                expected,
+                got: expected - 1
+            }
+        );
+        assert_eq!(
+            check(expected + 1).unwrap_err(),
+            Error::HandshakeMessageTooLarge {
+                expected,
+                got: expected + 1
+            }
+        );
+        assert_eq!(
+            check(u16::MAX as usize).unwrap_err(),
+            Error::HandshakeMessageTooLarge {
+                expected,
+                got: u16::MAX as usize
+            }
+        );
+        assert!(check(expected).is_ok());
+    }
+}
+
+#[test]
+fn sized_handshake_messages_complete_the_handshake() {
+    let key_pair = Responder::generate_key();
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+
+    let first_message = initiator.step_0().unwrap().to_vec();
+    let (second_message, _) = responder
+        .step_1(handshake::message_from_slice(&first_message).unwrap())
+        .unwrap();
+    let second_message = second_message.to_vec();
+    assert!(initiator
+        .step_2(handshake::message_from_slice(&second_message).unwrap())
+        .is_ok());
+}
+
+#[test]
+fn refreshed_certificate_is_used_by_the_handshake() {
+    let old_authority = Responder::generate_key();
+    let new_authority = Responder::generate_key();
+    let mut responder = Responder::new(old_authority, 31449600);
+    let static_key = responder.static_public_key();
+    responder
+        .refresh_certificate(new_authority.clone(), Duration::from_secs(3600))
+        .unwrap();
+    assert_eq!(responder.static_public_key(), static_key);
+
+    let mut initiator = Initiator::new(Some(new_authority.public_key().into()));
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, mut codec_responder) = responder.step_1(first_message).unwrap();
+    let mut codec_initiator = initiator.step_2(second_message).unwrap();
+    let certificate = initiator.certificate().unwrap();
+    assert_eq!(certificate.not_valid_after - certificate.valid_from, 3600);
+
+    let mut message = "ciao".as_bytes().to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    codec_responder.decrypt(&mut message).unwrap();
+    assert!(message == "ciao".as_bytes().to_vec());
+}
+
+#[test]
+fn certificate_of_the_old_authority_is_not_sent_after_a_refresh() {
+    let old_authority = Responder::generate_key();
+    let mut responder = Responder::new(old_authority.clone(), 31449600);
+    responder
+        .refresh_certificate(Responder::generate_key(), Duration::from_secs(3600))
+        .unwrap();
+
+    let mut initiator = Initiator::new(Some(old_authority.public_key().into()));
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    assert_eq!(
+        initiator.step_2(second_message).unwrap_err(),
+        Error::CertificateBadSignature
+    );
+}
+
+#[test]
+fn authority_public_key_must_match_the_private_key() {
+    let authority = Responder::generate_key();
+    let private = authority.secret_bytes();
+    let public = authority.x_only_public_key().0.serialize();
+    let other = Responder::generate_key().x_only_public_key().0.serialize();
+    let validity = Duration::from_secs(3600);
+
+    assert!(Responder::from_authority_kp(&public, private, validity).is_ok());
+    assert_eq!(
+        Responder::from_authority_kp(&other, private, validity).unwrap_err(),
+        Error::InvalidRawPublicKey
+    );
+}
+
+#[test]
+fn certificate_validity_that_does_not_fit_a_u32_is_rejected() {
+    let authority = Responder::generate_key();
+    let public = authority.x_only_public_key().0.serialize();
+    let private = authority.secret_bytes();
+    let validity = u64::from(u32::MAX) + 1;
+
+    assert_eq!(
+        Responder::from_authority_kp(&public, private, Duration::from_secs(validity)).unwrap_err(),
+        Error::InvalidCertificateValidity(validity)
+    );
+    let mut responder =
+        Responder::from_authority_kp(&public, private, Duration::from_secs(3600)).unwrap();
+    assert_eq!(
+        responder
+            .refresh_certificate(authority, Duration::from_secs(validity))
+            .unwrap_err(),
+        Error::InvalidCertificateValidity(validity)
+    );
+}
+
+#[test]
+fn certificate_validity_of_u32_max_is_capped() {
+    let authority = Responder::generate_key();
+    let mut initiator = Initiator::new(Some(authority.x_only_public_key().0));
+    let mut responder = Responder::new(authority, u32::MAX);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    initiator.step_2(second_message).unwrap();
+    assert_eq!(initiator.certificate().unwrap().not_valid_after, u32::MAX);
+}
+
+#[test]
+fn certificate_signed_by_any_trusted_authority_is_accepted() {
+    let old_authority = Responder::generate_key();
+    let new_authority = Responder::generate_key();
+    let mut responder = Responder::new(new_authority.clone(), 31449600);
+
+    let mut initiator = Initiator::with_authority_keys(vec![
+        old_authority.x_only_public_key().0,
+        new_authority.x_only_public_key().0,
+    ]);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    assert!(initiator.step_2(second_message).is_ok());
+    assert_eq!(
+        initiator.remote_static_key(),
+        Some(responder.static_public_key())
+    );
+
+    let mut responder = Responder::new(Responder::generate_key(), 31449600);
+    let mut initiator = Initiator::with_authority_keys(vec![
+        old_authority.x_only_public_key().0,
+        new_authority.x_only_public_key().0,
+    ]);
+    let first_message = initiator.step_0().unwrap();
+    let (second_message, _) = responder.step_1(first_message).unwrap();
+    assert_eq!(
+        initiator.step_2(second_message).unwrap_err(),
+        Error::CertificateBadSignature
+    );
+}
+
+async fn recv<T>(receiver: &mut Receiver<T>) -> Result<T, Error> {
+    receiver
+        .recv()
+        .await
+        .ok_or(Error::ExpectedIncomingHandshakeMessage)
+}
+
+#[tokio::test]
+async fn handshake_drivers_complete_the_handshake() {
+    let key_pair = Responder::generate_key();
+    let mut initiator = Initiator::new(Some(key_pair.public_key().into()));
+    let mut responder = Responder::new(key_pair, 31449600);
+    let (to_responder, mut from_initiator) = unbounded_channel();
+    let (to_initiator, mut from_responder) = unbounded_channel();
+
+    let (codec_initiator, codec_responder) = tokio::join!(
+        handshake::run_initiator(
+            &mut initiator,
+            |message| async move {
+                to_responder
+                    .send(message)
+                    .map_err(|_| Error::ExpectedIncomingHandshakeMessage)
+            },
+            || recv(&mut from_responder),
+        ),
+        handshake::run_responder(
+            &mut responder,
+            |message| async move {
+                to_initiator
+                    .send(message)
+                    .map_err(|_| Error::ExpectedIncomingHandshakeMessage)
+            },
+            || recv(&mut from_initiator),
+        ),
+    );
+    let mut codec_initiator: crate::NoiseCodec = codec_initiator.unwrap();
+    let mut codec_responder = codec_responder.unwrap();
+
+    let mut message = "ciao".as_bytes().to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    assert!(message != "ciao".as_bytes().to_vec());
+    codec_responder.decrypt(&mut message).unwrap();
+    assert!(message == "ciao".as_bytes().to_vec());
+}
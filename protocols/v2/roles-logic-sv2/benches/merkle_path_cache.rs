@@ -0,0 +1,40 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::Rng;
+use roles_logic_sv2::utils::{merkle_root_from_path_, MerklePathCache};
+use stratum_common::bitcoin::hashes::{sha256d, Hash};
+
+// Hashes every level concatenating the two nodes, allocating a vector for each level, as the
+// coinbase merkle root was computed before the engine based implementation
+fn concat_merkle_root(coinbase_id: [u8; 32], path: &[[u8; 32]]) -> [u8; 32] {
+    path.iter().fold(coinbase_id, |root, node| {
+        sha256d::Hash::hash(&[&root[..], &node[..]].concat()).into_inner()
+    })
+}
+
+fn bench_merkle_root(c: &mut Criterion) {
+    let mut rng = rand::thread_rng();
+    let mut group = c.benchmark_group("merkle_root");
+    for len in [1, 12] {
+        let path: Vec<[u8; 32]> = (0..len).map(|_| rng.gen()).collect();
+        let cache = MerklePathCache::new(&path).unwrap();
+        let coinbase_id: [u8; 32] = rng.gen();
+
+        group.bench_with_input(BenchmarkId::new("concat", len), &path, |b, path| {
+            b.iter(|| concat_merkle_root(black_box(coinbase_id), path))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("merkle_root_from_path_", len),
+            &path,
+            |b, path| b.iter(|| merkle_root_from_path_(black_box(coinbase_id), path).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("MerklePathCache", len),
+            &cache,
+            |b, cache| b.iter(|| cache.root_for_coinbase(black_box(coinbase_id))),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_merkle_root);
+criterion_main!(benches);
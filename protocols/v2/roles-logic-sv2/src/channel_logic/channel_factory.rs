@@ -8,10 +8,10 @@ use crate::{
 };
 
 use mining_sv2::{
-    ExtendedExtranonce, NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannelSuccess,
-    OpenMiningChannelError, OpenStandardMiningChannelSuccess, SetCustomMiningJob,
-    SetCustomMiningJobSuccess, SetNewPrevHash, SubmitSharesError, SubmitSharesExtended,
-    SubmitSharesStandard, Target,
+    ExtendedExtranonce, Extranonce, NewExtendedMiningJob, NewMiningJob,
+    OpenExtendedMiningChannelSuccess, OpenMiningChannelError, OpenStandardMiningChannelSuccess,
+    SetCustomMiningJob, SetCustomMiningJobSuccess, SetExtranoncePrefix, SetNewPrevHash,
+    SubmitSharesError, SubmitSharesExtended, SubmitSharesStandard, Target,
 };
 
 use nohash_hasher::BuildNoHashHasher;
@@ -250,13 +250,14 @@ impl ChannelFactory {
         let extended_channels_group = 0;
         let max_extranonce_size = self.extranonces.get_range2_len() as u16;
         if min_extranonce_size <= max_extranonce_size {
-            // SECURITY is very unlikely to finish the ids btw this unwrap could be used by an attaccher that
-            // want to dirsrupt the service maybe we should have a method to reuse ids that are no
-            // longer connected?
+            // SECURITY is very unlikely to finish the ids btw an attaccher could open channels until
+            // they are finished to dirsrupt the service maybe we should have a method to reuse ids
+            // that are no longer connected?
             let channel_id = self
                 .ids
                 .safe_lock(|ids| ids.new_channel_id(extended_channels_group))
-                .unwrap();
+                .unwrap()
+                .ok_or(Error::ChannelIdsExhausted)?;
             self.channel_to_group_id.insert(channel_id, 0);
             let target = match crate::utils::hash_rate_to_target(
                 hash_rate.into(),
@@ -274,7 +275,7 @@ impl ChannelFactory {
             let extranonce = self
                 .extranonces
                 .next_extended(max_extranonce_size as usize)
-                .unwrap();
+                .ok_or(Error::ExtranonceSpaceEnded)?;
             let extranonce_prefix = extranonce
                 .into_prefix(self.extranonces.get_prefix_len())
                 .unwrap();
@@ -333,6 +334,62 @@ impl ChannelFactory {
         self.extended_channels.insert(channel_id, success.clone());
         Some(())
     }
+    /// Called when the upstream sends a new extranonce prefix for the extended channel from which
+    /// the factory derives its channels. The upstream part of every channel's extranonce is
+    /// replaced, the part assigned by the factory is kept, so that the channels keep not
+    /// colliding. Returns the `SetExtranoncePrefix` to send to each channel.
+    fn update_upstream_extranonce_prefix(
+        &mut self,
+        prefix: &[u8],
+    ) -> Result<Vec<SetExtranoncePrefix<'static>>, Error> {
+        let upstream_len = self.extranonces.get_range0_len();
+        self.extranonces
+            .set_upstream_prefix(prefix)
+            .ok_or(Error::InvalidExtranonceSize(
+                upstream_len as u16,
+                prefix.len() as u16,
+            ))?;
+        let with_new_prefix = |old: Vec<u8>| [prefix, &old[upstream_len..]].concat();
+
+        let mut result = vec![];
+        for channel in self
+            .standard_channels_for_hom_downstreams
+            .values_mut()
+            .chain(self.standard_channels_for_non_hom_downstreams.values_mut())
+        {
+            let extranonce = with_new_prefix(channel.extranonce.clone().to_vec());
+            channel.extranonce =
+                Extranonce::from_vec_with_len(extranonce.clone(), extranonce.len());
+            result.push(SetExtranoncePrefix {
+                channel_id: channel.channel_id,
+                extranonce_prefix: extranonce.try_into()?,
+            });
+        }
+        for channel in self.extended_channels.values_mut() {
+            let extranonce_prefix = with_new_prefix(channel.extranonce_prefix.to_vec());
+            channel.extranonce_prefix = extranonce_prefix.clone().try_into()?;
+            result.push(SetExtranoncePrefix {
+                channel_id: channel.channel_id,
+                extranonce_prefix: extranonce_prefix.try_into()?,
+            });
+        }
+        Ok(result)
+    }
+
+    /// Extranonce prefixes given to the opened channels. For standard channels it is the whole
+    /// extranonce.
+    fn reserved_prefixes(&self) -> Vec<Extranonce> {
+        let standard = self
+            .standard_channels_for_hom_downstreams
+            .values()
+            .chain(self.standard_channels_for_non_hom_downstreams.values())
+            .map(|channel| channel.extranonce.clone());
+        let extended = self
+            .extended_channels
+            .values()
+            .map(|channel| channel.extranonce_prefix.clone().into());
+        standard.chain(extended).collect()
+    }
     /// Called when an `OpenStandardChannel` message is received for a header only mining channel.
     /// Here we save the downstream's target (based on hashrate) and and the
     /// channel's extranonce details before returning the relevant SV2 mining messages
@@ -399,7 +456,8 @@ impl ChannelFactory {
         let channel_id = self
             .ids
             .safe_lock(|ids| ids.new_channel_id(group_id))
-            .unwrap();
+            .unwrap()
+            .ok_or(Error::ChannelIdsExhausted)?;
         let complete_id = GroupId::into_complete_id(group_id, channel_id);
         let target = match crate::utils::hash_rate_to_target(
             downstream_hash_rate.into(),
@@ -644,6 +702,7 @@ impl ChannelFactory {
     /// Called when a new prev hash is received. If the respective job is available in the future job queue,
     /// we move the future job into the valid job slot and store the prev hash as the current prev hash to be referenced.
     fn on_new_prev_hash(&mut self, m: StagedPhash) -> Result<(), Error> {
+        let prev_hash = crate::utils::u256_to_block_hash(m.prev_hash.clone())?;
         while let Some(mut job) = self.future_jobs.pop() {
             if job.0.job_id == m.job_id {
                 let now = std::time::SystemTime::now()
@@ -657,7 +716,7 @@ impl ChannelFactory {
             self.last_valid_job = None;
         }
         self.future_jobs = vec![];
-        self.last_prev_hash_ = Some(crate::utils::u256_to_block_hash(m.prev_hash.clone()));
+        self.last_prev_hash_ = Some(prev_hash);
         let mut ids = vec![];
         for complete_id in self.standard_channels_for_non_hom_downstreams.keys() {
             let group_id = GroupId::into_group_id(*complete_id);
@@ -801,8 +860,7 @@ impl ChannelFactory {
             coinbase_tx_suffix,
             &extranonce[..],
             &merkle_path[..],
-        )
-        .ok_or(Error::InvalidCoinbase)?
+        )?
         .try_into()
         .unwrap();
         let version = match &m {
@@ -958,6 +1016,25 @@ impl ChannelFactory {
         channel.target = new_target.into();
         Some(true)
     }
+
+    /// Forgets the downstream channel `channel_id`, after that shares and jobs for it are no more
+    /// accepted. The extranonce prefix of the channel is not reused. Returns false if the channel
+    /// is unknown.
+    fn close_channel(&mut self, channel_id: u32) -> bool {
+        let group_id = self.channel_to_group_id.remove(&channel_id);
+        let extended = self.extended_channels.remove(&channel_id).is_some();
+        let hom = self
+            .standard_channels_for_hom_downstreams
+            .remove(&channel_id)
+            .is_some();
+        let non_hom = group_id
+            .and_then(|group_id| {
+                self.standard_channels_for_non_hom_downstreams
+                    .remove(&GroupId::into_complete_id(group_id, channel_id))
+            })
+            .is_some();
+        extended || hom || non_hom
+    }
 }
 
 /// Used by a pool to in order to manage all downstream channel. It add job creation capabilities
@@ -1032,6 +1109,10 @@ impl PoolChannelFactory {
         self.inner
             .new_extended_channel(request_id, hash_rate, min_extranonce_size)
     }
+    /// Calls [`ChannelFactory::reserved_prefixes`]
+    pub fn reserved_prefixes(&self) -> Vec<Extranonce> {
+        self.inner.reserved_prefixes()
+    }
     /// Called when we want to replicate a channel already opened by another actor.
     /// is used only in the jd client from the template provider module to mock a pool.
     /// Anything else should open channel with the new_extended_channel function
@@ -1154,9 +1235,9 @@ impl PoolChannelFactory {
             let merkle_path = referenced_job.merkle_path.to_vec();
             let pool_signature = self.pool_signature.clone();
             let extended_job =
-                job_creator::extended_job_from_custom_job(referenced_job, pool_signature, 32)
-                    .unwrap();
-            let prev_blockhash = crate::utils::u256_to_block_hash(referenced_job.prev_hash.clone());
+                job_creator::extended_job_from_custom_job(referenced_job, pool_signature, 32)?;
+            let prev_blockhash =
+                crate::utils::u256_to_block_hash(referenced_job.prev_hash.clone())?;
             let bits = referenced_job.nbits;
             self.inner.check_target(
                 Share::Extended(m.into_static()),
@@ -1211,14 +1292,13 @@ impl PoolChannelFactory {
         new_id
     }
     /// Utility function to return a new standard channel id
-    pub fn new_standard_id_for_hom(&mut self) -> u32 {
+    pub fn new_standard_id_for_hom(&mut self) -> Result<u32, Error> {
         let hom_group_id = 0;
-        let new_id = self
-            .inner
+        self.inner
             .ids
             .safe_lock(|ids| ids.new_channel_id(hom_group_id))
-            .unwrap();
-        new_id
+            .unwrap()
+            .ok_or(Error::ChannelIdsExhausted)
     }
     /// Returns the full extranonce, extranonce1 (static for channel) + extranonce2 (miner nonce space)
     pub fn extranonce_from_downstream_extranonce(
@@ -1273,6 +1353,11 @@ impl PoolChannelFactory {
     ) -> Option<bool> {
         self.inner.update_target_for_channel(channel_id, new_target)
     }
+
+    /// calls [`ChannelFactory::close_channel`]
+    pub fn close_channel(&mut self, channel_id: u32) -> bool {
+        self.inner.close_channel(channel_id)
+    }
     // Set the target for this channel. This is the upstream target.
     pub fn set_target(&mut self, new_target: &mut Target) {
         self.inner.kind.set_target(new_target);
@@ -1365,6 +1450,17 @@ impl ProxyExtendedChannelFactory {
         self.inner
             .new_extended_channel(request_id, hash_rate, min_extranonce_size)
     }
+    /// Calls [`ChannelFactory::reserved_prefixes`]
+    pub fn reserved_prefixes(&self) -> Vec<Extranonce> {
+        self.inner.reserved_prefixes()
+    }
+    /// Calls [`ChannelFactory::update_upstream_extranonce_prefix`]
+    pub fn update_upstream_extranonce_prefix(
+        &mut self,
+        prefix: &[u8],
+    ) -> Result<Vec<SetExtranoncePrefix<'static>>, Error> {
+        self.inner.update_upstream_extranonce_prefix(prefix)
+    }
     /// Called only when a new prev hash is received by a Template Provider when job declaration is used.
     /// It matches the message with a `job_id`, creates a new custom job, and calls [`ChannelFactory::on_new_prev_hash`]
     pub fn on_new_prev_hash_from_tp(
@@ -1733,6 +1829,11 @@ impl ProxyExtendedChannelFactory {
     ) -> Option<bool> {
         self.inner.update_target_for_channel(channel_id, new_target)
     }
+
+    /// calls [`ChannelFactory::close_channel`]
+    pub fn close_channel(&mut self, channel_id: u32) -> bool {
+        self.inner.close_channel(channel_id)
+    }
 }
 
 /// Used by proxies for tracking upstream targets.
@@ -1844,6 +1945,148 @@ mod test {
             .collect()
     }
 
+    #[test]
+    fn test_close_channel() {
+        let extranonces = ExtendedExtranonce::new(0..0, 0..8, 8..16);
+        let ids = Arc::new(Mutex::new(GroupId::new()));
+        let mut channel = ProxyExtendedChannelFactory::new(
+            ids,
+            extranonces,
+            None,
+            1.0,
+            ExtendedChannelKind::Proxy {
+                upstream_target: [255; 32].into(),
+            },
+            None,
+            "".to_string(),
+            0,
+        );
+        let channel_id = match &channel.new_extended_channel(0, 10_000.0, 8).unwrap()[0] {
+            Mining::OpenExtendedMiningChannelSuccess(success) => success.channel_id,
+            m => panic!("Expected OpenExtendedMiningChannelSuccess, got {:?}", m),
+        };
+        assert!(channel
+            .update_target_for_channel(channel_id, [255; 32].into())
+            .is_some());
+
+        assert!(channel.close_channel(channel_id));
+        assert!(channel
+            .update_target_for_channel(channel_id, [255; 32].into())
+            .is_none());
+        assert!(!channel.close_channel(channel_id));
+    }
+
+    #[test]
+    fn test_extranonce_prefixes_are_unique_until_exhausted() {
+        let out = TxOut {
+            value: BLOCK_REWARD,
+            script_pubkey: decode_hex(COINBASE_OUTPUT).unwrap().into(),
+        };
+        // One byte for the extended channels prefixes, two for the downstreams
+        let extranonces = ExtendedExtranonce::new(0..0, 0..1, 1..3);
+        let ids = Arc::new(Mutex::new(GroupId::new()));
+        let mut channel = PoolChannelFactory::new(
+            ids,
+            extranonces,
+            JobsCreators::new(3),
+            1.0,
+            ExtendedChannelKind::Pool,
+            vec![out],
+            "".to_string(),
+        );
+
+        for request_id in 0..255 {
+            channel
+                .new_extended_channel(request_id, 10_000.0, 2)
+                .unwrap();
+            if request_id % 100 == 0 {
+                let id = channel.new_standard_id_for_hom().unwrap();
+                channel
+                    .add_standard_channel(request_id, 10_000.0, true, id)
+                    .unwrap();
+            }
+        }
+        assert!(matches!(
+            channel.new_extended_channel(255, 10_000.0, 2),
+            Err(Error::ExtranonceSpaceEnded)
+        ));
+
+        let mut prefixes: Vec<Vec<u8>> = channel
+            .reserved_prefixes()
+            .into_iter()
+            .map(|prefix| prefix.to_vec()[..1].to_vec())
+            .collect();
+        assert_eq!(prefixes.len(), 258);
+        // The standard channels all have the first byte set to zero, the extended ones never
+        prefixes.retain(|prefix| prefix != &vec![0]);
+        prefixes.sort();
+        prefixes.dedup();
+        assert_eq!(prefixes.len(), 255);
+    }
+
+    #[test]
+    fn test_update_upstream_extranonce_prefix() {
+        // 8 bytes for the upstream, 4 for the proxy and 12 for the downstreams
+        let upstream_extranonce = Extranonce::from_vec_with_len(vec![0; 8], 8);
+        let extranonces =
+            ExtendedExtranonce::from_upstream_extranonce(upstream_extranonce, 0..8, 8..12, 12..24)
+                .unwrap();
+        let upstream_target: U256 = [255; 32].into();
+        let mut channel = ProxyExtendedChannelFactory::new(
+            Arc::new(Mutex::new(GroupId::new())),
+            extranonces,
+            None,
+            1.0,
+            ExtendedChannelKind::Proxy {
+                upstream_target: upstream_target.into(),
+            },
+            Some(vec![]),
+            "".to_string(),
+            0,
+        );
+        let channel_id = 1;
+        let old_extranonce = match &channel
+            .add_standard_channel(0, 10_000.0, true, channel_id)
+            .unwrap()[0]
+        {
+            Mining::OpenStandardMiningChannelSuccess(m) => m.extranonce_prefix.to_vec(),
+            _ => panic!(),
+        };
+
+        assert!(matches!(
+            channel.update_upstream_extranonce_prefix(&[9; 4]),
+            Err(Error::InvalidExtranonceSize(8, 4))
+        ));
+        let messages = channel.update_upstream_extranonce_prefix(&[9; 8]).unwrap();
+        let new_extranonce = [&[9; 8], &old_extranonce[8..]].concat();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].channel_id, channel_id);
+        assert_eq!(messages[0].extranonce_prefix.to_vec(), new_extranonce);
+
+        // The jobs for the channel are built with the new extranonce
+        let parsed = decode_hex(COINBASE).unwrap();
+        let coinbase_tx_prefix = [&parsed[..41], &[new_extranonce.len() as u8]].concat();
+        let job = NewExtendedMiningJob {
+            channel_id: 0,
+            job_id: 1,
+            min_ntime: binary_sv2::Sv2Option::new(None),
+            version: VERSION,
+            version_rolling_allowed: false,
+            merkle_path: get_merkle_path(),
+            coinbase_tx_prefix: coinbase_tx_prefix.try_into().unwrap(),
+            coinbase_tx_suffix: parsed[49..].to_vec().try_into().unwrap(),
+        };
+        let expected = extended_to_standard_job(&job, &new_extranonce, channel_id, None).unwrap();
+        match channel
+            .on_new_extended_mining_job(job)
+            .unwrap()
+            .get(&channel_id)
+        {
+            Some(Mining::NewMiningJob(m)) => assert_eq!(m.merkle_root, expected.merkle_root),
+            _ => panic!("No job for the standard channel"),
+        }
+    }
+
     #[test]
     fn test_complete_mining_round() {
         let (prefix, coinbase_extranonce, _) = get_coinbase();
@@ -1916,7 +2159,7 @@ mod test {
 
         // "Send" the OpenStandardMiningChannel to channel
         let result = loop {
-            let id = channel.new_standard_id_for_hom();
+            let id = channel.new_standard_id_for_hom().unwrap();
             let result = channel
                 .add_standard_channel(
                     open_standard_channel.get_request_id_as_u32(),
@@ -1,5 +1,6 @@
 pub mod channel_factory;
 pub mod proxy_group_channel;
+pub mod share_validation;
 
 use mining_sv2::{NewExtendedMiningJob, NewMiningJob};
 use std::convert::TryInto;
@@ -11,7 +12,7 @@ pub fn extended_to_standard_job<'a>(
     channel_id: u32,
     job_id: Option<u32>,
 ) -> Option<NewMiningJob<'a>> {
-    let merkle_root = crate::utils::merkle_root_from_path(
+    let merkle_root = crate::utils::merkle_root_from_path_option(
         extended.coinbase_tx_prefix.inner_as_ref(),
         extended.coinbase_tx_suffix.inner_as_ref(),
         coinbase_script,
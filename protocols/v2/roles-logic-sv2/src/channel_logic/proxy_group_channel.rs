@@ -71,6 +71,25 @@ impl GroupChannels {
     pub fn ids(&self) -> Vec<u32> {
         self.channels.keys().copied().collect()
     }
+    /// Called when a standard channel is closed. The channel is removed from its group and the
+    /// group is dropped when it has no more channels. Returns true if the group has been dropped.
+    pub fn on_close_channel(&mut self, channel_id: u32, group_id: u32) -> bool {
+        let is_empty = match self.channels.get_mut(&group_id) {
+            Some(group) => {
+                group.hom_downstreams.remove(&channel_id);
+                group.hom_downstreams.is_empty()
+            }
+            None => return false,
+        };
+        if is_empty {
+            self.channels.remove(&group_id);
+        }
+        is_empty
+    }
+    /// Called when a group channel is closed, drops the group and all the channels in it
+    pub fn remove_group(&mut self, group_id: u32) {
+        self.channels.remove(&group_id);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,259 @@
+//! Stateless validation of the shares submitted on an extended channel: given the job that the
+//! share refers to and the channel and network targets, [`validate_share`] tells if the share is
+//! stale, does not meet the channel target, is valid or is a block candidate.
+
+use crate::{
+    utils::{hash_block_header, merkle_root_from_path},
+    Error,
+};
+use mining_sv2::{NewExtendedMiningJob, SetNewPrevHash, SubmitSharesExtended, Target};
+use std::convert::TryInto;
+
+/// The data of an extended job needed to rebuild the header hashed by a share.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobState {
+    pub job_id: u32,
+    /// Previous block hash, in the same byte order of the sv2 messages
+    pub prev_hash: [u8; 32],
+    pub nbits: u32,
+    pub coinbase_tx_prefix: Vec<u8>,
+    pub coinbase_tx_suffix: Vec<u8>,
+    /// Extranonce prefix of the channel, the share only carries the part after it
+    pub extranonce_prefix: Vec<u8>,
+    pub merkle_path: Vec<[u8; 32]>,
+}
+
+impl JobState {
+    /// Builds the state of `job` mined on top of `prev_hash` by a channel with
+    /// `extranonce_prefix`.
+    pub fn new(
+        job: &NewExtendedMiningJob,
+        prev_hash: &SetNewPrevHash,
+        extranonce_prefix: &[u8],
+    ) -> Result<Self, Error> {
+        let merkle_path = job
+            .merkle_path
+            .to_vec()
+            .into_iter()
+            .map(|node| {
+                let len = node.len();
+                node.try_into().map_err(|_| Error::ExpectedLen32(len))
+            })
+            .collect::<Result<Vec<[u8; 32]>, Error>>()?;
+        let prev_hash_ = prev_hash.prev_hash.inner_as_ref();
+        Ok(Self {
+            job_id: job.job_id,
+            prev_hash: prev_hash_
+                .try_into()
+                .map_err(|_| Error::ExpectedLen32(prev_hash_.len()))?,
+            nbits: prev_hash.nbits,
+            coinbase_tx_prefix: job.coinbase_tx_prefix.to_vec(),
+            coinbase_tx_suffix: job.coinbase_tx_suffix.to_vec(),
+            extranonce_prefix: extranonce_prefix.to_vec(),
+            merkle_path,
+        })
+    }
+}
+
+/// Result of [`validate_share`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShareOutcome {
+    /// The share refers to a job other than the current one
+    Stale,
+    /// The share hash does not meet the channel target
+    BelowTarget,
+    /// The share hash meets the channel target
+    Valid,
+    /// The share hash meets the network target, the block must be propagated
+    BlockCandidate,
+}
+
+/// Rebuilds the coinbase and the header of `share` from `job` and checks the header hash against
+/// `channel_target` and `network_target`.
+///
+/// Errors when the coinbase made with the share extranonce can not be deserialized, eg because
+/// the extranonce has not the size expected by the job.
+pub fn validate_share(
+    job: &JobState,
+    share: &SubmitSharesExtended,
+    channel_target: &Target,
+    network_target: &Target,
+) -> Result<ShareOutcome, Error> {
+    if share.job_id != job.job_id {
+        return Ok(ShareOutcome::Stale);
+    }
+    let extranonce = [&job.extranonce_prefix[..], share.extranonce.inner_as_ref()].concat();
+    let merkle_root: [u8; 32] = merkle_root_from_path(
+        &job.coinbase_tx_prefix,
+        &job.coinbase_tx_suffix,
+        &extranonce,
+        &job.merkle_path,
+    )?
+    .try_into()
+    .map_err(|_| Error::ImpossibleToCalculateMerkleRoot)?;
+    let hash: Target = hash_block_header(
+        share.version,
+        &job.prev_hash,
+        &merkle_root,
+        share.ntime,
+        job.nbits,
+        share.nonce,
+    )
+    .into();
+
+    if &hash <= network_target {
+        Ok(ShareOutcome::BlockCandidate)
+    } else if &hash <= channel_target {
+        Ok(ShareOutcome::Valid)
+    } else {
+        Ok(ShareOutcome::BelowTarget)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stratum_common::bitcoin::{
+        consensus, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn, TxOut, Witness,
+    };
+
+    // The coinbase script_sig starts at byte 42: version (4), input count (1), outpoint (36) and
+    // script length (1)
+    const SCRIPT_START: usize = 42;
+    const EXTRANONCE_PREFIX_LEN: usize = 4;
+    const EXTRANONCE_LEN: usize = 8;
+
+    fn job_state() -> JobState {
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(vec![0; EXTRANONCE_PREFIX_LEN + EXTRANONCE_LEN]),
+                sequence: Sequence(0xffffffff),
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 625_000_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let coinbase = consensus::serialize(&coinbase);
+        let extranonce_end = SCRIPT_START + EXTRANONCE_PREFIX_LEN + EXTRANONCE_LEN;
+        JobState {
+            job_id: 1,
+            prev_hash: [7; 32],
+            nbits: 0x1d00ffff,
+            coinbase_tx_prefix: coinbase[..SCRIPT_START].to_vec(),
+            coinbase_tx_suffix: coinbase[extranonce_end..].to_vec(),
+            extranonce_prefix: vec![1; EXTRANONCE_PREFIX_LEN],
+            merkle_path: vec![[3; 32], [4; 32]],
+        }
+    }
+
+    fn share(job_id: u32, extranonce_len: usize) -> SubmitSharesExtended<'static> {
+        SubmitSharesExtended {
+            channel_id: 1,
+            sequence_number: 0,
+            job_id,
+            nonce: 42,
+            ntime: 1_700_000_000,
+            version: 0x2000_0000,
+            extranonce: vec![2; extranonce_len].try_into().unwrap(),
+        }
+    }
+
+    fn max_target() -> Target {
+        [0xff; 32].into()
+    }
+
+    fn min_target() -> Target {
+        [0; 32].into()
+    }
+
+    #[test]
+    fn share_for_another_job_is_stale() {
+        let outcome = validate_share(
+            &job_state(),
+            &share(2, EXTRANONCE_LEN),
+            &max_target(),
+            &max_target(),
+        );
+        assert_eq!(outcome.unwrap(), ShareOutcome::Stale);
+    }
+
+    #[test]
+    fn share_not_meeting_channel_target_is_below_target() {
+        let outcome = validate_share(
+            &job_state(),
+            &share(1, EXTRANONCE_LEN),
+            &min_target(),
+            &min_target(),
+        );
+        assert_eq!(outcome.unwrap(), ShareOutcome::BelowTarget);
+    }
+
+    #[test]
+    fn share_meeting_channel_target_is_valid() {
+        let outcome = validate_share(
+            &job_state(),
+            &share(1, EXTRANONCE_LEN),
+            &max_target(),
+            &min_target(),
+        );
+        assert_eq!(outcome.unwrap(), ShareOutcome::Valid);
+    }
+
+    #[test]
+    fn share_meeting_network_target_is_block_candidate() {
+        let outcome = validate_share(
+            &job_state(),
+            &share(1, EXTRANONCE_LEN),
+            &min_target(),
+            &max_target(),
+        );
+        assert_eq!(outcome.unwrap(), ShareOutcome::BlockCandidate);
+    }
+
+    #[test]
+    fn outcome_depends_on_the_share_hash() {
+        let job = job_state();
+        let share = share(1, EXTRANONCE_LEN);
+        let extranonce = [&job.extranonce_prefix[..], share.extranonce.inner_as_ref()].concat();
+        let merkle_root: [u8; 32] = merkle_root_from_path(
+            &job.coinbase_tx_prefix,
+            &job.coinbase_tx_suffix,
+            &extranonce,
+            &job.merkle_path,
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        let hash = hash_block_header(
+            share.version,
+            &job.prev_hash,
+            &merkle_root,
+            share.ntime,
+            job.nbits,
+            share.nonce,
+        );
+        let hash: Target = hash.into();
+
+        // a share exactly on the target meets it
+        let outcome = validate_share(&job, &share, &hash, &min_target());
+        assert_eq!(outcome.unwrap(), ShareOutcome::Valid);
+        let outcome = validate_share(&job, &share, &max_target(), &hash);
+        assert_eq!(outcome.unwrap(), ShareOutcome::BlockCandidate);
+    }
+
+    #[test]
+    fn share_with_wrong_extranonce_size_is_an_error() {
+        let outcome = validate_share(
+            &job_state(),
+            &share(1, EXTRANONCE_LEN + 1),
+            &max_target(),
+            &max_target(),
+        );
+        assert!(outcome.is_err());
+    }
+}
@@ -2,7 +2,7 @@
 use crate::selectors::{
     DownstreamMiningSelector, DownstreamSelector, NullDownstreamMiningSelector,
 };
-use common_messages_sv2::{has_requires_std_job, Protocol, SetupConnection};
+use common_messages_sv2::{MiningFlags, Protocol, ProtocolVersion, SetupConnection};
 use mining_sv2::{Extranonce, Target};
 use nohash_hasher::BuildNoHashHasher;
 use std::{collections::HashMap, fmt::Debug as D};
@@ -27,9 +27,9 @@ pub struct PairSettings {
 /// A trait that defines the basic properties of an upstream node.
 pub trait IsUpstream<Down: IsDownstream, Sel: DownstreamSelector<Down> + ?Sized> {
     /// Used to bitcoin protocol version for the channel.
-    fn get_version(&self) -> u16;
+    fn get_version(&self) -> ProtocolVersion;
     // Used to get flags for the defined sv2 message protocol
-    fn get_flags(&self) -> u32;
+    fn get_flags(&self) -> MiningFlags;
     /// Used to check if the upstream supports the protocol that the downstream wants to use
     fn get_supported_protocols(&self) -> Vec<Protocol>;
     /// Checking if the upstream supports the protocol that the downstream wants to use.
@@ -39,8 +39,9 @@ pub trait IsUpstream<Down: IsDownstream, Sel: DownstreamSelector<Down> + ?Sized>
         let max_v = pair_settings.max_v;
         let flags = pair_settings.flags;
 
-        let check_version = self.get_version() >= min_v && self.get_version() <= max_v;
-        let check_flags = SetupConnection::check_flags(protocol, self.get_flags(), flags);
+        let version = u16::from(self.get_version());
+        let check_version = version >= min_v && version <= max_v;
+        let check_flags = SetupConnection::check_flags(protocol, self.get_flags().bits(), flags);
         check_version && check_flags
     }
     /// Should return the channel id
@@ -85,7 +86,7 @@ pub trait IsMiningUpstream<Down: IsMiningDownstream, Sel: DownstreamMiningSelect
     fn get_opened_channels(&mut self) -> &mut Vec<UpstreamChannel>;
     fn update_channels(&mut self, c: UpstreamChannel);
     fn is_header_only(&self) -> bool {
-        has_requires_std_job(self.get_flags())
+        self.get_flags().requires_standard_jobs()
     }
 }
 
@@ -102,11 +103,11 @@ pub trait IsMiningDownstream: IsDownstream {
 
 /// Implemented for the NullDownstreamMiningSelector
 impl<Down: IsDownstream + D> IsUpstream<Down, NullDownstreamMiningSelector> for () {
-    fn get_version(&self) -> u16 {
+    fn get_version(&self) -> ProtocolVersion {
         unreachable!("Null upstream do not have a version");
     }
 
-    fn get_flags(&self) -> u32 {
+    fn get_flags(&self) -> MiningFlags {
         unreachable!("Null upstream do not have flags");
     }
 
@@ -172,16 +173,29 @@ impl RequestIdMapper {
     }
 
     /// Updates the `RequestIdMapper` with a new upstream/downstream mapping.
+    ///
+    /// The returned upstream id is never one that is still mapped, so requests that are pending
+    /// at the same time never share an id, also after `next_id` wraps around.
     pub fn on_open_channel(&mut self, id: u32) -> u32 {
-        let new_id = self.next_id;
-        self.next_id += 1;
+        let mut new_id = self.next_id;
+        while self.request_ids_map.contains_key(&new_id) {
+            new_id = new_id.wrapping_add(1);
+        }
+        self.next_id = new_id.wrapping_add(1);
 
         self.request_ids_map.insert(new_id, id);
         new_id
     }
 
-    /// Removes a upstream/downstream mapping from the `RequsetIdMapper`.
-    pub fn remove(&mut self, upstream_id: u32) -> Option<u32> {
+    /// Returns the downstream id mapped to `upstream_id`, if any.
+    pub fn get_original(&self, upstream_id: u32) -> Option<u32> {
+        self.request_ids_map.get(&upstream_id).copied()
+    }
+
+    /// Removes a upstream/downstream mapping from the `RequsetIdMapper` and returns the
+    /// downstream id. Returns `None` if `upstream_id` is not mapped, e.g. when the upstream
+    /// answers twice to the same request or with an id that it never received.
+    pub fn remove_checked(&mut self, upstream_id: u32) -> Option<u32> {
         self.request_ids_map.remove(&upstream_id)
     }
 }
@@ -224,7 +238,45 @@ mod tests {
         request_id_mapper.on_open_channel(0);
         assert!(!request_id_mapper.request_ids_map.is_empty());
 
-        request_id_mapper.remove(0);
+        request_id_mapper.remove_checked(0);
         assert!(request_id_mapper.request_ids_map.is_empty());
     }
+
+    #[test]
+    fn maps_and_removes_concurrent_requests() {
+        let mut request_id_mapper = RequestIdMapper::new();
+        let first = request_id_mapper.on_open_channel(7);
+        let second = request_id_mapper.on_open_channel(7);
+        assert_ne!(first, second);
+
+        assert_eq!(request_id_mapper.get_original(first), Some(7));
+        assert_eq!(request_id_mapper.get_original(second), Some(7));
+        assert_eq!(request_id_mapper.remove_checked(first), Some(7));
+        assert_eq!(request_id_mapper.get_original(first), None);
+        assert_eq!(request_id_mapper.remove_checked(second), Some(7));
+        assert!(request_id_mapper.request_ids_map.is_empty());
+    }
+
+    #[test]
+    fn removing_absent_id_returns_none() {
+        let mut request_id_mapper = RequestIdMapper::new();
+        assert_eq!(request_id_mapper.remove_checked(3), None);
+
+        let id = request_id_mapper.on_open_channel(1);
+        assert_eq!(request_id_mapper.remove_checked(id), Some(1));
+        assert_eq!(request_id_mapper.remove_checked(id), None);
+    }
+
+    #[test]
+    fn skips_pending_ids_when_wrapping() {
+        let mut request_id_mapper = RequestIdMapper::new();
+        let pending = request_id_mapper.on_open_channel(1);
+        request_id_mapper.next_id = u32::MAX;
+
+        assert_eq!(request_id_mapper.on_open_channel(2), u32::MAX);
+        let id = request_id_mapper.on_open_channel(3);
+        assert_ne!(id, pending);
+        assert_eq!(request_id_mapper.get_original(pending), Some(1));
+        assert_eq!(request_id_mapper.get_original(id), Some(3));
+    }
 }
@@ -1,8 +1,9 @@
 //! Errors specific to this crate
 
 use crate::{
-    common_properties::CommonDownstreamData, parsers::PoolMessages as AllMessages,
-    utils::InputError,
+    common_properties::CommonDownstreamData,
+    parsers::PoolMessages as AllMessages,
+    utils::{BlockBuildError, InputError, MerkleError},
 };
 use binary_sv2::Error as BinarySv2Error;
 use std::fmt::{self, Display, Formatter};
@@ -31,6 +32,7 @@ pub enum Error {
     UnexpectedPoolMessage,
     UnknownRequestId(u32),
     NoMoreExtranonces,
+    ChannelIdsExhausted,
     JobIsNotFutureButPrevHashNotPresent,
     ChannelIsNeitherExtendedNeitherInAPool,
     ExtranonceSpaceEnded,
@@ -43,6 +45,10 @@ pub enum Error {
     UnknownOutputScriptType,
     InvalidOutputScript,
     EmptyCoinbaseOutputs,
+    /// (number of outputs, number of values)
+    CoinbaseOutputsValuesMismatch(usize, usize),
+    /// (value paid by the outputs, value available in the coinbase)
+    CoinbaseOutputsValueTooBig(u64, u64),
     VersionTooBig,
     TxVersionTooBig,
     TxVersionTooLow,
@@ -59,6 +65,8 @@ pub enum Error {
     JobNotUpdated(u32, u32),
     TargetError(InputError),
     HashrateError(InputError),
+    MerkleRootError(MerkleError),
+    BlockBuildError(BlockBuildError),
     LogicErrorMessage(std::boxed::Box<AllMessages<'static>>),
     JDSMissingTransactions,
 }
@@ -69,6 +77,18 @@ impl From<BinarySv2Error> for Error {
     }
 }
 
+impl From<MerkleError> for Error {
+    fn from(v: MerkleError) -> Error {
+        Error::MerkleRootError(v)
+    }
+}
+
+impl From<BlockBuildError> for Error {
+    fn from(v: BlockBuildError) -> Error {
+        Error::BlockBuildError(v)
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         use Error::*;
@@ -125,6 +145,7 @@ impl Display for Error {
                 )
             },
             NoMoreExtranonces => write!(f, "No more extranonces"),
+            ChannelIdsExhausted => write!(f, "No more channel ids"),
             JobIsNotFutureButPrevHashNotPresent => write!(f, "A non future job always expect a previous new prev hash"),
             ChannelIsNeitherExtendedNeitherInAPool => write!(f, "If a channel is neither extended neither is part of a pool the only thing to do when a OpenStandardChannle is received is to relay it upstream with and updated request id"),
             ExtranonceSpaceEnded => write!(f, "No more avaible extranonces for downstream"),
@@ -137,6 +158,8 @@ impl Display for Error {
             UnknownOutputScriptType => write!(f, "Unknown script type in config"),
             InvalidOutputScript => write!(f, "Invalid output_script_value for your script type. It must be a valid public key/script"),
             EmptyCoinbaseOutputs => write!(f, "Empty coinbase outputs in config"),
+            CoinbaseOutputsValuesMismatch(outputs, values) => write!(f, "Coinbase has {} outputs but {} values were provided", outputs, values),
+            CoinbaseOutputsValueTooBig(paid, available) => write!(f, "Coinbase outputs pay {} sat but only {} sat are available", paid, available),
             VersionTooBig => write!(f, "We are trying to construct a block header with version bigger than i32::MAX"),
             TxVersionTooBig => write!(f, "Tx version can not be greater than i32::MAX"),
             TxVersionTooLow => write!(f, "Tx version can not be lower than 1"),
@@ -151,6 +174,8 @@ impl Display for Error {
             JobNotUpdated(ds_job_id, us_job_id) => write!(f, "Channel Factory did not update job: Downstream job id = {}, Upstream job id = {}", ds_job_id, us_job_id),
             TargetError(e) => write!(f, "Impossible to get Target: {:?}", e),
             HashrateError(e) => write!(f, "Impossible to get Hashrate: {:?}", e),
+            MerkleRootError(e) => write!(f, "Impossible to calculate merkle root: {}", e),
+            BlockBuildError(e) => write!(f, "Impossible to build the block: {}", e),
             LogicErrorMessage(e) => write!(f, "Message is well formatted but can not be handled: {:?}", e),
             JDSMissingTransactions => write!(f, "JD server cannot propagate the block: missing transactions"),
         }
@@ -72,6 +72,10 @@ impl JobsCreators {
         mut pool_coinbase_outputs: Vec<TxOut>,
         pool_signature: String,
     ) -> Result<NewExtendedMiningJob<'static>, Error> {
+        split_coinbase_value(
+            &mut pool_coinbase_outputs,
+            template.coinbase_tx_value_remaining,
+        )?;
         let server_tx_outputs = template.coinbase_tx_outputs.to_vec();
         let mut outputs = tx_outputs_to_costum_scripts(&server_tx_outputs);
         pool_coinbase_outputs.append(&mut outputs);
@@ -135,6 +139,29 @@ impl JobsCreators {
     }
 }
 
+/// Sets the values of the pool outputs of a coinbase that can pay `value_remaining` (the block
+/// subsidy plus the fees). Each output pays the value that it already has, the value that is left
+/// goes to the first output.
+///
+/// Errors if the outputs pay more than `value_remaining`.
+pub fn split_coinbase_value(outputs: &mut [TxOut], value_remaining: u64) -> Result<(), Error> {
+    if outputs.is_empty() {
+        return Err(Error::EmptyCoinbaseOutputs);
+    }
+    let configured = outputs
+        .iter()
+        .try_fold(0_u64, |sum, output| sum.checked_add(output.value))
+        .ok_or(Error::CoinbaseOutputsValueTooBig(u64::MAX, value_remaining))?;
+    let left = value_remaining
+        .checked_sub(configured)
+        .ok_or(Error::CoinbaseOutputsValueTooBig(
+            configured,
+            value_remaining,
+        ))?;
+    outputs[0].value += left;
+    Ok(())
+}
+
 pub fn extended_job_from_custom_job(
     referenced_job: &mining_sv2::SetCustomMiningJob,
     pool_signature: String,
@@ -155,6 +182,9 @@ pub fn extended_job_from_custom_job(
         coinbase_tx_locktime: referenced_job.coinbase_tx_locktime,
         merkle_path: referenced_job.merkle_path.clone(),
     };
+    // The outputs and their values are declared by the sender of the custom job, the pool outputs
+    // among them are set by the job declarator, so the pool config split is not applied here
+    split_coinbase_value(&mut outputs, referenced_job.coinbase_tx_value_remaining)?;
     new_extended_job(
         &mut template,
         &mut outputs,
@@ -170,7 +200,8 @@ pub fn extended_job_from_custom_job(
 ///
 /// Pool related arguments:
 ///
-/// * `coinbase_outputs`: coinbase output transactions specified by the pool.
+/// * `coinbase_outputs`: coinbase output transactions specified by the pool, with their values
+///   already set.
 /// * `job_id`: incremented job identifier specified by the pool.
 /// * `version_rolling_allowed`: boolean specified by the channel.
 /// * `extranonce_len`: extranonce length specified by the channel.
@@ -182,11 +213,6 @@ fn new_extended_job(
     version_rolling_allowed: bool,
     extranonce_len: u8,
 ) -> Result<NewExtendedMiningJob<'static>, Error> {
-    coinbase_outputs[0].value = match new_template.coinbase_tx_value_remaining.checked_mul(1) {
-        //check that value_remaining is updated by TP
-        Some(result) => result,
-        None => return Err(Error::ValueRemainingNotUpdated),
-    };
     let tx_version = new_template
         .coinbase_tx_version
         .try_into()
@@ -526,9 +552,6 @@ pub mod tests {
     const PRIVATE_KEY_BTC: [u8; 32] = [34; 32];
     const NETWORK: Network = Network::Testnet;
 
-    #[cfg(feature = "prop_test")]
-    const BLOCK_REWARD: u64 = 625_000_000_000;
-
     pub fn new_pub_key() -> PublicKey {
         let priv_k = PrivateKey::from_slice(&PRIVATE_KEY_BTC, NETWORK).unwrap();
         let secp = Secp256k1::default();
@@ -551,7 +574,7 @@ pub mod tests {
             template.coinbase_prefix = prefix.try_into().unwrap();
         };
         let out = TxOut {
-            value: BLOCK_REWARD,
+            value: 0,
             script_pubkey: Script::new_p2pk(&new_pub_key()),
         };
         let mut jobs_creators = JobsCreators::new(32);
@@ -574,7 +597,7 @@ pub mod tests {
     #[quickcheck_macros::quickcheck]
     fn test_reset_new_template(mut template: NewTemplate<'static>) {
         let out = TxOut {
-            value: BLOCK_REWARD,
+            value: 0,
             script_pubkey: Script::new_p2pk(&new_pub_key()),
         };
         let mut jobs_creators = JobsCreators::new(32);
@@ -610,7 +633,7 @@ pub mod tests {
     #[quickcheck_macros::quickcheck]
     fn test_on_new_prev_hash(mut template: NewTemplate<'static>) {
         let out = TxOut {
-            value: BLOCK_REWARD,
+            value: 0,
             script_pubkey: Script::new_p2pk(&new_pub_key()),
         };
         let mut jobs_creators = JobsCreators::new(32);
@@ -747,4 +770,75 @@ pub mod tests {
         // println!("SIZE: {:?}", i);
         Transaction::deserialize(&encoded_clone).unwrap();
     }
+
+    #[test]
+    fn split_coinbase_value_pays_what_is_left_to_the_first_output() {
+        let output = |value| TxOut {
+            value,
+            script_pubkey: stratum_common::bitcoin::Script::new_p2pk(&new_pub_key()),
+        };
+        let mut outputs = vec![output(0), output(100), output(200)];
+        split_coinbase_value(&mut outputs, 1_000).unwrap();
+        let values: Vec<u64> = outputs.iter().map(|o| o.value).collect();
+        assert_eq!(values, vec![700, 100, 200]);
+
+        let mut outputs = vec![output(800), output(200)];
+        split_coinbase_value(&mut outputs, 1_000).unwrap();
+        let values: Vec<u64> = outputs.iter().map(|o| o.value).collect();
+        assert_eq!(values, vec![800, 200]);
+
+        let mut outputs = vec![output(800), output(201)];
+        assert!(matches!(
+            split_coinbase_value(&mut outputs, 1_000),
+            Err(Error::CoinbaseOutputsValueTooBig(1_001, 1_000))
+        ));
+        assert!(matches!(
+            split_coinbase_value(&mut [], 1_000),
+            Err(Error::EmptyCoinbaseOutputs)
+        ));
+    }
+
+    #[test]
+    fn custom_job_keeps_declared_output_values() {
+        let output = |value| TxOut {
+            value,
+            script_pubkey: stratum_common::bitcoin::Script::new_p2pk(&new_pub_key()),
+        };
+        let mut coinbase_tx_outputs = vec![];
+        for out in [output(0), output(100)] {
+            out.consensus_encode(&mut coinbase_tx_outputs).unwrap();
+        }
+        let custom_job = mining_sv2::SetCustomMiningJob {
+            channel_id: 1,
+            request_id: 0,
+            token: vec![].try_into().unwrap(),
+            version: 0x2000_0000,
+            prev_hash: [0; 32].into(),
+            min_ntime: 0,
+            nbits: 0x1d00_ffff,
+            coinbase_tx_version: 2,
+            coinbase_prefix: vec![2, 1, 2, 3].try_into().unwrap(),
+            coinbase_tx_input_n_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 1_000,
+            coinbase_tx_outputs: coinbase_tx_outputs.try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: vec![].into(),
+            extranonce_size: 32,
+        };
+
+        let job = extended_job_from_custom_job(&custom_job, "".to_string(), 32).unwrap();
+        let mut coinbase = job.coinbase_tx_prefix.to_vec();
+        coinbase.extend_from_slice(&[0; 32]);
+        coinbase.extend_from_slice(job.coinbase_tx_suffix.as_ref());
+        let coinbase = Transaction::deserialize(&coinbase).unwrap();
+        let values: Vec<u64> = coinbase.output.iter().map(|o| o.value).collect();
+        assert_eq!(values, vec![900, 100]);
+
+        let mut custom_job = custom_job;
+        custom_job.coinbase_tx_value_remaining = 99;
+        assert!(matches!(
+            extended_job_from_custom_job(&custom_job, "".to_string(), 32),
+            Err(Error::CoinbaseOutputsValueTooBig(100, 99))
+        ));
+    }
 }
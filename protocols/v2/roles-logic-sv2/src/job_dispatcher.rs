@@ -6,17 +6,19 @@
 
 use crate::{
     common_properties::StandardChannel,
-    utils::{merkle_root_from_path, Id, Mutex},
+    utils::{hash_block_header, merkle_root_from_path, Id, Mutex},
     Error,
 };
 use mining_sv2::{
     NewExtendedMiningJob, NewMiningJob, SetNewPrevHash, SubmitSharesError, SubmitSharesStandard,
-    Target,
+    Target, DIFFICULTY_TOO_LOW_ERROR_CODE, INVALID_JOB_ID_ERROR_CODE, STALE_SHARE_ERROR_CODE,
 };
 use nohash_hasher::BuildNoHashHasher;
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
-
-use stratum_common::bitcoin::hashes::{sha256d, Hash, HashEngine};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    sync::Arc,
+};
 
 /// Used to convert an extended mining job to a standard mining job. The `extranonce` field must
 /// be exactly 32 bytes.
@@ -25,70 +27,49 @@ pub fn extended_to_standard_job_for_group_channel<'a>(
     extranonce: &[u8],
     channel_id: u32,
     job_id: u32,
-) -> Option<NewMiningJob<'a>> {
+) -> Result<NewMiningJob<'a>, Error> {
     let merkle_root = merkle_root_from_path(
         extended.coinbase_tx_prefix.inner_as_ref(),
         extended.coinbase_tx_suffix.inner_as_ref(),
         extranonce,
         &extended.merkle_path.inner_as_ref(),
-    );
+    )?;
 
-    Some(NewMiningJob {
+    Ok(NewMiningJob {
         channel_id,
         job_id,
         min_ntime: extended.min_ntime.clone().into_static(),
         version: extended.version,
-        merkle_root: merkle_root?.try_into().ok()?,
+        merkle_root: merkle_root
+            .try_into()
+            .map_err(|_| Error::ImpossibleToCalculateMerkleRoot)?,
     })
 }
-#[allow(dead_code)]
-struct BlockHeader<'a> {
-    version: u32,
-    prev_hash: &'a [u8],
-    merkle_root: &'a [u8],
-    timestamp: u32,
-    nbits: u32,
-    nonce: u32,
-}
-
-impl<'a> BlockHeader<'a> {
-    /// calculates the sha256 blockhash of the header
-    #[allow(dead_code)]
-    pub fn hash(&self) -> Target {
-        let mut engine = sha256d::Hash::engine();
-        engine.input(&self.version.to_le_bytes());
-        engine.input(self.prev_hash);
-        engine.input(self.merkle_root);
-        engine.input(&self.timestamp.to_be_bytes());
-        engine.input(&self.nbits.to_be_bytes());
-        engine.input(&self.nonce.to_be_bytes());
-        let hashed = sha256d::Hash::from_engine(engine).into_inner();
-        hashed.into()
-    }
-}
-
-#[allow(dead_code)]
 fn target_from_shares(
     job: &DownstreamJob,
-    prev_hash: &[u8],
+    prev_hash: &[u8; 32],
     nbits: u32,
     share: &SubmitSharesStandard,
 ) -> Target {
-    let header = BlockHeader {
-        version: share.version,
+    hash_block_header(
+        share.version,
         prev_hash,
-        merkle_root: &job.merkle_root,
-        timestamp: share.ntime,
+        &job.merkle_root,
+        share.ntime,
         nbits,
-        nonce: share.nonce,
-    };
-    header.hash()
+        share.nonce,
+    )
+    .into()
 }
 
 #[derive(Debug)]
 struct DownstreamJob {
-    merkle_root: Vec<u8>,
+    merkle_root: [u8; 32],
     extended_job_id: u32,
+    /// Prev hash epoch the job has been activated in, future jobs get it when activated
+    epoch: u32,
+    /// Target of the downstream channel the job has been sent to
+    target: Target,
 }
 
 #[derive(Debug)]
@@ -97,19 +78,30 @@ struct ExtendedJobs {
     upstream_target: Vec<u8>,
 }
 
+/// Default number of extended jobs whose standard jobs are kept by a
+/// [`GroupChannelJobDispatcher`]
+pub const DEFAULT_MAX_JOBS: usize = 16;
+
 /// Used by proxies to keep track of standard jobs in the group channel
 /// created with the sv2 server
 #[derive(Debug)]
 pub struct GroupChannelJobDispatcher {
     //channels: Vec<StandardChannel>,
-    #[allow(dead_code)]
+    /// Target of the group channel, shares that meet it are sent upstream
     target: Target,
-    prev_hash: Vec<u8>,
+    prev_hash: [u8; 32],
+    /// Incremented on every new prev hash, jobs of an older epoch are stale
+    epoch: u32,
     // extended_job_id -> standard_job_id -> standard_job
     future_jobs:
         HashMap<u32, HashMap<u32, DownstreamJob, BuildNoHashHasher<u32>>, BuildNoHashHasher<u32>>,
     // standard_job_id -> standard_job
     jobs: HashMap<u32, DownstreamJob, BuildNoHashHasher<u32>>,
+    /// Extended job ids of the jobs in `jobs`, oldest first
+    job_history: VecDeque<u32>,
+    /// Maximum number of extended jobs in `job_history`, the standard jobs of the oldest one are
+    /// evicted when it is exceeded
+    max_jobs: usize,
     ids: Arc<Mutex<Id>>,
     // extended_id -> channel_id -> standard_id
     extended_id_to_job_id:
@@ -118,18 +110,30 @@ pub struct GroupChannelJobDispatcher {
 }
 
 pub enum SendSharesResponse {
-    //ValidAndMeetUpstreamTarget((SubmitSharesStandard,SubmitSharesSuccess)),
+    /// The share meets the downstream channel target and the group channel target
+    ValidAndMeetUpstreamTarget(SubmitSharesStandard),
+    /// The share meets the downstream channel target
     Valid(SubmitSharesStandard),
     Invalid(SubmitSharesError<'static>),
 }
 
 impl GroupChannelJobDispatcher {
     pub fn new(ids: Arc<Mutex<Id>>) -> Self {
+        Self::with_max_jobs(ids, DEFAULT_MAX_JOBS)
+    }
+
+    /// Creates a dispatcher that keeps the standard jobs of the last `max_jobs` extended jobs.
+    /// Shares for the jobs of older extended jobs are rejected as for unknown jobs, so
+    /// `max_jobs` must cover the jobs that can still have shares in flight.
+    pub fn with_max_jobs(ids: Arc<Mutex<Id>>, max_jobs: usize) -> Self {
         Self {
             target: [0_u8; 32].into(),
-            prev_hash: Vec::new(),
+            prev_hash: [0; 32],
+            epoch: 0,
             future_jobs: HashMap::with_hasher(BuildNoHashHasher::default()),
             jobs: HashMap::with_hasher(BuildNoHashHasher::default()),
+            job_history: VecDeque::new(),
+            max_jobs,
             ids,
             nbits: 0,
             extended_id_to_job_id: HashMap::with_hasher(BuildNoHashHasher::default()),
@@ -146,8 +150,7 @@ impl GroupChannelJobDispatcher {
         &mut self,
         extended: &NewExtendedMiningJob,
         channel: &StandardChannel,
-        // should be changed to return a Result<Option<NewMiningJob>>
-    ) -> Option<NewMiningJob<'static>> {
+    ) -> Result<NewMiningJob<'static>, Error> {
         if extended.is_future() {
             self.future_jobs
                 .entry(extended.job_id)
@@ -167,9 +170,14 @@ impl GroupChannelJobDispatcher {
             channel.channel_id,
             standard_job_id,
         )?;
+        let merkle_root = new_mining_job_message.merkle_root.inner_as_ref();
         let job = DownstreamJob {
-            merkle_root: new_mining_job_message.merkle_root.to_vec(),
+            merkle_root: merkle_root
+                .try_into()
+                .map_err(|_| Error::ExpectedLen32(merkle_root.len()))?,
             extended_job_id: extended.job_id,
+            epoch: self.epoch,
+            target: channel.target.clone(),
         };
         if extended.is_future() {
             self.future_jobs
@@ -186,24 +194,54 @@ impl GroupChannelJobDispatcher {
             channel_id_to_standard_id.insert(channel.channel_id, standard_job_id);
         } else {
             self.jobs.insert(new_mining_job_message.job_id, job);
+            if !self.job_history.contains(&extended.job_id) {
+                self.job_history.push_back(extended.job_id);
+                self.evict_old_jobs();
+            }
         };
-        Some(new_mining_job_message)
+        Ok(new_mining_job_message)
+    }
+
+    // Drops the standard jobs of the oldest extended jobs until at most `max_jobs` are left
+    fn evict_old_jobs(&mut self) {
+        while self.job_history.len() > self.max_jobs {
+            if let Some(extended_job_id) = self.job_history.pop_front() {
+                self.jobs
+                    .retain(|_, job| job.extended_job_id != extended_job_id);
+            }
+        }
     }
 
     /// Called when a SetNewPrevHash message is received.
-    /// This function will move all future jobs to current jobs, clear old jobs,
-    /// and update `self` to reference the latest prev_hash and nbits
-    /// associated with the latest job.
+    /// This function will move all future jobs to current jobs, mark the current jobs as stale,
+    /// clear the jobs that were already stale, and update `self` to reference the latest
+    /// prev_hash and nbits associated with the latest job.
     pub fn on_new_prev_hash(
         &mut self,
         message: &SetNewPrevHash,
     ) -> Result<HashMap<u32, u32, BuildNoHashHasher<u32>>, Error> {
-        let jobs = self
+        let mut jobs = self
             .future_jobs
-            .get_mut(&message.job_id)
+            .remove(&message.job_id)
             .ok_or(Error::PrevHashRequireNonExistentJobId(message.job_id))?;
-        std::mem::swap(&mut self.jobs, jobs);
-        self.prev_hash = message.prev_hash.to_vec();
+        let stale_epoch = self.epoch;
+        self.epoch = self.epoch.wrapping_add(1);
+        for job in jobs.values_mut() {
+            job.epoch = self.epoch;
+        }
+        // Jobs of the previous prev hash are kept so that late shares for them can be told apart
+        // from shares for unknown jobs
+        self.jobs.retain(|_, job| job.epoch == stale_epoch);
+        let jobs_ = &self.jobs;
+        self.job_history
+            .retain(|id| jobs_.values().any(|job| job.extended_job_id == *id));
+        self.jobs.extend(jobs);
+        self.job_history.push_back(message.job_id);
+        self.evict_old_jobs();
+        let prev_hash = message.prev_hash.inner_as_ref();
+        self.prev_hash = prev_hash
+            .try_into()
+            .map_err(|_| Error::ExpectedLen32(prev_hash.len()))?;
         self.nbits = message.nbits;
         self.future_jobs.clear();
         match self.extended_id_to_job_id.remove(&message.job_id) {
@@ -218,11 +256,35 @@ impl GroupChannelJobDispatcher {
         }
     }
 
+    /// Updates the target of the group channel, called when the upstream sends a SetTarget for
+    /// the group.
+    pub fn set_target(&mut self, target: Target) {
+        self.target = target;
+    }
+
     /// takes shares submitted by a group channel miner and determines if the shares correspond to a valid
-    /// job.
+    /// job that has not been made stale by a new prev hash, and if the share hash meets the target
+    /// of the downstream channel the job has been sent to and the target of the group channel.
     pub fn on_submit_shares(&self, shares: SubmitSharesStandard) -> SendSharesResponse {
         let id = shares.job_id;
         if let Some(job) = self.jobs.get(&id) {
+            if job.epoch != self.epoch {
+                let error = SubmitSharesError::with_code(
+                    shares.channel_id,
+                    shares.sequence_number,
+                    STALE_SHARE_ERROR_CODE,
+                );
+                return SendSharesResponse::Invalid(error);
+            }
+            let hash = target_from_shares(job, &self.prev_hash, self.nbits, &shares);
+            if hash > job.target {
+                let error = SubmitSharesError::with_code(
+                    shares.channel_id,
+                    shares.sequence_number,
+                    DIFFICULTY_TOO_LOW_ERROR_CODE,
+                );
+                return SendSharesResponse::Invalid(error);
+            }
             let success = SubmitSharesStandard {
                 channel_id: shares.channel_id,
                 sequence_number: shares.sequence_number,
@@ -231,15 +293,17 @@ impl GroupChannelJobDispatcher {
                 ntime: shares.ntime,
                 version: shares.version,
             };
-            SendSharesResponse::Valid(success)
+            if hash <= self.target {
+                SendSharesResponse::ValidAndMeetUpstreamTarget(success)
+            } else {
+                SendSharesResponse::Valid(success)
+            }
         } else {
-            let error = SubmitSharesError {
-                channel_id: shares.channel_id,
-                sequence_number: shares.sequence_number,
-                // Below unwrap never panic because an empty string will always fit
-                // in a `Inner<false, 1, 1, 255>` type
-                error_code: "".to_string().into_bytes().try_into().unwrap(),
-            };
+            let error = SubmitSharesError::with_code(
+                shares.channel_id,
+                shares.sequence_number,
+                INVALID_JOB_ID_ERROR_CODE,
+            );
             SendSharesResponse::Invalid(error)
         }
     }
@@ -262,8 +326,6 @@ mod tests {
 
     use stratum_common::bitcoin::{Script, TxOut};
 
-    const BLOCK_REWARD: u64 = 625_000_000_000;
-
     #[test]
     fn test_block_hash() {
         let le_version = "0x32950000".strip_prefix("0x").unwrap();
@@ -284,19 +346,17 @@ mod tests {
         let le_nonce = u32::from_str_radix(le_nonce, 16).expect("Failed converting hex to u32");
         be_prev_hash.reverse();
         be_merkle_root.reverse();
-        let le_prev_hash = be_prev_hash.as_slice();
-        let le_merkle_root = be_merkle_root.as_slice();
-
-        let block_header: BlockHeader = BlockHeader {
-            version: le_version,
-            prev_hash: le_prev_hash,
-            merkle_root: le_merkle_root,
-            timestamp: le_timestamp.to_be(),
-            nbits: le_nbits.to_be(),
-            nonce: le_nonce.to_be(),
-        };
-
-        let target = U256::from(block_header.hash());
+        let le_prev_hash: [u8; 32] = be_prev_hash.try_into().unwrap();
+        let le_merkle_root: [u8; 32] = be_merkle_root.try_into().unwrap();
+
+        let target = U256::from(hash_block_header(
+            le_version,
+            &le_prev_hash,
+            &le_merkle_root,
+            le_timestamp,
+            le_nbits,
+            le_nonce,
+        ));
         let mut actual_block_hash =
             utils::decode_hex("00000000000000000000199349a95526c4f83959f0ef06697048a297f25e7fac")
                 .expect("Failed converting hex to bytes");
@@ -310,8 +370,9 @@ mod tests {
 
     #[test]
     fn test_group_channel_job_dispatcher() {
+        // The value of the template is random, the pool output gets all of it
         let out = TxOut {
-            value: BLOCK_REWARD,
+            value: 0,
             script_pubkey: Script::new_p2pk(&new_pub_key()),
         };
         let pool_signature = "Stratum v2 SRI Pool".to_string();
@@ -329,7 +390,8 @@ mod tests {
         let ids = Arc::new(Mutex::new(Id::new()));
         let mut group_channel_dispatcher = GroupChannelJobDispatcher::new(ids);
         // create standard channel
-        let target = Target::from(U256::try_from(utils::extranonce_gen()).unwrap());
+        // Every share meets the channel target
+        let target = Target::from([255_u8; 32]);
         let standard_channel_id = 2;
         let extranonce = Extranonce::try_from(utils::extranonce_gen())
             .expect("Failed to convert bytes to extranonce");
@@ -433,7 +495,7 @@ mod tests {
                 "job_id not stored correctly in future_jobs"
             );
             assert_eq!(
-                job.merkle_root,
+                job.merkle_root.to_vec(),
                 new_mining_job.merkle_root.to_vec(),
                 "job merkle root not stored correctly in future jobs"
             );
@@ -470,7 +532,8 @@ mod tests {
             .get(&future_job_id)
             .unwrap();
         assert_eq!(
-            new_current_job.merkle_root, test_merkle_root,
+            new_current_job.merkle_root.to_vec(),
+            test_merkle_root,
             "Future job not moved to current job correctly (merkle root)"
         );
         assert_eq!(
@@ -482,7 +545,7 @@ mod tests {
             "nbits not updated for SetNewPrevHash"
         );
         assert_eq!(
-            group_channel_job_dispatcher.prev_hash,
+            group_channel_job_dispatcher.prev_hash.to_vec(),
             new_message.prev_hash.to_vec(),
             "prev_hash not updated for SetNewPrevHash"
         );
@@ -519,6 +582,9 @@ mod tests {
 
         for (index, shares) in vec![shares, faulty_shares].iter().enumerate() {
             match group_channel_job_dispatcher.on_submit_shares(shares.clone()) {
+                SendSharesResponse::ValidAndMeetUpstreamTarget(_) => {
+                    panic!("The group channel target is 0, no share can meet it")
+                }
                 SendSharesResponse::Valid(resp) => {
                     assert_eq!(
                         index, 0,
@@ -540,20 +606,222 @@ mod tests {
                     assert_eq!(err.sequence_number, shares.sequence_number);
                     assert_eq!(
                         err.error_code,
-                        "".to_string().into_bytes().try_into().unwrap()
+                        INVALID_JOB_ID_ERROR_CODE
+                            .to_string()
+                            .into_bytes()
+                            .try_into()
+                            .unwrap()
                     );
                 }
             };
         }
     }
 
+    fn dispatcher_with_job(
+        standard_job_id: u32,
+        channel_target: Target,
+    ) -> GroupChannelJobDispatcher {
+        let ids = Arc::new(Mutex::new(Id::new()));
+        let mut dispatcher = GroupChannelJobDispatcher::new(ids);
+        dispatcher.prev_hash = [7; 32];
+        dispatcher.nbits = 0x17079e15;
+        dispatcher.jobs.insert(
+            standard_job_id,
+            DownstreamJob {
+                merkle_root: [3; 32],
+                extended_job_id: 42,
+                epoch: 0,
+                target: channel_target,
+            },
+        );
+        dispatcher
+    }
+
+    fn share_with_nonce(job_id: u32, nonce: u32) -> SubmitSharesStandard {
+        SubmitSharesStandard {
+            channel_id: 1,
+            sequence_number: 0,
+            job_id,
+            nonce,
+            ntime: 0x637B9A4C,
+            version: 0x20000000,
+        }
+    }
+
+    // Returns a nonce whose share hash has the most significant byte set to 0 (when `zero` is
+    // true) or different from 0 (when `zero` is false)
+    fn find_nonce(dispatcher: &GroupChannelJobDispatcher, job_id: u32, zero: bool) -> u32 {
+        let job = dispatcher.jobs.get(&job_id).unwrap();
+        (0..u32::MAX)
+            .find(|nonce| {
+                let share = share_with_nonce(job_id, *nonce);
+                let hash: U256 =
+                    target_from_shares(job, &dispatcher.prev_hash, dispatcher.nbits, &share).into();
+                (hash.inner_as_ref()[31] == 0) == zero
+            })
+            .unwrap()
+    }
+
+    // Every hash with the most significant byte set to 0 meets this target
+    fn crafted_target() -> Target {
+        let mut target = [255_u8; 32];
+        target[31] = 0;
+        target.into()
+    }
+
+    #[test]
+    fn share_that_meets_channel_target_is_valid() {
+        let dispatcher = dispatcher_with_job(1, crafted_target());
+        let nonce = find_nonce(&dispatcher, 1, true);
+        match dispatcher.on_submit_shares(share_with_nonce(1, nonce)) {
+            SendSharesResponse::Valid(share) => {
+                assert_eq!(share.job_id, 42);
+                assert_eq!(share.nonce, nonce);
+            }
+            _ => panic!("Share should be valid"),
+        }
+    }
+
+    #[test]
+    fn share_that_does_not_meet_channel_target_is_invalid() {
+        let dispatcher = dispatcher_with_job(1, crafted_target());
+        let nonce = find_nonce(&dispatcher, 1, false);
+        match dispatcher.on_submit_shares(share_with_nonce(1, nonce)) {
+            SendSharesResponse::Invalid(error) => assert_eq!(
+                error.error_code,
+                "difficulty-too-low"
+                    .to_string()
+                    .into_bytes()
+                    .try_into()
+                    .unwrap()
+            ),
+            _ => panic!("Share should be invalid"),
+        }
+    }
+
+    #[test]
+    fn share_for_unknown_job_is_invalid_job_id() {
+        let dispatcher = dispatcher_with_job(1, [255; 32].into());
+        match dispatcher.on_submit_shares(share_with_nonce(2, 0)) {
+            SendSharesResponse::Invalid(error) => {
+                assert_eq!(error.channel_id, 1);
+                assert_eq!(
+                    error.error_code.to_vec(),
+                    INVALID_JOB_ID_ERROR_CODE.as_bytes()
+                );
+            }
+            _ => panic!("Share should be invalid"),
+        }
+    }
+
+    #[test]
+    fn share_for_job_of_previous_prev_hash_is_stale() {
+        let mut dispatcher = dispatcher_with_job(1, [255; 32].into());
+        let new_prev_hash = |job_id| SetNewPrevHash {
+            channel_id: 0,
+            job_id,
+            prev_hash: u256_from_int(45_u32),
+            min_ntime: 0,
+            nbits: 0x17079e15,
+        };
+        dispatcher
+            .future_jobs
+            .insert(43, HashMap::with_hasher(BuildNoHashHasher::default()));
+        dispatcher.on_new_prev_hash(&new_prev_hash(43)).unwrap();
+
+        match dispatcher.on_submit_shares(share_with_nonce(1, 0)) {
+            SendSharesResponse::Invalid(error) => {
+                assert_eq!(error.error_code.to_vec(), STALE_SHARE_ERROR_CODE.as_bytes());
+            }
+            _ => panic!("Share should be stale"),
+        }
+
+        // after another prev hash the job is forgotten
+        dispatcher
+            .future_jobs
+            .insert(44, HashMap::with_hasher(BuildNoHashHasher::default()));
+        dispatcher.on_new_prev_hash(&new_prev_hash(44)).unwrap();
+        match dispatcher.on_submit_shares(share_with_nonce(1, 0)) {
+            SendSharesResponse::Invalid(error) => {
+                assert_eq!(
+                    error.error_code.to_vec(),
+                    INVALID_JOB_ID_ERROR_CODE.as_bytes()
+                );
+            }
+            _ => panic!("Share should be invalid"),
+        }
+    }
+
+    #[test]
+    fn jobs_of_oldest_extended_job_are_evicted() {
+        let out = TxOut {
+            value: 0,
+            script_pubkey: Script::new_p2pk(&new_pub_key()),
+        };
+        let mut jobs_creators = JobsCreators::new(32);
+        let ids = Arc::new(Mutex::new(Id::new()));
+        let mut dispatcher = GroupChannelJobDispatcher::with_max_jobs(ids, 2);
+        let channel = StandardChannel {
+            channel_id: 1,
+            group_id: 1,
+            target: [255_u8; 32].into(),
+            extranonce: Extranonce::try_from(utils::extranonce_gen()).unwrap(),
+        };
+
+        let mut standard_job_ids = vec![];
+        for _ in 0..3 {
+            let mut template = template_from_gen(&mut Gen::new(255));
+            template.template_id %= u64::MAX;
+            template.future_template = false;
+            let extended = jobs_creators
+                .on_new_template(&mut template, false, vec![out.clone()], "".to_string())
+                .unwrap();
+            let job = dispatcher
+                .on_new_extended_mining_job(&extended, &channel)
+                .unwrap();
+            standard_job_ids.push(job.job_id);
+        }
+
+        match dispatcher.on_submit_shares(share_with_nonce(standard_job_ids[0], 0)) {
+            SendSharesResponse::Invalid(error) => {
+                assert_eq!(
+                    error.error_code.to_vec(),
+                    INVALID_JOB_ID_ERROR_CODE.as_bytes()
+                );
+            }
+            _ => panic!("Job of the oldest extended job should be evicted"),
+        }
+        for job_id in &standard_job_ids[1..] {
+            match dispatcher.on_submit_shares(share_with_nonce(*job_id, 0)) {
+                SendSharesResponse::Valid(_) => (),
+                _ => panic!("Share should be valid"),
+            }
+        }
+    }
+
+    #[test]
+    fn share_that_meets_group_target_is_sent_upstream() {
+        let mut dispatcher = dispatcher_with_job(1, [255; 32].into());
+        dispatcher.set_target(crafted_target());
+        let nonce = find_nonce(&dispatcher, 1, true);
+        match dispatcher.on_submit_shares(share_with_nonce(1, nonce)) {
+            SendSharesResponse::ValidAndMeetUpstreamTarget(share) => {
+                assert_eq!(share.job_id, 42)
+            }
+            _ => panic!("Share should meet the group channel target"),
+        }
+    }
+
     #[test]
     fn builds_group_channel_job_dispatcher() {
         let expect = GroupChannelJobDispatcher {
             target: [0_u8; 32].into(),
-            prev_hash: Vec::new(),
+            prev_hash: [0; 32],
+            epoch: 0,
             future_jobs: HashMap::with_hasher(BuildNoHashHasher::default()),
             jobs: HashMap::with_hasher(BuildNoHashHasher::default()),
+            job_history: VecDeque::new(),
+            max_jobs: DEFAULT_MAX_JOBS,
             ids: Arc::new(Mutex::new(Id::new())),
             nbits: 0,
             extended_id_to_job_id: HashMap::with_hasher(BuildNoHashHasher::default()),
@@ -6,6 +6,7 @@
 //! - Routers in [`routing_logic`] are used by the traits in `handlers` to decide which downstream/upstream to relay/send by using [`selectors`]
 //! - For serializing/deserializing messages, see [`parsers`]
 //! - see [`utils`] for helpers such as safe locking, target and merkle root calculations
+//! - see [`sv1_sv2_conversions`] for the Sv1 difficulty to Sv2 target conversions
 //!
 //!```txt
 //! MiningDevice:
@@ -41,6 +42,9 @@ pub mod job_dispatcher;
 pub mod parsers;
 pub mod routing_logic;
 pub mod selectors;
+#[cfg(feature = "status")]
+pub mod status;
+pub mod sv1_sv2_conversions;
 pub mod utils;
 pub use common_messages_sv2;
 pub use errors::Error;
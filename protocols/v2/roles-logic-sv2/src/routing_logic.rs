@@ -207,7 +207,7 @@ impl<
             .safe_lock(|u| {
                 u.get_mapper()
                     .ok_or(crate::Error::RequestIdNotMapped(upstream_request_id))?
-                    .remove(upstream_request_id)
+                    .remove_checked(upstream_request_id)
                     .ok_or(Error::RequestIdNotMapped(upstream_request_id))
             })
             .map_err(|e| Error::PoisonLock(e.to_string()))??;
@@ -364,7 +364,7 @@ impl<
         let message = SetupConnectionSuccess {
             used_version: 2,
             flags: upstream
-                .safe_lock(|u| u.get_flags())
+                .safe_lock(|u| u.get_flags().bits())
                 .map_err(|e| Error::PoisonLock(e.to_string()))?,
         };
         self.downstream_to_upstream_map
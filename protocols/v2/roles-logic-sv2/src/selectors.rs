@@ -14,6 +14,7 @@ pub struct ProxyDownstreamMiningSelector<Down: IsDownstream> {
     request_id_to_remotes: HashMap<u32, Arc<Mutex<Down>>, BuildNoHashHasher<u32>>,
     channel_id_to_downstreams: HashMap<u32, Vec<Arc<Mutex<Down>>>, BuildNoHashHasher<u32>>,
     channel_id_to_downstream: HashMap<u32, Arc<Mutex<Down>>, BuildNoHashHasher<u32>>,
+    channel_id_to_group_id: HashMap<u32, u32, BuildNoHashHasher<u32>>,
 }
 
 impl<Down: IsDownstream> ProxyDownstreamMiningSelector<Down> {
@@ -22,6 +23,7 @@ impl<Down: IsDownstream> ProxyDownstreamMiningSelector<Down> {
             request_id_to_remotes: HashMap::with_hasher(BuildNoHashHasher::default()),
             channel_id_to_downstreams: HashMap::with_hasher(BuildNoHashHasher::default()),
             channel_id_to_downstream: HashMap::with_hasher(BuildNoHashHasher::default()),
+            channel_id_to_group_id: HashMap::with_hasher(BuildNoHashHasher::default()),
         }
     }
     pub fn new_as_mutex() -> Arc<Mutex<Self>>
@@ -37,6 +39,37 @@ impl<Down: IsMiningDownstream> ProxyDownstreamMiningSelector<Down> {
         self.request_id_to_remotes.retain(|_, v| !Arc::ptr_eq(v, d));
         self.channel_id_to_downstream
             .retain(|_, v| !Arc::ptr_eq(v, d));
+        let channel_id_to_downstream = &self.channel_id_to_downstream;
+        self.channel_id_to_group_id
+            .retain(|channel_id, _| channel_id_to_downstream.contains_key(channel_id));
+    }
+
+    /// Removes a standard or extended channel and returns the downstream that opened it. Groups
+    /// left without downstreams are removed as well. Returns `None` if `channel_id` do not
+    /// belong to a single downstream (eg it is a group channel id).
+    pub fn remove_channel(&mut self, channel_id: u32) -> Option<Arc<Mutex<Down>>> {
+        let downstream = self.channel_id_to_downstream.remove(&channel_id)?;
+        let group_id = self
+            .channel_id_to_group_id
+            .remove(&channel_id)
+            .unwrap_or(channel_id);
+        // A group holds the downstream once per channel: only the entry of the closed channel is
+        // removed, the downstream can still have other channels open in the group
+        if let Some(dws) = self.channel_id_to_downstreams.get_mut(&group_id) {
+            if let Some(index) = dws.iter().position(|d| Arc::ptr_eq(d, &downstream)) {
+                dws.remove(index);
+            }
+            if dws.is_empty() {
+                self.channel_id_to_downstreams.remove(&group_id);
+            }
+        }
+        Some(downstream)
+    }
+
+    /// Removes a pending open channel request, e.g. when the upstream rejects it, and returns the
+    /// downstream that sent it. Returns `None` if `request_id` is not pending.
+    pub fn remove_request(&mut self, request_id: u32) -> Option<Arc<Mutex<Down>>> {
+        self.request_id_to_remotes.remove(&request_id)
     }
 }
 
@@ -59,6 +92,7 @@ impl<Down: IsMiningDownstream> DownstreamMiningSelector<Down>
             .ok_or(Error::UnknownRequestId(request_id))?;
         self.channel_id_to_downstream
             .insert(channel_id, downstream.clone());
+        self.channel_id_to_group_id.insert(channel_id, g_channel_id);
         match self.channel_id_to_downstreams.get_mut(&g_channel_id) {
             None => {
                 self.channel_id_to_downstreams
@@ -86,8 +120,10 @@ impl<Down: IsMiningDownstream> DownstreamMiningSelector<Down>
 
     fn remove_downstream(&mut self, d: &Arc<Mutex<Down>>) {
         for dws in self.channel_id_to_downstreams.values_mut() {
-            dws.retain(|d| !Arc::ptr_eq(d, d));
+            dws.retain(|dw| !Arc::ptr_eq(dw, d));
         }
+        self.channel_id_to_downstreams
+            .retain(|_, dws| !dws.is_empty());
 
         self._remove_downstream(d);
     }
@@ -207,6 +243,14 @@ pub trait UpstreamMiningSelctor<
         pair_settings: &PairSettings,
     ) -> Result<(Vec<Arc<Mutex<Up>>>, u32), Error>;
     fn get_upstream(&self, upstream_id: u32) -> Option<Arc<Mutex<Up>>>;
+    /// Among the upstreams that can be paired with `pair_settings`, return the one with the
+    /// lowest total hash rate, so that new downstreams can be spread across the upstreams.
+    ///
+    /// The default implementation do not know the upstreams of the selector and returns `None`,
+    /// so that existing selectors keep compiling
+    fn select_least_loaded(&self, _pair_settings: &PairSettings) -> Option<Arc<Mutex<Up>>> {
+        None
+    }
 }
 
 /// Upstream selector is used to chose between a set of known mining upstream nodes which one/ones
@@ -276,7 +320,7 @@ impl<
                 .unwrap();
             if is_pairable {
                 // Is ok to unwrap safe_lock result
-                supported_flags |= node.safe_lock(|n| n.get_flags()).unwrap();
+                supported_flags |= node.safe_lock(|n| n.get_flags().bits()).unwrap();
                 supported_upstreams.push(node.clone());
             }
         }
@@ -290,4 +334,215 @@ impl<
     fn get_upstream(&self, upstream_id: u32) -> Option<Arc<Mutex<Up>>> {
         self.id_to_upstream.get(&upstream_id).cloned()
     }
+
+    /// If more upstreams have the lowest hash rate the first one is returned
+    fn select_least_loaded(&self, pair_settings: &PairSettings) -> Option<Arc<Mutex<Up>>> {
+        self.upstreams
+            .iter()
+            .filter_map(|node| {
+                node.safe_lock(|node| {
+                    node.is_pairable(pair_settings)
+                        .then(|| node.total_hash_rate())
+                })
+                // Is ok to unwrap safe_lock result
+                .unwrap()
+                .map(|hash_rate| (hash_rate, node))
+            })
+            .min_by_key(|(hash_rate, _)| *hash_rate)
+            .map(|(_, node)| node.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common_properties::{IsUpstream, RequestIdMapper, UpstreamChannel};
+    use common_messages_sv2::{MiningFlags, Protocol, ProtocolVersion};
+
+    #[derive(Debug)]
+    struct TestUpstream {
+        id: u32,
+        version: u16,
+        hash_rate: u64,
+        selector: NullDownstreamMiningSelector,
+    }
+
+    impl TestUpstream {
+        fn new(id: u32, version: u16, hash_rate: u64) -> Arc<Mutex<Self>> {
+            Arc::new(Mutex::new(Self {
+                id,
+                version,
+                hash_rate,
+                selector: NullDownstreamMiningSelector::new(),
+            }))
+        }
+    }
+
+    impl IsUpstream<(), NullDownstreamMiningSelector> for TestUpstream {
+        fn get_version(&self) -> ProtocolVersion {
+            self.version.into()
+        }
+        fn get_flags(&self) -> MiningFlags {
+            MiningFlags::empty()
+        }
+        fn get_supported_protocols(&self) -> Vec<Protocol> {
+            vec![Protocol::MiningProtocol]
+        }
+        fn get_id(&self) -> u32 {
+            self.id
+        }
+        fn get_mapper(&mut self) -> Option<&mut RequestIdMapper> {
+            None
+        }
+        fn get_remote_selector(&mut self) -> &mut NullDownstreamMiningSelector {
+            &mut self.selector
+        }
+    }
+
+    impl IsMiningUpstream<(), NullDownstreamMiningSelector> for TestUpstream {
+        fn total_hash_rate(&self) -> u64 {
+            self.hash_rate
+        }
+        fn add_hash_rate(&mut self, to_add: u64) {
+            self.hash_rate += to_add;
+        }
+        fn get_opened_channels(&mut self) -> &mut Vec<UpstreamChannel> {
+            unreachable!()
+        }
+        fn update_channels(&mut self, _: UpstreamChannel) {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn selects_least_loaded_pairable_upstream() {
+        let upstreams = vec![
+            TestUpstream::new(0, 2, 300),
+            TestUpstream::new(1, 2, 100),
+            TestUpstream::new(2, 2, 200),
+            // Lowest hash rate but it does not support version 2
+            TestUpstream::new(3, 1, 10),
+        ];
+        let selector: GeneralMiningSelector<NullDownstreamMiningSelector, (), TestUpstream> =
+            GeneralMiningSelector::new(upstreams);
+        let pair_settings = PairSettings {
+            protocol: Protocol::MiningProtocol,
+            min_v: 2,
+            max_v: 2,
+            flags: 0,
+        };
+
+        let selected = selector.select_least_loaded(&pair_settings).unwrap();
+        assert_eq!(selected.safe_lock(|u| u.get_id()).unwrap(), 1);
+
+        selected.safe_lock(|u| u.add_hash_rate(250)).unwrap();
+        let selected = selector.select_least_loaded(&pair_settings).unwrap();
+        assert_eq!(selected.safe_lock(|u| u.get_id()).unwrap(), 2);
+    }
+
+    #[test]
+    fn no_least_loaded_upstream_if_none_is_pairable() {
+        let upstreams = vec![TestUpstream::new(0, 1, 100)];
+        let selector: GeneralMiningSelector<NullDownstreamMiningSelector, (), TestUpstream> =
+            GeneralMiningSelector::new(upstreams);
+        let pair_settings = PairSettings {
+            protocol: Protocol::MiningProtocol,
+            min_v: 2,
+            max_v: 2,
+            flags: 0,
+        };
+
+        assert!(selector.select_least_loaded(&pair_settings).is_none());
+    }
+
+    // Selector that only implements the required methods of `UpstreamMiningSelctor`
+    struct MinimalSelector;
+
+    impl UpstreamSelector for MinimalSelector {}
+
+    impl UpstreamMiningSelctor<(), TestUpstream, NullDownstreamMiningSelector> for MinimalSelector {
+        fn on_setup_connection(
+            &mut self,
+            _: &PairSettings,
+        ) -> Result<(Vec<Arc<Mutex<TestUpstream>>>, u32), Error> {
+            unreachable!()
+        }
+        fn get_upstream(&self, _: u32) -> Option<Arc<Mutex<TestUpstream>>> {
+            None
+        }
+    }
+
+    #[test]
+    fn default_select_least_loaded_selects_nothing() {
+        let pair_settings = PairSettings {
+            protocol: Protocol::MiningProtocol,
+            min_v: 2,
+            max_v: 2,
+            flags: 0,
+        };
+
+        assert!(MinimalSelector
+            .select_least_loaded(&pair_settings)
+            .is_none());
+    }
+
+    #[test]
+    fn removing_a_downstream_keeps_the_others_in_its_group() {
+        let mut selector: ProxyDownstreamMiningSelector<()> = ProxyDownstreamMiningSelector::new();
+        let downstreams: Vec<Arc<Mutex<()>>> = (0..3).map(|_| Arc::new(Mutex::new(()))).collect();
+        for (i, downstream) in downstreams.iter().enumerate() {
+            selector.on_open_standard_channel_request(i as u32, downstream.clone());
+            selector
+                .on_open_standard_channel_success(i as u32, 1, i as u32 + 2)
+                .unwrap();
+        }
+        // A downstream alone in its group
+        let alone = Arc::new(Mutex::new(()));
+        selector.on_open_standard_channel_request(3, alone.clone());
+        selector
+            .on_open_standard_channel_success(3, 10, 11)
+            .unwrap();
+
+        selector.remove_downstream(&downstreams[1]);
+        let group = selector.get_downstreams_in_channel(1).unwrap();
+        assert_eq!(group.len(), 2);
+        assert!(Arc::ptr_eq(&group[0], &downstreams[0]));
+        assert!(Arc::ptr_eq(&group[1], &downstreams[2]));
+        assert!(selector.downstream_from_channel_id(3).is_none());
+        assert!(selector.downstream_from_channel_id(4).is_some());
+
+        selector.remove_downstream(&alone);
+        assert!(selector.get_downstreams_in_channel(10).is_none());
+    }
+
+    #[test]
+    fn closing_a_channel_keeps_the_other_channels_of_its_downstream() {
+        let mut selector: ProxyDownstreamMiningSelector<()> = ProxyDownstreamMiningSelector::new();
+        let downstream = Arc::new(Mutex::new(()));
+        let other = Arc::new(Mutex::new(()));
+        // Two channels of the same downstream in group 1, one of another downstream in group 10
+        for (request_id, group_id, channel_id) in [(0, 1, 2), (1, 1, 3)] {
+            selector.on_open_standard_channel_request(request_id, downstream.clone());
+            selector
+                .on_open_standard_channel_success(request_id, group_id, channel_id)
+                .unwrap();
+        }
+        selector.on_open_standard_channel_request(2, other.clone());
+        selector
+            .on_open_standard_channel_success(2, 10, 11)
+            .unwrap();
+
+        let removed = selector.remove_channel(2).unwrap();
+        assert!(Arc::ptr_eq(&removed, &downstream));
+        let group = selector.get_downstreams_in_channel(1).unwrap();
+        assert_eq!(group.len(), 1);
+        assert!(Arc::ptr_eq(&group[0], &downstream));
+        assert!(selector.downstream_from_channel_id(3).is_some());
+        // The other groups are untouched
+        assert_eq!(selector.get_downstreams_in_channel(10).unwrap().len(), 1);
+
+        selector.remove_channel(3).unwrap();
+        assert!(selector.get_downstreams_in_channel(1).is_none());
+        assert!(selector.remove_channel(3).is_none());
+    }
 }
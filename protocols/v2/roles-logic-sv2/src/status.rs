@@ -0,0 +1,127 @@
+//! Status channel shared by the roles. Each component of a role (a downstream, the upstream, the
+//! template receiver, ...) reports its lifecycle events with a [`StatusReporter`] tagged with the
+//! component, and the main loop of the role receives them all from a single channel and decides
+//! what to do (restart the component, drop a downstream, shut down, ...).
+use async_channel::Sender;
+use core::marker::PhantomData;
+use error_handling::ErrorBranch;
+
+/// Lifecycle event reported by the component `C`
+#[derive(Debug)]
+pub enum State<C, E> {
+    /// The component stopped because of the error
+    Shutdown(C, E),
+    /// The instance with the given id (e.g. a downstream) has been dropped, the other instances
+    /// of the component keep running
+    InstanceDropped(C, u32),
+    /// The component handled an error and keeps running
+    Healthy(C, String),
+}
+
+/// Sending side of the status channel used by the component `C`. `S` is the message received by
+/// the main loop, a role that wants its own status type only has to implement
+/// `From<State<C, E>>` for it.
+#[derive(Debug)]
+pub struct StatusReporter<C, E, S = State<C, E>> {
+    component: C,
+    sender: Sender<S>,
+    error: PhantomData<fn() -> E>,
+}
+
+impl<C: Clone, E, S> Clone for StatusReporter<C, E, S> {
+    fn clone(&self) -> Self {
+        Self {
+            component: self.component.clone(),
+            sender: self.sender.clone(),
+            error: PhantomData,
+        }
+    }
+}
+
+impl<C: Clone, E, S: From<State<C, E>>> StatusReporter<C, E, S> {
+    pub fn new(component: C, sender: Sender<S>) -> Self {
+        Self {
+            component,
+            sender,
+            error: PhantomData,
+        }
+    }
+
+    pub fn component(&self) -> &C {
+        &self.component
+    }
+
+    /// Report that the component stopped because of `error`, `outcome` is returned as is so that
+    /// the caller can break or continue the loop it is in
+    pub async fn send_error(&self, error: E, outcome: ErrorBranch) -> ErrorBranch {
+        self.send(State::Shutdown(self.component.clone(), error))
+            .await;
+        outcome
+    }
+
+    /// Report that the component handled `error` and keeps running
+    pub async fn send_healthy(&self, error: E, outcome: ErrorBranch) -> ErrorBranch
+    where
+        E: core::fmt::Display,
+    {
+        self.send(State::Healthy(self.component.clone(), error.to_string()))
+            .await;
+        outcome
+    }
+
+    /// Report that the instance `id` of the component has been dropped
+    pub async fn send_instance_dropped(&self, id: u32, outcome: ErrorBranch) -> ErrorBranch {
+        self.send(State::InstanceDropped(self.component.clone(), id))
+            .await;
+        outcome
+    }
+
+    // When the main loop is gone the role is shutting down, there is no one left to notify
+    async fn send(&self, state: State<C, E>) {
+        self.sender.send(state.into()).await.unwrap_or(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Component {
+        Downstream,
+        Upstream,
+    }
+
+    #[tokio::test]
+    async fn errors_are_tagged_with_the_reporting_component() {
+        let (tx, rx) = async_channel::unbounded();
+        let downstream = StatusReporter::<_, String>::new(Component::Downstream, tx.clone());
+        let upstream = StatusReporter::<_, String>::new(Component::Upstream, tx);
+
+        let outcome = upstream
+            .send_error("connection lost".to_string(), ErrorBranch::Break)
+            .await;
+        assert!(matches!(outcome, ErrorBranch::Break));
+        match rx.recv().await.unwrap() {
+            State::Shutdown(Component::Upstream, e) => assert_eq!(e, "connection lost"),
+            state => panic!("unexpected state {:?}", state),
+        }
+
+        let outcome = downstream
+            .send_healthy("bad share".to_string(), ErrorBranch::Continue)
+            .await;
+        assert!(matches!(outcome, ErrorBranch::Continue));
+        match rx.recv().await.unwrap() {
+            State::Healthy(Component::Downstream, e) => assert_eq!(e, "bad share"),
+            state => panic!("unexpected state {:?}", state),
+        }
+
+        downstream
+            .send_instance_dropped(7, ErrorBranch::Continue)
+            .await;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            State::InstanceDropped(Component::Downstream, 7)
+        ));
+    }
+}
@@ -0,0 +1,49 @@
+//! Conversions between the Sv1 share difficulty and the Sv2 share target, shared by the roles
+//! that translate between the two protocols (e.g. the translator proxy, or a pool that serves Sv1
+//! miners).
+//!
+//! Targets are 32 bytes **little endian**, as carried by the Sv2 messages (`SetTarget`,
+//! `OpenExtendedMiningChannelSuccess`, ...). The difficulty is the Sv1 `mining.set_difficulty`
+//! value, that is pdiff / target where pdiff is the target of a difficulty 1 share
+//! (`0x00000000ffff...ff`).
+use crate::utils::Target;
+
+/// Returns the Sv1 difficulty of the little endian `target`. A zero target returns a difficulty
+/// of 0, difficulties bigger than 1 are truncated to an integer.
+pub fn target_to_sv1_difficulty(target: &[u8; 32]) -> f64 {
+    Target::from(*target).to_difficulty()
+}
+
+/// Returns the little endian target of a share of Sv1 difficulty `difficulty`. Difficulties that
+/// are not positive, or too small to be represented, return the max target.
+pub fn sv1_difficulty_to_target(difficulty: f64) -> [u8; 32] {
+    Target::from_difficulty(difficulty).to_le_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_to_sv1_difficulty() {
+        let target = [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 255, 127,
+            0, 0, 0, 0, 0,
+        ];
+        assert_eq!(target_to_sv1_difficulty(&target), 512.0);
+        assert_eq!(target_to_sv1_difficulty(&[0; 32]), 0.0);
+    }
+
+    #[test]
+    fn test_sv1_difficulty_target_round_trip() {
+        for difficulty in [1.0, 2.0, 512.0, 65536.0, 1_000_000.0] {
+            let target = sv1_difficulty_to_target(difficulty);
+            assert_eq!(target_to_sv1_difficulty(&target), difficulty);
+        }
+        // difficulty 1 is pdiff, the most significant 4 bytes are zero
+        let mut pdiff = [255; 32];
+        pdiff[28..].copy_from_slice(&[0; 4]);
+        assert_eq!(sv1_difficulty_to_target(1.0), pdiff);
+        assert_eq!(sv1_difficulty_to_target(0.0), [255; 32]);
+    }
+}
@@ -1,11 +1,15 @@
 use std::{
     convert::{TryFrom, TryInto},
-    ops::{Div, Mul},
+    ops::Div,
     str::FromStr,
-    sync::{Mutex as Mutex_, MutexGuard, PoisonError},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex as Mutex_, MutexGuard, PoisonError,
+    },
 };
 
 use binary_sv2::{Seq064K, ShortTxId, U256};
+use common_messages_sv2::{Protocol, SetupConnection};
 use job_declaration_sv2::{DeclareMiningJob, SubmitSolutionJd};
 use siphasher::sip::SipHasher24;
 //compact_target_from_u256
@@ -15,21 +19,24 @@ use stratum_common::{
     bitcoin::{
         blockdata::block::BlockHeader,
         hash_types::{BlockHash, TxMerkleNode},
-        hashes::{sha256, sha256d::Hash as DHash, Hash},
+        hashes::{hex::FromHex, sha256, sha256d::Hash as DHash, Hash, HashEngine},
         secp256k1::{All, Secp256k1},
         util::{
             psbt::serialize::Deserialize,
-            uint::{Uint128, Uint256},
+            uint::{ParseLengthError, Uint256},
             BitArray,
         },
-        PublicKey, Script, Transaction, XOnlyPublicKey,
+        PublicKey, Script, Transaction, TxOut, XOnlyPublicKey,
     },
 };
 use tracing::error;
 
 use crate::errors::Error;
 
-/// Generator of unique ids
+/// Generator of unique ids, the first id is 1.
+///
+/// After `u32::MAX` [`Id::next`] wraps around and starts again from 1, so it can return an id
+/// that is still in use. Use [`Id::next_checked`] where that is not acceptable.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Id {
     state: u32,
@@ -39,12 +46,21 @@ impl Id {
     pub fn new() -> Self {
         Self { state: 0 }
     }
-    /// return current state and increment
+    /// increment and return current state, wrapping to 1 after `u32::MAX`
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u32 {
-        self.state += 1;
+        self.state = self.state.checked_add(1).unwrap_or(1);
         self.state
     }
+    /// increment and return current state, `None` when every id has already been returned
+    pub fn next_checked(&mut self) -> Option<u32> {
+        self.state = self.state.checked_add(1)?;
+        Some(self.state)
+    }
+    /// start again from the first id
+    pub fn reset(&mut self) {
+        self.state = 0;
+    }
 }
 
 impl Default for Id {
@@ -55,7 +71,24 @@ impl Default for Id {
 
 /// Safer Mutex wrapper
 #[derive(Debug)]
-pub struct Mutex<T: ?Sized>(Mutex_<T>);
+pub struct Mutex<T: ?Sized> {
+    // Set by `safe_lock_or_recover`, a poisoned lock is then used as a healthy one until the next
+    // panic under the lock. Stands for `std::sync::Mutex::clear_poison` that is not available in
+    // our MSRV.
+    recovered: AtomicBool,
+    inner: Mutex_<T>,
+}
+
+// Forgets a recovered poisoning when the thread panics while holding the lock
+struct PoisonOnPanic<'a>(&'a AtomicBool);
+
+impl Drop for PoisonOnPanic<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.store(false, Ordering::Release);
+        }
+    }
+}
 
 impl<T> Mutex<T> {
     /// `safe_lock` takes a closure that takes a mutable reference to the inner value, and returns a
@@ -64,6 +97,10 @@ impl<T> Mutex<T> {
     /// `PoisonLock` errors, unwraps should never be used within the closure. Always return the result and
     /// handle outside of the safe lock.
     ///
+    /// Once a poisoned lock has been recovered by [`Mutex::safe_lock_or_recover`], `safe_lock`
+    /// stops reporting that poisoning for every caller and runs `thunk` on the recovered value.
+    /// A later panic while holding the lock is reported again.
+    ///
     /// Arguments:
     ///
     /// * `thunk`: A closure that takes a mutable reference to the value inside the Mutex and returns a
@@ -73,12 +110,48 @@ impl<T> Mutex<T> {
     where
         F: FnOnce(&mut T) -> Ret,
     {
-        let mut lock = self.0.lock()?;
+        let mut lock = match self.inner.lock() {
+            Ok(lock) => lock,
+            Err(e) if self.recovered.load(Ordering::Acquire) => e.into_inner(),
+            Err(e) => return Err(e),
+        };
+        let poison_on_panic = PoisonOnPanic(&self.recovered);
         let return_value = thunk(&mut *lock);
+        drop(poison_on_panic);
         drop(lock);
         Ok(return_value)
     }
 
+    /// Like [`Mutex::safe_lock`] but a lock poisoned by a thread that panicked while holding it
+    /// is recovered instead of returning an error. `recover` is called once on the value left by
+    /// the panicked thread, to bring it back to a consistent state, before `thunk`. From then on
+    /// the lock is healthy again for every caller, until the next panic under the lock.
+    pub fn safe_lock_or_recover<R, F, Ret>(&self, recover: R, thunk: F) -> Ret
+    where
+        R: FnOnce(&mut T),
+        F: FnOnce(&mut T) -> Ret,
+    {
+        let mut lock = match self.inner.lock() {
+            Ok(lock) => lock,
+            Err(e) => {
+                let mut lock = e.into_inner();
+                if !self.recovered.load(Ordering::Acquire) {
+                    error!("A thread panicked while holding the lock, recovering it");
+                    let poison_on_panic = PoisonOnPanic(&self.recovered);
+                    recover(&mut *lock);
+                    drop(poison_on_panic);
+                    self.recovered.store(true, Ordering::Release);
+                }
+                lock
+            }
+        };
+        let poison_on_panic = PoisonOnPanic(&self.recovered);
+        let return_value = thunk(&mut *lock);
+        drop(poison_on_panic);
+        drop(lock);
+        return_value
+    }
+
     pub fn super_safe_lock<F, Ret>(&self, thunk: F) -> Ret
     where
         F: FnOnce(&mut T) -> Ret,
@@ -112,11 +185,14 @@ impl<T> Mutex<T> {
     }
 
     pub fn new(v: T) -> Self {
-        Mutex(Mutex_::new(v))
+        Mutex {
+            recovered: AtomicBool::new(false),
+            inner: Mutex_::new(v),
+        }
     }
 
     pub fn to_remove(&self) -> Result<MutexGuard<'_, T>, PoisonError<MutexGuard<'_, T>>> {
-        self.0.lock()
+        self.inner.lock()
     }
 }
 
@@ -136,56 +212,135 @@ impl<T> Mutex<T> {
 ///
 /// Returns:
 ///
-/// A 32 byte merkle root as a vector if successful and None if the arguments are invalid.
+/// A 32 byte merkle root as a vector if successful and a [`MerkleError`] if the arguments are
+/// invalid.
 pub fn merkle_root_from_path<T: AsRef<[u8]>>(
     coinbase_tx_prefix: &[u8],
     coinbase_tx_suffix: &[u8],
     extranonce: &[u8],
     path: &[T],
-) -> Option<Vec<u8>> {
+) -> Result<Vec<u8>, MerkleError> {
     let mut coinbase =
         Vec::with_capacity(coinbase_tx_prefix.len() + coinbase_tx_suffix.len() + extranonce.len());
     coinbase.extend_from_slice(coinbase_tx_prefix);
     coinbase.extend_from_slice(extranonce);
     coinbase.extend_from_slice(coinbase_tx_suffix);
-    let coinbase = match Transaction::deserialize(&coinbase[..]) {
-        Ok(trans) => trans,
+    let coinbase =
+        Transaction::deserialize(&coinbase[..]).map_err(MerkleError::CoinbaseDeserialize)?;
+
+    let coinbase_id: [u8; 32] = coinbase
+        .txid()
+        .to_vec()
+        .try_into()
+        .map_err(|_| MerkleError::BadCoinbaseId)?;
+    Ok(merkle_root_from_path_(coinbase_id, path)?.to_vec())
+}
+
+/// Same as [`merkle_root_from_path`] but the error is only logged and `None` is returned
+pub fn merkle_root_from_path_option<T: AsRef<[u8]>>(
+    coinbase_tx_prefix: &[u8],
+    coinbase_tx_suffix: &[u8],
+    extranonce: &[u8],
+    path: &[T],
+) -> Option<Vec<u8>> {
+    match merkle_root_from_path(coinbase_tx_prefix, coinbase_tx_suffix, extranonce, path) {
+        Ok(root) => Some(root),
         Err(e) => {
             error!("ERROR: {}", e);
-            return None;
+            None
         }
-    };
+    }
+}
 
-    let coinbase_id: [u8; 32] = match coinbase.txid().to_vec().try_into() {
-        Ok(id) => id,
-        Err(_e) => return None,
-    };
-    Some(merkle_root_from_path_(coinbase_id, path).to_vec())
+/// Errors returned by [`merkle_root_from_path`] and [`MerklePathCache::new`]
+#[derive(Debug)]
+pub enum MerkleError {
+    /// coinbase_tx_prefix + extranonce + coinbase_tx_suffix is not a valid transaction
+    CoinbaseDeserialize(bitcoin::consensus::encode::Error),
+    /// The coinbase txid can not be converted in a 32 bytes array
+    BadCoinbaseId,
+    /// A node of the merkle path is not 32 bytes long, it contains the length of the node
+    InvalidPathNode(usize),
 }
 
-// TODO remove when we have https://github.com/rust-bitcoin/rust-bitcoin/issues/1319
-pub fn merkle_root_from_path_<T: AsRef<[u8]>>(coinbase_id: [u8; 32], path: &[T]) -> [u8; 32] {
-    match path.len() {
-        0 => coinbase_id,
-        _ => reduce_path(coinbase_id, path),
+impl std::fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MerkleError::CoinbaseDeserialize(e) => {
+                write!(f, "Impossible to deserialize the coinbase: {}", e)
+            }
+            MerkleError::BadCoinbaseId => write!(f, "Invalid coinbase id"),
+            MerkleError::InvalidPathNode(len) => write!(
+                f,
+                "Expected merkle path nodes of length 32, received length {}",
+                len
+            ),
+        }
     }
 }
+
+/// Returns the merkle root of the coinbase with id `coinbase_id` and merkle path `path`, the
+/// nodes are hashed without allocating. Errors if a node of the path is not 32 bytes long.
 // TODO remove when we have https://github.com/rust-bitcoin/rust-bitcoin/issues/1319
-fn reduce_path<T: AsRef<[u8]>>(coinbase_id: [u8; 32], path: &[T]) -> [u8; 32] {
+pub fn merkle_root_from_path_<T: AsRef<[u8]>>(
+    coinbase_id: [u8; 32],
+    path: &[T],
+) -> Result<[u8; 32], MerkleError> {
     let mut root = coinbase_id;
     for node in path {
-        let to_hash = [&root[..], node.as_ref()].concat();
-        root = bitcoin::hashes::sha256d::Hash::hash(&to_hash)
-            .to_vec()
+        let node = node.as_ref();
+        let node: &[u8; 32] = node
             .try_into()
-            .unwrap();
+            .map_err(|_| MerkleError::InvalidPathNode(node.len()))?;
+        root = hash_merkle_nodes(&root, node);
+    }
+    Ok(root)
+}
+
+fn hash_merkle_nodes(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut engine = DHash::engine();
+    engine.input(left);
+    engine.input(right);
+    DHash::from_engine(engine).into_inner()
+}
+
+/// Merkle path of a job, used to compute the merkle root of many coinbases (eg one for each
+/// extranonce rolled by a proxy) over the same path. The path is validated only once, while
+/// [`merkle_root_from_path_`] validates it on every call, and a root is computed without
+/// allocating, hashing every level with a sha256d engine instead of concatenating the nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerklePathCache {
+    path: Vec<[u8; 32]>,
+}
+
+impl MerklePathCache {
+    /// Errors if a node of the path is not 32 bytes long
+    pub fn new<T: AsRef<[u8]>>(path: &[T]) -> Result<Self, MerkleError> {
+        let path = path
+            .iter()
+            .map(|node| {
+                node.as_ref()
+                    .try_into()
+                    .map_err(|_| MerkleError::InvalidPathNode(node.as_ref().len()))
+            })
+            .collect::<Result<Vec<[u8; 32]>, MerkleError>>()?;
+        Ok(Self { path })
+    }
+
+    /// Returns the merkle root for a coinbase with id `coinbase_id`
+    pub fn root_for_coinbase(&self, coinbase_id: [u8; 32]) -> [u8; 32] {
+        self.path
+            .iter()
+            .fold(coinbase_id, |root, node| hash_merkle_nodes(&root, node))
     }
-    root
 }
 
 //
 // Coinbase output construction utils
 //
+/// Script of a pay to anchor output: `OP_1 OP_PUSHBYTES_2 4e73`
+const P2A_SCRIPT: [u8; 4] = [0x51, 0x02, 0x4e, 0x73];
+
 #[derive(Debug, Clone)]
 pub struct CoinbaseOutput {
     pub output_script_type: String,
@@ -247,11 +402,70 @@ impl TryFrom<CoinbaseOutput> for Script {
                     None,
                 ))
             }
+            "OP_RETURN" => {
+                // `output_script_value` is the hex encoded data to commit to (eg the witness
+                // commitment)
+                let data = Vec::<u8>::from_hex(&value.output_script_value)
+                    .map_err(|_| Error::InvalidOutputScript)?;
+                Ok(Script::new_op_return(&data))
+            }
+            "P2A" => {
+                // Pay to anchor (`OP_1 <0x4e73>`) has no parameters, so `output_script_value` is
+                // ignored
+                Ok(Script::from(P2A_SCRIPT.to_vec()))
+            }
+            "RAW" => {
+                // `output_script_value` is the hex encoded script, used as is
+                let script = Vec::<u8>::from_hex(&value.output_script_value)
+                    .map_err(|_| Error::InvalidOutputScript)?;
+                Ok(Script::from(script))
+            }
             _ => Err(Error::UnknownOutputScriptType),
         }
     }
 }
 
+/// The outputs that a coinbase must pay, useful when the reward is splitted between more than
+/// one script or the coinbase must contain OP_RETURN commitments
+#[derive(Debug, Clone)]
+pub struct CoinbaseOutputs(pub Vec<CoinbaseOutput>);
+
+impl CoinbaseOutputs {
+    /// Builds the coinbase outputs. `values[i]` is the value in satoshi of the i-th output, so
+    /// `values` must have one element for each output.
+    pub fn to_tx_outs(&self, values: &[u64]) -> Result<Vec<TxOut>, Error> {
+        if self.0.is_empty() {
+            return Err(Error::EmptyCoinbaseOutputs);
+        }
+        if self.0.len() != values.len() {
+            return Err(Error::CoinbaseOutputsValuesMismatch(
+                self.0.len(),
+                values.len(),
+            ));
+        }
+        self.0
+            .iter()
+            .zip(values)
+            .map(|(output, value)| {
+                Ok(TxOut {
+                    value: *value,
+                    script_pubkey: output.clone().try_into()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Builds outputs with value 0, the actual value is added when the coinbase is built from a
+/// template (see `get_coinbase_output` in the pool).
+impl TryFrom<CoinbaseOutputs> for Vec<TxOut> {
+    type Error = Error;
+
+    fn try_from(value: CoinbaseOutputs) -> Result<Self, Self::Error> {
+        value.to_tx_outs(&vec![0; value.0.len()])
+    }
+}
+
 #[derive(Debug)]
 pub enum InputError {
     NegativeInput,
@@ -305,17 +519,16 @@ pub fn hash_rate_to_target(
     };
 
     // if we want 5 shares per minute, this means that s=60/5=12 seconds interval between shares
-    // this quantity will be at the numerator, so we multiply the result by 100 again later
     let shares_occurrency_frequence = 60_f64 / share_per_min;
 
+    // h*s is rounded to the nearest integer, truncating it would always bias the target up (and
+    // so the hashrate we get back from it down)
     let h_times_s = hashrate * shares_occurrency_frequence;
-    let h_times_s = h_times_s as u128;
+    let h_times_s = h_times_s.round() as u128;
 
     // We calculate the denominator: h*s+1
     // the denominator is h*s+1, where h*s is an u128, so always positive.
     // this means that the denominator can never be zero
-    // we add 100 in place of 1 because h*s is actually h*s*100, we in order to simplify later we
-    // must calculate (h*s+1)*100
     let h_times_s_plus_one = h_times_s + 1;
 
     let h_times_s_plus_one: Uint256 = from_u128_to_uint256(h_times_s_plus_one);
@@ -338,6 +551,11 @@ pub fn hash_rate_to_target(
 /// this function utilizes the equation used in [`hash_rate_to_target`], but
 /// translated to solve for hash_rate given a target: h = (2^256-t)/s(t+1)
 /// where s is seconds_between_two_consecutive_shares and t is target
+///
+/// The quotient is computed in floating point, so the result is not truncated to an integer.
+/// `hash_rate_from_target(hash_rate_to_target(h, spm)?, spm)` gives back h with a relative
+/// error of at most 1/(2*h*s) (the rounding of h*s in [`hash_rate_to_target`]) plus the f64
+/// rounding error, that is below 1e-12.
 pub fn hash_rate_from_target(target: U256<'static>, share_per_min: f64) -> Result<f64, Error> {
     // checks that we are not dividing by zero
     if share_per_min == 0.0 {
@@ -356,32 +574,118 @@ pub fn hash_rate_from_target(target: U256<'static>, share_per_min: f64) -> Resul
     // note that [255_u8,;32] actually is 2^256 -1, but 2^256 -t = (2^256-1) - (t-1)
     let max_target = [255_u8; 32];
     let max_target = Uint256::from_be_bytes(max_target);
+    // with the max target every hash is a share, h*s = (2^256-t)/(t+1) would be 0 and there is
+    // nothing to infer the hashrate from. In 256 bits t+1 overflows to zero.
+    if target == max_target {
+        return Err(Error::HashrateError(InputError::DivisionByZero));
+    }
     let numerator = max_target - (target - Uint256::one());
 
     // now we calcualte the denominator s(t+1)
-    // *100 here to move the fractional bit up so we can make this an int later
-    let shares_occurrency_frequence = 60_f64 / (share_per_min) * 100.0;
+    let shares_occurrency_frequence = 60_f64 / share_per_min;
     // note that t+1 cannot be zero because t unsigned. Therefore the denominator is zero if and
-    // only if s is zero.
-    let shares_occurrency_frequence = shares_occurrency_frequence as u128;
-    if shares_occurrency_frequence == 0_u128 {
+    // only if s is zero (share_per_min is infinite).
+    if shares_occurrency_frequence == 0.0 {
         return Err(Error::HashrateError(InputError::DivisionByZero));
     }
-    let shares_occurrency_frequence = u128_as_u256(shares_occurrency_frequence);
-    let mut target_plus_one = Uint256::from_be_bytes(target_arr);
-    target_plus_one.increment();
-    let denominator = shares_occurrency_frequence
-        .mul(target_plus_one)
-        .div(Uint256::from_u64(100).unwrap());
+    let denominator = shares_occurrency_frequence * (uint256_as_f64(target) + 1.0);
+
+    Ok(uint256_as_f64(numerator) / denominator)
+}
+
+/// Target of the difficulty 1 share (pdiff), in big endian
+const DIFFICULTY_1_TARGET: [u8; 32] = [
+    0, 0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+    255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+];
+
+/// A 256 bit target. Targets are ordered as big endian 256 bit numbers, so a lower target means
+/// an higher difficulty. Sv2 messages and block hashes carry targets in little endian, the
+/// conversions below take care of that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target(Uint256);
+
+impl Target {
+    /// Returns the target of a share of difficulty `difficulty`, computed as pdiff / difficulty.
+    /// Difficulties that are not positive, or too small to be represented, return the max target.
+    pub fn from_difficulty(difficulty: f64) -> Self {
+        // Scale the difficulty by 2^32 so that fractional difficulties survive the conversion to
+        // an integer, the dividend is scaled by the same factor. `as` saturates at u128::MAX.
+        let scaled_difficulty = (difficulty * 2_f64.powi(32)) as u128;
+        if difficulty.is_nan() || scaled_difficulty == 0 {
+            return Self(Uint256::from_be_bytes([255; 32]));
+        }
+        let dividend = Uint256::from_be_bytes(DIFFICULTY_1_TARGET) << 32;
+        Self(dividend.div(u128_as_u256(scaled_difficulty)))
+    }
 
-    let result = from_uint128_to_u128(numerator.div(denominator).low_128());
-    // we multiply back by 100 so that it cancels with the same factor at the denominator
-    Ok(result as f64)
+    /// Returns the difficulty of the target, computed as pdiff / target. A zero target returns a
+    /// difficulty of 0.
+    pub fn to_difficulty(&self) -> f64 {
+        if self.0.bits() == 0 {
+            return 0.0;
+        }
+        let pdiff = Uint256::from_be_bytes(DIFFICULTY_1_TARGET);
+        uint256_as_f64(pdiff) / uint256_as_f64(self.0)
+    }
+
+    /// Returns the target as little endian bytes
+    pub fn to_le_bytes(&self) -> [u8; 32] {
+        let mut bytes = self.0.to_be_bytes();
+        bytes.reverse();
+        bytes
+    }
 }
 
-fn from_uint128_to_u128(input: Uint128) -> u128 {
-    let input = input.to_be_bytes();
-    u128::from_be_bytes(input)
+impl From<[u8; 32]> for Target {
+    /// `v` is a little endian target
+    fn from(mut v: [u8; 32]) -> Self {
+        v.reverse();
+        Self(Uint256::from_be_bytes(v))
+    }
+}
+
+impl TryFrom<&[u8]> for Target {
+    type Error = ParseLengthError;
+
+    /// `v` is a little endian target
+    fn try_from(v: &[u8]) -> Result<Self, Self::Error> {
+        let mut v = v.to_vec();
+        v.reverse();
+        Ok(Self(Uint256::from_be_slice(&v)?))
+    }
+}
+
+impl<'a> From<U256<'a>> for Target {
+    fn from(v: U256<'a>) -> Self {
+        let mut bytes = [0_u8; 32];
+        bytes.copy_from_slice(v.inner_as_ref());
+        bytes.into()
+    }
+}
+
+impl From<Target> for U256<'static> {
+    fn from(v: Target) -> Self {
+        v.to_le_bytes().into()
+    }
+}
+
+impl From<BlockHash> for Target {
+    fn from(v: BlockHash) -> Self {
+        v.into_inner().into()
+    }
+}
+
+impl From<Target> for BlockHash {
+    fn from(v: Target) -> Self {
+        BlockHash::from_inner(v.to_le_bytes())
+    }
+}
+
+fn uint256_as_f64(v: Uint256) -> f64 {
+    v.to_be_bytes()
+        .iter()
+        .fold(0.0, |acc, byte| acc * 256.0 + *byte as f64)
 }
 
 pub fn from_u128_to_uint256(input: u128) -> Uint256 {
@@ -393,7 +697,8 @@ pub fn from_u128_to_uint256(input: u128) -> Uint256 {
     Uint256::from_be_bytes(be_bytes)
 }
 
-/// Used to package multiple SV2 channels into a single group.
+/// Used to package multiple SV2 channels into a single group. Channel ids are shared by every
+/// group: shares only carry the channel id, so it must identify the channel on its own.
 #[derive(Debug, Default)]
 pub struct GroupId {
     group_ids: Id,
@@ -414,11 +719,14 @@ impl GroupId {
         self.group_ids.next()
     }
 
-    /// Create a channel for a paricular group and return the channel id
+    /// Create a channel for a paricular group and return the channel id, channel ids start from 1.
     /// _group_id is left for a future use of this API where we have an hirearchy of ids so that we
-    /// don't break old versions
-    pub fn new_channel_id(&mut self, _group_id: u32) -> u32 {
-        self.channel_ids.next()
+    /// don't break old versions. Until shares carry the group id, channel ids must stay unique
+    /// across groups.
+    ///
+    /// Return `None` when there are no more channel ids, channel ids are never reused.
+    pub fn new_channel_id(&mut self, _group_id: u32) -> Option<u32> {
+        self.channel_ids.next_checked()
     }
 
     /// Concatenate a group and a channel id into a complete id
@@ -456,7 +764,48 @@ fn test_group_id_new_channel_id() {
     let _ = group_ids.new_group_id();
     let id = group_ids.new_group_id();
     let channel_id = group_ids.new_channel_id(id);
-    assert!(channel_id == 1);
+    assert!(channel_id == Some(1));
+    let complete_id = GroupId::into_complete_id(id, channel_id.unwrap());
+    assert_eq!(GroupId::into_group_id(complete_id), id);
+    assert_eq!(GroupId::into_channel_id(complete_id), 1);
+}
+#[test]
+fn test_id_next_checked_at_u32_max() {
+    let mut ids = Id {
+        state: u32::MAX - 1,
+    };
+    assert_eq!(ids.next_checked(), Some(u32::MAX));
+    assert_eq!(ids.next_checked(), None);
+    assert_eq!(ids.next_checked(), None);
+    // The unchecked API wraps around
+    assert_eq!(ids.next(), 1);
+
+    ids.reset();
+    assert_eq!(ids.next_checked(), Some(1));
+}
+#[test]
+fn test_group_id_channel_ids_exhausted() {
+    let mut group_ids = GroupId::new();
+    group_ids.channel_ids = Id { state: u32::MAX };
+    assert_eq!(group_ids.new_channel_id(0), None);
+}
+#[test]
+fn test_group_id_channel_ids_are_unique_across_groups() {
+    let mut group_ids = GroupId::new();
+    let group_1 = group_ids.new_group_id();
+    let group_2 = group_ids.new_group_id();
+    let channel_1 = group_ids.new_channel_id(group_1).unwrap();
+    let channel_2 = group_ids.new_channel_id(group_2).unwrap();
+    // A share only carries the channel id, that alone identifies the channel
+    assert_ne!(channel_1, channel_2);
+
+    let complete_1 = GroupId::into_complete_id(group_1, channel_1);
+    let complete_2 = GroupId::into_complete_id(group_2, channel_2);
+    assert_ne!(complete_1, complete_2);
+    assert_eq!(GroupId::into_group_id(complete_1), group_1);
+    assert_eq!(GroupId::into_group_id(complete_2), group_2);
+    assert_eq!(GroupId::into_channel_id(complete_1), channel_1);
+    assert_eq!(GroupId::into_channel_id(complete_2), channel_2);
 }
 #[test]
 fn test_group_id_new_into_complete_id() {
@@ -569,27 +918,30 @@ fn test_merkle_root_from_path() {
     .unwrap();
     assert_eq!(coinbase_id, root);
 
-    //Target None return path on serialization
-    assert_eq!(
+    //Target error return path on serialization
+    assert!(matches!(
         merkle_root_from_path(&coinbase_bytes, &coinbase_bytes, &coinbase_bytes, &path),
+        Err(MerkleError::CoinbaseDeserialize(_))
+    ));
+    assert_eq!(
+        merkle_root_from_path_option(&coinbase_bytes, &coinbase_bytes, &coinbase_bytes, &path),
         None
     );
 }
 
-pub fn u256_to_block_hash(v: U256<'static>) -> BlockHash {
-    let hash: [u8; 32] = v.to_vec().try_into().unwrap();
+/// Returns an error if `v` is not 32 bytes long, that can happen only if `v` has not been built
+/// with the checked constructors
+pub fn u256_to_block_hash(v: U256<'static>) -> Result<BlockHash, Error> {
+    let hash: [u8; 32] = v
+        .inner_as_ref()
+        .try_into()
+        .map_err(|_| Error::ExpectedLen32(v.inner_as_ref().len()))?;
     let hash = Hash::from_inner(hash);
-    BlockHash::from_hash(hash)
+    Ok(BlockHash::from_hash(hash))
 }
 
-/// Returns a new `BlockHeader`.
-/// Expected endianness inputs:
-/// version     LE
-/// prev_hash   BE
-/// merkle_root BE
-/// time        BE
-/// bits        BE
-/// nonce       BE
+/// Returns a new `BlockHeader`. `prev_hash` and `merkle_root` must be in the internal byte order,
+/// see [`hash_block_header`].
 #[allow(dead_code)]
 pub(crate) fn new_header(
     version: i32,
@@ -623,19 +975,46 @@ pub(crate) fn new_header(
     })
 }
 
-/// Returns hash of the `BlockHeader`.
-/// Endianness reference for the correct hash:
-/// version     LE
-/// prev_hash   BE
-/// merkle_root BE
-/// time        BE
-/// bits        BE
-/// nonce       BE
+/// Returns hash of the `BlockHeader`, see [`hash_block_header`].
 #[allow(dead_code)]
-pub(crate) fn new_header_hash<'decoder>(header: BlockHeader) -> U256<'decoder> {
-    let hash = header.block_hash().to_vec();
-    // below never panic an header hash is always U256
-    hash.try_into().unwrap()
+pub(crate) fn new_header_hash<'decoder>(header: BlockHeader) -> Result<U256<'decoder>, Error> {
+    Ok(hash_block_header(
+        header.version as u32,
+        &header.prev_blockhash.into_inner(),
+        &header.merkle_root.into_inner(),
+        header.time,
+        header.bits,
+        header.nonce,
+    )
+    .into())
+}
+
+/// Returns the double SHA256 of the 80 bytes block header made of the given fields, that is the
+/// hash compared with the share and network targets.
+///
+/// The header is serialized as in the Bitcoin consensus encoding:
+/// * `version`, `time`, `nbits` and `nonce` are 4 bytes little endian integers
+/// * `prev_hash` and `merkle_root` are copied as they are, so they must be in the internal byte
+///   order used by the sv2 messages, that is the reverse of the hex shown by block explorers
+///
+/// The returned hash is in the internal byte order as well: reverse it to get the block explorer
+/// representation.
+pub fn hash_block_header(
+    version: u32,
+    prev_hash: &[u8; 32],
+    merkle_root: &[u8; 32],
+    time: u32,
+    nbits: u32,
+    nonce: u32,
+) -> [u8; 32] {
+    let mut engine = DHash::engine();
+    engine.input(&version.to_le_bytes());
+    engine.input(prev_hash);
+    engine.input(merkle_root);
+    engine.input(&time.to_le_bytes());
+    engine.input(&nbits.to_le_bytes());
+    engine.input(&nonce.to_le_bytes());
+    DHash::from_engine(engine).into_inner()
 }
 
 fn u128_as_u256(v: u128) -> Uint256 {
@@ -646,75 +1025,64 @@ fn u128_as_u256(v: u128) -> Uint256 {
     Uint256::from_be_slice(&u256).unwrap()
 }
 
-/// target = u256_max * (shar_per_min / 60) * (2^32 / hash_per_second)
-/// target = u128_max * ((shar_per_min / 60) * (2^32 / hash_per_second) * u128_max)
-pub fn target_from_hash_rate(hash_per_second: f32, share_per_min: f32) -> U256<'static> {
-    assert!(hash_per_second >= 1000000000.0);
-    let operand = (share_per_min as f64 / 60.0) * (u32::MAX as f64 / hash_per_second as f64);
-    assert!(operand <= 1.0);
-    let operand = operand * (u128::MAX as f64);
-    let target = u128_as_u256(u128::MAX) * u128_as_u256(operand as u128);
-    let mut target: [u8; 32] = target.to_be_bytes();
-    target.reverse();
-    target.into()
-}
+/// Number of transactions above which [`hash_lists_tuple`] computes the short hashes in parallel.
+/// Only used when the `rayon` feature is enabled.
+pub const PARALLEL_SHORT_HASH_THRESHOLD: usize = 1024;
 
-#[cfg_attr(feature = "cargo-clippy", allow(clippy::too_many_arguments))]
-pub fn get_target(
-    nonce: u32,
-    version: u32,
-    ntime: u32,
-    extranonce: &[u8],
-    coinbase_tx_prefix: &[u8],
-    coinbase_tx_suffix: &[u8],
-    prev_hash: BlockHash,
-    merkle_path: Vec<Vec<u8>>,
-    nbits: u32,
-) -> [u8; 32] {
-    let merkle_root: [u8; 32] = merkle_root_from_path(
-        coinbase_tx_prefix,
-        coinbase_tx_suffix,
-        extranonce,
-        &(merkle_path[..]),
-    )
-    .unwrap()
-    .try_into()
-    .unwrap();
-    let merkle_root = Hash::from_inner(merkle_root);
-    let merkle_root = TxMerkleNode::from_hash(merkle_root);
-    // TODO  how should version be transoformed from u32 into i32???
-    let version = version as i32;
-    let header = BlockHeader {
-        version,
-        prev_blockhash: prev_hash,
-        merkle_root,
-        time: ntime,
-        bits: nbits,
-        nonce,
-    };
-
-    let hash_ = header.block_hash();
-    let mut hash = hash_.as_hash().into_inner();
-    hash.reverse();
-    hash
-}
 pub fn hash_lists_tuple(
     tx_data: Vec<Transaction>,
     tx_short_hash_nonce: u64,
 ) -> (Seq064K<'static, ShortTxId<'static>>, U256<'static>) {
-    let mut txid_list: Vec<bitcoin::Txid> = Vec::new();
-    for tx in tx_data {
-        txid_list.push(tx.txid());
-    }
-    let mut tx_short_hash_list_: Vec<ShortTxId> = Vec::new();
-    for txid in txid_list.clone() {
-        tx_short_hash_list_.push(get_short_hash(txid, tx_short_hash_nonce));
-    }
+    hash_lists_tuple_with_threshold(tx_data, tx_short_hash_nonce, PARALLEL_SHORT_HASH_THRESHOLD)
+}
+
+/// Same as [`hash_lists_tuple`], when the `rayon` feature is enabled and there are more than
+/// `parallel_threshold` transactions the short hashes are computed in parallel. The output is the
+/// same in both cases.
+pub fn hash_lists_tuple_with_threshold(
+    tx_data: Vec<Transaction>,
+    tx_short_hash_nonce: u64,
+    parallel_threshold: usize,
+) -> (Seq064K<'static, ShortTxId<'static>>, U256<'static>) {
+    let txid_list: Vec<bitcoin::Txid> = tx_data.iter().map(|tx| tx.txid()).collect();
+    let tx_short_hash_list_ = short_hash_list(&txid_list, tx_short_hash_nonce, parallel_threshold);
     let tx_short_hash_list: Seq064K<'static, ShortTxId> = Seq064K::from(tx_short_hash_list_);
     let tx_hash_list_hash = tx_hash_list_hash_builder(txid_list);
     (tx_short_hash_list, tx_hash_list_hash)
 }
 
+#[cfg(feature = "rayon")]
+fn short_hash_list(
+    txid_list: &[bitcoin::Txid],
+    tx_short_hash_nonce: u64,
+    parallel_threshold: usize,
+) -> Vec<ShortTxId<'static>> {
+    use rayon::prelude::*;
+    if txid_list.len() > parallel_threshold {
+        txid_list
+            .par_iter()
+            .map(|txid| get_short_hash(*txid, tx_short_hash_nonce))
+            .collect()
+    } else {
+        txid_list
+            .iter()
+            .map(|txid| get_short_hash(*txid, tx_short_hash_nonce))
+            .collect()
+    }
+}
+
+#[cfg(not(feature = "rayon"))]
+fn short_hash_list(
+    txid_list: &[bitcoin::Txid],
+    tx_short_hash_nonce: u64,
+    _parallel_threshold: usize,
+) -> Vec<ShortTxId<'static>> {
+    txid_list
+        .iter()
+        .map(|txid| get_short_hash(*txid, tx_short_hash_nonce))
+        .collect()
+}
+
 pub fn get_short_hash(txid: bitcoin::Txid, tx_short_hash_nonce: u64) -> ShortTxId<'static> {
     // hash the short hash nonce
     let nonce_hash = sha256::Hash::hash(&tx_short_hash_nonce.to_le_bytes());
@@ -745,65 +1113,321 @@ pub struct BlockCreator<'a> {
     last_declare: DeclareMiningJob<'a>,
     tx_list: Vec<bitcoin::Transaction>,
     message: SubmitSolutionJd<'a>,
+    witness_reserved_value: [u8; 32],
 }
 impl<'a> BlockCreator<'a> {
+    /// `witness_reserved_value` is the value committed together with the witness merkle root in
+    /// the coinbase, Bitcoin Core always uses 32 zero bytes.
     pub fn new(
         last_declare: DeclareMiningJob<'a>,
         tx_list: Vec<bitcoin::Transaction>,
         message: SubmitSolutionJd<'a>,
+        witness_reserved_value: [u8; 32],
     ) -> BlockCreator<'a> {
         BlockCreator {
             last_declare,
             tx_list,
             message,
+            witness_reserved_value,
+        }
+    }
+
+    /// Assemble the block mined with `message`: the coinbase is rebuilt from the declared job and
+    /// the extranonce of the solution and followed by the transactions in `tx_list`.
+    pub fn try_build(self) -> Result<bitcoin::Block, BlockBuildError> {
+        let prefix = self.last_declare.coinbase_prefix.inner_as_ref();
+        let extranonce = self.message.extranonce.inner_as_ref();
+        let suffix = self.last_declare.coinbase_suffix.inner_as_ref();
+        let coinbase = Transaction::deserialize(&[prefix, extranonce, suffix].concat()[..])
+            .map_err(|error| BlockBuildError::InvalidCoinbase {
+                prefix_len: prefix.len(),
+                extranonce_len: extranonce.len(),
+                suffix_len: suffix.len(),
+                error,
+            })?;
+        let mut txdata = self.tx_list;
+        txdata.insert(0, coinbase);
+
+        let prev_hash_len = self.message.prev_hash.inner_as_ref().len();
+        let header = BlockHeader {
+            version: self.message.version as i32,
+            prev_blockhash: u256_to_block_hash(self.message.prev_hash.into_static())
+                .map_err(|_| BlockBuildError::InvalidPrevHash(prev_hash_len))?,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: self.message.ntime,
+            bits: self.message.nbits,
+            nonce: self.message.nonce,
+        };
+        let mut block = Block { header, txdata };
+        check_witness_commitment(&mut block, self.witness_reserved_value)?;
+
+        // Can not fail, the block has at least the coinbase
+        if let Some(merkle_root) = block.compute_merkle_root() {
+            block.header.merkle_root = merkle_root;
+        }
+        Ok(block)
+    }
+}
+
+/// Errors returned by [`BlockCreator::try_build`]
+#[derive(Debug)]
+pub enum BlockBuildError {
+    /// coinbase_prefix + extranonce + coinbase_suffix is not a valid transaction, the lengths of
+    /// the three parts tell which one is likely wrong
+    InvalidCoinbase {
+        prefix_len: usize,
+        extranonce_len: usize,
+        suffix_len: usize,
+        error: bitcoin::consensus::encode::Error,
+    },
+    /// The prev hash of the solution is not 32 bytes long
+    InvalidPrevHash(usize),
+    /// The coinbase has no input to carry the witness reserved value
+    CoinbaseWithoutInputs,
+    /// The coinbase does not commit to the witnesses of the block transactions
+    InvalidWitnessCommitment,
+}
+
+impl std::fmt::Display for BlockBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BlockBuildError::InvalidCoinbase {
+                prefix_len,
+                extranonce_len,
+                suffix_len,
+                error,
+            } => write!(
+                f,
+                "Impossible to deserialize the coinbase made of a {} bytes prefix, a {} bytes \
+                 extranonce and a {} bytes suffix: {}",
+                prefix_len, extranonce_len, suffix_len, error
+            ),
+            BlockBuildError::InvalidPrevHash(len) => {
+                write!(
+                    f,
+                    "Expected a prev hash of length 32, received length {}",
+                    len
+                )
+            }
+            BlockBuildError::CoinbaseWithoutInputs => write!(f, "Coinbase without inputs"),
+            BlockBuildError::InvalidWitnessCommitment => write!(
+                f,
+                "Coinbase does not commit to the witnesses of the block transactions"
+            ),
         }
     }
 }
 
-/// TODO write a test for this function that takes an already mined block, and test if the new
-/// block created with the hash of the new block created with the block creator coincides with the
-/// hash of the mined block
-impl<'a> From<BlockCreator<'a>> for bitcoin::Block {
-    fn from(block_creator: BlockCreator<'a>) -> bitcoin::Block {
-        let last_declare = block_creator.last_declare;
-        let mut tx_list = block_creator.tx_list;
-        let message = block_creator.message;
-
-        let coinbase_pre = last_declare.coinbase_prefix.to_vec();
-        let extranonce = message.extranonce.to_vec();
-        let coinbase_suf = last_declare.coinbase_suffix.to_vec();
-        let mut path: Vec<Vec<u8>> = vec![];
-        for tx in &tx_list {
-            let id = tx.txid();
-            let id = id.as_ref().to_vec();
-            path.push(id);
+/// When a transaction in `block` has a witness the coinbase must commit to the witnesses in its
+/// last output that starts with `OP_RETURN 0xaa21a9ed`: the commitment is the double SHA256 of the
+/// witness merkle root and of the witness reserved value, that is the only element of the
+/// coinbase input witness.
+///
+/// If the commitment is missing or wrong an error is returned, adding it would change the coinbase
+/// txid and so the header that the miner has worked on. An empty coinbase witness is set to
+/// `witness_reserved_value`.
+fn check_witness_commitment(
+    block: &mut Block,
+    witness_reserved_value: [u8; 32],
+) -> Result<(), BlockBuildError> {
+    const MAGIC: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+    let has_witness = block
+        .txdata
+        .iter()
+        .skip(1)
+        .any(|tx| tx.input.iter().any(|input| !input.witness.is_empty()));
+    if !has_witness {
+        return Ok(());
+    }
+
+    let input = block
+        .txdata
+        .first_mut()
+        .and_then(|coinbase| coinbase.input.first_mut())
+        .ok_or(BlockBuildError::CoinbaseWithoutInputs)?;
+    if input.witness.is_empty() {
+        input.witness.push(witness_reserved_value);
+    } else if input.witness.to_vec() != vec![witness_reserved_value.to_vec()] {
+        return Err(BlockBuildError::InvalidWitnessCommitment);
+    }
+
+    let witness_root = block
+        .witness_root()
+        .ok_or(BlockBuildError::InvalidWitnessCommitment)?;
+    let commitment = Block::compute_witness_commitment(&witness_root, &witness_reserved_value);
+    let committed = block.txdata[0].output.iter().rev().find_map(|output| {
+        let script = output.script_pubkey.as_bytes();
+        match script.len() >= 38 && script[..6] == MAGIC {
+            true => Some(&script[6..38]),
+            false => None,
         }
-        let merkle_root =
-            merkle_root_from_path(&coinbase_pre[..], &coinbase_suf[..], &extranonce[..], &path)
-                .expect("Invalid coinbase");
-        let merkle_root = Hash::from_inner(merkle_root.try_into().unwrap());
-
-        let prev_blockhash = u256_to_block_hash(message.prev_hash.into_static());
-        let header = stratum_common::bitcoin::blockdata::block::BlockHeader {
-            version: message.version as i32,
-            prev_blockhash,
-            merkle_root,
-            time: message.ntime,
-            bits: message.nbits,
-            nonce: message.nonce,
-        };
+    });
+    match committed {
+        Some(committed) if committed == &commitment[..] => Ok(()),
+        _ => Err(BlockBuildError::InvalidWitnessCommitment),
+    }
+}
 
-        let coinbase = [coinbase_pre, extranonce, coinbase_suf].concat();
-        let coinbase = Transaction::deserialize(&coinbase[..]).unwrap();
-        tx_list.insert(0, coinbase);
+impl<'a> TryFrom<BlockCreator<'a>> for bitcoin::Block {
+    type Error = Error;
 
-        let mut block = Block {
-            header,
-            txdata: tx_list.clone(),
-        };
+    fn try_from(block_creator: BlockCreator<'a>) -> Result<bitcoin::Block, Error> {
+        Ok(block_creator.try_build()?)
+    }
+}
+
+/// Builds a [`SetupConnection`] from plain strings. Every string field is empty unless set, the
+/// conversions to `Str0255` are done by [`SetupConnectionBuilder::try_build`] that fails if a
+/// field is longer than 255 bytes.
+#[derive(Debug, Clone)]
+pub struct SetupConnectionBuilder<'a> {
+    protocol: Protocol,
+    min_version: u16,
+    max_version: u16,
+    flags: u32,
+    endpoint_host: &'a str,
+    endpoint_port: u16,
+    vendor: &'a str,
+    hardware_version: &'a str,
+    firmware: &'a str,
+    device_id: &'a str,
+}
+
+impl<'a> SetupConnectionBuilder<'a> {
+    pub fn new(protocol: Protocol, min_version: u16, max_version: u16) -> Self {
+        Self {
+            protocol,
+            min_version,
+            max_version,
+            flags: 0,
+            endpoint_host: "",
+            endpoint_port: 0,
+            vendor: "",
+            hardware_version: "",
+            firmware: "",
+            device_id: "",
+        }
+    }
+
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Host and port the connection is opened to
+    pub fn endpoint(mut self, host: &'a str, port: u16) -> Self {
+        self.endpoint_host = host;
+        self.endpoint_port = port;
+        self
+    }
+
+    pub fn vendor(mut self, vendor: &'a str) -> Self {
+        self.vendor = vendor;
+        self
+    }
+
+    pub fn hardware_version(mut self, hardware_version: &'a str) -> Self {
+        self.hardware_version = hardware_version;
+        self
+    }
+
+    pub fn firmware(mut self, firmware: &'a str) -> Self {
+        self.firmware = firmware;
+        self
+    }
+
+    pub fn device_id(mut self, device_id: &'a str) -> Self {
+        self.device_id = device_id;
+        self
+    }
+
+    pub fn try_build(self) -> Result<SetupConnection<'static>, Error> {
+        Ok(SetupConnection {
+            protocol: self.protocol,
+            min_version: self.min_version,
+            max_version: self.max_version,
+            flags: self.flags,
+            endpoint_host: self.endpoint_host.as_bytes().to_vec().try_into()?,
+            endpoint_port: self.endpoint_port,
+            vendor: self.vendor.as_bytes().to_vec().try_into()?,
+            hardware_version: self.hardware_version.as_bytes().to_vec().try_into()?,
+            firmware: self.firmware.as_bytes().to_vec().try_into()?,
+            device_id: self.device_id.as_bytes().to_vec().try_into()?,
+        })
+    }
+}
 
-        block.header.merkle_root = block.compute_merkle_root().unwrap();
-        block
+/// Errors returned by [`negotiate_setup_connection`]
+#[derive(Debug)]
+pub enum SetupError<E> {
+    /// The upstream refused the connection and there are no flags left to drop: (flags,
+    /// error_code) of the `SetupConnectionError`
+    Rejected(u32, String),
+    /// The upstream answered with a message that is not a `SetupConnection` response
+    UnexpectedMessage(u8),
+    /// Sending or receiving failed
+    Transport(E),
+}
+
+impl<E: std::fmt::Debug> std::fmt::Display for SetupError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SetupError::Rejected(flags, error_code) => write!(
+                f,
+                "SetupConnection rejected with flags {:b}: {}",
+                flags, error_code
+            ),
+            SetupError::UnexpectedMessage(type_) => write!(
+                f,
+                "Unexpected message in response to SetupConnection: {:x}",
+                type_
+            ),
+            SetupError::Transport(e) => write!(f, "Transport error: {:?}", e),
+        }
+    }
+}
+
+/// Sends `SetupConnection` messages until the upstream accepts one, returning the version used
+/// by the upstream and the flags that were accepted.
+///
+/// `send` must send a `SetupConnection` with the given flags, min version and max version.
+/// `recv` must return the type and the payload of the next message from the upstream. When the
+/// upstream answers with a `SetupConnectionError` the flags that it reports are dropped and the
+/// setup is tried again. If it reports no flags, or only flags that were not proposed, there is
+/// nothing left to drop and [`SetupError::Rejected`] is returned.
+pub async fn negotiate_setup_connection<E, S, SFut, R, RFut>(
+    mut send: S,
+    mut recv: R,
+    proposed_flags: u32,
+    min_v: u16,
+    max_v: u16,
+) -> Result<(u16, u32), SetupError<E>>
+where
+    S: FnMut(u32, u16, u16) -> SFut,
+    SFut: std::future::Future<Output = Result<(), E>>,
+    R: FnMut() -> RFut,
+    RFut: std::future::Future<Output = Result<(u8, Vec<u8>), E>>,
+{
+    let mut flags = proposed_flags;
+    loop {
+        send(flags, min_v, max_v)
+            .await
+            .map_err(SetupError::Transport)?;
+        let (message_type, mut payload) = recv().await.map_err(SetupError::Transport)?;
+        match crate::parsers::CommonMessages::try_from((message_type, payload.as_mut_slice())) {
+            Ok(crate::parsers::CommonMessages::SetupConnectionSuccess(m)) => {
+                return Ok((m.used_version, flags))
+            }
+            Ok(crate::parsers::CommonMessages::SetupConnectionError(m)) => {
+                let reduced_flags = flags & !m.flags;
+                if m.flags == 0 || reduced_flags == flags {
+                    let error_code = String::from_utf8_lossy(m.error_code.inner_as_ref());
+                    return Err(SetupError::Rejected(m.flags, error_code.into_owned()));
+                }
+                flags = reduced_flags;
+            }
+            _ => return Err(SetupError::UnexpectedMessage(message_type)),
+        }
     }
 }
 
@@ -937,6 +1561,41 @@ mod tests {
             path,
         }
     }
+    #[test]
+    #[cfg(feature = "serde")]
+    fn block_creator_rebuilds_mined_block() {
+        let block = get_test_block();
+        // The block only has the coinbase, so the transaction list is empty
+        assert!(block.path.inner_as_ref().is_empty());
+
+        let prefix = block.coinbase_tx_prefix.inner_as_ref();
+        let coinbase = [
+            prefix,
+            &block.coinbase_script[..],
+            block.coinbase_tx_suffix.inner_as_ref(),
+        ]
+        .concat();
+        let prev_hash: [u8; 32] = block.prev_hash.clone().try_into().unwrap();
+        let header = BlockHeader {
+            version: block.version as i32,
+            prev_blockhash: BlockHash::from_inner(prev_hash),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: block.time,
+            bits: block.nbits,
+            nonce: block.nonce,
+        };
+        let extranonce = prefix.len()..prefix.len() + block.coinbase_script.len();
+
+        let rebuilt = block_creator(&coinbase, extranonce, &header, vec![])
+            .try_build()
+            .unwrap();
+        assert_eq!(rebuilt.header.merkle_root.to_vec(), block.merkle_root);
+        assert_eq!(
+            rebuilt.block_hash().to_vec(),
+            block.block_hash.inner_as_ref().to_vec()
+        );
+    }
+
     #[test]
     #[cfg(feature = "serde")]
     fn gets_merkle_root_from_path() {
@@ -945,10 +1604,11 @@ mod tests {
 
         let actual = merkle_root_from_path(
             block.coinbase_tx_prefix.inner_as_ref(),
-            &block.coinbase_script,
             block.coinbase_tx_suffix.inner_as_ref(),
+            &block.coinbase_script,
             &block.path.inner_as_ref(),
-        );
+        )
+        .unwrap();
         assert_eq!(expect, actual);
     }
 
@@ -1012,11 +1672,54 @@ mod tests {
             nonce: block.nonce,
         };
 
-        let actual = new_header_hash(header);
+        let actual = new_header_hash(header).unwrap();
 
         assert_eq!(actual, expect);
     }
 
+    #[test]
+    fn hash_block_header_of_mainnet_block() {
+        use super::{hash_block_header, new_header, new_header_hash};
+        use std::convert::TryInto;
+        use stratum_common::bitcoin::hashes::{hex::FromHex, Hash};
+
+        // Mainnet block 125552, the values are the ones shown by block explorers
+        let from_explorer_hex = |s: &str| {
+            let mut bytes = Vec::<u8>::from_hex(s).unwrap();
+            bytes.reverse();
+            let bytes: [u8; 32] = bytes.try_into().unwrap();
+            bytes
+        };
+        let prev_hash =
+            from_explorer_hex("00000000000008a3a41b85b8b29ad444def299fee21793cd8b9e567eab02cd81");
+        let merkle_root =
+            from_explorer_hex("2b12fcf1b09288fcaff797d71e950e71ae42b91e8bdb2304758dfcffc2b620e3");
+        let expect =
+            from_explorer_hex("00000000000000001e8d6829a8a21adc5d38d0a473b144b6765798e61f98bd1d");
+
+        let hash = hash_block_header(
+            1,
+            &prev_hash,
+            &merkle_root,
+            1305998791,
+            0x1a44b9f2,
+            2504433986,
+        );
+        assert_eq!(hash, expect);
+
+        let header = new_header(
+            1,
+            &prev_hash,
+            &merkle_root,
+            1305998791,
+            0x1a44b9f2,
+            2504433986,
+        )
+        .unwrap();
+        assert_eq!(header.block_hash().into_inner(), expect);
+        assert_eq!(new_header_hash(header).unwrap().to_vec(), expect.to_vec());
+    }
+
     #[test]
     fn test_hash_rate_to_target() {
         let mut rng = rand::thread_rng();
@@ -1053,6 +1756,27 @@ mod tests {
         assert!(delta.abs() < 100);
     }
 
+    #[test]
+    fn test_hash_rate_to_target_expected_hashes_per_share() {
+        // A hash is below t with probability (t+1)/2^256, so on average a share takes
+        // 2^256/(t+1) = h*s+1 hashes, the same expectation test_hash_rate_to_target samples
+        let two_to_256 = 2_f64.powi(256);
+        for (hr, spm) in [
+            (10.0, 1.0),
+            (1e6, 6.0),
+            (1.5e12, 10.0),
+            (2e15, 0.5),
+            (5e20, 60.0),
+        ] {
+            let mut target = hash_rate_to_target(hr, spm).unwrap().to_vec();
+            target.reverse();
+            let target = bitcoin::util::uint::Uint256::from_be_slice(&target[..]).unwrap();
+            let hashes_per_share = two_to_256 / (super::uint256_as_f64(target) + 1.0);
+            let expected = (hr * 60.0 / spm).round() + 1.0;
+            assert!(((hashes_per_share - expected) / expected).abs() < 1e-12);
+        }
+    }
+
     #[test]
     fn test_hash_rate_from_target() {
         let hr = 202470.828;
@@ -1060,14 +1784,110 @@ mod tests {
         let target = hash_rate_to_target(hr, expected_share_per_min).unwrap();
         let realized_share_per_min = expected_share_per_min * 10.0; // increase SPM by 10x
         let hash_rate = hash_rate_from_target(target.clone(), realized_share_per_min).unwrap();
-        let new_hr = (hr * 10.0).trunc();
+        let new_hr = hr * 10.0;
 
+        // h*s is rounded to an integer when the target is computed, so the hashrate we get back
+        // is off by at most 1/(2*h*s) of it
+        let tolerance = new_hr * 0.5 / (hr * 60.0);
         assert!(
-            hash_rate == new_hr,
+            (hash_rate - new_hr).abs() <= tolerance,
             "hash_rate_from_target equation was not properly transformed"
         )
     }
 
+    #[test]
+    fn test_hash_rate_target_round_trip() {
+        let shares_per_min = [0.1, 0.5, 1.0, 2.5, 6.0, 10.0, 60.0, 600.0];
+        let mantissas = [
+            1.0,
+            1.000_001,
+            1.5,
+            2.468_013_579,
+            3.333_333_333,
+            7.25,
+            9.999_999_9,
+        ];
+        for exp in 3..=18 {
+            for mantissa in mantissas {
+                let hr = 10_f64.powi(exp) * mantissa;
+                for spm in shares_per_min {
+                    let target = hash_rate_to_target(hr, spm).unwrap();
+                    let back = hash_rate_from_target(target, spm).unwrap();
+                    let h_times_s = hr * 60.0 / spm;
+                    let tolerance = 0.5 / h_times_s + 1e-12;
+                    let error = (back - hr).abs() / hr;
+                    assert!(
+                        error <= tolerance,
+                        "hr: {}, spm: {}, back: {}, relative error: {}",
+                        hr,
+                        spm,
+                        back,
+                        error
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_hash_rate_from_max_target() {
+        let target = binary_sv2::U256::from([255_u8; 32]);
+        assert!(matches!(
+            hash_rate_from_target(target, 10.0),
+            Err(crate::Error::HashrateError(
+                super::InputError::DivisionByZero
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_target_ordering() {
+        // Little endian, the most significant byte is the last one
+        let mut low = [0_u8; 32];
+        low[0] = 255;
+        let mut high = [0_u8; 32];
+        high[31] = 1;
+        let low = super::Target::from(low);
+        let high = super::Target::from(high);
+
+        assert!(low < high);
+        assert_eq!(low.max(high), high);
+        assert!(super::Target::from_difficulty(2.0) < super::Target::from_difficulty(1.0));
+    }
+
+    #[test]
+    fn test_target_difficulty_round_trip() {
+        for difficulty in [1.0, 2.0, 512.0, 65536.0] {
+            let target = super::Target::from_difficulty(difficulty);
+            assert_eq!(target.to_difficulty(), difficulty);
+        }
+        for difficulty in [0.5, 0.001, 1.5, 1000.25, 1_000_000.0, 123_456.789] {
+            let target = super::Target::from_difficulty(difficulty);
+            let delta = (target.to_difficulty() - difficulty).abs() / difficulty;
+            assert!(delta < 1e-6);
+        }
+        assert_eq!(super::Target::from([0; 32]).to_difficulty(), 0.0);
+    }
+
+    #[test]
+    fn test_target_conversions() {
+        use binary_sv2::U256;
+        use bitcoin::{hash_types::BlockHash, hashes::Hash};
+        use std::convert::TryFrom;
+
+        let target = super::Target::from_difficulty(512.0);
+        let u256: U256<'static> = target.into();
+        assert_eq!(super::Target::from(u256.clone()), target);
+        assert_eq!(
+            super::Target::try_from(u256.inner_as_ref()).unwrap(),
+            target
+        );
+
+        let hash: BlockHash = target.into();
+        assert_eq!(hash.into_inner(), target.to_le_bytes());
+        assert_eq!(super::Target::from(hash), target);
+    }
+
     #[test]
     fn test_super_safe_lock() {
         let m = super::Mutex::new(1u32);
@@ -1075,4 +1895,579 @@ mod tests {
         // m.super_safe_lock(|i| *i = (*i).checked_add(1).unwrap()); // will not compile
         m.super_safe_lock(|i| *i = (*i).checked_add(1).unwrap_or_default()); // compiles
     }
+
+    #[test]
+    fn test_coinbase_outputs_round_trip() {
+        use super::{CoinbaseOutput, CoinbaseOutputs};
+        use bitcoin::{consensus, OutPoint, Transaction, TxIn, TxOut};
+        use std::convert::TryInto;
+
+        let outputs = CoinbaseOutputs(vec![
+            CoinbaseOutput {
+                output_script_type: "P2WPKH".to_string(),
+                output_script_value:
+                    "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798".to_string(),
+            },
+            // Witness commitment header followed by the commitment hash
+            CoinbaseOutput {
+                output_script_type: "OP_RETURN".to_string(),
+                output_script_value: format!("aa21a9ed{}", "11".repeat(32)),
+            },
+        ]);
+        assert!(outputs.to_tx_outs(&[625_000_000]).is_err());
+        let tx_outs = outputs.to_tx_outs(&[625_000_000, 0]).unwrap();
+        assert!(tx_outs[0].script_pubkey.is_v0_p2wpkh());
+        assert!(tx_outs[1].script_pubkey.is_op_return());
+
+        let default_values: Vec<TxOut> = outputs.try_into().unwrap();
+        assert!(default_values.iter().all(|o| o.value == 0));
+
+        let coinbase = Transaction {
+            version: 2,
+            lock_time: bitcoin::PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: vec![3, 1, 2, 3].into(),
+                sequence: bitcoin::Sequence::MAX,
+                witness: bitcoin::Witness::from_vec(vec![vec![0; 32]]),
+            }],
+            output: tx_outs,
+        };
+        let serialized = consensus::serialize(&coinbase);
+        let deserialized: Transaction = consensus::deserialize(&serialized).unwrap();
+        assert_eq!(deserialized, coinbase);
+        assert_eq!(deserialized.output.len(), 2);
+    }
+
+    #[test]
+    fn test_p2a_and_raw_coinbase_outputs() {
+        use super::{CoinbaseOutput, Error};
+        use bitcoin::Script;
+        use std::convert::TryInto;
+
+        let output = |output_script_type: &str, output_script_value: &str| CoinbaseOutput {
+            output_script_type: output_script_type.to_string(),
+            output_script_value: output_script_value.to_string(),
+        };
+
+        let p2a: Script = output("P2A", "").try_into().unwrap();
+        assert_eq!(p2a.as_bytes(), &[0x51, 0x02, 0x4e, 0x73]);
+
+        // OP_TRUE
+        let raw: Script = output("RAW", "51").try_into().unwrap();
+        assert_eq!(raw.as_bytes(), &[0x51]);
+        // OP_RETURN OP_PUSHBYTES_4 aa21a9ed
+        let raw: Script = output("RAW", "6a04aa21a9ed").try_into().unwrap();
+        assert_eq!(raw.as_bytes(), &[0x6a, 0x04, 0xaa, 0x21, 0xa9, 0xed]);
+
+        for invalid in ["5", "zz", "0x51"] {
+            let raw: Result<Script, Error> = output("RAW", invalid).try_into();
+            assert!(matches!(raw, Err(Error::InvalidOutputScript)));
+        }
+    }
+
+    fn random_merkle_path(len: usize) -> Vec<[u8; 32]> {
+        let mut rng = rand::thread_rng();
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    // Hashes every level concatenating the two nodes, allocating a vector for each level, as the
+    // coinbase merkle root was computed before the engine based implementation
+    fn concat_merkle_root(coinbase_id: [u8; 32], path: &[[u8; 32]]) -> [u8; 32] {
+        use stratum_common::bitcoin::hashes::{sha256d, Hash};
+
+        path.iter().fold(coinbase_id, |root, node| {
+            sha256d::Hash::hash(&[&root[..], &node[..]].concat()).into_inner()
+        })
+    }
+
+    #[test]
+    fn test_merkle_path_cache() {
+        use super::{merkle_root_from_path_, MerkleError, MerklePathCache};
+
+        for len in [0, 1, 2, 12] {
+            let path = random_merkle_path(len);
+            let cache = MerklePathCache::new(&path).unwrap();
+            for _ in 0..10 {
+                let coinbase_id: [u8; 32] = rand::thread_rng().gen();
+                assert_eq!(
+                    cache.root_for_coinbase(coinbase_id),
+                    merkle_root_from_path_(coinbase_id, &path).unwrap()
+                );
+            }
+        }
+        assert!(matches!(
+            MerklePathCache::new(&[vec![0_u8; 31]]),
+            Err(MerkleError::InvalidPathNode(31))
+        ));
+    }
+
+    #[test]
+    fn merkle_root_from_path_matches_reference_implementation() {
+        use super::{merkle_root_from_path_, MerkleError};
+
+        for len in [0, 1, 2, 12] {
+            let path = random_merkle_path(len);
+            for _ in 0..10 {
+                let coinbase_id: [u8; 32] = rand::thread_rng().gen();
+                assert_eq!(
+                    merkle_root_from_path_(coinbase_id, &path).unwrap(),
+                    concat_merkle_root(coinbase_id, &path)
+                );
+            }
+        }
+
+        let path = vec![vec![0_u8; 32], vec![0_u8; 33]];
+        assert!(matches!(
+            merkle_root_from_path_([0; 32], &path),
+            Err(MerkleError::InvalidPathNode(33))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn test_hash_lists_tuple_parallel_and_serial_match() {
+        use super::hash_lists_tuple_with_threshold;
+        use bitcoin::{PackedLockTime, Transaction};
+
+        let txs: Vec<Transaction> = (0..100)
+            .map(|i| Transaction {
+                version: 2,
+                lock_time: PackedLockTime(i),
+                input: vec![],
+                output: vec![],
+            })
+            .collect();
+        let nonce = 42;
+        let (parallel_short_hashes, parallel_hash) =
+            hash_lists_tuple_with_threshold(txs.clone(), nonce, 0);
+        let (serial_short_hashes, serial_hash) =
+            hash_lists_tuple_with_threshold(txs, nonce, usize::MAX);
+
+        assert_eq!(parallel_short_hashes.to_vec(), serial_short_hashes.to_vec());
+        assert_eq!(parallel_hash.to_vec(), serial_hash.to_vec());
+    }
+
+    #[test]
+    fn test_block_creator_checks_witness_commitment() {
+        use super::BlockBuildError;
+        use bitcoin::{
+            consensus::serialize,
+            hashes::{sha256d, Hash},
+            BlockHash, BlockHeader, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn,
+            TxMerkleNode, TxOut, Txid, Witness,
+        };
+
+        const MAGIC: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        let witness_reserved_value = [0; 32];
+        let spend = |witness: Vec<Vec<u8>>| Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_inner([1; 32]), 0),
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_vec(witness),
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        // The script sig is 4 bytes followed by an 8 bytes extranonce
+        let coinbase = |commitment: Option<&[u8]>| {
+            let mut output = vec![TxOut {
+                value: 625_000_000,
+                script_pubkey: Script::new(),
+            }];
+            if let Some(commitment) = commitment {
+                output.push(TxOut {
+                    value: 0,
+                    script_pubkey: Script::from([&MAGIC[..], commitment].concat()),
+                });
+            }
+            Transaction {
+                version: 2,
+                lock_time: PackedLockTime(0),
+                input: vec![TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: Script::from(vec![0xff; 12]),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                }],
+                output,
+            }
+        };
+        let header = BlockHeader {
+            version: 0x2000_0000,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 0,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        // version, inputs count, outpoint, script sig len and the first 4 bytes of it
+        let extranonce_start = 4 + 1 + 36 + 1 + 4;
+        let create_block = |coinbase: Transaction, spend: Transaction| {
+            block_creator(
+                &serialize(&coinbase),
+                extranonce_start..extranonce_start + 8,
+                &header,
+                vec![spend],
+            )
+            .try_build()
+        };
+
+        // Witness root of the coinbase (whose wtxid counts as zero) and of the spending tx, then
+        // the commitment to it and to the witness reserved value
+        let segwit_spend = spend(vec![vec![2; 72], vec![3; 33]]);
+        let witness_root = sha256d::Hash::hash(&[&[0; 32][..], &segwit_spend.wtxid()[..]].concat());
+        let commitment =
+            sha256d::Hash::hash(&[&witness_root[..], &witness_reserved_value[..]].concat());
+
+        let block = create_block(coinbase(Some(&commitment[..])), segwit_spend.clone()).unwrap();
+        assert!(block.check_witness_commitment());
+        assert_eq!(
+            block.txdata[0].input[0].witness.to_vec(),
+            vec![witness_reserved_value.to_vec()]
+        );
+        assert_eq!(
+            &block.txdata[0].output[1].script_pubkey.as_bytes()[6..],
+            &commitment[..]
+        );
+
+        assert!(matches!(
+            create_block(coinbase(None), segwit_spend.clone()),
+            Err(BlockBuildError::InvalidWitnessCommitment)
+        ));
+        assert!(matches!(
+            create_block(coinbase(Some(&[0x11; 32])), segwit_spend),
+            Err(BlockBuildError::InvalidWitnessCommitment)
+        ));
+        // Without witnesses the commitment is not required
+        assert!(create_block(coinbase(None), spend(vec![])).is_ok());
+    }
+
+    /// `BlockCreator` for a job declared with the serialized `coinbase`, where the extranonce is
+    /// at `extranonce`, and for a solution with the fields of `header`
+    fn block_creator(
+        coinbase: &[u8],
+        extranonce: std::ops::Range<usize>,
+        header: &bitcoin::BlockHeader,
+        tx_list: Vec<bitcoin::Transaction>,
+    ) -> super::BlockCreator<'static> {
+        use bitcoin::hashes::Hash as _;
+        use std::convert::TryInto as _;
+
+        let declare = job_declaration_sv2::DeclareMiningJob {
+            request_id: 0,
+            mining_job_token: vec![].try_into().unwrap(),
+            version: header.version as u32,
+            coinbase_prefix: coinbase[..extranonce.start].to_vec().try_into().unwrap(),
+            coinbase_suffix: coinbase[extranonce.end..].to_vec().try_into().unwrap(),
+            tx_short_hash_nonce: 0,
+            tx_short_hash_list: binary_sv2::Seq064K::new(vec![]).unwrap(),
+            tx_hash_list_hash: [0; 32].into(),
+            excess_data: vec![].try_into().unwrap(),
+        };
+        let solution = job_declaration_sv2::SubmitSolutionJd {
+            extranonce: coinbase[extranonce].to_vec().try_into().unwrap(),
+            prev_hash: header.prev_blockhash.into_inner().into(),
+            ntime: header.time,
+            nonce: header.nonce,
+            nbits: header.bits,
+            version: header.version as u32,
+        };
+        super::BlockCreator::new(declare, tx_list, solution, [0; 32])
+    }
+
+    #[test]
+    fn test_block_creator_rebuilds_genesis_block() {
+        use bitcoin::{
+            blockdata::constants::genesis_block, consensus::serialize, hashes::hex::FromHex,
+            BlockHash, Network,
+        };
+
+        let genesis = genesis_block(Network::Bitcoin);
+        let coinbase = serialize(&genesis.txdata[0]);
+        let block = block_creator(&coinbase, 0..0, &genesis.header, vec![])
+            .try_build()
+            .unwrap();
+
+        assert_eq!(
+            block.block_hash(),
+            BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_u256_to_block_hash_wrong_length() {
+        use super::{u256_to_block_hash, BlockBuildError, Error};
+        use binary_sv2::U256;
+        use bitcoin::{blockdata::constants::genesis_block, consensus::serialize, Network};
+
+        assert!(u256_to_block_hash([1; 32].into()).is_ok());
+        assert!(matches!(
+            u256_to_block_hash(U256::Owned(vec![1; 31])),
+            Err(Error::ExpectedLen32(31))
+        ));
+
+        let genesis = genesis_block(Network::Bitcoin);
+        let coinbase = serialize(&genesis.txdata[0]);
+        let mut block_creator = block_creator(&coinbase, 0..0, &genesis.header, vec![]);
+        block_creator.message.prev_hash = U256::Owned(vec![0; 33]);
+        assert!(matches!(
+            block_creator.try_build(),
+            Err(BlockBuildError::InvalidPrevHash(33))
+        ));
+    }
+
+    #[test]
+    fn test_block_creator_reports_coinbase_parts() {
+        use super::BlockBuildError;
+        use bitcoin::{blockdata::constants::genesis_block, consensus::serialize, Network};
+        use std::convert::TryInto as _;
+
+        let genesis = genesis_block(Network::Bitcoin);
+        let coinbase = serialize(&genesis.txdata[0]);
+        // The extranonce of the solution is one byte longer than the one of the declared job
+        let mut block_creator = block_creator(&coinbase, 42..50, &genesis.header, vec![]);
+        block_creator.message.extranonce = vec![0; 9].try_into().unwrap();
+        let error = block_creator.try_build().unwrap_err();
+        assert!(matches!(
+            error,
+            BlockBuildError::InvalidCoinbase {
+                prefix_len: 42,
+                extranonce_len: 9,
+                suffix_len,
+                ..
+            } if suffix_len == coinbase.len() - 50
+        ));
+        assert!(error
+            .to_string()
+            .contains("a 42 bytes prefix, a 9 bytes extranonce"));
+    }
+
+    #[test]
+    fn setup_connection_builder_sets_every_field() {
+        use common_messages_sv2::Protocol;
+
+        let setup_connection = super::SetupConnectionBuilder::new(Protocol::MiningProtocol, 2, 3)
+            .flags(0b110)
+            .endpoint("127.0.0.1", 34254)
+            .vendor("vendor")
+            .hardware_version("hw-1")
+            .firmware("fw-2")
+            .device_id("device")
+            .try_build()
+            .unwrap();
+
+        assert_eq!(setup_connection.protocol, Protocol::MiningProtocol);
+        assert_eq!(setup_connection.min_version, 2);
+        assert_eq!(setup_connection.max_version, 3);
+        assert_eq!(setup_connection.flags, 0b110);
+        assert_eq!(setup_connection.endpoint_host.to_vec(), b"127.0.0.1");
+        assert_eq!(setup_connection.endpoint_port, 34254);
+        assert_eq!(setup_connection.vendor.to_vec(), b"vendor");
+        assert_eq!(setup_connection.hardware_version.to_vec(), b"hw-1");
+        assert_eq!(setup_connection.firmware.to_vec(), b"fw-2");
+        assert_eq!(setup_connection.device_id.to_vec(), b"device");
+    }
+
+    #[test]
+    fn setup_connection_builder_rejects_too_long_fields() {
+        use common_messages_sv2::Protocol;
+
+        let firmware = "a".repeat(256);
+        let result = super::SetupConnectionBuilder::new(Protocol::MiningProtocol, 2, 2)
+            .firmware(&firmware)
+            .try_build();
+        assert!(matches!(result, Err(crate::Error::BinarySv2Error(_))));
+
+        let firmware = "a".repeat(255);
+        assert!(
+            super::SetupConnectionBuilder::new(Protocol::MiningProtocol, 2, 2)
+                .firmware(&firmware)
+                .try_build()
+                .is_ok()
+        );
+    }
+
+    type NegotiationResult = Result<(u16, u32), super::SetupError<()>>;
+
+    // Upstream that does not support the flags in `unsupported` and answers to SetupConnection
+    // with `flags`, returns the results of the negotiation and the flags that have been proposed
+    async fn negotiate_with_mock(
+        unsupported: u32,
+        proposed_flags: u32,
+    ) -> (NegotiationResult, Vec<u32>) {
+        use common_messages_sv2::{SetupConnectionError, SetupConnectionSuccess};
+        use const_sv2::{
+            MESSAGE_TYPE_SETUP_CONNECTION_ERROR, MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS,
+        };
+        use std::{cell::RefCell, convert::TryInto as _, rc::Rc};
+
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let sent_ = sent.clone();
+        let send = move |flags: u32, _min_v: u16, _max_v: u16| {
+            sent_.borrow_mut().push(flags);
+            async { Ok(()) }
+        };
+        let sent_ = sent.clone();
+        let recv = move || {
+            let flags = *sent_.borrow().last().unwrap();
+            let response = if flags & unsupported == 0 {
+                let m = SetupConnectionSuccess {
+                    used_version: 2,
+                    flags: 0,
+                };
+                (
+                    MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS,
+                    binary_sv2::to_bytes(m).unwrap(),
+                )
+            } else {
+                let m = SetupConnectionError {
+                    flags: flags & unsupported,
+                    error_code: "unsupported-feature-flags"
+                        .to_string()
+                        .into_bytes()
+                        .try_into()
+                        .unwrap(),
+                };
+                (
+                    MESSAGE_TYPE_SETUP_CONNECTION_ERROR,
+                    binary_sv2::to_bytes(m).unwrap(),
+                )
+            };
+            async { Ok(response) }
+        };
+        let result = super::negotiate_setup_connection(send, recv, proposed_flags, 2, 2).await;
+        let sent = sent.borrow().clone();
+        (result, sent)
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_setup_connection_drops_rejected_flags() {
+        let (result, sent) = negotiate_with_mock(0b0100, 0b0110).await;
+        assert_eq!(result.unwrap(), (2, 0b0010));
+        assert_eq!(sent, vec![0b0110, 0b0010]);
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_setup_connection_accepts_first_flags() {
+        let (result, sent) = negotiate_with_mock(0b0100, 0b0010).await;
+        assert_eq!(result.unwrap(), (2, 0b0010));
+        assert_eq!(sent, vec![0b0010]);
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_setup_connection_hard_failure() {
+        use std::{cell::RefCell, convert::TryInto as _, rc::Rc};
+
+        let sent = Rc::new(RefCell::new(0));
+        let sent_ = sent.clone();
+        let send = move |_flags: u32, _min_v: u16, _max_v: u16| {
+            *sent_.borrow_mut() += 1;
+            async { Ok::<(), ()>(()) }
+        };
+        let recv = || {
+            let m = common_messages_sv2::SetupConnectionError {
+                flags: 0,
+                error_code: "unsupported-protocol"
+                    .to_string()
+                    .into_bytes()
+                    .try_into()
+                    .unwrap(),
+            };
+            let payload = binary_sv2::to_bytes(m).unwrap();
+            async move { Ok((const_sv2::MESSAGE_TYPE_SETUP_CONNECTION_ERROR, payload)) }
+        };
+        let result = super::negotiate_setup_connection(send, recv, 0b0110, 2, 2).await;
+        match result {
+            Err(super::SetupError::Rejected(0, error_code)) => {
+                assert_eq!(error_code, "unsupported-protocol")
+            }
+            _ => panic!("expected a rejection, got {:?}", result),
+        }
+        assert_eq!(*sent.borrow(), 1);
+    }
+
+    #[test]
+    fn test_poisoned_mutex_is_recovered() {
+        use std::sync::Arc;
+
+        let mutex = Arc::new(super::Mutex::new(vec![1, 2]));
+        let poisoner = mutex.clone();
+        std::thread::spawn(move || {
+            poisoner
+                .safe_lock(|v| {
+                    v.push(3);
+                    panic!("poison the lock");
+                })
+                .unwrap();
+        })
+        .join()
+        .unwrap_err();
+        assert!(mutex.safe_lock(|v| v.len()).is_err());
+
+        let recovering = mutex.clone();
+        let len = std::thread::spawn(move || {
+            recovering.safe_lock_or_recover(|v| v.retain(|n| *n != 3), |v| v.len())
+        })
+        .join()
+        .unwrap();
+        assert_eq!(len, 2);
+        // recovered for every caller, `recover` is not called again
+        assert_eq!(mutex.safe_lock(|v| v.clone()).unwrap(), vec![1, 2]);
+        assert_eq!(mutex.safe_lock_or_recover(|v| v.clear(), |v| v.len()), 2);
+
+        // a new panic poisons the lock again
+        let poisoner = mutex.clone();
+        std::thread::spawn(move || {
+            poisoner.safe_lock(|_| panic!("poison the lock")).unwrap();
+        })
+        .join()
+        .unwrap_err();
+        assert!(mutex.safe_lock(|v| v.len()).is_err());
+        assert_eq!(mutex.safe_lock_or_recover(|v| v.clear(), |v| v.len()), 0);
+    }
+
+    #[test]
+    fn test_recovered_mutex_reports_a_later_panic() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let mutex = Arc::new(super::Mutex::new(0_u32));
+        let recoveries = Arc::new(AtomicUsize::new(0));
+        let poison = |mutex: Arc<super::Mutex<u32>>, recoveries: Arc<AtomicUsize>| {
+            std::thread::spawn(move || {
+                mutex.safe_lock_or_recover(
+                    |_| {
+                        recoveries.fetch_add(1, Ordering::SeqCst);
+                    },
+                    |v| {
+                        *v += 1;
+                        panic!("poison the lock")
+                    },
+                )
+            })
+            .join()
+            .unwrap_err();
+        };
+
+        poison(mutex.clone(), recoveries.clone());
+        assert!(mutex.safe_lock(|v| *v).is_err());
+        assert_eq!(mutex.safe_lock_or_recover(|v| *v = 0, |v| *v), 0);
+        assert_eq!(mutex.safe_lock(|v| *v).unwrap(), 0);
+
+        // panicking in the thunk of a recovered lock poisons it again
+        poison(mutex.clone(), recoveries.clone());
+        assert_eq!(recoveries.load(Ordering::SeqCst), 0);
+        assert!(mutex.safe_lock(|v| *v).is_err());
+        assert_eq!(mutex.safe_lock_or_recover(|v| *v = 10, |v| *v), 10);
+        assert_eq!(mutex.safe_lock(|v| *v).unwrap(), 10);
+    }
 }
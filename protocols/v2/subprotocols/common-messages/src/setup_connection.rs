@@ -1,4 +1,3 @@
-#[cfg(not(feature = "with_serde"))]
 use alloc::vec::Vec;
 #[cfg(not(feature = "with_serde"))]
 use binary_sv2::{
@@ -121,6 +120,125 @@ pub fn has_work_selection(flags: u32) -> bool {
     flag != 0
 }
 
+/// Flags of a mining protocol [`SetupConnection`], with the bit positions used by
+/// [`has_requires_std_job`], [`has_version_rolling`] and [`has_work_selection`]. Bits that are
+/// not defined are kept, so that converting back to `u32` gives the flags received.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MiningFlags(u32);
+
+impl MiningFlags {
+    pub const REQUIRES_STANDARD_JOBS: Self = Self(0b001);
+    pub const REQUIRES_VERSION_ROLLING: Self = Self(0b010);
+    pub const REQUIRES_WORK_SELECTION: Self = Self(0b100);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// True if all the flags set in `other` are set in `self`
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn requires_standard_jobs(&self) -> bool {
+        self.contains(Self::REQUIRES_STANDARD_JOBS)
+    }
+
+    pub const fn requires_work_selection(&self) -> bool {
+        self.contains(Self::REQUIRES_WORK_SELECTION)
+    }
+
+    pub const fn requires_version_rolling(&self) -> bool {
+        self.contains(Self::REQUIRES_VERSION_ROLLING)
+    }
+}
+
+impl core::ops::BitOr for MiningFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl core::ops::BitOrAssign for MiningFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0
+    }
+}
+
+impl From<u32> for MiningFlags {
+    fn from(bits: u32) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<MiningFlags> for u32 {
+    fn from(flags: MiningFlags) -> Self {
+        flags.0
+    }
+}
+
+/// Version of the Sv2 protocol used on a connection, as negotiated with [`SetupConnection`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion(u16);
+
+impl From<u16> for ProtocolVersion {
+    fn from(version: u16) -> Self {
+        Self(version)
+    }
+}
+
+impl From<ProtocolVersion> for u16 {
+    fn from(version: ProtocolVersion) -> Self {
+        version.0
+    }
+}
+
+impl core::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Name of each flag of a [`SetupConnection`] for `protocol`, indexed by bit
+fn flag_names(protocol: Protocol) -> &'static [&'static str] {
+    match protocol {
+        Protocol::MiningProtocol => &[
+            "REQUIRES_STANDARD_JOBS",
+            "REQUIRES_VERSION_ROLLING",
+            "REQUIRES_WORK_SELECTION",
+        ],
+        Protocol::JobDeclarationProtocol => &["REQUIRES_ASYNC_JOB_MINING"],
+        Protocol::TemplateDistributionProtocol | Protocol::JobDistributionProtocol => &[],
+    }
+}
+
+/// Names of the flags set in the `flags` of a [`SetupConnectionError`] for `protocol`, eg to log
+/// which features the upstream does not support. Bits that are not defined for `protocol` are
+/// reported once as `"UNKNOWN"`.
+pub fn describe_unsupported_flags(protocol: Protocol, flags: u32) -> Vec<&'static str> {
+    let names = flag_names(protocol);
+    let mut described: Vec<&'static str> = names
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| flags & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if flags >> names.len() != 0 {
+        described.push("UNKNOWN");
+    }
+    described
+}
+
 #[repr(C)]
 #[cfg(not(feature = "with_serde"))]
 #[derive(Debug, Clone)]
@@ -416,6 +534,32 @@ mod test {
         assert_eq!(has_version_rolling(flags), false);
     }
 
+    #[test]
+    fn test_describe_unsupported_flags() {
+        let mining = crate::Protocol::MiningProtocol;
+        assert!(describe_unsupported_flags(mining, 0).is_empty());
+        assert_eq!(
+            describe_unsupported_flags(mining, 0b001),
+            ["REQUIRES_STANDARD_JOBS"]
+        );
+        assert_eq!(
+            describe_unsupported_flags(mining, 0b110),
+            ["REQUIRES_VERSION_ROLLING", "REQUIRES_WORK_SELECTION"]
+        );
+        assert_eq!(
+            describe_unsupported_flags(mining, 0b1001_0001),
+            ["REQUIRES_STANDARD_JOBS", "UNKNOWN"]
+        );
+        assert_eq!(
+            describe_unsupported_flags(crate::Protocol::JobDeclarationProtocol, 0b1),
+            ["REQUIRES_ASYNC_JOB_MINING"]
+        );
+        assert_eq!(
+            describe_unsupported_flags(crate::Protocol::TemplateDistributionProtocol, 0b1),
+            ["UNKNOWN"]
+        );
+    }
+
     #[test]
     fn test_has_work_selection() {
         let flags = 0b_0000_0000_0000_0000_0000_0000_0000_0100;
@@ -424,6 +568,45 @@ mod test {
         assert_eq!(has_work_selection(flags), false);
     }
 
+    #[test]
+    fn test_mining_flags() {
+        let flags = MiningFlags::from_bits(0b_0000_0000_0000_0000_0000_0000_0000_0001);
+        assert!(flags.requires_standard_jobs());
+        assert!(!flags.requires_version_rolling());
+        assert!(!flags.requires_work_selection());
+
+        let flags = MiningFlags::from_bits(0b_0000_0000_0000_0000_0000_0000_0000_0110);
+        assert!(!flags.requires_standard_jobs());
+        assert!(flags.requires_version_rolling());
+        assert!(flags.requires_work_selection());
+        assert_eq!(
+            flags,
+            MiningFlags::REQUIRES_VERSION_ROLLING | MiningFlags::REQUIRES_WORK_SELECTION
+        );
+
+        // unknown bits do not set any flag and survive the round trip
+        let flags = MiningFlags::from(0b_1000_0000_0000_0000_0000_0000_0000_0100);
+        assert!(!flags.requires_standard_jobs());
+        assert!(!flags.requires_version_rolling());
+        assert!(flags.requires_work_selection());
+        assert_eq!(u32::from(flags), 0b_1000_0000_0000_0000_0000_0000_0000_0100);
+
+        for bits in 0..8 {
+            let flags = MiningFlags::from_bits(bits);
+            assert_eq!(flags.requires_standard_jobs(), has_requires_std_job(bits));
+            assert_eq!(flags.requires_version_rolling(), has_version_rolling(bits));
+            assert_eq!(flags.requires_work_selection(), has_work_selection(bits));
+        }
+        assert_eq!(MiningFlags::empty(), MiningFlags::default());
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let version = ProtocolVersion::from(2);
+        assert_eq!(u16::from(version), 2);
+        assert!(version > ProtocolVersion::from(1));
+    }
+
     fn create_setup_connection() -> SetupConnection<'static> {
         SetupConnection {
             protocol: Protocol::MiningProtocol,
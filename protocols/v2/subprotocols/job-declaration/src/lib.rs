@@ -21,4 +21,4 @@ pub use identify_transactions::{IdentifyTransactions, IdentifyTransactionsSucces
 pub use provide_missing_transactions::{
     ProvideMissingTransactions, ProvideMissingTransactionsSuccess,
 };
-pub use submit_solution::SubmitSolutionJd;
+pub use submit_solution::{SolutionError, SubmitSolutionJd};
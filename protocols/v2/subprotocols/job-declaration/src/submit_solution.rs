@@ -1,8 +1,10 @@
+use crate::DeclareMiningJob;
 #[cfg(not(feature = "with_serde"))]
 use alloc::vec::Vec;
 #[cfg(not(feature = "with_serde"))]
 use binary_sv2::binary_codec_sv2;
 use binary_sv2::{Deserialize, Serialize, B032, U256};
+use core::convert::TryFrom;
 #[cfg(not(feature = "with_serde"))]
 use core::convert::TryInto;
 
@@ -20,6 +22,121 @@ pub struct SubmitSolutionJd<'decoder> {
     pub version: u32,
 }
 
+/// Error returned by [SubmitSolutionJd::validate_against]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SolutionError {
+    /// The coinbase prefix and suffix of the declared job do not describe a coinbase transaction
+    InvalidCoinbase,
+    /// The extranonce does not fill the space left for it in the coinbase script sig
+    ExtranonceLengthMismatch { expected: usize, received: usize },
+}
+
+impl core::fmt::Display for SolutionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SolutionError::InvalidCoinbase => {
+                write!(
+                    f,
+                    "The declared coinbase prefix and suffix are not a coinbase"
+                )
+            }
+            SolutionError::ExtranonceLengthMismatch { expected, received } => write!(
+                f,
+                "Expected an extranonce of length {}, received length {}",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl<'d> SubmitSolutionJd<'d> {
+    /// Check that the solution fits the declared job before trying to assemble the block: the
+    /// extranonce must fill the coinbase script sig between the declared prefix and suffix.
+    pub fn validate_against(&self, declare: &DeclareMiningJob) -> Result<(), SolutionError> {
+        let expected = expected_extranonce_len(
+            declare.coinbase_prefix.inner_as_ref(),
+            declare.coinbase_suffix.inner_as_ref(),
+        )
+        .ok_or(SolutionError::InvalidCoinbase)?;
+        let received = self.extranonce.inner_as_ref().len();
+        match expected == received {
+            true => Ok(()),
+            false => Err(SolutionError::ExtranonceLengthMismatch { expected, received }),
+        }
+    }
+}
+
+/// Length of the extranonce that goes between `prefix` and `suffix`. The prefix ends inside the
+/// script sig of the coinbase and its length is declared in the prefix, the extranonce and the
+/// start of the suffix fill the rest of it. The extranonce is usually at the end of the script
+/// sig so the longest extranonce that lets the suffix parse up to the lock time is returned.
+fn expected_extranonce_len(prefix: &[u8], suffix: &[u8]) -> Option<usize> {
+    let mut reader = Reader(prefix);
+    reader.take(4)?; // version
+    let segwit = reader.0.starts_with(&[0x00, 0x01]);
+    if segwit {
+        reader.take(2)?;
+    }
+    // a coinbase has a single input
+    if reader.compact_size()? != 1 {
+        return None;
+    }
+    reader.take(36)?; // previous output
+    let script_len = reader.compact_size()?;
+    let left_in_script = script_len.checked_sub(reader.0.len())?;
+    (0..=left_in_script.min(suffix.len()))
+        .find(|script_in_suffix| parses_after_script(&suffix[*script_in_suffix..], segwit))
+        .map(|script_in_suffix| left_in_script - script_in_suffix)
+}
+
+/// Whether `bytes` are exactly the sequence, the outputs, the witness and the lock time of a
+/// coinbase
+fn parses_after_script(bytes: &[u8], segwit: bool) -> bool {
+    let mut reader = Reader(bytes);
+    let mut parse = || {
+        reader.take(4)?; // sequence
+        for _ in 0..reader.compact_size()? {
+            reader.take(8)?; // value
+            let script_len = reader.compact_size()?;
+            reader.take(script_len)?;
+        }
+        if segwit {
+            for _ in 0..reader.compact_size()? {
+                let item_len = reader.compact_size()?;
+                reader.take(item_len)?;
+            }
+        }
+        reader.take(4) // lock time
+    };
+    parse().is_some() && reader.0.is_empty()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn compact_size(&mut self) -> Option<usize> {
+        let first = self.take(1)?[0];
+        let len = match first {
+            0xfd => 2,
+            0xfe => 4,
+            0xff => 8,
+            n => return Some(n as usize),
+        };
+        let mut bytes = [0; 8];
+        bytes[..len].copy_from_slice(self.take(len)?);
+        usize::try_from(u64::from_le_bytes(bytes)).ok()
+    }
+}
+
 #[cfg(feature = "with_serde")]
 use binary_sv2::GetSize;
 #[cfg(feature = "with_serde")]
@@ -32,3 +149,82 @@ impl<'d> GetSize for SubmitSolutionJd<'d> {
             + self.nbits.get_size()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    #[cfg(feature = "with_serde")]
+    use core::convert::TryInto;
+
+    // version 2, one input spending the null outpoint with a 12 bytes script sig made of 4 bytes
+    // followed by an 8 bytes extranonce, one output and lock time 0
+    fn declare(segwit: bool) -> DeclareMiningJob<'static> {
+        let mut prefix = vec![2, 0, 0, 0];
+        if segwit {
+            prefix.extend_from_slice(&[0x00, 0x01]);
+        }
+        prefix.push(1);
+        prefix.extend_from_slice(&[0; 32]);
+        prefix.extend_from_slice(&[0xff; 4]);
+        prefix.push(12);
+        prefix.extend_from_slice(&[3, 1, 2, 3]);
+        let mut suffix = vec![0xff; 4];
+        suffix.push(1);
+        suffix.extend_from_slice(&625_000_000_u64.to_le_bytes());
+        suffix.extend_from_slice(&[1, 0x51]);
+        if segwit {
+            suffix.push(1);
+            suffix.push(32);
+            suffix.extend_from_slice(&[0; 32]);
+        }
+        suffix.extend_from_slice(&[0; 4]);
+        DeclareMiningJob {
+            request_id: 0,
+            mining_job_token: vec![].try_into().unwrap(),
+            version: 0x2000_0000,
+            coinbase_prefix: prefix.try_into().unwrap(),
+            coinbase_suffix: suffix.try_into().unwrap(),
+            tx_short_hash_nonce: 0,
+            tx_short_hash_list: binary_sv2::Seq064K::new(vec![]).unwrap(),
+            tx_hash_list_hash: [0; 32].into(),
+            excess_data: vec![].try_into().unwrap(),
+        }
+    }
+
+    fn solution(extranonce_len: usize) -> SubmitSolutionJd<'static> {
+        SubmitSolutionJd {
+            extranonce: vec![0; extranonce_len].try_into().unwrap(),
+            prev_hash: [0; 32].into(),
+            ntime: 0,
+            nonce: 0,
+            nbits: 0x207fffff,
+            version: 0x2000_0000,
+        }
+    }
+
+    #[test]
+    fn solution_with_the_declared_extranonce_length_is_valid() {
+        assert_eq!(solution(8).validate_against(&declare(false)), Ok(()));
+        assert_eq!(solution(8).validate_against(&declare(true)), Ok(()));
+    }
+
+    #[test]
+    fn solution_with_another_extranonce_length_is_rejected() {
+        for len in [7, 9] {
+            assert_eq!(
+                solution(len).validate_against(&declare(false)),
+                Err(SolutionError::ExtranonceLengthMismatch {
+                    expected: 8,
+                    received: len
+                })
+            );
+        }
+        let mut declare = declare(false);
+        declare.coinbase_suffix = vec![0; 3].try_into().unwrap();
+        assert_eq!(
+            solution(8).validate_against(&declare),
+            Err(SolutionError::InvalidCoinbase)
+        );
+    }
+}
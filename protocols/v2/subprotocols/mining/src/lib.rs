@@ -143,6 +143,8 @@ pub use set_new_prev_hash::SetNewPrevHash;
 pub use set_target::SetTarget;
 pub use submit_shares::{
     SubmitSharesError, SubmitSharesExtended, SubmitSharesStandard, SubmitSharesSuccess,
+    DIFFICULTY_TOO_LOW_ERROR_CODE, INVALID_CHANNEL_ID_ERROR_CODE, INVALID_JOB_ID_ERROR_CODE,
+    STALE_SHARE_ERROR_CODE,
 };
 pub use update_channel::{UpdateChannel, UpdateChannelError};
 const MAX_EXTRANONCE_LEN: usize = 32;
@@ -332,6 +334,20 @@ impl From<&mut ExtendedExtranonce> for Extranonce {
     }
 }
 
+/// Error returned by [ExtendedExtranonce::from_ranges] when the given ranges can not describe a
+/// valid extended extranonce.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtranonceError {
+    /// The range starts after its end
+    InvalidRange(Range<usize>),
+    /// The second range starts before the end of the first one
+    OverlappingRanges(Range<usize>, Range<usize>),
+    /// The second range does not start right after the first one
+    NonContiguousRanges(Range<usize>, Range<usize>),
+    /// The ranges end after `MAX_EXTRANONCE_LEN`, it contains the total length
+    ExceedsMaxLen(usize),
+}
+
 #[derive(Debug, Clone)]
 /// Downstram and upstream are not global terms but are relative
 /// to an actor of the protocol P. In simple terms, upstream is the part of the protocol that a
@@ -491,6 +507,49 @@ impl ExtendedExtranonce {
         }
     }
 
+    /// Creates an ExtendedExtranonce whose range_0 is filled with `prefix`, eg the extranonce
+    /// prefix received from the upstream, and range_1 and range_2 are set to zero. Unlike
+    /// [Self::new] and [Self::from_upstream_extranonce] the ranges are checked: range_0 is
+    /// `0..prefix.len()`, every range must be well formed, each range must start where the
+    /// previous one ends and range_2 must end within `MAX_EXTRANONCE_LEN`.
+    pub fn from_ranges(
+        prefix: Extranonce,
+        range_1: Range<usize>,
+        range_2: Range<usize>,
+    ) -> Result<Self, ExtranonceError> {
+        let range_0 = 0..prefix.extranonce.len();
+        for range in [&range_1, &range_2].iter() {
+            if range.start > range.end {
+                return Err(ExtranonceError::InvalidRange((*range).clone()));
+            }
+        }
+        for (first, second) in [(&range_0, &range_1), (&range_1, &range_2)].iter() {
+            if second.start < first.end {
+                return Err(ExtranonceError::OverlappingRanges(
+                    (*first).clone(),
+                    (*second).clone(),
+                ));
+            }
+            if second.start > first.end {
+                return Err(ExtranonceError::NonContiguousRanges(
+                    (*first).clone(),
+                    (*second).clone(),
+                ));
+            }
+        }
+        if range_2.end > MAX_EXTRANONCE_LEN {
+            return Err(ExtranonceError::ExceedsMaxLen(range_2.end));
+        }
+        let mut inner = [0; MAX_EXTRANONCE_LEN];
+        inner[range_0.clone()].copy_from_slice(&prefix.extranonce);
+        Ok(Self {
+            inner,
+            range_0,
+            range_1,
+            range_2,
+        })
+    }
+
     pub fn new_with_inner_only_test(
         range_0: Range<usize>,
         range_1: Range<usize>,
@@ -573,15 +632,23 @@ impl ExtendedExtranonce {
 
     /// This function takes in input an ExtendedExtranonce for the extended channel. The number
     /// represented by the bytes in range_2 is incremented by 1 and the ExtendedExtranonce is
-    /// converted in an Extranonce. If range_2 is at maximum value, the output is None.
+    /// converted in an Extranonce, with range_1 set to zero. If range_2 is at maximum value, the
+    /// output is None.
+    ///
+    /// range_1 is only zeroed in the output: self keeps the last value given by
+    /// [Self::next_extended], otherwise the following extended extranonces would reuse prefixes
+    /// already given out. Extended prefixes never have range_1 set to zero, so they never collide
+    /// with the standard extranonces.
     pub fn next_standard(&mut self) -> Option<Extranonce> {
-        let reserved_extranonce_bytes = &mut self.inner[self.range_1.start..self.range_1.end];
-        for b in reserved_extranonce_bytes {
-            *b = 0
-        }
         let non_reserved_extranonces_bytes = &mut self.inner[self.range_2.start..self.range_2.end];
         match increment_bytes_be(non_reserved_extranonces_bytes) {
-            Ok(_) => Some(self.into()),
+            Ok(_) => {
+                let mut extranonce: Extranonce = self.into();
+                for b in &mut extranonce.extranonce[self.range_1.start..self.range_1.end] {
+                    *b = 0
+                }
+                Some(extranonce)
+            }
             Err(_) => None,
         }
     }
@@ -629,6 +696,17 @@ impl ExtendedExtranonce {
         }
     }
 
+    /// Replaces the bytes in range_0 with `prefix`, eg when the upstream sends a
+    /// `SetExtranoncePrefix`. The bytes reserved to P and to the downstreams are left untouched.
+    /// If `prefix` is not as long as range_0, self is not changed and the output is None.
+    pub fn set_upstream_prefix(&mut self, prefix: &[u8]) -> Option<()> {
+        if prefix.len() != self.range_0.end - self.range_0.start {
+            return None;
+        }
+        self.inner[self.range_0.start..self.range_0.end].copy_from_slice(prefix);
+        Some(())
+    }
+
     pub fn upstream_part(&self) -> Extranonce {
         self.inner[self.range_0.start..self.range_1.end]
             .to_vec()
@@ -681,6 +759,54 @@ pub mod tests {
         assert!(extended_extranonce.is_none());
     }
 
+    #[test]
+    fn test_from_ranges() {
+        let prefix: Extranonce = vec![1, 2, 3, 4].try_into().unwrap();
+        let extended = ExtendedExtranonce::from_ranges(prefix.clone(), 4..8, 8..16).unwrap();
+        assert_eq!(extended.get_range0_len(), 4);
+        assert_eq!(extended.get_prefix_len(), 8);
+        assert_eq!(extended.get_range2_len(), 8);
+        assert_eq!(extended.get_len(), 16);
+        assert_eq!(
+            extended.upstream_part().to_vec(),
+            vec![1, 2, 3, 4, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            extended,
+            ExtendedExtranonce::from_upstream_extranonce(prefix, 0..4, 4..8, 8..16).unwrap()
+        );
+
+        let no_prefix = ExtendedExtranonce::from_ranges(Extranonce::new(0).unwrap(), 0..0, 0..32);
+        assert!(no_prefix.is_ok());
+    }
+
+    #[test]
+    fn test_from_ranges_errors() {
+        let prefix: Extranonce = vec![1, 2, 3, 4].try_into().unwrap();
+        assert_eq!(
+            ExtendedExtranonce::from_ranges(prefix.clone(), 2..8, 8..16),
+            Err(ExtranonceError::OverlappingRanges(0..4, 2..8))
+        );
+        assert_eq!(
+            ExtendedExtranonce::from_ranges(prefix.clone(), 4..8, 6..16),
+            Err(ExtranonceError::OverlappingRanges(4..8, 6..16))
+        );
+        assert_eq!(
+            ExtendedExtranonce::from_ranges(prefix.clone(), 4..8, 9..16),
+            Err(ExtranonceError::NonContiguousRanges(4..8, 9..16))
+        );
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 8..4;
+        assert_eq!(
+            ExtendedExtranonce::from_ranges(prefix.clone(), reversed.clone(), 8..16),
+            Err(ExtranonceError::InvalidRange(reversed))
+        );
+        assert_eq!(
+            ExtendedExtranonce::from_ranges(prefix, 4..8, 8..MAX_EXTRANONCE_LEN + 1),
+            Err(ExtranonceError::ExceedsMaxLen(MAX_EXTRANONCE_LEN + 1))
+        );
+    }
+
     #[test]
     fn test_extranonce_from_downstream_extranonce() {
         let downstream_len = 10;
@@ -987,6 +1113,53 @@ pub mod tests {
             }
         }
     }
+    #[test]
+    fn test_standard_and_extended_extranonces_do_not_collide() {
+        let mut extended_extranonce = ExtendedExtranonce::new(0..0, 0..1, 1..2);
+        let mut prefixes = Vec::new();
+        for _ in 0..3 {
+            let extended = extended_extranonce.next_extended(1).unwrap().to_vec();
+            let standard = extended_extranonce.next_standard().unwrap().to_vec();
+            prefixes.push(extended);
+            prefixes.push(standard[..1].to_vec());
+        }
+        assert_eq!(
+            prefixes,
+            vec![vec![1], vec![0], vec![2], vec![0], vec![3], vec![0]]
+        );
+    }
+
+    #[test]
+    fn test_next_extended_exhausted() {
+        let mut extended_extranonce = ExtendedExtranonce::new(0..0, 0..1, 1..2);
+        for i in 1..=255 {
+            let prefix = extended_extranonce.next_extended(1).unwrap();
+            assert_eq!(prefix.to_vec(), vec![i]);
+        }
+        assert!(extended_extranonce.next_extended(1).is_none());
+        // Once exhausted it does not start again from the first prefix
+        assert!(extended_extranonce.next_extended(1).is_none());
+    }
+
+    #[test]
+    fn test_set_upstream_prefix() {
+        let upstream_extranonce = Extranonce::from_vec_with_len(vec![1, 1], 2);
+        let mut extended_extranonce =
+            ExtendedExtranonce::from_upstream_extranonce(upstream_extranonce, 0..2, 2..3, 3..5)
+                .unwrap();
+        let first = extended_extranonce.next_extended(2).unwrap();
+        assert_eq!(first.to_vec(), vec![1, 1, 1]);
+
+        assert!(extended_extranonce
+            .set_upstream_prefix(&[2, 2, 2])
+            .is_none());
+        assert!(extended_extranonce.set_upstream_prefix(&[2, 2]).is_some());
+        // The part reserved to P keeps going from where it was
+        let second = extended_extranonce.next_extended(2).unwrap();
+        assert_eq!(second.to_vec(), vec![2, 2, 2]);
+        assert_eq!(extended_extranonce.upstream_part().to_vec(), vec![2, 2, 2]);
+    }
+
     #[quickcheck_macros::quickcheck]
     fn test_target_from_u256(input: (u128, u128)) -> bool {
         let target_expected = Target {
@@ -3,7 +3,6 @@ use alloc::vec::Vec;
 #[cfg(not(feature = "with_serde"))]
 use binary_sv2::binary_codec_sv2;
 use binary_sv2::{Deserialize, Serialize, Str0255, B032};
-#[cfg(not(feature = "with_serde"))]
 use core::convert::TryInto;
 
 /// # SubmitSharesStandard (Client -> Server)
@@ -100,18 +99,47 @@ pub struct SubmitSharesError<'decoder> {
     pub error_code: Str0255<'decoder>,
 }
 
+/// The share refers to a channel that does not exist
+pub const INVALID_CHANNEL_ID_ERROR_CODE: &str = "invalid-channel-id";
+/// The share refers to a job that has been invalidated by a new prev hash
+pub const STALE_SHARE_ERROR_CODE: &str = "stale-share";
+/// The share hash does not meet the channel target
+pub const DIFFICULTY_TOO_LOW_ERROR_CODE: &str = "difficulty-too-low";
+/// The share refers to a job that is not known by the server
+pub const INVALID_JOB_ID_ERROR_CODE: &str = "invalid-job-id";
+
 impl<'a> SubmitSharesError<'a> {
     pub fn invalid_channel_error_code() -> &'static str {
-        "invalid-channel-id"
+        INVALID_CHANNEL_ID_ERROR_CODE
     }
     pub fn stale_share_error_code() -> &'static str {
-        "stale-share"
+        STALE_SHARE_ERROR_CODE
     }
     pub fn difficulty_too_low_error_code() -> &'static str {
-        "difficulty-too-low"
+        DIFFICULTY_TOO_LOW_ERROR_CODE
     }
     pub fn invalid_job_id_error_code() -> &'static str {
-        "invalid-job-id"
+        INVALID_JOB_ID_ERROR_CODE
+    }
+
+    /// Builds the error for the share `sequence_number` of `channel_id`, `code` should be one of
+    /// the `*_ERROR_CODE` constants.
+    ///
+    /// Panics if `code` is longer than 255 bytes.
+    pub fn with_code(
+        channel_id: u32,
+        sequence_number: u32,
+        code: &'static str,
+    ) -> SubmitSharesError<'static> {
+        SubmitSharesError {
+            channel_id,
+            sequence_number,
+            error_code: code
+                .as_bytes()
+                .to_vec()
+                .try_into()
+                .expect("error code longer than 255 bytes"),
+        }
     }
 }
 #[cfg(feature = "with_serde")]
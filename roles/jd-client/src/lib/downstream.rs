@@ -647,7 +647,7 @@ impl ParseDownstreamCommonMessages<roles_logic_sv2::routing_logic::NoRouting>
     }
 }
 
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::noise_connection_tokio::{Connection, ConnectionParts};
 use std::net::SocketAddr;
 use tokio::{
     net::TcpListener,
@@ -676,14 +676,19 @@ pub async fn listen_for_downstream_mining(
     if let Ok((stream, _)) = listner.accept().await {
         let responder = Responder::from_authority_kp(
             &authority_public_key.into_bytes(),
-            &authority_secret_key.into_bytes(),
+            authority_secret_key.into_bytes(),
             std::time::Duration::from_secs(cert_validity_sec),
         )
         .unwrap();
-        let (receiver, sender, recv_task_abort_handler, send_task_abort_handler) =
-            Connection::new(stream, HandshakeRole::Responder(responder))
-                .await
-                .expect("impossible to connect");
+        let ConnectionParts {
+            receiver,
+            sender,
+            recv_task: recv_task_abort_handler,
+            send_task: send_task_abort_handler,
+            ..
+        } = Connection::new(stream, HandshakeRole::Responder(responder))
+            .await
+            .expect("impossible to connect");
         let node = DownstreamMiningNode::new(
             receiver,
             sender,
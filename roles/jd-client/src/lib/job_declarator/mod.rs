@@ -2,7 +2,7 @@ pub mod message_handler;
 use async_channel::{Receiver, Sender};
 use binary_sv2::{Seq0255, Seq064K, B016M, B064K, U256};
 use codec_sv2::{HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::noise_connection_tokio::{Connection, ConnectionParts};
 use roles_logic_sv2::{
     handlers::SendTo_,
     job_declaration_sv2::{AllocateMiningJobTokenSuccess, SubmitSolutionJd},
@@ -86,10 +86,13 @@ impl JobDeclarator {
     ) -> Result<Arc<Mutex<Self>>, Error<'static>> {
         let stream = tokio::net::TcpStream::connect(address).await?;
         let initiator = Initiator::from_raw_k(authority_public_key)?;
-        let (mut receiver, mut sender, _, _) =
-            Connection::new(stream, HandshakeRole::Initiator(initiator))
-                .await
-                .expect("impossible to connect");
+        let ConnectionParts {
+            mut receiver,
+            mut sender,
+            ..
+        } = Connection::new(stream, HandshakeRole::Initiator(initiator))
+            .await
+            .expect("impossible to connect");
 
         let proxy_address = SocketAddr::new(
             IpAddr::from_str(&config.downstream_address).unwrap(),
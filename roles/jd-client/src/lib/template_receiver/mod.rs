@@ -3,7 +3,7 @@ use async_channel::{Receiver, Sender};
 use codec_sv2::{Frame, HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
 use error_handling::handle_result;
 use key_utils::Secp256k1PublicKey;
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::noise_connection_tokio::{Connection, ConnectionParts};
 use roles_logic_sv2::{
     handlers::{template_distribution::ParseServerTemplateDistributionMessages, SendTo_},
     job_declaration_sv2::AllocateMiningJobTokenSuccess,
@@ -67,10 +67,13 @@ impl TemplateRx {
             None => Initiator::without_pk(),
         }
         .unwrap();
-        let (mut receiver, mut sender, _, _) =
-            Connection::new(stream, HandshakeRole::Initiator(initiator))
-                .await
-                .unwrap();
+        let ConnectionParts {
+            mut receiver,
+            mut sender,
+            ..
+        } = Connection::new(stream, HandshakeRole::Initiator(initiator))
+            .await
+            .unwrap();
 
         info!("Template Receiver try to set up connection");
         SetupConnectionHandler::setup(&mut receiver, &mut sender, address)
@@ -14,10 +14,10 @@ use binary_sv2::{Seq0255, U256};
 use codec_sv2::{Frame, HandshakeRole, Initiator};
 use error_handling::handle_result;
 use key_utils::Secp256k1PublicKey;
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::noise_connection_tokio::{Connection, ConnectionParts};
 use roles_logic_sv2::{
     channel_logic::channel_factory::PoolChannelFactory,
-    common_messages_sv2::{Protocol, SetupConnection},
+    common_messages_sv2::{MiningFlags, Protocol, ProtocolVersion, SetupConnection},
     common_properties::{IsMiningUpstream, IsUpstream},
     handlers::{
         common::{ParseUpstreamCommonMessages, SendTo as SendToCommon},
@@ -178,7 +178,9 @@ impl Upstream {
         );
 
         // Channel to send and receive messages to the SV2 Upstream role
-        let (receiver, sender, _, _) = Connection::new(socket, HandshakeRole::Initiator(initiator))
+        let ConnectionParts {
+            receiver, sender, ..
+        } = Connection::new(socket, HandshakeRole::Initiator(initiator))
             .await
             .expect("Failed to create connection");
 
@@ -455,11 +457,11 @@ impl Upstream {
 }
 
 impl IsUpstream<Downstream, NullDownstreamMiningSelector> for Upstream {
-    fn get_version(&self) -> u16 {
+    fn get_version(&self) -> ProtocolVersion {
         todo!()
     }
 
-    fn get_flags(&self) -> u32 {
+    fn get_flags(&self) -> MiningFlags {
         todo!()
     }
 
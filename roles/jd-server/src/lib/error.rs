@@ -4,7 +4,10 @@ use std::{
     sync::{MutexGuard, PoisonError},
 };
 
-use roles_logic_sv2::parsers::Mining;
+use roles_logic_sv2::{
+    job_declaration_sv2::SolutionError, parsers::Mining, utils::BlockBuildError,
+};
+use stratum_common::bitcoin::Txid;
 
 use crate::mempool::error::JdsMempoolError;
 
@@ -122,3 +125,102 @@ impl From<JdsMempoolError> for JdsError {
         JdsError::MempoolError(error)
     }
 }
+
+/// Why the block mined with a solution for the last declared job could not be rebuilt
+#[derive(std::fmt::Debug)]
+pub enum BlockReconstructError {
+    /// The solution does not fit the declared job
+    Solution(SolutionError),
+    /// The coinbase, the prev hash or the witness commitment of the job is not valid
+    Block(BlockBuildError),
+    /// The transaction at this position of the declared job is not in the mempool
+    TxNotInMempool(usize, Txid),
+    /// The transaction at this position of the declared job is in the mempool without its data
+    TxDataNotInMempool(usize, Txid),
+    /// The transaction at this position of the declared job has never been provided
+    UnknownTransaction(usize),
+}
+
+impl std::fmt::Display for BlockReconstructError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use BlockReconstructError::*;
+        match self {
+            Solution(e) => write!(f, "Invalid solution: {}", e),
+            Block(e) => write!(f, "{}", e),
+            TxNotInMempool(index, txid) => {
+                write!(
+                    f,
+                    "Transaction {} ({}) not found in jds mempool",
+                    index, txid
+                )
+            }
+            TxDataNotInMempool(index, txid) => write!(
+                f,
+                "Transaction {} ({}) found in jds mempool but its data is not present",
+                index, txid
+            ),
+            UnknownTransaction(index) => write!(f, "Transaction {} is unknown", index),
+        }
+    }
+}
+
+impl From<BlockBuildError> for BlockReconstructError {
+    fn from(error: BlockBuildError) -> Self {
+        BlockReconstructError::Block(error)
+    }
+}
+
+impl From<SolutionError> for BlockReconstructError {
+    fn from(error: SolutionError) -> Self {
+        BlockReconstructError::Solution(error)
+    }
+}
+
+impl From<BlockReconstructError> for JdsError {
+    fn from(error: BlockReconstructError) -> Self {
+        JdsError::ImpossibleToReconstructBlock(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stratum_common::bitcoin::{consensus::encode, hashes::Hash};
+
+    #[test]
+    fn block_reconstruct_errors_have_distinct_messages() {
+        let txid = Txid::all_zeros();
+        let errors = vec![
+            BlockReconstructError::Solution(SolutionError::ExtranonceLengthMismatch {
+                expected: 8,
+                received: 9,
+            }),
+            BlockReconstructError::Block(BlockBuildError::InvalidCoinbase {
+                prefix_len: 42,
+                extranonce_len: 9,
+                suffix_len: 150,
+                error: encode::Error::ParseFailed("data not consumed entirely"),
+            }),
+            BlockReconstructError::Block(BlockBuildError::InvalidPrevHash(33)),
+            BlockReconstructError::Block(BlockBuildError::CoinbaseWithoutInputs),
+            BlockReconstructError::Block(BlockBuildError::InvalidWitnessCommitment),
+            BlockReconstructError::TxNotInMempool(1, txid),
+            BlockReconstructError::TxDataNotInMempool(1, txid),
+            BlockReconstructError::UnknownTransaction(1),
+        ];
+        let messages: Vec<String> = errors
+            .into_iter()
+            .map(|e| match JdsError::from(e) {
+                JdsError::ImpossibleToReconstructBlock(message) => message,
+                e => panic!("unexpected error {:?}", e),
+            })
+            .collect();
+        for (i, message) in messages.iter().enumerate() {
+            assert!(!messages[i + 1..].contains(message), "{}", message);
+        }
+        assert!(messages[0].starts_with("Invalid solution"));
+        assert!(messages[1].contains("a 42 bytes prefix, a 9 bytes extranonce"));
+        assert!(messages[2].contains("length 33"));
+        assert!(messages[5].starts_with("Transaction 1 "));
+    }
+}
@@ -17,6 +17,7 @@ use stratum_common::bitcoin::consensus::Decodable;
 use tracing::info;
 
 use super::JobDeclaratorDownstream;
+use crate::mempool::error::handle_error;
 
 impl JobDeclaratorDownstream {
     fn verify_job(&mut self, message: &DeclareMiningJob) -> bool {
@@ -75,22 +76,26 @@ impl ParseClientJobDeclarationMessages for JobDeclaratorDownstream {
                 .map(|x| x.to_vec().try_into().unwrap())
                 .collect();
             let nonce = message.tx_short_hash_nonce;
-            // TODO return None when we have a collision handle that case as weel
-            let short_id_mempool = self
+            let mempool_snapshot = self
                 .mempool
-                .safe_lock(|x| x.to_short_ids(nonce))
-                .unwrap()
-                .unwrap();
+                .safe_lock(|x| x.snapshot(nonce))
+                .map_err(|e| Error::PoisonLock(e.to_string()))?
+                .map_err(|e| {
+                    handle_error(&e);
+                    Error::NoValidJob
+                })?;
             let mut transactions_with_state =
                 vec![TransactionState::Missing; short_hash_list.len()];
             let mut missing_txs: Vec<u16> = Vec::new();
 
             for (i, sid) in short_hash_list.iter().enumerate() {
                 let sid_: [u8; 6] = sid.to_vec().try_into().unwrap();
-                match short_id_mempool.get(&sid_) {
-                    Some(tx_data) => {
-                        transactions_with_state[i] = TransactionState::PresentInMempool(tx_data.id);
-                        known_transactions.push(tx_data.id);
+                // A short txid shared by several transactions of the mempool does not identify a
+                // transaction, it is requested in full as the ones missing from the mempool
+                match mempool_snapshot.get(&sid_) {
+                    Some(txid) => {
+                        transactions_with_state[i] = TransactionState::PresentInMempool(txid);
+                        known_transactions.push(txid);
                     }
                     None => {
                         transactions_with_state[i] = TransactionState::Missing;
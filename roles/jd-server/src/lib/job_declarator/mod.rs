@@ -1,11 +1,15 @@
 pub mod message_handler;
-use super::{error::JdsError, mempool::JDsMempool, status, Configuration, EitherFrame, StdFrame};
+use super::{
+    error::{BlockReconstructError, JdsError},
+    mempool::JDsMempool,
+    status, Configuration, EitherFrame, StdFrame,
+};
 use async_channel::{Receiver, Sender};
 use binary_sv2::{B0255, U256};
 use codec_sv2::{Frame, HandshakeRole, Responder};
 use error_handling::handle_result;
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey, SignatureService};
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::noise_connection_tokio::{Connection, ConnectionParts};
 use nohash_hasher::BuildNoHashHasher;
 use roles_logic_sv2::{
     common_messages_sv2::SetupConnectionSuccess,
@@ -112,10 +116,19 @@ impl JobDeclaratorDownstream {
             .safe_lock(|x| x.declared_mining_job.clone())
             .map_err(|e| Box::new(JdsError::PoisonLock(e.to_string())))?;
         let last_declare = last_declare_.ok_or(Box::new(JdsError::NoLastDeclaredJob))?;
+        message
+            .validate_against(&last_declare)
+            .map_err(|e| Box::new(BlockReconstructError::from(e).into()))?;
         let transactions_list = Self::collect_txs_in_job(self_mutex)?;
-        let block: Block =
-            roles_logic_sv2::utils::BlockCreator::new(last_declare, transactions_list, message)
-                .into();
+        // Bitcoin Core templates always use 32 zero bytes as witness reserved value
+        let block: Block = roles_logic_sv2::utils::BlockCreator::new(
+            last_declare,
+            transactions_list,
+            message,
+            [0; 32],
+        )
+        .try_build()
+        .map_err(|e| Box::new(BlockReconstructError::from(e).into()))?;
         Ok(hex::encode(serialize(&block)))
     }
 
@@ -128,22 +141,22 @@ impl JobDeclaratorDownstream {
             .safe_lock(|x| x.mempool.clone())
             .map_err(|e| Box::new(JdsError::PoisonLock(e.to_string())))?;
         let mut transactions_list: Vec<Transaction> = Vec::new();
-        for tx_with_state in transactions_with_state.iter().enumerate() {
-            if let TransactionState::PresentInMempool(txid) = tx_with_state.1 {
+        for (index, tx_with_state) in transactions_with_state.iter().enumerate() {
+            if let TransactionState::PresentInMempool(txid) = tx_with_state {
                 let tx = mempool
                     .safe_lock(|x| x.mempool.get(txid).cloned())
                     .map_err(|e| JdsError::PoisonLock(e.to_string()))?
-                    .ok_or(Box::new(JdsError::ImpossibleToReconstructBlock(
-                        "Txid not found in jds mempool".to_string(),
-                    )))?
-                    .ok_or(Box::new(JdsError::ImpossibleToReconstructBlock(
-                        "Txid found in jds mempool but transactions not present".to_string(),
-                    )))?;
+                    .ok_or_else(|| {
+                        Box::new(BlockReconstructError::TxNotInMempool(index, *txid).into())
+                    })?
+                    .ok_or_else(|| {
+                        Box::new(BlockReconstructError::TxDataNotInMempool(index, *txid).into())
+                    })?;
                 transactions_list.push(tx);
             } else {
-                return Err(Box::new(JdsError::ImpossibleToReconstructBlock(
-                    "Unknown transaction".to_string(),
-                )));
+                return Err(Box::new(
+                    BlockReconstructError::UnknownTransaction(index).into(),
+                ));
             };
         }
         Ok(transactions_list)
@@ -441,14 +454,15 @@ impl JobDeclarator {
         while let Ok((stream, _)) = listner.accept().await {
             let responder = Responder::from_authority_kp(
                 &config.authority_public_key.into_bytes(),
-                &config.authority_secret_key.into_bytes(),
+                config.authority_secret_key.into_bytes(),
                 std::time::Duration::from_secs(config.cert_validity_sec),
             )
             .unwrap();
             let addr = stream.peer_addr();
 
-            if let Ok((receiver, sender, _, _)) =
-                Connection::new(stream, HandshakeRole::Responder(responder)).await
+            if let Ok(ConnectionParts {
+                receiver, sender, ..
+            }) = Connection::new(stream, HandshakeRole::Responder(responder)).await
             {
                 let setup_message_from_proxy_jd = receiver.recv().await.unwrap();
                 info!(
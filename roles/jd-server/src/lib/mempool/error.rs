@@ -8,6 +8,8 @@ pub enum JdsMempoolError {
     NoClient,
     Rpc(RpcError),
     PoisonLock(String),
+    /// A short txid is not 6 bytes long, it contains the length of the short txid
+    InvalidShortTxId(usize),
 }
 
 impl From<RpcError> for JdsMempoolError {
@@ -40,5 +42,8 @@ pub fn handle_error(err: &JdsMempoolError) {
             error!("{:?}", err);
             error!("Poison lock error)");
         }
+        JdsMempoolError::InvalidShortTxId(_) => {
+            error!("{:?}", err);
+        }
     }
 }
@@ -9,10 +9,107 @@ use rpc_sv2::{mini_rpc_client, mini_rpc_client::RpcError};
 use std::{convert::TryInto, str::FromStr, sync::Arc};
 use stratum_common::{bitcoin, bitcoin::hash_types::Txid};
 
+/// Transaction set of the mempool at a given time, keyed by short txid. Two successive snapshots
+/// can be compared with [`MempoolSnapshot::diff`] to only send the transactions that changed.
+///
+/// Transactions that share their short txid with another transaction of the snapshot can not be
+/// identified by it: [`MempoolSnapshot::get`] does not return them, so that they are fetched in
+/// full from the client, and [`MempoolSnapshot::diff`] compares them by txid.
 #[derive(Clone, Debug)]
-pub struct TransactionWithHash {
-    pub id: Txid,
-    pub tx: Option<Transaction>,
+pub struct MempoolSnapshot {
+    tx_short_hash_nonce: u64,
+    transactions: HashMap<[u8; 6], Txid>,
+    colliding: HashMap<[u8; 6], Vec<Txid>>,
+}
+
+/// Transactions added to and removed from a snapshot since the previous one, sorted by txid
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TxSetDiff {
+    pub added: Vec<Txid>,
+    pub removed: Vec<Txid>,
+}
+
+impl MempoolSnapshot {
+    pub fn new(
+        txids: impl IntoIterator<Item = Txid>,
+        tx_short_hash_nonce: u64,
+    ) -> Result<Self, JdsMempoolError> {
+        let mut short_ids = vec![];
+        for txid in txids {
+            let short_id = roles_logic_sv2::utils::get_short_hash(txid, tx_short_hash_nonce);
+            let short_id: [u8; 6] = short_id
+                .inner_as_ref()
+                .try_into()
+                .map_err(|_| JdsMempoolError::InvalidShortTxId(short_id.inner_as_ref().len()))?;
+            short_ids.push((short_id, txid));
+        }
+        Ok(Self::from_short_ids(short_ids, tx_short_hash_nonce))
+    }
+
+    fn from_short_ids(
+        short_ids: impl IntoIterator<Item = ([u8; 6], Txid)>,
+        tx_short_hash_nonce: u64,
+    ) -> Self {
+        let mut transactions = HashMap::new();
+        let mut colliding: HashMap<[u8; 6], Vec<Txid>> = HashMap::new();
+        for (short_id, txid) in short_ids {
+            if let Some(txids) = colliding.get_mut(&short_id) {
+                txids.push(txid);
+            } else if let Some(other) = transactions.insert(short_id, txid) {
+                transactions.remove(&short_id);
+                colliding.insert(short_id, vec![other, txid]);
+            }
+        }
+        Self {
+            tx_short_hash_nonce,
+            transactions,
+            colliding,
+        }
+    }
+
+    /// Returns the transaction with short txid `short_id`, or `None` if the short txid is unknown
+    /// or shared by several transactions
+    pub fn get(&self, short_id: &[u8; 6]) -> Option<Txid> {
+        self.transactions.get(short_id).copied()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&[u8; 6], &Txid)> {
+        self.transactions.iter().chain(
+            self.colliding
+                .iter()
+                .flat_map(|(short_id, txids)| txids.iter().map(move |txid| (short_id, txid))),
+        )
+    }
+
+    fn contains(&self, short_id: &[u8; 6], txid: &Txid) -> bool {
+        self.transactions.get(short_id) == Some(txid)
+            || self
+                .colliding
+                .get(short_id)
+                .map_or(false, |txids| txids.contains(txid))
+    }
+
+    /// Transactions to add to and remove from `prev` to get `self`. Short txids of snapshots taken
+    /// with different nonces can not be compared: every transaction of `prev` is removed and
+    /// every transaction of `self` added.
+    // Only used by the users of the jd_server library, the binary includes this module privately
+    #[allow(dead_code)]
+    pub fn diff(&self, prev: &MempoolSnapshot) -> TxSetDiff {
+        let missing_from = |a: &Self, b: &Self| {
+            let same_nonce = a.tx_short_hash_nonce == b.tx_short_hash_nonce;
+            let mut txids: Vec<Txid> = a
+                .iter()
+                .filter(|(short_id, txid)| !same_nonce || !b.contains(short_id, txid))
+                .map(|(_, txid)| *txid)
+                .collect();
+            txids.sort();
+            txids
+        };
+        TxSetDiff {
+            added: missing_from(self, prev),
+            removed: missing_from(prev, self),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -138,23 +235,64 @@ impl JDsMempool {
         Ok(())
     }
 
-    pub fn to_short_ids(&self, nonce: u64) -> Option<HashMap<[u8; 6], TransactionWithHash>> {
-        let mut ret = HashMap::new();
-        for tx in &self.mempool {
-            let s_id = roles_logic_sv2::utils::get_short_hash(*tx.0, nonce)
-                .to_vec()
-                .try_into()
-                .unwrap();
-            let tx_data = TransactionWithHash {
-                id: *tx.0,
-                tx: tx.1.clone(),
-            };
-            if ret.insert(s_id, tx_data.clone()).is_none() {
-                continue;
-            } else {
-                return None;
+    /// Snapshot of the transactions currently in the mempool, keyed by their short txid for
+    /// `tx_short_hash_nonce`
+    pub fn snapshot(&self, tx_short_hash_nonce: u64) -> Result<MempoolSnapshot, JdsMempoolError> {
+        MempoolSnapshot::new(self.mempool.keys().copied(), tx_short_hash_nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stratum_common::bitcoin::hashes::Hash;
+
+    fn txids(range: std::ops::Range<u8>) -> Vec<Txid> {
+        range.map(|i| Txid::from_inner([i; 32])).collect()
+    }
+
+    #[test]
+    fn diff_contains_exactly_the_added_and_removed_transactions() {
+        let prev = MempoolSnapshot::new(txids(0..6), 42).unwrap();
+        let next = MempoolSnapshot::new(txids(3..10), 42).unwrap();
+
+        assert_eq!(
+            next.diff(&prev),
+            TxSetDiff {
+                added: txids(6..10),
+                removed: txids(0..3),
             }
-        }
-        Some(ret)
+        );
+        assert_eq!(next.diff(&next), TxSetDiff::default());
+
+        // Short txids computed with another nonce can not be compared
+        let other_nonce = MempoolSnapshot::new(txids(3..10), 43).unwrap();
+        assert_eq!(
+            other_nonce.diff(&prev),
+            TxSetDiff {
+                added: txids(3..10),
+                removed: txids(0..6),
+            }
+        );
+    }
+
+    #[test]
+    fn colliding_short_ids_are_not_resolved_and_diffed_by_txid() {
+        let [a, b, c, d] = [0, 1, 2, 3].map(|i| Txid::from_inner([i; 32]));
+        let prev = MempoolSnapshot::from_short_ids([([0; 6], a), ([1; 6], d)], 42);
+        // b and c share the short txid of a
+        let next = MempoolSnapshot::from_short_ids([([0; 6], a), ([0; 6], b), ([0; 6], c)], 42);
+
+        assert_eq!(prev.get(&[0; 6]), Some(a));
+        assert_eq!(next.get(&[0; 6]), None);
+        assert_eq!(next.get(&[1; 6]), None);
+        assert_eq!(
+            next.diff(&prev),
+            TxSetDiff {
+                added: vec![b, c],
+                removed: vec![d],
+            }
+        );
+        assert_eq!(next.diff(&next), TxSetDiff::default());
     }
 }
@@ -1,4 +1,4 @@
-use roles_logic_sv2::parsers::Mining;
+use roles_logic_sv2::{parsers::Mining, status};
 
 use super::error::JdsError;
 
@@ -22,6 +22,26 @@ impl Clone for Sender {
     }
 }
 
+/// Component tag of the jd-server [`status::StatusReporter`]s
+#[derive(Debug, Clone, Copy)]
+pub enum Component {
+    Downstream,
+    DownstreamListener,
+    Upstream,
+}
+
+impl Sender {
+    fn reporter(&self) -> status::StatusReporter<Component, JdsError, Status> {
+        match self {
+            Self::Downstream(tx) => status::StatusReporter::new(Component::Downstream, tx.clone()),
+            Self::DownstreamListener(tx) => {
+                status::StatusReporter::new(Component::DownstreamListener, tx.clone())
+            }
+            Self::Upstream(tx) => status::StatusReporter::new(Component::Upstream, tx.clone()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum State {
     DownstreamShutdown(JdsError),
@@ -36,6 +56,21 @@ pub struct Status {
     pub state: State,
 }
 
+impl From<status::State<Component, JdsError>> for Status {
+    fn from(state: status::State<Component, JdsError>) -> Self {
+        let state = match state {
+            status::State::Shutdown(Component::Downstream, e @ JdsError::MempoolError(_))
+            | status::State::Shutdown(Component::Upstream, e) => State::TemplateProviderShutdown(e),
+            status::State::Shutdown(Component::Downstream | Component::DownstreamListener, e) => {
+                State::DownstreamShutdown(e)
+            }
+            status::State::InstanceDropped(_, id) => State::DownstreamInstanceDropped(id),
+            status::State::Healthy(_, msg) => State::Healthy(msg),
+        };
+        Status { state }
+    }
+}
+
 /// this function is used to discern which componnent experienced the event.
 /// With this knowledge we can wrap the status message with information (`State` variants) so
 /// the main status loop can decide what should happen
@@ -44,54 +79,21 @@ async fn send_status(
     e: JdsError,
     outcome: error_handling::ErrorBranch,
 ) -> error_handling::ErrorBranch {
-    match sender {
-        Sender::Downstream(tx) => match e {
+    let reporter = sender.reporter();
+    match reporter.component() {
+        Component::Downstream => match e {
             JdsError::Sv2ProtocolError((id, Mining::OpenMiningChannelError(_))) => {
-                tx.send(Status {
-                    state: State::DownstreamInstanceDropped(id),
-                })
-                .await
-                .unwrap_or(());
+                reporter.send_instance_dropped(id, outcome).await
             }
-            JdsError::ChannelRecv(_) => {
-                tx.send(Status {
-                    state: State::DownstreamShutdown(e),
-                })
-                .await
-                .unwrap_or(());
-            }
-            JdsError::MempoolError(_) => {
-                tx.send(Status {
-                    state: State::TemplateProviderShutdown(e),
-                })
-                .await
-                .unwrap_or(());
-            }
-            _ => {
-                let string_err = e.to_string();
-                tx.send(Status {
-                    state: State::Healthy(string_err),
-                })
-                .await
-                .unwrap_or(());
+            JdsError::ChannelRecv(_) | JdsError::MempoolError(_) => {
+                reporter.send_error(e, outcome).await
             }
+            _ => reporter.send_healthy(e, outcome).await,
         },
-        Sender::DownstreamListener(tx) => {
-            tx.send(Status {
-                state: State::DownstreamShutdown(e),
-            })
-            .await
-            .unwrap_or(());
-        }
-        Sender::Upstream(tx) => {
-            tx.send(Status {
-                state: State::TemplateProviderShutdown(e),
-            })
-            .await
-            .unwrap_or(());
+        Component::DownstreamListener | Component::Upstream => {
+            reporter.send_error(e, outcome).await
         }
     }
-    outcome
 }
 
 // this is called by `error_handling::handle_result!`
@@ -150,6 +150,10 @@ async fn main() {
                             mempool::error::handle_error(&err);
                             handle_result!(sender_update_mempool, Err(err));
                         }
+                        JdsMempoolError::InvalidShortTxId(_) => {
+                            mempool::error::handle_error(&err);
+                            handle_result!(sender_update_mempool, Err(err));
+                        }
                     }
                 }
                 tokio::time::sleep(mempool_update_interval).await;
@@ -0,0 +1,128 @@
+use codec_sv2::Frame;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use mining_proxy_sv2::{
+    downstream_mining::{DownstreamMiningNode, DownstreamMiningNodeStatus},
+    upstream_mining::{ReconnectBackoff, StdFrame, UpstreamMiningNode},
+    ChannelKind, ROUTING_LOGIC,
+};
+use roles_logic_sv2::{
+    common_properties::{CommonDownstreamData, IsUpstream},
+    handlers::mining::ParseUpstreamMiningMessages,
+    mining_sv2::{NewExtendedMiningJob, OpenExtendedMiningChannelSuccess},
+    parsers::{Mining, PoolMessages},
+    routing_logic::MiningProxyRoutingLogic,
+    selectors::{DownstreamMiningSelector, GeneralMiningSelector},
+    utils::{GroupId, Id, Mutex, Target},
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+// Serialized and parsed again, as a frame read from the upstream connection
+fn received_frame(message: Mining<'static>) -> StdFrame {
+    let frame: StdFrame = PoolMessages::Mining(message).try_into().unwrap();
+    let mut bytes = vec![0; frame.encoded_length()];
+    frame.serialize(&mut bytes).unwrap();
+    StdFrame::from_bytes(bytes.into()).unwrap()
+}
+
+fn bench_relay_new_extended_mining_job(c: &mut Criterion) {
+    ROUTING_LOGIC.get_or_init(|| {
+        Mutex::new(MiningProxyRoutingLogic {
+            upstream_selector: GeneralMiningSelector::new(vec![]),
+            downstream_id_generator: Id::new(),
+            downstream_to_upstream_map: HashMap::new(),
+        })
+    });
+    let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+    let mut upstream = UpstreamMiningNode::new(
+        0,
+        address,
+        [0; 32],
+        ChannelKind::Group,
+        Arc::new(Mutex::new(GroupId::new())),
+        Arc::new(Mutex::new(Id::new())),
+        10.0,
+        None,
+        None,
+        100_000.0,
+        false,
+        ReconnectBackoff::default(),
+        None,
+    );
+    let (sender, receiver) = async_channel::bounded(10);
+    let mut downstream = DownstreamMiningNode::new(receiver.clone(), sender, 0);
+    downstream.status = DownstreamMiningNodeStatus::Paired(CommonDownstreamData {
+        header_only: false,
+        work_selection: false,
+        version_rolling: false,
+    });
+
+    // Open an extended channel for the downstream as if the upstream accepted its request
+    let channel_id = 3;
+    let request_id = upstream.get_mapper().unwrap().on_open_channel(channel_id);
+    upstream
+        .get_remote_selector()
+        .on_open_standard_channel_request(request_id, Arc::new(Mutex::new(downstream)));
+    upstream
+        .handle_open_extended_mining_channel_success(OpenExtendedMiningChannelSuccess {
+            request_id,
+            channel_id,
+            target: Target::from_difficulty(1.0).to_le_bytes().into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 16].try_into().unwrap(),
+        })
+        .unwrap();
+    let upstream = Arc::new(Mutex::new(upstream));
+
+    // Same pass-through NewExtendedMiningJob frame for both paths
+    let job = NewExtendedMiningJob {
+        channel_id,
+        job_id: 1,
+        min_ntime: binary_sv2::Sv2Option::new(None),
+        version: 0x2000_0000,
+        version_rolling_allowed: true,
+        merkle_path: vec![[1; 32].into(); 12].into(),
+        coinbase_tx_prefix: vec![2; 42].try_into().unwrap(),
+        coinbase_tx_suffix: vec![3; 100].try_into().unwrap(),
+    };
+    let frame = || received_frame(Mining::NewExtendedMiningJob(job.clone()));
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("relay_new_extended_mining_job");
+    group.bench_function("next", |b| {
+        b.to_async(&runtime).iter_batched(
+            frame,
+            |frame| {
+                let upstream = upstream.clone();
+                let receiver = &receiver;
+                async move {
+                    UpstreamMiningNode::next(upstream, frame).await;
+                    receiver.try_recv().unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("next_relay_only", |b| {
+        b.to_async(&runtime).iter_batched(
+            frame,
+            |frame| {
+                let upstream = upstream.clone();
+                let receiver = &receiver;
+                async move {
+                    UpstreamMiningNode::next_relay_only(upstream, frame).await;
+                    receiver.try_recv().unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_relay_new_extended_mining_job);
+criterion_main!(benches);
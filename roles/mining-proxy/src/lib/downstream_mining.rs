@@ -4,18 +4,21 @@ use super::upstream_mining::{StdFrame as UpstreamFrame, UpstreamMiningNode};
 use async_channel::{Receiver, SendError, Sender};
 use roles_logic_sv2::{
     common_messages_sv2::{SetupConnection, SetupConnectionSuccess},
-    common_properties::{CommonDownstreamData, IsDownstream, IsMiningDownstream},
+    common_properties::{CommonDownstreamData, IsDownstream, IsMiningDownstream, IsUpstream},
     errors::Error,
     handlers::{
         common::{ParseDownstreamCommonMessages, SendTo as SendToCommon},
-        mining::{ParseDownstreamMiningMessages, SendTo, SupportedChannelTypes},
+        mining::{
+            ParseDownstreamMiningMessages, ParseUpstreamMiningMessages, SendTo,
+            SupportedChannelTypes,
+        },
     },
     mining_sv2::*,
     parsers::{Mining, MiningDeviceMessages, PoolMessages},
     routing_logic::MiningProxyRoutingLogic,
     utils::Mutex,
 };
-use tracing::info;
+use tracing::{debug, info, info_span, warn, Instrument};
 
 use codec_sv2::{Frame, StandardEitherFrame, StandardSv2Frame};
 
@@ -34,7 +37,7 @@ pub struct DownstreamMiningNode {
     sender: Sender<EitherFrame>,
     pub status: DownstreamMiningNodeStatus,
     pub prev_job_id: Option<u32>,
-    upstream: Option<Arc<Mutex<UpstreamMiningNode>>>,
+    pub upstream: Option<Arc<Mutex<UpstreamMiningNode>>>,
 }
 
 #[derive(Debug)]
@@ -181,6 +184,10 @@ impl DownstreamMiningNode {
         self.status.add_extended_from_non_hom_for_up_extended(id);
     }
 
+    pub fn get_id(&self) -> u32 {
+        self.id
+    }
+
     pub fn new(receiver: Receiver<EitherFrame>, sender: Sender<EitherFrame>, id: u32) -> Self {
         Self {
             receiver,
@@ -205,12 +212,17 @@ impl DownstreamMiningNode {
             let setup_connection_success: MiningDeviceMessages = setup_connection_success.into();
 
             {
-                DownstreamMiningNode::send(
+                if DownstreamMiningNode::send(
                     self_mutex.clone(),
                     setup_connection_success.try_into().unwrap(),
                 )
                 .await
-                .unwrap();
+                .is_err()
+                {
+                    info!("Downstream connection closed");
+                    Self::exit(self_mutex);
+                    return;
+                }
             }
             let receiver = self_mutex
                 .safe_lock(|self_| self_.receiver.clone())
@@ -220,6 +232,7 @@ impl DownstreamMiningNode {
                 let incoming: StdFrame = message.try_into().unwrap();
                 Self::next(self_mutex.clone(), incoming).await;
             }
+            info!("Downstream connection closed");
             Self::exit(self_mutex);
         } else {
             panic!()
@@ -230,6 +243,7 @@ impl DownstreamMiningNode {
     pub async fn next(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) {
         let message_type = incoming.get_header().unwrap().msg_type();
         let payload = incoming.payload();
+        debug!(message_type, ?payload, "Received message from downstream");
 
         let routing_logic = super::get_routing_logic();
 
@@ -258,9 +272,12 @@ impl DownstreamMiningNode {
             Ok(SendTo::Respond(message)) => {
                 let message = MiningDeviceMessages::Mining(message);
                 let frame: StdFrame = message.try_into().unwrap();
-                DownstreamMiningNode::send(self_mutex.clone(), frame)
+                if DownstreamMiningNode::send(self_mutex.clone(), frame)
                     .await
-                    .unwrap();
+                    .is_err()
+                {
+                    Self::exit(self_mutex);
+                }
             }
             Ok(SendTo::Multiple(sends_to)) => {
                 for message in sends_to {
@@ -269,23 +286,35 @@ impl DownstreamMiningNode {
                             Mining::NewMiningJob(_) => {
                                 let message = MiningDeviceMessages::Mining(m);
                                 let frame: StdFrame = message.try_into().unwrap();
-                                DownstreamMiningNode::send(self_mutex.clone(), frame)
+                                if DownstreamMiningNode::send(self_mutex.clone(), frame)
                                     .await
-                                    .unwrap();
+                                    .is_err()
+                                {
+                                    Self::exit(self_mutex);
+                                    return;
+                                }
                             }
                             Mining::OpenStandardMiningChannelSuccess(_) => {
                                 let message = MiningDeviceMessages::Mining(m);
                                 let frame: StdFrame = message.try_into().unwrap();
-                                DownstreamMiningNode::send(self_mutex.clone(), frame)
+                                if DownstreamMiningNode::send(self_mutex.clone(), frame)
                                     .await
-                                    .unwrap();
+                                    .is_err()
+                                {
+                                    Self::exit(self_mutex);
+                                    return;
+                                }
                             }
                             Mining::SetNewPrevHash(_) => {
                                 let message = MiningDeviceMessages::Mining(m);
                                 let frame: StdFrame = message.try_into().unwrap();
-                                DownstreamMiningNode::send(self_mutex.clone(), frame)
+                                if DownstreamMiningNode::send(self_mutex.clone(), frame)
                                     .await
-                                    .unwrap();
+                                    .is_err()
+                                {
+                                    Self::exit(self_mutex);
+                                    return;
+                                }
                             }
                             m => panic!("{:?}", m),
                         },
@@ -299,19 +328,14 @@ impl DownstreamMiningNode {
         }
     }
 
-    /// Send a message downstream
+    /// Send a message downstream, fails if the downstream connection is closed
     pub async fn send(
         self_mutex: Arc<Mutex<Self>>,
         sv2_frame: StdFrame,
-    ) -> Result<(), SendError<StdFrame>> {
+    ) -> Result<(), SendError<EitherFrame>> {
         let either_frame = sv2_frame.into();
         let sender = self_mutex.safe_lock(|self_| self_.sender.clone()).unwrap();
-        match sender.send(either_frame).await {
-            Ok(_) => Ok(()),
-            Err(_) => {
-                todo!()
-            }
-        }
+        sender.send(either_frame).await
     }
 
     pub fn exit(self_: Arc<Mutex<Self>>) {
@@ -337,11 +361,23 @@ impl
     > for DownstreamMiningNode
 {
     fn get_channel_type(&self) -> SupportedChannelTypes {
-        SupportedChannelTypes::Group
+        // SetCustomMiningJob is only accepted on extended channels, so they are advertised when
+        // the upstream connection negotiated work selection
+        if self.is_work_selection_enabled() {
+            SupportedChannelTypes::GroupAndExtended
+        } else {
+            SupportedChannelTypes::Group
+        }
     }
 
     fn is_work_selection_enabled(&self) -> bool {
-        false
+        // Custom jobs can be relayed only if the upstream connection negotiated work selection
+        match &self.upstream {
+            Some(upstream) => upstream
+                .safe_lock(|u| u.is_work_selection_enabled())
+                .unwrap_or(false),
+            None => false,
+        }
     }
 
     fn is_downstream_authorized(
@@ -361,7 +397,12 @@ impl
             .expect("No upstream initialized")
             .safe_lock(|s| s.channel_ids.safe_lock(|r| r.next()).unwrap())
             .unwrap();
-        info!(channel_id);
+        info!(
+            downstream_id = self.id,
+            channel_id,
+            request_id = req.request_id.as_u32(),
+            "Opening standard channel"
+        );
         let cloned = up.as_ref().expect("No upstream initialized").clone();
         up.as_ref()
             .expect("No upstream initialized")
@@ -384,6 +425,7 @@ impl
                     let messages = messages.into_iter().map(SendTo::Respond).collect();
                     Ok(SendTo::Multiple(messages))
                 } else {
+                    up.on_standard_channel_request(req.request_id.as_u32(), req.nominal_hash_rate);
                     Ok(SendTo::RelaySameMessageToRemote(cloned))
                 }
             })
@@ -394,14 +436,51 @@ impl
         &mut self,
         _: OpenExtendedMiningChannel,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        todo!()
+        // The proxy do not open extended channels for downstreams
+        Err(Error::UnexpectedMessage(
+            const_sv2::MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL,
+        ))
     }
 
     fn handle_update_channel(
         &mut self,
-        _: UpdateChannel,
+        m: UpdateChannel,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        todo!()
+        match &self.status {
+            // The channel is opened by the upstream, the proxy only relays the request and keeps
+            // track of it in case the upstream rejects it
+            DownstreamMiningNodeStatus::ChannelOpened(Channel::DowntreamHomUpstreamGroup {
+                ..
+            })
+            | DownstreamMiningNodeStatus::ChannelOpened(
+                Channel::DowntreamNonHomUpstreamExtended { .. },
+            ) => {
+                let remote = self.upstream.as_ref().ok_or(Error::NoUpstreamsConnected)?;
+                remote
+                    .safe_lock(|r| r.on_update_channel_request(m.into_static()))
+                    .map_err(|e| Error::PoisonLock(e.to_string()))?;
+                Ok(SendTo::RelaySameMessageToRemote(remote.clone()))
+            }
+            // The downstream has no channel yet, or its channel is opened by the proxy channel
+            // factory and is unknown to the upstream
+            _ => {
+                warn!(
+                    downstream_id = self.id,
+                    channel_id = m.channel_id,
+                    "UpdateChannel for a channel that can not be updated"
+                );
+                let error = UpdateChannelError {
+                    channel_id: m.channel_id,
+                    // Below unwrap never panic because the string is shorter than 255 bytes
+                    error_code: INVALID_CHANNEL_ID_ERROR_CODE
+                        .to_string()
+                        .into_bytes()
+                        .try_into()
+                        .unwrap(),
+                };
+                Ok(SendTo::Respond(Mining::UpdateChannelError(error)))
+            }
+        }
     }
 
     fn handle_submit_shares_standard(
@@ -443,14 +522,61 @@ impl
         &mut self,
         _: SubmitSharesExtended,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        todo!()
+        Err(Error::UnexpectedMessage(
+            const_sv2::MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED,
+        ))
     }
 
     fn handle_set_custom_mining_job(
         &mut self,
-        _: SetCustomMiningJob,
+        m: SetCustomMiningJob,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        todo!()
+        // Only channels opened by the upstream can carry custom jobs, the channels opened by the
+        // proxy channel factory are unknown to the upstream
+        match &self.status {
+            DownstreamMiningNodeStatus::ChannelOpened(Channel::DowntreamHomUpstreamGroup {
+                channel_id,
+                ..
+            }) if *channel_id == m.channel_id => (),
+            _ => {
+                warn!(
+                    downstream_id = self.id,
+                    channel_id = m.channel_id,
+                    "SetCustomMiningJob for a channel that can not carry custom jobs"
+                );
+                let error = SetCustomMiningJobError {
+                    channel_id: m.channel_id,
+                    request_id: m.request_id,
+                    // Below unwrap never panic because the string is shorter than 255 bytes
+                    error_code: INVALID_CHANNEL_ID_ERROR_CODE
+                        .to_string()
+                        .into_bytes()
+                        .try_into()
+                        .unwrap(),
+                };
+                return Ok(SendTo::Respond(Mining::SetCustomMiningJobError(error)));
+            }
+        }
+        let upstream = self
+            .upstream
+            .as_ref()
+            .ok_or(Error::NoUpstreamsConnected)?
+            .clone();
+        // The request id from the downstream is not unique upstream side, the upstream use the
+        // mapper to give back the original one in SetCustomMiningJobSuccess/Error
+        let request_id = upstream
+            .safe_lock(|u| {
+                u.get_mapper()
+                    .map(|mapper| mapper.on_open_channel(m.request_id))
+            })
+            .map_err(|e| Error::PoisonLock(e.to_string()))?
+            .unwrap_or(m.request_id);
+        let mut m = m.into_static();
+        m.request_id = request_id;
+        Ok(SendTo::RelayNewMessageToRemote(
+            upstream,
+            Mining::SetCustomMiningJob(m),
+        ))
     }
 }
 
@@ -465,14 +591,12 @@ impl
         result: Option<Result<(CommonDownstreamData, SetupConnectionSuccess), Error>>,
     ) -> Result<roles_logic_sv2::handlers::common::SendTo, Error> {
         let (data, message) = result.unwrap().unwrap();
-        let upstream = match super::get_routing_logic() {
-            roles_logic_sv2::routing_logic::MiningRoutingLogic::Proxy(proxy_routing) => {
-                proxy_routing
-                    .safe_lock(|r| r.downstream_to_upstream_map.get(&data).unwrap()[0].clone())
-                    .unwrap()
-            }
-            _ => unreachable!(),
-        };
+        let upstream = super::with_routing_logic(|r| {
+            r.downstream_to_upstream_map
+                .get(&data)
+                .map(|upstreams| upstreams[0].clone())
+        })
+        .ok_or(Error::NoCompatibleUpstream(data))?;
         self.upstream = Some(upstream);
 
         self.status.pair(data);
@@ -484,43 +608,83 @@ impl
 }
 
 use network_helpers_sv2::plain_connection_tokio::PlainConnection;
-use std::net::SocketAddr;
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use tokio::net::TcpListener;
 
-pub async fn listen_for_downstream_mining(address: SocketAddr) {
+/// Counts a connected downstream in the live connections until dropped, that is when the task
+/// serving the downstream ends, also if it panics.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accept downstream connections on `address`. When `max_connections` downstreams are connected
+/// new connections are closed until one of them disconnects.
+pub async fn listen_for_downstream_mining(address: SocketAddr, max_connections: Option<usize>) {
     info!("Listening for downstream mining connections on {}", address);
     let listner = TcpListener::bind(address).await.unwrap();
-    let mut ids = roles_logic_sv2::utils::Id::new();
+    accept_downstream_mining(listner, max_connections).await
+}
 
-    while let Ok((stream, _)) = listner.accept().await {
+async fn accept_downstream_mining(listner: TcpListener, max_connections: Option<usize>) {
+    let mut ids = roles_logic_sv2::utils::Id::new();
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    while let Ok((stream, peer)) = listner.accept().await {
+        let connected = connections.load(Ordering::SeqCst);
+        if max_connections.map_or(false, |max| connected >= max) {
+            warn!(
+                %peer,
+                connected, "Downstream connection refused, too many downstreams connected"
+            );
+            drop(stream);
+            continue;
+        }
+        connections.fetch_add(1, Ordering::SeqCst);
+        let slot = ConnectionSlot(connections.clone());
         let (receiver, sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
             PlainConnection::new(stream).await;
         let node = DownstreamMiningNode::new(receiver, sender, ids.next());
-
-        task::spawn(async move {
-            let mut incoming: StdFrame = node.receiver.recv().await.unwrap().try_into().unwrap();
-            let message_type = incoming.get_header().unwrap().msg_type();
-            let payload = incoming.payload();
-            let routing_logic = super::get_common_routing_logic();
-            let node = Arc::new(Mutex::new(node));
-
-            // Call handle_setup_connection or fail
-            match DownstreamMiningNode::handle_message_common(
-                node.clone(),
-                message_type,
-                payload,
-                routing_logic,
-            ) {
-                Ok(SendToCommon::RelayNewMessageToRemote(_, message)) => {
-                    let message = match message {
-                        roles_logic_sv2::parsers::CommonMessages::SetupConnectionSuccess(m) => m,
-                        _ => panic!(),
-                    };
-                    DownstreamMiningNode::start(node, message).await
+        let span = info_span!("downstream", downstream_id = node.id, %peer);
+        span.in_scope(|| info!("Downstream connected"));
+
+        task::spawn(
+            async move {
+                let _slot = slot;
+                let mut incoming: StdFrame =
+                    node.receiver.recv().await.unwrap().try_into().unwrap();
+                let message_type = incoming.get_header().unwrap().msg_type();
+                let payload = incoming.payload();
+                let routing_logic = super::get_common_routing_logic();
+                let node = Arc::new(Mutex::new(node));
+
+                // Call handle_setup_connection or fail
+                match DownstreamMiningNode::handle_message_common(
+                    node.clone(),
+                    message_type,
+                    payload,
+                    routing_logic,
+                ) {
+                    Ok(SendToCommon::RelayNewMessageToRemote(_, message)) => {
+                        let message = match message {
+                            roles_logic_sv2::parsers::CommonMessages::SetupConnectionSuccess(m) => {
+                                m
+                            }
+                            _ => panic!(),
+                        };
+                        DownstreamMiningNode::start(node, message).await
+                    }
+                    _ => panic!(),
                 }
-                _ => panic!(),
             }
-        });
+            .instrument(span),
+        );
     }
 }
 
@@ -544,3 +708,80 @@ impl IsDownstream for DownstreamMiningNode {
     }
 }
 impl IsMiningDownstream for DownstreamMiningNode {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    #[tokio::test]
+    async fn connections_past_the_limit_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        task::spawn(accept_downstream_mining(listener, Some(1)));
+
+        // The first downstream holds the only slot while it is waiting to set up the connection
+        let mut first = TcpStream::connect(address).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut second = TcpStream::connect(address).await.unwrap();
+
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), second.read(&mut buf))
+            .await
+            .expect("the second connection should have been closed");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), first.read(&mut buf))
+                .await
+                .is_err(),
+            "the first connection should still be open"
+        );
+    }
+
+    fn update_channel(channel_id: u32) -> UpdateChannel<'static> {
+        UpdateChannel {
+            channel_id,
+            nominal_hash_rate: 10_000.0,
+            maximum_target: [255; 32].into(),
+        }
+    }
+
+    #[test]
+    fn update_channel_without_a_channel_is_rejected() {
+        let (sender, receiver) = async_channel::bounded(10);
+        let mut downstream = DownstreamMiningNode::new(receiver, sender, 0);
+
+        match downstream.handle_update_channel(update_channel(3)).unwrap() {
+            SendTo::Respond(Mining::UpdateChannelError(m)) => {
+                assert_eq!(m.channel_id, 3);
+                assert_eq!(
+                    m.error_code.to_vec(),
+                    INVALID_CHANNEL_ID_ERROR_CODE.as_bytes()
+                );
+            }
+            _ => panic!("UpdateChannel should be rejected"),
+        }
+    }
+
+    #[test]
+    fn update_channel_without_an_upstream_is_an_error() {
+        let (sender, receiver) = async_channel::bounded(10);
+        let mut downstream = DownstreamMiningNode::new(receiver, sender, 0);
+        downstream.status =
+            DownstreamMiningNodeStatus::ChannelOpened(Channel::DowntreamHomUpstreamGroup {
+                data: CommonDownstreamData {
+                    header_only: true,
+                    work_selection: false,
+                    version_rolling: false,
+                },
+                channel_id: 3,
+                group_id: 1,
+            });
+
+        assert!(matches!(
+            downstream.handle_update_channel(update_channel(3)),
+            Err(Error::NoUpstreamsConnected)
+        ));
+    }
+}
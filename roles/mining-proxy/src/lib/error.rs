@@ -12,7 +12,11 @@ pub type EitherFrame = StandardEitherFrame<Message>;
 pub enum Error {
     SendError(SendError<EitherFrame>),
     UpstreamNotAvailabe(SocketAddr),
+    /// The upstream static key is not the `expected_static_key` of its config
+    StaticKeyMismatch(SocketAddr),
     SetupConnectionError(String),
+    /// The health check is enabled but the upstream at this address has a Group `channel_kind`
+    HealthCheckOnGroupUpstream(String),
 }
 
 impl From<SendError<EitherFrame>> for Error {
@@ -1,7 +1,9 @@
 pub mod downstream_mining;
 pub mod error;
+pub mod status;
 pub mod upstream_mining;
 
+use nohash_hasher::BuildNoHashHasher;
 use once_cell::sync::OnceCell;
 use roles_logic_sv2::{
     routing_logic::{CommonRoutingLogic, MiningProxyRoutingLogic, MiningRoutingLogic},
@@ -9,7 +11,8 @@ use roles_logic_sv2::{
     utils::{GroupId, Id, Mutex},
 };
 use serde::Deserialize;
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tracing::{error, warn};
 use upstream_mining::UpstreamMiningNode;
 
 type RLogic = MiningProxyRoutingLogic<
@@ -18,48 +21,205 @@ type RLogic = MiningProxyRoutingLogic<
     upstream_mining::ProxyRemoteSelector,
 >;
 
-/// Panic whene we are looking one of this 2 global mutex would force the proxy to go down as every
-/// part of the program depend on them.
-/// SAFTEY note: we use global mutable memory instead of a dedicated struct that use a dedicated
-/// task to change the mutable state and communicate with the other parts of the program via
-/// messages cause it is impossible for a task to panic while is using one of the two below Mutex.
-/// So it make sense to use shared mutable memory to lower the complexity of the codebase and to
-/// have some performance gain.
+/// Every part of the program depends on the routing logic, a poisoned lock would force the proxy to
+/// go down. The lock is taken with [`with_routing_logic`] that, if a task panicked while holding
+/// it, logs the panic and recovers the routing logic with [`recover_routing_logic`].
+/// We use global mutable memory instead of a dedicated struct that use a dedicated task to change
+/// the mutable state and communicate with the other parts of the program via messages, to lower
+/// the complexity of the codebase and to have some performance gain.
 pub static ROUTING_LOGIC: OnceCell<Mutex<RLogic>> = OnceCell::new();
 static MIN_EXTRANONCE_SIZE: u16 = 6;
 static EXTRANONCE_RANGE_1_LENGTH: usize = 4;
 
-pub async fn initialize_upstreams(min_version: u16, max_version: u16) {
-    let upstreams = ROUTING_LOGIC
+/// Runs `thunk` on the routing logic, recovering it when a task panicked while holding the lock
+fn with_routing_logic<F, Ret>(thunk: F) -> Ret
+where
+    F: FnOnce(&mut RLogic) -> Ret,
+{
+    ROUTING_LOGIC
         .get()
         .expect("BUG: ROUTING_LOGIC has not been set yet")
-        .safe_lock(|r_logic| r_logic.upstream_selector.upstreams.clone())
-        .unwrap();
+        .safe_lock_or_recover(recover_routing_logic, thunk)
+}
+
+/// Brings the routing logic back to a usable state after that a task panicked while holding it.
+/// Every change to the routing logic is a single assignment or insert, so what the task left is
+/// the last known good state. Only the upstreams poisoned by the panic are dropped.
+fn recover_routing_logic(r_logic: &mut RLogic) {
+    let is_healthy = |upstream: &Arc<Mutex<UpstreamMiningNode>>| upstream.safe_lock(|_| ()).is_ok();
+    let upstreams: Vec<_> = r_logic
+        .upstream_selector
+        .upstreams
+        .iter()
+        .filter(|upstream| is_healthy(upstream))
+        .cloned()
+        .collect();
+    let dropped = r_logic.upstream_selector.upstreams.len() - upstreams.len();
+    if dropped > 0 {
+        warn!(
+            "Dropped {} poisoned upstreams from the routing logic",
+            dropped
+        );
+    }
+    r_logic.upstream_selector.update_upstreams(upstreams);
+    r_logic.downstream_to_upstream_map.retain(|_, upstreams| {
+        upstreams.retain(is_healthy);
+        !upstreams.is_empty()
+    });
+}
+
+pub async fn initialize_upstreams(min_version: u16, max_version: u16) {
+    let upstreams = with_routing_logic(|r_logic| r_logic.upstream_selector.upstreams.clone());
     let available_upstreams = upstream_mining::scan(upstreams, min_version, max_version).await;
-    ROUTING_LOGIC
-        .get()
-        .unwrap()
-        .safe_lock(|rl| rl.upstream_selector.update_upstreams(available_upstreams))
-        .unwrap();
+    with_routing_logic(|rl| rl.upstream_selector.update_upstreams(available_upstreams));
 }
 
 fn remove_upstream(id: u32) {
-    let upstreams = ROUTING_LOGIC
-        .get()
-        .expect("BUG: ROUTING_LOGIC has not been set yet")
-        .safe_lock(|r_logic| r_logic.upstream_selector.upstreams.clone())
-        .unwrap();
-    let mut updated_upstreams = vec![];
-    for upstream in upstreams {
-        if upstream.safe_lock(|s| s.get_id()).unwrap() != id {
-            updated_upstreams.push(upstream)
+    let upstreams = with_routing_logic(|r_logic| r_logic.upstream_selector.upstreams.clone());
+    let updated_upstreams = upstreams_without(upstreams, id);
+    with_routing_logic(|rl| rl.upstream_selector.update_upstreams(updated_upstreams));
+}
+
+/// Make an upstream available again after that the proxy reconnected to it. Does nothing if the
+/// upstream is already available.
+fn add_upstream(upstream: Arc<Mutex<UpstreamMiningNode>>) {
+    let upstreams = with_routing_logic(|r_logic| r_logic.upstream_selector.upstreams.clone());
+    if let Some(updated_upstreams) = upstreams_with(upstreams, upstream) {
+        with_routing_logic(|rl| rl.upstream_selector.update_upstreams(updated_upstreams));
+    }
+}
+
+/// `upstreams` without the upstream `id`. An upstream poisoned by a panic is dropped too, as
+/// [`recover_routing_logic`] does.
+fn upstreams_without(
+    upstreams: Vec<Arc<Mutex<UpstreamMiningNode>>>,
+    id: u32,
+) -> Vec<Arc<Mutex<UpstreamMiningNode>>> {
+    upstreams
+        .into_iter()
+        .filter(|upstream| match upstream.safe_lock(|s| s.get_id()) {
+            Ok(upstream_id) => upstream_id != id,
+            Err(_) => {
+                warn!("Dropped a poisoned upstream from the routing logic");
+                false
+            }
+        })
+        .collect()
+}
+
+/// `upstreams` with `upstream` added, `None` if it is already there or if it is poisoned by a
+/// panic. The other poisoned upstreams are skipped when looking for `upstream`.
+fn upstreams_with(
+    mut upstreams: Vec<Arc<Mutex<UpstreamMiningNode>>>,
+    upstream: Arc<Mutex<UpstreamMiningNode>>,
+) -> Option<Vec<Arc<Mutex<UpstreamMiningNode>>>> {
+    let id = match upstream.safe_lock(|s| s.get_id()) {
+        Ok(id) => id,
+        Err(_) => {
+            warn!("Upstream poisoned by a panic, it is not made available again");
+            return None;
         }
+    };
+    if upstreams
+        .iter()
+        .any(|u| matches!(u.safe_lock(|s| s.get_id()), Ok(u_id) if u_id == id))
+    {
+        return None;
+    }
+    upstreams.push(upstream);
+    Some(upstreams)
+}
+
+/// Close every channel opened with the upstreams and wait, at most `timeout`, for the queued
+/// messages to be handed to the upstream connections. Called before the proxy exits.
+pub async fn shutdown(timeout: Duration) {
+    if ROUTING_LOGIC.get().is_none() {
+        return;
+    }
+    let upstreams = with_routing_logic(|r_logic| r_logic.upstream_selector.upstreams.clone());
+    shutdown_upstreams(upstreams, timeout).await
+}
+
+async fn shutdown_upstreams(upstreams: Vec<Arc<Mutex<UpstreamMiningNode>>>, timeout: Duration) {
+    for upstream in &upstreams {
+        if let Err(e) = UpstreamMiningNode::close_channels(upstream.clone()).await {
+            error!("Failed to close upstream channels: {:?}", e);
+        }
+    }
+    let pending_sends = futures::future::join_all(
+        upstreams
+            .into_iter()
+            .map(UpstreamMiningNode::wait_for_pending_sends),
+    );
+    if tokio::time::timeout(timeout, pending_sends).await.is_err() {
+        warn!("Shutdown timeout reached before all the upstream messages were sent");
+    }
+}
+
+/// Upstream that sent each job, for the downstreams that reference the upstream jobs by id. The
+/// jobs of an upstream are tagged with the epoch of the prev hash they are built on, that is
+/// the number of `SetNewPrevHash` received from that upstream, so that all the jobs made stale by
+/// a new prev hash are removed at once.
+// Not used by the proxy binary yet, the only upstream jobs referenced by id are the non HOM ones,
+// see `UpstreamMiningNode::handle_new_mining_job`
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct JobIdToUpstream {
+    jobs: HashMap<u32, UpstreamJob, BuildNoHashHasher<u32>>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct UpstreamJob {
+    upstream_id: u32,
+    prev_hash_epoch: u32,
+    upstream: Arc<Mutex<UpstreamMiningNode>>,
+}
+
+#[allow(dead_code)]
+impl JobIdToUpstream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `job_id` has been sent by the upstream `upstream_id` for the prev hash
+    /// `prev_hash_epoch`. A job id already used by another upstream is taken over by this one.
+    pub fn add_job_id(
+        &mut self,
+        job_id: u32,
+        upstream_id: u32,
+        prev_hash_epoch: u32,
+        upstream: Arc<Mutex<UpstreamMiningNode>>,
+    ) {
+        let job = UpstreamJob {
+            upstream_id,
+            prev_hash_epoch,
+            upstream,
+        };
+        if let Some(old) = self.jobs.insert(job_id, job) {
+            if old.upstream_id != upstream_id {
+                warn!(
+                    "Job id {} of upstream {} is reused by upstream {}",
+                    job_id, old.upstream_id, upstream_id
+                );
+            }
+        }
+    }
+
+    /// Returns the upstream that sent `job_id`, `None` if the job is unknown or stale
+    pub fn upstream_from_job_id(&self, job_id: u32) -> Option<Arc<Mutex<UpstreamMiningNode>>> {
+        self.jobs.get(&job_id).map(|job| job.upstream.clone())
+    }
+
+    /// Removes the jobs of the upstream `upstream_id` built on a prev hash older than
+    /// `prev_hash_epoch`, returns how many jobs have been removed
+    pub fn remove_stale_job_ids(&mut self, upstream_id: u32, prev_hash_epoch: u32) -> usize {
+        let before = self.jobs.len();
+        self.jobs.retain(|_, job| {
+            job.upstream_id != upstream_id || job.prev_hash_epoch >= prev_hash_epoch
+        });
+        before - self.jobs.len()
     }
-    ROUTING_LOGIC
-        .get()
-        .unwrap()
-        .safe_lock(|rl| rl.upstream_selector.update_upstreams(updated_upstreams))
-        .unwrap();
 }
 
 pub fn get_routing_logic() -> MiningRoutingLogic<
@@ -88,6 +248,9 @@ pub struct UpstreamMiningValues {
     port: u16,
     pub_key: key_utils::Secp256k1PublicKey,
     channel_kind: ChannelKind,
+    /// Static key the upstream must present in the noise handshake, on top of having it signed
+    /// by `pub_key`. Any static key signed by `pub_key` is accepted when not set.
+    expected_static_key: Option<key_utils::Secp256k1PublicKey>,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -106,13 +269,76 @@ pub struct Config {
     downstream_share_per_minute: f32,
     expected_total_downstream_hr: f32,
     reconnect: bool,
+    /// Delay before the first reconnection attempt to an upstream, in milliseconds. It doubles
+    /// after each failed attempt.
+    reconnect_backoff_base_ms: Option<u64>,
+    /// Maximum delay between two reconnection attempts, in milliseconds
+    reconnect_backoff_cap_ms: Option<u64>,
+    /// How long a connection must stay up for the reconnection delay to go back to the base
+    /// delay when it drops, in milliseconds
+    reconnect_min_uptime_ms: Option<u64>,
+    /// How often an upstream is probed to check that it is alive, in milliseconds. The health
+    /// check is disabled when not set. Only the extended channel opened by the proxy can be
+    /// probed, so the config is rejected if it is set and an upstream has `channel_kind = "Group"`.
+    health_check_interval_ms: Option<u64>,
+    /// How long an upstream has to answer a probe, in milliseconds. Defaults to the interval.
+    health_check_deadline_ms: Option<u64>,
+    /// Maximum number of downstreams connected at the same time, further connections are closed
+    /// as soon as they are accepted. Unlimited when not set.
+    pub max_downstream_connections: Option<usize>,
 }
+impl Config {
+    /// Checks the settings that can not be checked while deserializing the config
+    pub fn validate(&self) -> Result<(), error::Error> {
+        if self.health_check_interval_ms.is_some() {
+            if let Some(upstream) = self
+                .upstreams
+                .iter()
+                .find(|u| matches!(u.channel_kind, ChannelKind::Group))
+            {
+                return Err(error::Error::HealthCheckOnGroupUpstream(format!(
+                    "{}:{}",
+                    upstream.address, upstream.port
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
 pub async fn initialize_r_logic(
     upstreams: &[UpstreamMiningValues],
     group_id: Arc<Mutex<GroupId>>,
     config: Config,
+    status_sender: status::Sender,
 ) -> RLogic {
     let channel_ids = Arc::new(Mutex::new(Id::new()));
+    let reconnect_backoff = upstream_mining::ReconnectBackoff::new(
+        config
+            .reconnect_backoff_base_ms
+            .map(Duration::from_millis)
+            .unwrap_or(upstream_mining::ReconnectBackoff::DEFAULT_BASE),
+        config
+            .reconnect_backoff_cap_ms
+            .map(Duration::from_millis)
+            .unwrap_or(upstream_mining::ReconnectBackoff::DEFAULT_CAP),
+    )
+    .with_min_uptime(
+        config
+            .reconnect_min_uptime_ms
+            .map(Duration::from_millis)
+            .unwrap_or(upstream_mining::ReconnectBackoff::DEFAULT_MIN_UPTIME),
+    );
+    let health_check = config.health_check_interval_ms.map(|interval| {
+        let interval = Duration::from_millis(interval);
+        upstream_mining::HealthCheck {
+            interval,
+            deadline: config
+                .health_check_deadline_ms
+                .map(Duration::from_millis)
+                .unwrap_or(interval),
+        }
+    });
     let mut upstream_mining_nodes = Vec::with_capacity(upstreams.len());
     for (index, upstream_) in upstreams.iter().enumerate() {
         let socket = SocketAddr::new(upstream_.address.parse().unwrap(), upstream_.port);
@@ -129,7 +355,17 @@ pub async fn initialize_r_logic(
             None,
             config.expected_total_downstream_hr,
             config.reconnect,
+            reconnect_backoff,
+            health_check,
         )));
+        upstream
+            .safe_lock(|u| {
+                u.set_status_sender(status_sender.clone());
+                if let Some(key) = upstream_.expected_static_key {
+                    u.set_expected_static_key(key);
+                }
+            })
+            .unwrap();
 
         match upstream_.channel_kind {
             ChannelKind::Group => (),
@@ -145,3 +381,141 @@ pub async fn initialize_r_logic(
         downstream_to_upstream_map: std::collections::HashMap::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn new_upstream(id: u32) -> Arc<Mutex<UpstreamMiningNode>> {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        Arc::new(Mutex::new(UpstreamMiningNode::new(
+            id,
+            address,
+            [0; 32],
+            ChannelKind::Group,
+            Arc::new(Mutex::new(GroupId::new())),
+            Arc::new(Mutex::new(Id::new())),
+            10.0,
+            None,
+            None,
+            100_000.0,
+            false,
+            upstream_mining::ReconnectBackoff::default(),
+            None,
+        )))
+    }
+
+    #[test]
+    fn health_check_is_rejected_for_group_upstreams() {
+        let example = include_str!("../../config-examples/proxy-config-example.toml");
+        let config: Config = toml::from_str(example).unwrap();
+        assert!(config.validate().is_ok());
+
+        let with_health_check = example.replace(
+            "# health_check_interval_ms = 30000",
+            "health_check_interval_ms = 30000",
+        );
+        let config: Config = toml::from_str(&with_health_check).unwrap();
+        assert!(config.validate().is_ok());
+
+        let group =
+            with_health_check.replace("channel_kind = \"Extended\"", "channel_kind = \"Group\"");
+        let config: Config = toml::from_str(&group).unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(error::Error::HealthCheckOnGroupUpstream(address)) if address == "0.0.0.0:34265"
+        ));
+    }
+    #[test]
+    fn job_ids_map_to_their_upstream() {
+        let upstream_0 = new_upstream(0);
+        let upstream_1 = new_upstream(1);
+        let mut jobs = JobIdToUpstream::new();
+        jobs.add_job_id(1, 0, 0, upstream_0.clone());
+        jobs.add_job_id(2, 0, 0, upstream_0.clone());
+        jobs.add_job_id(3, 1, 0, upstream_1.clone());
+
+        assert!(Arc::ptr_eq(
+            &jobs.upstream_from_job_id(2).unwrap(),
+            &upstream_0
+        ));
+        assert!(Arc::ptr_eq(
+            &jobs.upstream_from_job_id(3).unwrap(),
+            &upstream_1
+        ));
+        assert!(jobs.upstream_from_job_id(4).is_none());
+    }
+
+    #[test]
+    fn stale_job_ids_are_removed_per_upstream() {
+        let upstream_0 = new_upstream(0);
+        let upstream_1 = new_upstream(1);
+        let mut jobs = JobIdToUpstream::new();
+        jobs.add_job_id(1, 0, 0, upstream_0.clone());
+        jobs.add_job_id(2, 0, 0, upstream_0.clone());
+        jobs.add_job_id(3, 0, 1, upstream_0.clone());
+        jobs.add_job_id(4, 1, 0, upstream_1.clone());
+
+        // upstream 0 sent a new prev hash, its jobs of epoch 0 are stale
+        assert_eq!(jobs.remove_stale_job_ids(0, 1), 2);
+        assert!(jobs.upstream_from_job_id(1).is_none());
+        assert!(jobs.upstream_from_job_id(2).is_none());
+        assert!(jobs.upstream_from_job_id(3).is_some());
+        // the jobs of the other upstreams are untouched
+        assert!(jobs.upstream_from_job_id(4).is_some());
+        assert_eq!(jobs.remove_stale_job_ids(0, 1), 0);
+    }
+
+    #[test]
+    fn poisoned_routing_logic_is_recovered() {
+        let healthy = new_upstream(0);
+        let poisoned = new_upstream(1);
+        let r_logic = Arc::new(Mutex::new(MiningProxyRoutingLogic {
+            upstream_selector: GeneralMiningSelector::new(vec![healthy.clone(), poisoned.clone()]),
+            downstream_id_generator: Id::new(),
+            downstream_to_upstream_map: std::collections::HashMap::new(),
+        }));
+
+        // A task panics while holding both the routing logic and an upstream
+        let r_logic_ = r_logic.clone();
+        std::thread::spawn(move || {
+            r_logic_
+                .safe_lock(|_| poisoned.safe_lock(|_| panic!("poison the locks")))
+                .unwrap()
+                .unwrap();
+        })
+        .join()
+        .unwrap_err();
+        assert!(r_logic.safe_lock(|_| ()).is_err());
+
+        let r_logic_ = r_logic.clone();
+        let upstream_ids = std::thread::spawn(move || {
+            r_logic_.safe_lock_or_recover(recover_routing_logic, |r_logic| {
+                r_logic
+                    .upstream_selector
+                    .upstreams
+                    .iter()
+                    .filter_map(|u| u.safe_lock(|u| u.get_id()).ok())
+                    .collect::<Vec<_>>()
+            })
+        })
+        .join()
+        .unwrap();
+        assert_eq!(upstream_ids, vec![0]);
+        // The routing logic handlers can use it again
+        assert!(r_logic.safe_lock(|_| ()).is_ok());
+
+        // Removing and adding upstreams go on with the poisoned upstream still around
+        let upstreams = vec![healthy.clone(), poisoned.clone(), new_upstream(2)];
+        let updated = upstreams_without(upstreams, 2);
+        assert_eq!(updated.len(), 1);
+        assert!(Arc::ptr_eq(&updated[0], &healthy));
+        let upstreams = vec![healthy.clone(), poisoned.clone()];
+        let updated = upstreams_with(upstreams.clone(), new_upstream(2)).unwrap();
+        assert_eq!(updated.len(), 3);
+        assert!(upstreams_with(upstreams, healthy.clone()).is_none());
+        // A poisoned upstream is not made available again
+        assert!(upstreams_with(vec![healthy], poisoned).is_none());
+    }
+}
@@ -0,0 +1,22 @@
+//! Status channel of the proxy. The upstream nodes report their connection events on it and the
+//! main loop of the proxy receives them.
+use std::{net::SocketAddr, time::Duration};
+
+pub type Sender = async_channel::Sender<Status>;
+
+/// Connection event of the upstream `upstream_id`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    /// The upstream is not connected, the proxy tries to connect again after `delay`
+    Reconnecting {
+        upstream_id: u32,
+        address: SocketAddr,
+        delay: Duration,
+    },
+    /// The connection with the upstream is set up again and the upstream is available to the
+    /// downstreams
+    Reconnected {
+        upstream_id: u32,
+        address: SocketAddr,
+    },
+}
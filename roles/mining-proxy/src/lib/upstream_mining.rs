@@ -3,18 +3,21 @@
 use super::EXTRANONCE_RANGE_1_LENGTH;
 use roles_logic_sv2::utils::Id;
 
-use super::downstream_mining::{Channel, DownstreamMiningNode, StdFrame as DownstreamFrame};
+use super::downstream_mining::{
+    Channel, DownstreamMiningNode, DownstreamMiningNodeStatus, StdFrame as DownstreamFrame,
+};
 use async_channel::{Receiver, SendError, Sender};
 use async_recursion::async_recursion;
 use codec_sv2::{Frame, HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::noise_connection_tokio::{Connection, ConnectionParts};
+pub use network_helpers_sv2::reconnect_backoff::ReconnectBackoff;
 use nohash_hasher::BuildNoHashHasher;
 use roles_logic_sv2::{
     channel_logic::{
         channel_factory::{ExtendedChannelKind, OnNewShare, ProxyExtendedChannelFactory, Share},
         proxy_group_channel::GroupChannels,
     },
-    common_messages_sv2::{Protocol, SetupConnection},
+    common_messages_sv2::{describe_unsupported_flags, MiningFlags, Protocol, ProtocolVersion},
     common_properties::{
         IsMiningDownstream, IsMiningUpstream, IsUpstream, RequestIdMapper, UpstreamChannel,
     },
@@ -26,11 +29,15 @@ use roles_logic_sv2::{
     routing_logic::MiningProxyRoutingLogic,
     selectors::{DownstreamMiningSelector, ProxyDownstreamMiningSelector as Prs},
     template_distribution_sv2::SubmitSolution,
-    utils::{GroupId, Mutex},
+    utils::{negotiate_setup_connection, GroupId, Mutex, SetupConnectionBuilder, SetupError},
+};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    convert::TryFrom,
+    sync::Arc,
 };
-use std::{collections::HashMap, sync::Arc};
 use tokio::{net::TcpStream, task};
-use tracing::error;
+use tracing::{error, warn};
 
 use stratum_common::bitcoin::TxOut;
 
@@ -125,7 +132,6 @@ struct UpstreamMiningConnection {
 
 impl UpstreamMiningConnection {
     async fn send(&mut self, sv2_frame: StdFrame) -> Result<(), SendError<EitherFrame>> {
-        info!("SEND");
         let either_frame = sv2_frame.into();
         match self.sender.send(either_frame).await {
             Ok(_) => Ok(()),
@@ -134,22 +140,51 @@ impl UpstreamMiningConnection {
     }
 }
 
+/// Periodic liveness probe of an upstream. Every `interval` the proxy sends a message that the
+/// upstream must answer, if the answer is not received within `deadline` the upstream is
+/// considered unhealthy and the connection is dropped.
+#[derive(Clone, Copy, Debug)]
+pub struct HealthCheck {
+    pub interval: Duration,
+    pub deadline: Duration,
+}
+
+/// Meters the shares that the upstream accepted or rejected, e.g. to bill or monitor each
+/// downstream. See [`UpstreamMiningNode::set_share_accountant`].
+pub trait ShareAccountant: std::fmt::Debug + Send {
+    /// Called once for every `SubmitShares.Success` on `channel_id`, `count` is the number of
+    /// shares accepted and `difficulty` is the difficulty of the channel's current target (0 if the
+    /// proxy does not know the target of the channel)
+    fn on_accepted(&mut self, channel_id: u32, count: u32, difficulty: f64);
+    /// Called for every share rejected on `channel_id`, `reason` is the error code sent by the
+    /// upstream
+    fn on_rejected(&mut self, channel_id: u32, reason: &str);
+}
+
+/// Default [`ShareAccountant`], ignores every share
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullAccountant;
+
+impl ShareAccountant for NullAccountant {
+    fn on_accepted(&mut self, _channel_id: u32, _count: u32, _difficulty: f64) {}
+    fn on_rejected(&mut self, _channel_id: u32, _reason: &str) {}
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Sv2MiningConnection {
-    version: u16,
-    setup_connection_flags: u32,
-    #[allow(dead_code)]
-    setup_connection_success_flags: u32,
+    version: ProtocolVersion,
+    setup_connection_flags: MiningFlags,
 }
 
-// Efficient stack do use JobDispatcher so the smaller variant (None) do not impact performance
+// Efficient stack do use JobDispatcher so the smaller variant (Extended) do not impact performance
 // cause is used in already non performant environments. That to justify the below allow.
 // https://rust-lang.github.io/rust-clippy/master/index.html#large_enum_varianT
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum JobDispatcher {
     Group(GroupChannelJobDispatcher),
-    None,
+    /// Extended channel opened by a downstream, its jobs are relayed as they are
+    Extended,
 }
 
 /// Can be either a mining pool or another proxy
@@ -161,6 +196,8 @@ pub struct UpstreamMiningNode {
     connection: Option<UpstreamMiningConnection>,
     sv2_connection: Option<Sv2MiningConnection>,
     authority_public_key: [u8; 32],
+    /// When set the noise handshake fails unless the upstream static key is this one
+    expected_static_key: Option<key_utils::Secp256k1PublicKey>,
     /// group_channel id/channel_id -> dispatcher
     pub channel_id_to_job_dispatcher: HashMap<u32, JobDispatcher, BuildNoHashHasher<u32>>,
     /// Each relayed message that has a `request_id` field must have a unique `request_id` number,
@@ -186,11 +223,55 @@ pub struct UpstreamMiningNode {
         HashMap<u32, Vec<(Arc<Mutex<DownstreamMiningNode>>, u32)>, BuildNoHashHasher<u32>>,
     downstream_hash_rate: f32,
     reconnect: bool,
+    reconnect_backoff: ReconnectBackoff,
+    /// When the connection with the upstream has been set up
+    connected_at: Instant,
+    /// Where the reconnection attempts are reported, see [`super::status::Status`]
+    status_sender: Option<super::status::Sender>,
+    /// Channels opened with the upstream, see `on_channel_opened`
+    opened_channels: Vec<UpstreamChannel>,
+    /// Id of each channel in `opened_channels`, in the same order, and the nominal hash rate that
+    /// the channel adds to `total_hash_rate`
+    opened_channel_ids: Vec<(u32, u64)>,
+    /// upstream request id -> nominal hash rate of the standard channel requested by a downstream
+    requested_hash_rates: HashMap<u32, f32, BuildNoHashHasher<u32>>,
+    jobs_relayed: u64,
+    /// `None` when the health check is disabled
+    health_check: Option<HealthCheck>,
+    /// Channel id and reception time of the last `SetTarget` for the extended channel opened by
+    /// the proxy, the answer to the health probe
+    last_probe_answer: Option<(u32, Instant)>,
+    share_accountant: Box<dyn ShareAccountant>,
+    /// channel id -> current target of the channel
+    channel_targets: HashMap<u32, roles_logic_sv2::utils::Target, BuildNoHashHasher<u32>>,
+    /// channel id -> last `UpdateChannel` relayed for the channel, see
+    /// `handle_update_channel_error`
+    update_channel_requests: HashMap<u32, UpdateChannel<'static>, BuildNoHashHasher<u32>>,
+}
+
+/// `UpdateChannelError` error codes that can be recovered retrying the `UpdateChannel` with a
+/// smaller maximum target, every other code (e.g. `invalid-channel-id`) is fatal for the request.
+fn is_recoverable_update_channel_error(error_code: &str) -> bool {
+    matches!(error_code, "max-target-out-of-range")
+}
+
+/// Snapshot of what an upstream is carrying, returned by [`UpstreamMiningNode::stats`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Sum of the nominal hash rate of the channels opened with the upstream
+    pub total_hash_rate: u64,
+    pub open_channels: usize,
+    pub downstream_count: usize,
+    /// Jobs received from the upstream and sent to downstreams
+    pub jobs_relayed: u64,
 }
 
 use core::convert::TryInto;
-use std::{net::SocketAddr, time::Duration};
-use tracing::{debug, info};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, Instrument};
 
 /// It assume that endpoint NEVER change flags and version!
 /// I can open both extended and group channel with upstream.
@@ -208,9 +289,23 @@ impl UpstreamMiningNode {
         recv_coinbase_out: Option<Receiver<(Vec<TxOut>, Vec<u8>)>>,
         downstream_hash_rate: f32,
         reconnect: bool,
+        reconnect_backoff: ReconnectBackoff,
+        health_check: Option<HealthCheck>,
     ) -> Self {
         let request_id_mapper = RequestIdMapper::new();
         let downstream_selector = ProxyRemoteSelector::new();
+        // A group upstream only carries the downstreams' channels, probing one of them would
+        // change a downstream channel, see `health_probe`
+        let health_check = match channel_kind {
+            super::ChannelKind::Group if health_check.is_some() => {
+                warn!(
+                    upstream_id = id,
+                    "Health check disabled, it is only supported for extended upstreams"
+                );
+                None
+            }
+            _ => health_check,
+        };
         Self {
             id,
             total_hash_rate: 0,
@@ -218,6 +313,7 @@ impl UpstreamMiningNode {
             connection: None,
             sv2_connection: None,
             authority_public_key,
+            expected_static_key: None,
             channel_id_to_job_dispatcher: HashMap::with_hasher(BuildNoHashHasher::default()),
             request_id_mapper,
             downstream_selector,
@@ -231,8 +327,111 @@ impl UpstreamMiningNode {
             job_up_to_down_ids: HashMap::with_hasher(BuildNoHashHasher::default()),
             downstream_hash_rate,
             reconnect,
+            reconnect_backoff,
+            connected_at: Instant::now(),
+            status_sender: None,
+            opened_channels: vec![],
+            opened_channel_ids: vec![],
+            requested_hash_rates: HashMap::with_hasher(BuildNoHashHasher::default()),
+            jobs_relayed: 0,
+            health_check,
+            last_probe_answer: None,
+            share_accountant: Box::new(NullAccountant),
+            channel_targets: HashMap::with_hasher(BuildNoHashHasher::default()),
+            update_channel_requests: HashMap::with_hasher(BuildNoHashHasher::default()),
+        }
+    }
+
+    /// Replace the [`ShareAccountant`] notified about the shares accepted or rejected upstream,
+    /// by default shares are not accounted
+    pub fn set_share_accountant(&mut self, share_accountant: Box<dyn ShareAccountant>) {
+        self.share_accountant = share_accountant;
+    }
+
+    /// Report the reconnection attempts to this upstream on the status channel of the proxy
+    pub fn set_status_sender(&mut self, status_sender: super::status::Sender) {
+        self.status_sender = Some(status_sender);
+    }
+
+    /// Send `status` on the status channel of the proxy, if there is one. A closed channel means
+    /// that the proxy is shutting down, so the error is ignored.
+    async fn send_status(self_mutex: &Arc<Mutex<Self>>, status: super::status::Status) {
+        let status_sender = self_mutex
+            .safe_lock(|self_| self_.status_sender.clone())
+            .unwrap();
+        if let Some(status_sender) = status_sender {
+            status_sender.send(status).await.unwrap_or(());
+        }
+    }
+
+    /// Pin the upstream static key: the connection is refused when the upstream presents another
+    /// key, even if its certificate is signed by the authority key
+    pub fn set_expected_static_key(&mut self, key: key_utils::Secp256k1PublicKey) {
+        self.expected_static_key = Some(key);
+    }
+
+    fn set_channel_target(&mut self, channel_id: u32, target: &binary_sv2::U256) {
+        self.channel_targets
+            .insert(channel_id, target.clone().into());
+    }
+
+    /// Called when an `UpdateChannel` from a downstream is relayed to the upstream, so that it can
+    /// be retried if the upstream rejects it with a recoverable error
+    pub fn on_update_channel_request(&mut self, m: UpdateChannel<'static>) {
+        self.update_channel_requests.insert(m.channel_id, m);
+    }
+
+    /// Group channels are not counted in `Stats::open_channels`, only the channels in them
+    pub fn stats(&self) -> Stats {
+        let open_channels = self
+            .opened_channels
+            .iter()
+            .filter(|channel| !matches!(channel, UpstreamChannel::Group))
+            .count();
+        Stats {
+            total_hash_rate: self.total_hash_rate,
+            open_channels,
+            downstream_count: self.downstream_selector.get_all_downstreams().len(),
+            jobs_relayed: self.jobs_relayed,
+        }
+    }
+
+    /// Record a channel opened with the upstream, `hash_rate` is the nominal hash rate that it
+    /// carries
+    fn on_channel_opened(&mut self, channel_id: u32, channel: UpstreamChannel, hash_rate: u64) {
+        self.opened_channels.push(channel);
+        self.opened_channel_ids.push((channel_id, hash_rate));
+        self.add_hash_rate(hash_rate);
+    }
+
+    /// Forget a channel closed by the upstream and the hash rate that it carried
+    fn on_channel_closed(&mut self, channel_id: u32) {
+        if let Some(index) = self
+            .opened_channel_ids
+            .iter()
+            .position(|(id, _)| *id == channel_id)
+        {
+            let (_, hash_rate) = self.opened_channel_ids.remove(index);
+            self.opened_channels.remove(index);
+            self.total_hash_rate = self.total_hash_rate.saturating_sub(hash_rate);
         }
     }
+
+    /// Forget every channel, called when the connection with the upstream is lost: the channels
+    /// are opened again after the reconnection
+    fn reset_channels(&mut self) {
+        self.opened_channels.clear();
+        self.opened_channel_ids.clear();
+        self.requested_hash_rates.clear();
+        self.total_hash_rate = 0;
+    }
+
+    /// Called when a standard channel requested by a downstream is relayed to the upstream, the
+    /// nominal hash rate is accounted when the upstream opens the channel.
+    pub fn on_standard_channel_request(&mut self, request_id: u32, nominal_hash_rate: f32) {
+        self.requested_hash_rates
+            .insert(request_id, nominal_hash_rate);
+    }
     fn on_p_hash(
         &mut self,
         mut m: SetNewPrevHash<'static>,
@@ -267,8 +466,8 @@ impl UpstreamMiningNode {
 
     /// Try send a message to the upstream node.
     /// If the node is connected and there are no error return Ok(())
-    /// If the node is connected and there is an error the message is not sent and an error is
-    ///     returned and the upstream is marked as not connected.
+    /// If the node is connected and there is an error the connection is dropped and the node
+    ///     reconnects, waiting for the delay of its `ReconnectBackoff` first.
     /// If the node is not connected it try to connect and send the message and everything is ok
     ///     the upstream is marked as connected and Ok(()) is returned if not an error is returned.
     pub async fn send(
@@ -294,12 +493,20 @@ impl UpstreamMiningNode {
                         "Error sending message to upstream node. Trying to reconnect to {}: {}",
                         address, e
                     );
-                    Self::connect(self_mutex.clone()).await.unwrap();
+                    // Drop the dead connection so that `connect` opens a new one after the
+                    // backoff delay
+                    self_mutex
+                        .safe_lock(|self_| {
+                            self_.connection = None;
+                            let uptime = self_.connected_at.elapsed();
+                            self_.reconnect_backoff.on_disconnect(uptime);
+                        })
+                        .unwrap();
+                    Self::connect(self_mutex.clone()).await?;
                     // It assume that enpoint NEVER change flags and version!
-                    match Self::setup_connection(self_mutex).await {
-                        Ok(()) => Ok(()),
-                        Err(()) => panic!(),
-                    }
+                    Self::setup_connection(self_mutex)
+                        .await
+                        .map_err(|()| super::error::Error::UpstreamNotAvailabe(address))
                 }
             },
             // It assume that no downstream try to send messages before that the upstream is
@@ -316,10 +523,9 @@ impl UpstreamMiningNode {
                     .safe_lock(|self_| self_.connection.clone())
                     .unwrap();
                 match connection.as_mut().unwrap().send(sv2_frame).await {
-                    Ok(_) => match Self::setup_connection(self_mutex).await {
-                        Ok(()) => Ok(()),
-                        Err(()) => panic!(),
-                    },
+                    Ok(_) => Self::setup_connection(self_mutex)
+                        .await
+                        .map_err(|()| super::error::Error::UpstreamNotAvailabe(address)),
                     Err(e) => {
                         error!(
                             "Error sending message to upstream node at {} with error {}",
@@ -353,6 +559,8 @@ impl UpstreamMiningNode {
         }
     }
 
+    /// Connect to the upstream and do the noise handshake. If the previous attempts failed it
+    /// first waits for the delay given by the node's `ReconnectBackoff`.
     async fn connect(self_mutex: Arc<Mutex<Self>>) -> Result<(), super::error::Error> {
         let has_connection = self_mutex
             .safe_lock(|self_| self_.connection.is_some())
@@ -360,35 +568,138 @@ impl UpstreamMiningNode {
         match has_connection {
             true => Ok(()),
             false => {
-                let (address, authority_public_key) = self_mutex
-                    .safe_lock(|self_| (self_.address, self_.authority_public_key))
-                    .unwrap();
-                let socket = TcpStream::connect(address).await.map_err(|_| {
-                    error!("Upstream node {} is not available", address);
-                    super::error::Error::UpstreamNotAvailabe(address)
-                })?;
-                info!(
-                    "Connected to upstream node {}: now handling noise handshake",
-                    address
-                );
-
-                let initiator = Initiator::from_raw_k(authority_public_key).unwrap();
-                let (receiver, sender, _, _) =
-                    Connection::new(socket, HandshakeRole::Initiator(initiator))
-                        .await
-                        .expect("impossible to conenct");
-                let connection = UpstreamMiningConnection { receiver, sender };
+                let (upstream_id, address, authority_public_key, expected_static_key, delay) =
+                    self_mutex
+                        .safe_lock(|self_| {
+                            (
+                                self_.id,
+                                self_.address,
+                                self_.authority_public_key,
+                                self_.expected_static_key,
+                                self_.reconnect_backoff.delay(),
+                            )
+                        })
+                        .unwrap();
+                if !delay.is_zero() {
+                    let status = super::status::Status::Reconnecting {
+                        upstream_id,
+                        address,
+                        delay,
+                    };
+                    Self::send_status(&self_mutex, status).await;
+                    tokio::time::sleep(delay).await;
+                }
+                let connection =
+                    Self::connect_(address, authority_public_key, expected_static_key).await?;
                 self_mutex
-                    .safe_lock(|self_| {
-                        self_.connection = Some(connection);
-                    })
+                    .safe_lock(|self_| self_.connection = Some(connection))
                     .unwrap();
-                info!("handshare done");
+                info!(%address, "Noise handshake with upstream node done");
                 Ok(())
             }
         }
     }
 
+    async fn connect_(
+        address: SocketAddr,
+        authority_public_key: [u8; 32],
+        expected_static_key: Option<key_utils::Secp256k1PublicKey>,
+    ) -> Result<UpstreamMiningConnection, super::error::Error> {
+        let socket = TcpStream::connect(address).await.map_err(|_| {
+            error!("Upstream node {} is not available", address);
+            super::error::Error::UpstreamNotAvailabe(address)
+        })?;
+        info!(
+            "Connected to upstream node {}: now handling noise handshake",
+            address
+        );
+
+        let mut initiator = Initiator::from_raw_k(authority_public_key).unwrap();
+        if let Some(key) = expected_static_key {
+            initiator = initiator.with_expected_static_key(key.0);
+        }
+        let ConnectionParts {
+            receiver, sender, ..
+        } = Connection::new(socket, HandshakeRole::Initiator(initiator))
+            .await
+            .map_err(|e| match e {
+                network_helpers_sv2::Error::CodecError(codec_sv2::Error::NoiseSv2Error(
+                    codec_sv2::noise_sv2::Error::StaticKeyMismatch,
+                )) => {
+                    error!(
+                        "Upstream node {} static key is not the one pinned in the config",
+                        address
+                    );
+                    super::error::Error::StaticKeyMismatch(address)
+                }
+                e => {
+                    error!(
+                        "Noise handshake with upstream node {} failed: {:?}",
+                        address, e
+                    );
+                    super::error::Error::UpstreamNotAvailabe(address)
+                }
+            })?;
+        Ok(UpstreamMiningConnection { receiver, sender })
+    }
+
+    /// Connect to the upstream and set up the connection. Failed attempts increase the delay
+    /// that `connect` waits before the next attempt. The delay is reset when a connection that
+    /// stayed up long enough drops, see [`ReconnectBackoff::on_disconnect`].
+    async fn try_setup(
+        self_mutex: Arc<Mutex<Self>>,
+        flags: Option<u32>,
+        min_version: u16,
+        max_version: u16,
+    ) -> Result<(), super::error::Error> {
+        let result =
+            Self::setup_flag_and_version(self_mutex.clone(), flags, min_version, max_version).await;
+        self_mutex
+            .safe_lock(|self_| match result {
+                Ok(()) => self_.connected_at = Instant::now(),
+                Err(_) => {
+                    self_.connection = None;
+                    self_.reconnect_backoff.on_failure();
+                }
+            })
+            .unwrap();
+        result
+    }
+
+    /// Keep trying to set up the connection with the upstream. When it succeeds the upstream is
+    /// made available to the downstreams again.
+    fn reconnect_in_background(
+        self_mutex: Arc<Mutex<Self>>,
+        flags: Option<u32>,
+        min_version: u16,
+        max_version: u16,
+    ) {
+        task::spawn(async move {
+            loop {
+                match Self::try_setup(self_mutex.clone(), flags, min_version, max_version).await {
+                    Ok(()) => {
+                        let (upstream_id, address) = self_mutex
+                            .safe_lock(|self_| (self_.id, self_.address))
+                            .unwrap();
+                        let status = super::status::Status::Reconnected {
+                            upstream_id,
+                            address,
+                        };
+                        Self::send_status(&self_mutex, status).await;
+                        super::add_upstream(self_mutex);
+                        break;
+                    }
+                    // The upstream do not support the proxy, retrying is useless
+                    Err(super::error::Error::SetupConnectionError(e)) => {
+                        error!("Impossible to set up the connection with upstream: {}", e);
+                        break;
+                    }
+                    Err(e) => error!("Upstream still not available: {:?}", e),
+                }
+            }
+        });
+    }
+
     #[async_recursion]
     async fn setup_connection(self_mutex: Arc<Mutex<Self>>) -> Result<(), ()> {
         let sv2_connection = self_mutex.safe_lock(|self_| self_.sv2_connection).unwrap();
@@ -396,8 +707,8 @@ impl UpstreamMiningNode {
         match sv2_connection {
             None => Ok(()),
             Some(sv2_connection) => {
-                let flags = sv2_connection.setup_connection_flags;
-                let version = sv2_connection.version;
+                let flags = sv2_connection.setup_connection_flags.bits();
+                let version = sv2_connection.version.into();
                 let frame = self_mutex
                     .safe_lock(|self_| self_.new_setup_connection_frame(flags, version, version))
                     .unwrap();
@@ -416,7 +727,10 @@ impl UpstreamMiningNode {
                 match (message_type, payload).try_into() {
                     Ok(CommonMessages::SetupConnectionSuccess(_)) => {
                         let receiver = self_mutex
-                            .safe_lock(|self_| self_.connection.clone().unwrap().receiver)
+                            .safe_lock(|self_| {
+                                self_.connected_at = Instant::now();
+                                self_.connection.clone().unwrap().receiver
+                            })
                             .unwrap();
                         Self::relay_incoming_messages(self_mutex, receiver);
                         Ok(())
@@ -431,20 +745,102 @@ impl UpstreamMiningNode {
         self_: Arc<Mutex<Self>>,
         //_downstreams: HashMap<u32, Downstream>,
         receiver: Receiver<EitherFrame>,
+    ) {
+        if let Some(health_check) = self_.safe_lock(|s| s.health_check).unwrap() {
+            Self::check_health(self_.clone(), receiver.clone(), health_check);
+        }
+        let span = self_
+            .safe_lock(|s| info_span!("upstream", upstream_id = s.id, address = %s.address))
+            .unwrap();
+        task::spawn(
+            async move {
+                loop {
+                    if let Ok(message) = receiver.recv().await {
+                        let m: StdFrame = message.try_into().unwrap();
+                        let incoming: StdFrame = m;
+                        Self::next_relay_only(self_.clone(), incoming).await;
+                    } else {
+                        info!("Upstream connection closed");
+                        Self::exit(self_);
+                        break;
+                    }
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Probe the upstream until `receiver` is closed. When the upstream does not answer a probe
+    /// in time the connection is dropped and `receiver` closed, so that `relay_incoming_messages`
+    /// exits and the upstream is treated as any other upstream that dropped the connection.
+    fn check_health(
+        self_mutex: Arc<Mutex<Self>>,
+        receiver: Receiver<EitherFrame>,
+        health_check: HealthCheck,
     ) {
         task::spawn(async move {
             loop {
-                if let Ok(message) = receiver.recv().await {
-                    let m: StdFrame = message.try_into().unwrap();
-                    let incoming: StdFrame = m;
-                    Self::next(self_.clone(), incoming).await;
-                } else {
-                    Self::exit(self_);
+                tokio::time::sleep(health_check.interval).await;
+                if receiver.is_closed() {
+                    break;
+                }
+                let (connection, probe) = self_mutex
+                    .safe_lock(|s| (s.connection.clone(), s.health_probe()))
+                    .unwrap();
+                let (mut connection, (channel_id, probe)) = match (connection, probe) {
+                    (Some(connection), Some(probe)) => (connection, probe),
+                    // Not connected or nothing to probe yet
+                    _ => continue,
+                };
+                let sent_at = Instant::now();
+                if connection.send(probe).await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(health_check.deadline).await;
+                let (answered, address) = self_mutex
+                    .safe_lock(|s| {
+                        let answered = matches!(
+                            s.last_probe_answer,
+                            Some((answer_channel_id, received_at))
+                                if answer_channel_id == channel_id && received_at >= sent_at
+                        );
+                        (answered, s.address)
+                    })
+                    .unwrap();
+                if !answered && !receiver.is_closed() {
+                    error!(
+                        "Upstream node {} did not answer in {:?}, dropping the connection",
+                        address, health_check.deadline
+                    );
+                    self_mutex.safe_lock(|s| s.connection = None).unwrap();
+                    receiver.close();
                     break;
                 }
             }
         });
     }
+
+    /// Message used to check that the upstream is alive: an `UpdateChannel` for the extended
+    /// channel opened by the proxy that repeats the nominal hash rate the channel has been opened
+    /// with and its current target as maximum target, so that it does not change the channel. The
+    /// upstream answers it with a `SetTarget`. Returns the id of the probed channel and the
+    /// probe, `None` until the channel is open.
+    fn health_probe(&self) -> Option<(u32, StdFrame)> {
+        match &self.channel_kind {
+            ChannelKind::Extended(Some(factory)) => {
+                let channel_id = factory.get_this_channel_id();
+                let target = *self.channel_targets.get(&channel_id)?;
+                let message = PoolMessages::Mining(Mining::UpdateChannel(UpdateChannel {
+                    channel_id,
+                    nominal_hash_rate: self.downstream_hash_rate,
+                    maximum_target: target.into(),
+                }));
+                Some((channel_id, message.try_into().unwrap()))
+            }
+            _ => None,
+        }
+    }
+
     pub fn get_id(&self) -> u32 {
         self.id
     }
@@ -455,6 +851,67 @@ impl UpstreamMiningNode {
             .unwrap();
     }
 
+    /// Ids of the channels that are open with this upstream: the extended channel opened by the
+    /// proxy, or the channels opened by the downstreams when the proxy only relay them.
+    fn open_channel_ids(&self) -> Vec<u32> {
+        match &self.channel_kind {
+            ChannelKind::Extended(Some(factory)) => vec![factory.get_this_channel_id()],
+            ChannelKind::Extended(None) => vec![],
+            ChannelKind::Group(_) => {
+                let mut ids = vec![];
+                for downstream in self.downstream_selector.get_all_downstreams() {
+                    downstream
+                        .safe_lock(|d| match &d.status {
+                            DownstreamMiningNodeStatus::ChannelOpened(
+                                Channel::DowntreamHomUpstreamGroup { channel_id, .. }
+                                | Channel::DowntreamHomUpstreamExtended { channel_id, .. },
+                            ) => ids.push(*channel_id),
+                            DownstreamMiningNodeStatus::ChannelOpened(
+                                Channel::DowntreamNonHomUpstreamExtended { extended_ids, .. },
+                            ) => ids.extend(extended_ids),
+                            _ => (),
+                        })
+                        .unwrap();
+                }
+                ids.sort_unstable();
+                ids.dedup();
+                ids
+            }
+        }
+    }
+
+    /// Send a `CloseChannel` for every channel open with this upstream. If the upstream is not
+    /// connected there is nothing to close and nothing is sent.
+    pub async fn close_channels(self_mutex: Arc<Mutex<Self>>) -> Result<(), super::error::Error> {
+        let (connection, channel_ids) = self_mutex
+            .safe_lock(|self_| (self_.connection.clone(), self_.open_channel_ids()))
+            .unwrap();
+        let mut connection = match connection {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+        for channel_id in channel_ids {
+            let message = PoolMessages::Mining(Mining::CloseChannel(CloseChannel {
+                channel_id,
+                reason_code: "proxy-shutdown".to_string().try_into().unwrap(),
+            }));
+            connection.send(message.try_into().unwrap()).await?;
+        }
+        Ok(())
+    }
+
+    /// Return when every frame queued for this upstream has been taken by the connection task
+    pub async fn wait_for_pending_sends(self_mutex: Arc<Mutex<Self>>) {
+        let sender = self_mutex
+            .safe_lock(|self_| self_.connection.as_ref().map(|c| c.sender.clone()))
+            .unwrap();
+        if let Some(sender) = sender {
+            while !sender.is_empty() && !sender.is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+
     fn exit(self_: Arc<Mutex<Self>>) {
         if !self_.safe_lock(|s| s.reconnect).unwrap() {
             super::remove_upstream(self_.safe_lock(|s| s.id).unwrap());
@@ -495,19 +952,59 @@ impl UpstreamMiningNode {
             }
             DownstreamMiningNode::exit(d);
         }
+        self_.safe_lock(|s| s.reset_channels()).unwrap();
         if self_.safe_lock(|s| s.reconnect).unwrap() {
             self_.safe_lock(|s| s.connection = None).unwrap();
             let flags = self_
-                .safe_lock(|s| s.sv2_connection.unwrap().setup_connection_flags)
+                .safe_lock(|s| s.sv2_connection.unwrap().setup_connection_flags.bits())
                 .unwrap();
             self_.safe_lock(|s| s.sv2_connection = None).unwrap();
             self_.safe_lock(|s| s.channel_kind.reset()).unwrap();
-            tokio::task::spawn(async move {
-                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-                Self::setup_flag_and_version(self_, Some(flags), 2, 2)
-                    .await
-                    .unwrap();
-            });
+            // The upstream dropped the connection, wait before trying to reconnect
+            self_
+                .safe_lock(|s| {
+                    let uptime = s.connected_at.elapsed();
+                    s.reconnect_backoff.on_disconnect(uptime)
+                })
+                .unwrap();
+            Self::reconnect_in_background(self_, Some(flags), 2, 2);
+        }
+    }
+
+    /// Send a frame to `downstream`. A downstream whose connection is closed is dropped so that
+    /// the relay can go on with the remaining downstreams.
+    async fn send_downstream(
+        self_mutex: Arc<Mutex<Self>>,
+        downstream: Arc<Mutex<DownstreamMiningNode>>,
+        frame: DownstreamFrame,
+    ) {
+        if DownstreamMiningNode::send(downstream.clone(), frame)
+            .await
+            .is_err()
+        {
+            let (upstream_id, downstream_id) = (
+                self_mutex.safe_lock(|s| s.id).unwrap(),
+                downstream.safe_lock(|d| d.get_id()).unwrap(),
+            );
+            warn!(
+                upstream_id,
+                downstream_id, "Downstream connection closed, downstream dropped"
+            );
+            Self::remove_dowstream(self_mutex, &downstream);
+            DownstreamMiningNode::exit(downstream);
+        }
+    }
+
+    /// Send `message` back to the upstream. `send` already dropped a dead connection and tried to
+    /// reconnect, so a failure is only logged and the upstream is left disconnected: the next send
+    /// or the relay loop exiting reconnects it.
+    async fn respond(self_mutex: Arc<Mutex<Self>>, message: Mining<'static>) {
+        let message = PoolMessages::Mining(message);
+        let frame: StdFrame = message.try_into().unwrap();
+        if let Err(e) = UpstreamMiningNode::send(self_mutex.clone(), frame).await {
+            let upstream_id = self_mutex.safe_lock(|s| s.id).unwrap();
+            error!(upstream_id, "Failed to respond to upstream: {:?}", e);
+            self_mutex.safe_lock(|s| s.connection = None).unwrap();
         }
     }
 
@@ -521,47 +1018,33 @@ impl UpstreamMiningNode {
                 let sv2_frame: codec_sv2::Sv2Frame<MiningDeviceMessages, buffer_sv2::Slice> =
                     incoming.map(|payload| payload.try_into().unwrap());
 
-                DownstreamMiningNode::send(downstream.clone(), sv2_frame)
-                    .await
-                    .unwrap();
+                Self::send_downstream(self_mutex, downstream, sv2_frame).await;
             }
             Ok(SendTo::RelayNewMessageToRemote(downstream_mutex, message)) => {
                 let message = MiningDeviceMessages::Mining(message);
                 let frame: DownstreamFrame = message.try_into().unwrap();
-                DownstreamMiningNode::send(downstream_mutex, frame)
-                    .await
-                    .unwrap();
-            }
-            Ok(SendTo::Respond(message)) => {
-                let message = PoolMessages::Mining(message);
-                let frame: StdFrame = message.try_into().unwrap();
-                UpstreamMiningNode::send(self_mutex, frame).await.unwrap();
+                Self::send_downstream(self_mutex, downstream_mutex, frame).await;
             }
+            Ok(SendTo::Respond(message)) => Self::respond(self_mutex, message).await,
             Ok(SendTo::Multiple(sends_to)) => {
                 for send_to in sends_to {
                     match send_to {
                         SendTo::RelayNewMessageToRemote(downstream_mutex, message) => {
                             let message = MiningDeviceMessages::Mining(message);
                             let frame: DownstreamFrame = message.try_into().unwrap();
-                            DownstreamMiningNode::send(downstream_mutex, frame)
-                                .await
-                                .unwrap();
+                            Self::send_downstream(self_mutex.clone(), downstream_mutex, frame)
+                                .await;
                         }
                         SendTo::RelaySameMessageToRemote(downstream_mutex) => {
                             let frame: codec_sv2::Sv2Frame<
                                 MiningDeviceMessages,
                                 buffer_sv2::Slice,
                             > = incoming.clone().map(|payload| payload.try_into().unwrap());
-                            DownstreamMiningNode::send(downstream_mutex, frame)
-                                .await
-                                .unwrap();
+                            Self::send_downstream(self_mutex.clone(), downstream_mutex, frame)
+                                .await;
                         }
                         SendTo::Respond(message) => {
-                            let message = PoolMessages::Mining(message);
-                            let frame: StdFrame = message.try_into().unwrap();
-                            UpstreamMiningNode::send(self_mutex.clone(), frame)
-                                .await
-                                .unwrap();
+                            Self::respond(self_mutex.clone(), message).await
                         }
                         SendTo::None(_) => (),
                         SendTo::Multiple(_) => panic!("Nested SendTo::Multiple not supported"),
@@ -572,7 +1055,10 @@ impl UpstreamMiningNode {
             Ok(SendTo::None(_)) => (),
             Ok(_) => panic!(),
             Err(Error::NoDownstreamsConnected) => (),
-            Err(e) => panic!("{:?}", e),
+            Err(e) => {
+                let upstream_id = self_mutex.safe_lock(|s| s.id).unwrap();
+                error!(upstream_id, "Error handling upstream message: {:?}", e);
+            }
         }
     }
 
@@ -591,7 +1077,6 @@ impl UpstreamMiningNode {
         Self::match_next_message(self_mutex, next_message_to_send, incoming).await;
     }
 
-    #[async_recursion]
     async fn setup_flag_and_version(
         self_mutex: Arc<Mutex<Self>>,
         flags: Option<u32>,
@@ -599,63 +1084,73 @@ impl UpstreamMiningNode {
         max_version: u16,
     ) -> Result<(), super::error::Error> {
         let flags = flags.unwrap_or(0b0000_0000_0000_0000_0000_0000_0000_0110);
-        let (frame, downstream_hr) = self_mutex
+        let send = |flags, min_version, max_version| {
+            let self_mutex = self_mutex.clone();
+            async move {
+                let frame = self_mutex
+                    .safe_lock(|self_| {
+                        self_.new_setup_connection_frame(flags, min_version, max_version)
+                    })
+                    .unwrap();
+                Self::send(self_mutex, frame).await
+            }
+        };
+        let recv = || {
+            let self_mutex = self_mutex.clone();
+            async move {
+                let mut response = task::spawn(async { Self::receive(self_mutex).await })
+                    .await
+                    .unwrap()?;
+                let message_type = response.get_header().unwrap().msg_type();
+                if let Ok(CommonMessages::SetupConnectionError(m)) =
+                    CommonMessages::try_from((message_type, response.payload()))
+                {
+                    warn!(
+                        "Upstream rejected: {}",
+                        describe_unsupported_flags(Protocol::MiningProtocol, m.flags).join(", ")
+                    );
+                }
+                Ok((message_type, response.payload().to_vec()))
+            }
+        };
+        let (version, flags) =
+            negotiate_setup_connection(send, recv, flags, min_version, max_version)
+                .await
+                .map_err(|e| match e {
+                    SetupError::Transport(e) => e,
+                    SetupError::Rejected(0, error_code) => {
+                        super::error::Error::SetupConnectionError(error_code)
+                    }
+                    SetupError::Rejected(flags, error_code) => {
+                        super::error::Error::SetupConnectionError(format!(
+                            "{}, upstream rejected: {}",
+                            error_code,
+                            describe_unsupported_flags(Protocol::MiningProtocol, flags).join(", ")
+                        ))
+                    }
+                    e => super::error::Error::SetupConnectionError(e.to_string()),
+                })?;
+
+        let (receiver, downstream_hr) = self_mutex
             .safe_lock(|self_| {
+                self_.sv2_connection = Some(Sv2MiningConnection {
+                    version: version.into(),
+                    setup_connection_flags: flags.into(),
+                });
                 (
-                    self_.new_setup_connection_frame(flags, min_version, max_version),
+                    self_.connection.clone().unwrap().receiver,
                     self_.downstream_hash_rate,
                 )
             })
             .unwrap();
-        Self::send(self_mutex.clone(), frame).await?;
-
-        let cloned = self_mutex.clone();
-        let mut response = task::spawn(async { Self::receive(cloned).await })
-            .await
+        Self::relay_incoming_messages(self_mutex.clone(), receiver);
+        if self_mutex
+            .safe_lock(|s| s.channel_kind.is_extended())
             .unwrap()
-            .unwrap();
-
-        let message_type = response.get_header().unwrap().msg_type();
-        let payload = response.payload();
-        match (message_type, payload).try_into() {
-            Ok(CommonMessages::SetupConnectionSuccess(m)) => {
-                let receiver = self_mutex
-                    .safe_lock(|self_| {
-                        self_.sv2_connection = Some(Sv2MiningConnection {
-                            version: m.used_version,
-                            setup_connection_flags: flags,
-                            setup_connection_success_flags: m.flags,
-                        });
-                        self_.connection.clone().unwrap().receiver
-                    })
-                    .unwrap();
-                Self::relay_incoming_messages(self_mutex.clone(), receiver);
-                if self_mutex
-                    .safe_lock(|s| s.channel_kind.is_extended())
-                    .unwrap()
-                {
-                    Self::open_extended_channel(self_mutex.clone(), downstream_hr).await
-                }
-                Ok(())
-            }
-            Ok(CommonMessages::SetupConnectionError(m)) => {
-                if m.flags != 0 {
-                    let flags = flags ^ m.flags;
-                    // We need to send SetupConnection again as we do not yet know the version of
-                    // upstream
-                    // debounce this?
-                    Self::setup_flag_and_version(self_mutex, Some(flags), min_version, max_version)
-                        .await
-                } else {
-                    let error_message = std::str::from_utf8(m.error_code.inner_as_ref())
-                        .unwrap()
-                        .to_string();
-                    Err(super::error::Error::SetupConnectionError(error_message))
-                }
-            }
-            Ok(_) => todo!(),
-            Err(_) => todo!(),
+        {
+            Self::open_extended_channel(self_mutex.clone(), downstream_hr).await
         }
+        Ok(())
     }
 
     async fn open_extended_channel(self_mutex: Arc<Mutex<Self>>, nominal_hash_rate: f32) {
@@ -694,30 +1189,15 @@ impl UpstreamMiningNode {
         min_version: u16,
         max_version: u16,
     ) -> StdFrame {
-        let endpoint_host = self
-            .address
-            .ip()
-            .to_string()
-            .into_bytes()
-            .try_into()
-            .unwrap();
-        let vendor = String::new().try_into().unwrap();
-        let hardware_version = String::new().try_into().unwrap();
-        let firmware = String::new().try_into().unwrap();
-        let device_id = String::new().try_into().unwrap();
-        let setup_connection: PoolMessages = SetupConnection {
-            protocol: Protocol::MiningProtocol,
-            min_version,
-            max_version,
-            flags,
-            endpoint_host,
-            endpoint_port: self.address.port(),
-            vendor,
-            hardware_version,
-            firmware,
-            device_id,
-        }
-        .into();
+        let endpoint_host = self.address.ip().to_string();
+        // An IP address is far shorter than the 255 bytes allowed for the host
+        let setup_connection: PoolMessages =
+            SetupConnectionBuilder::new(Protocol::MiningProtocol, min_version, max_version)
+                .flags(flags)
+                .endpoint(&endpoint_host, self.address.port())
+                .try_build()
+                .expect("An IP address always fits in SetupConnection")
+                .into();
         setup_connection.try_into().unwrap()
     }
 
@@ -753,43 +1233,121 @@ impl UpstreamMiningNode {
         }
     }
 
-    pub fn handle_std_shr(
-        self_: Arc<Mutex<Self>>,
-        share_: SubmitSharesStandard,
-    ) -> Result<Mining<'static>, Error> {
-        if self_.safe_lock(|s| s.channel_kind.is_extended()).unwrap() {
-            let share = self_
-                .safe_lock(|s| {
-                    let factory = s.channel_kind.get_factory();
-                    factory.on_submit_shares_standard(share_.clone())
-                })
-                .unwrap()?;
-            match share {
-                OnNewShare::SendErrorDownstream(e) => {
-                    tracing::error!("Received invalid share");
-                    Ok(Mining::SubmitSharesError(e))
-                }
-                OnNewShare::SendSubmitShareUpstream((s, _)) => match s {
-                    Share::Extended(s) => {
-                        let message = Mining::SubmitSharesExtended(s);
-                        let message = PoolMessages::Mining(message);
-                        let frame: StdFrame = message.try_into().unwrap();
-                        tokio::task::spawn(async move {
-                            UpstreamMiningNode::send(self_.clone(), frame)
-                                .await
-                                .unwrap();
-                        });
-                        let success = SubmitSharesSuccess {
-                            channel_id: share_.channel_id,
-                            last_sequence_number: share_.sequence_number,
-                            new_submits_accepted_count: 1,
-                            new_shares_sum: 1,
-                        };
-                        let message = Mining::SubmitSharesSuccess(success);
-                        Ok(message)
-                    }
-                    Share::Standard(_) => unreachable!(),
-                },
+    fn on_proxy_extended_channel_success(
+        &mut self,
+        m: OpenExtendedMiningChannelSuccess,
+    ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        let extranonce_prefix: Extranonce = m.extranonce_prefix.clone().into();
+        let range_0 = 0..m.extranonce_prefix.clone().to_vec().len();
+        let range_1 = range_0.end..(range_0.end + EXTRANONCE_RANGE_1_LENGTH);
+        let range_2 = range_1.end..(range_0.end + m.extranonce_size as usize);
+        let extranonces = ExtendedExtranonce::from_upstream_extranonce(
+            extranonce_prefix,
+            range_0,
+            range_1,
+            range_2,
+        )
+        .unwrap();
+
+        info!(
+            upstream_id = self.id,
+            channel_id = m.channel_id,
+            "Opened extended channel for the proxy"
+        );
+        self.set_channel_target(m.channel_id, &m.target);
+        self.channel_kind.initialize_factory(
+            self.group_id.clone(),
+            extranonces,
+            self.downstream_share_per_minute,
+            m.target.clone().into(),
+            m.channel_id,
+        );
+        // The channel carries the hash rate of every downstream connected to the proxy
+        self.on_channel_opened(
+            m.channel_id,
+            UpstreamChannel::Extended,
+            self.downstream_hash_rate as u64,
+        );
+        Ok(SendTo::None(None))
+    }
+
+    fn on_downstream_extended_channel_success(
+        &mut self,
+        m: OpenExtendedMiningChannelSuccess,
+    ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        let upstream_request_id = m.request_id;
+        // An extended channel is not part of any group so it is registered as a group made of a
+        // single channel, that way `get_downstreams_in_channel` work for it too.
+        let downstream = self.downstream_selector.on_open_standard_channel_success(
+            upstream_request_id,
+            m.channel_id,
+            m.channel_id,
+        )?;
+        self.set_channel_target(m.channel_id, &m.target);
+        info!(
+            upstream_id = self.id,
+            downstream_id = downstream.safe_lock(|d| d.get_id()).unwrap(),
+            channel_id = m.channel_id,
+            "Opened extended channel for downstream"
+        );
+        downstream
+            .safe_lock(|d| d.add_extended_from_non_hom_for_up_extended(m.channel_id))
+            .map_err(|e| Error::PoisonLock(e.to_string()))?;
+        self.channel_id_to_job_dispatcher
+            .entry(m.channel_id)
+            .or_insert(JobDispatcher::Extended);
+        self.on_channel_opened(m.channel_id, UpstreamChannel::Extended, 0);
+
+        match self.request_id_mapper.remove_checked(upstream_request_id) {
+            Some(original_request_id) if original_request_id != upstream_request_id => {
+                let mut m = m.into_static();
+                m.request_id = original_request_id;
+                Ok(SendTo::RelayNewMessageToRemote(
+                    downstream,
+                    Mining::OpenExtendedMiningChannelSuccess(m),
+                ))
+            }
+            _ => Ok(SendTo::RelaySameMessageToRemote(downstream)),
+        }
+    }
+
+    pub fn handle_std_shr(
+        self_: Arc<Mutex<Self>>,
+        share_: SubmitSharesStandard,
+    ) -> Result<Mining<'static>, Error> {
+        if self_.safe_lock(|s| s.channel_kind.is_extended()).unwrap() {
+            let share = self_
+                .safe_lock(|s| {
+                    let factory = s.channel_kind.get_factory();
+                    factory.on_submit_shares_standard(share_.clone())
+                })
+                .unwrap()?;
+            match share {
+                OnNewShare::SendErrorDownstream(e) => {
+                    tracing::error!("Received invalid share");
+                    Ok(Mining::SubmitSharesError(e))
+                }
+                OnNewShare::SendSubmitShareUpstream((s, _)) => match s {
+                    Share::Extended(s) => {
+                        let message = Mining::SubmitSharesExtended(s);
+                        let message = PoolMessages::Mining(message);
+                        let frame: StdFrame = message.try_into().unwrap();
+                        tokio::task::spawn(async move {
+                            UpstreamMiningNode::send(self_.clone(), frame)
+                                .await
+                                .unwrap();
+                        });
+                        let success = SubmitSharesSuccess {
+                            channel_id: share_.channel_id,
+                            last_sequence_number: share_.sequence_number,
+                            new_submits_accepted_count: 1,
+                            new_shares_sum: 1,
+                        };
+                        let message = Mining::SubmitSharesSuccess(success);
+                        Ok(message)
+                    }
+                    Share::Standard(_) => unreachable!(),
+                },
                 OnNewShare::RelaySubmitShareUpstream => todo!(),
                 OnNewShare::ShareMeetBitcoinTarget((share, Some(template_id), coinbase, _)) => {
                     match share {
@@ -853,29 +1411,60 @@ impl UpstreamMiningNode {
         }
     }
 
-    // Example of how next could be implemented more efficently if no particular good log are
-    // needed it just relay the majiority of messages downstream without serializing and
-    // deserializing them. In order to find the Downstream at which the message must bu relayed the
-    // channel id must be deserialized, but knowing the message type that is a very easy task is
-    // either 4 bytes after the header or the first 4 bytes after the header + 4 bytes
-    // #[cfg(test)]
-    // #[allow(unused)]
-    // pub async fn next_faster(&mut self, mut incoming: StdFrame) {
-    //     let message_type = incoming.get_header().unwrap().msg_type();
-
-    //     // When a channel is opened we need to setup the channel id in order to relay next messages
-    //     // to the right Downstream
-    //     if todo!() { // check if message_type is channel related
-
-    //         // When a mining message is received (that is not a channel related message) always relay it downstream
-    //     } else if todo!()  { // check if message_type is is a mining message
-    //         // everything here can be just relayed downstream
+    /// Like `next` but frames that the proxy relays as they are to a single downstream are sent
+    /// without being deserialized: only the channel id, the first field of the payload, is read to
+    /// find the downstream. Every other frame goes through `next`.
+    pub async fn next_relay_only(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) {
+        let message_type = incoming.get_header().unwrap().msg_type();
+        let payload = incoming.payload();
+        let downstream = self_mutex
+            .safe_lock(|s| s.relay_only_downstream(message_type, payload))
+            .unwrap();
+        match downstream {
+            Some(downstream) => {
+                let to_send = Ok(SendTo::RelaySameMessageToRemote(downstream));
+                Self::match_next_message(self_mutex, to_send, incoming).await;
+            }
+            None => Self::next(self_mutex, incoming).await,
+        }
+    }
 
-    //         // Other sub(protocol) messages
-    //     } else {
-    //         todo!()
-    //     }
-    // }
+    /// Downstream to which a frame can be relayed without deserializing it, `None` when the
+    /// message must be handled by `next`. Must return the downstream the message handler would
+    /// relay the message to, and update the same state the handler would.
+    fn relay_only_downstream(
+        &mut self,
+        message_type: u8,
+        payload: &[u8],
+    ) -> Option<Arc<Mutex<DownstreamMiningNode>>> {
+        let channel_id = u32::from_le_bytes(payload.get(0..4)?.try_into().ok()?);
+        match message_type {
+            // Extended channels opened by downstreams get the upstream jobs as they are
+            const_sv2::MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB => {
+                match self.channel_id_to_job_dispatcher.get(&channel_id) {
+                    Some(JobDispatcher::Extended) => {
+                        let downstream = self
+                            .downstream_selector
+                            .downstream_from_channel_id(channel_id)?;
+                        self.jobs_relayed += 1;
+                        Some(downstream)
+                    }
+                    _ => None,
+                }
+            }
+            const_sv2::MESSAGE_TYPE_SET_EXTRANONCE_PREFIX => {
+                // The prefix of the channel opened by the proxy is rewritten for every downstream
+                if let ChannelKind::Extended(Some(factory)) = &self.channel_kind {
+                    if channel_id == factory.get_this_channel_id() {
+                        return None;
+                    }
+                }
+                self.downstream_selector
+                    .downstream_from_channel_id(channel_id)
+            }
+            _ => None,
+        }
+    }
 }
 
 impl
@@ -890,7 +1479,9 @@ impl
     }
 
     fn is_work_selection_enabled(&self) -> bool {
-        true
+        self.sv2_connection
+            .map(|connection| connection.setup_connection_flags.requires_work_selection())
+            .unwrap_or(false)
     }
 
     fn handle_open_standard_mining_channel_success(
@@ -898,31 +1489,54 @@ impl
         m: OpenStandardMiningChannelSuccess,
         remote: Option<Arc<Mutex<DownstreamMiningNode>>>,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        self.set_channel_target(m.channel_id, &m.target);
         match &mut self.channel_kind {
+            // The upstream only carries standard channels (grouped by the upstream), so whether or
+            // not the downstream is header only the proxy presents it a single standard channel:
+            // the success is relayed as is and the channel is mapped in the group dispatcher. A non
+            // HOM downstream is tracked exactly as a HOM one, it will just never see extended jobs
+            // on this channel.
             ChannelKind::Group(group) => {
-                let down_is_header_only = remote
-                    .as_ref()
-                    .unwrap()
-                    .safe_lock(|remote| remote.is_header_only())
-                    .unwrap();
                 let remote = remote.unwrap();
-                if down_is_header_only {
-                    let mut res = vec![SendTo::RelaySameMessageToRemote(remote.clone())];
-                    for message in group.on_channel_success_for_hom_downtream(&m)? {
-                        res.push(SendTo::RelayNewMessageToRemote(remote.clone(), message));
-                    }
-                    remote
-                        .safe_lock(|r| {
-                            r.open_channel_for_down_hom_up_group(m.channel_id, m.group_channel_id)
-                        })
-                        .unwrap();
-                    Ok(SendTo::Multiple(res))
-                } else {
-                    // Here we want to support only the case where downstream is non HOM and want to open
-                    // extended channels with the proxy. Dowstream non HOM that try to open standard
-                    // channel (grouped in groups) do not make much sense so for now is not supported
-                    panic!()
+                let (downstream_id, down_is_header_only) = remote
+                    .safe_lock(|remote| (remote.get_id(), remote.is_header_only()))
+                    .unwrap();
+                info!(
+                    upstream_id = self.id,
+                    downstream_id,
+                    header_only = down_is_header_only,
+                    channel_id = m.channel_id,
+                    group_channel_id = m.group_channel_id,
+                    "Opened standard channel for downstream"
+                );
+                let mut res = vec![SendTo::RelaySameMessageToRemote(remote.clone())];
+                for message in group.on_channel_success_for_hom_downtream(&m)? {
+                    res.push(SendTo::RelayNewMessageToRemote(remote.clone(), message));
+                }
+                remote
+                    .safe_lock(|r| {
+                        r.open_channel_for_down_hom_up_group(m.channel_id, m.group_channel_id)
+                    })
+                    .unwrap();
+                if let Entry::Vacant(entry) =
+                    self.channel_id_to_job_dispatcher.entry(m.group_channel_id)
+                {
+                    let channel_ids = self.channel_ids.clone();
+                    entry.insert(JobDispatcher::Group(GroupChannelJobDispatcher::new(
+                        channel_ids,
+                    )));
+                    self.on_channel_opened(m.group_channel_id, UpstreamChannel::Group, 0);
                 }
+                let nominal_hash_rate = self
+                    .requested_hash_rates
+                    .remove(&m.request_id.as_u32())
+                    .unwrap_or(0.0);
+                self.on_channel_opened(
+                    m.channel_id,
+                    UpstreamChannel::Standard(nominal_hash_rate),
+                    nominal_hash_rate as u64,
+                );
+                Ok(SendTo::Multiple(res))
             }
             // If we opened and extended channel upstreams we should not receive this message
             ChannelKind::Extended(_) => todo!(),
@@ -933,60 +1547,185 @@ impl
         &mut self,
         m: OpenExtendedMiningChannelSuccess,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        let extranonce_prefix: Extranonce = m.extranonce_prefix.clone().into();
-        let range_0 = 0..m.extranonce_prefix.clone().to_vec().len();
-        let range_1 = range_0.end..(range_0.end + EXTRANONCE_RANGE_1_LENGTH);
-        let range_2 = range_1.end..(range_0.end + m.extranonce_size as usize);
-        let extranonces = ExtendedExtranonce::from_upstream_extranonce(
-            extranonce_prefix,
-            range_0,
-            range_1,
-            range_2,
-        )
-        .unwrap();
-
-        self.channel_kind.initialize_factory(
-            self.group_id.clone(),
-            extranonces,
-            self.downstream_share_per_minute,
-            m.target.clone().into(),
-            m.channel_id,
-        );
-        Ok(SendTo::None(None))
+        match &self.channel_kind {
+            // The proxy opened this channel for itself (see `open_extended_channel`): use it to
+            // build the channel factory
+            ChannelKind::Extended(None) => self.on_proxy_extended_channel_success(m),
+            // The channel has been requested by a non HOM downstream, register it and relay the
+            // success message downstream
+            _ => self.on_downstream_extended_channel_success(m),
+        }
     }
 
     fn handle_open_mining_channel_error(
         &mut self,
-        _m: OpenMiningChannelError,
+        m: OpenMiningChannelError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("460")
+        // The request is not pending anymore: forget it and relay the error, with the original
+        // request id, to the downstream that sent it
+        let upstream_request_id = m.request_id;
+        let original_request_id = self
+            .request_id_mapper
+            .remove_checked(upstream_request_id)
+            .ok_or(Error::RequestIdNotMapped(upstream_request_id))?;
+        self.requested_hash_rates.remove(&upstream_request_id);
+        let downstream = self
+            .downstream_selector
+            .remove_request(upstream_request_id)
+            .ok_or(Error::UnknownRequestId(upstream_request_id))?;
+        info!(
+            upstream_id = self.id,
+            downstream_id = downstream.safe_lock(|d| d.get_id()).unwrap(),
+            request_id = original_request_id,
+            error_code = std::str::from_utf8(m.error_code.as_ref()).unwrap_or("unknown error code"),
+            "Upstream rejected channel open"
+        );
+        let mut m = m.into_static();
+        m.request_id = original_request_id;
+        Ok(SendTo::RelayNewMessageToRemote(
+            downstream,
+            Mining::OpenMiningChannelError(m),
+        ))
     }
 
     fn handle_update_channel_error(
         &mut self,
-        _m: UpdateChannelError,
+        m: UpdateChannelError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("470")
+        let downstream = self
+            .downstream_selector
+            .downstream_from_channel_id(m.channel_id)
+            .ok_or(Error::NoDownstreamsConnected)?;
+        let error_code = std::str::from_utf8(m.error_code.as_ref()).unwrap_or("unknown error code");
+        let request = self.update_channel_requests.remove(&m.channel_id);
+        let relay = SendTo::RelaySameMessageToRemote(downstream);
+        if !is_recoverable_update_channel_error(error_code) {
+            return Ok(relay);
+        }
+        // The upstream must honor a maximum target not bigger than the channel's current one, so
+        // the request is retried once with the maximum target clamped to the current target. The
+        // request is not tracked anymore so a second error is only relayed.
+        match (request, self.channel_targets.get(&m.channel_id)) {
+            (Some(mut request), Some(current_target)) => {
+                let requested_target: roles_logic_sv2::utils::Target =
+                    request.maximum_target.clone().into();
+                if requested_target <= *current_target {
+                    return Ok(relay);
+                }
+                request.maximum_target = current_target.to_le_bytes().into();
+                Ok(SendTo::Multiple(vec![
+                    relay,
+                    SendTo::Respond(Mining::UpdateChannel(request)),
+                ]))
+            }
+            _ => Ok(relay),
+        }
     }
 
     fn handle_close_channel(
         &mut self,
-        _m: CloseChannel,
+        m: CloseChannel,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("480")
+        self.channel_targets.remove(&m.channel_id);
+        self.update_channel_requests.remove(&m.channel_id);
+        if let Some(downstream) = self.downstream_selector.remove_channel(m.channel_id) {
+            self.channel_id_to_job_dispatcher.remove(&m.channel_id);
+            self.on_channel_closed(m.channel_id);
+            let channel = downstream.safe_lock(|d| d.get_channel().clone()).unwrap();
+            if let Channel::DowntreamHomUpstreamGroup { group_id, .. } = channel {
+                if let ChannelKind::Group(group) = &mut self.channel_kind {
+                    group.on_close_channel(m.channel_id, group_id);
+                }
+                // The group dispatcher is dropped only when the last channel in the group closes
+                if self
+                    .downstream_selector
+                    .get_downstreams_in_channel(group_id)
+                    .is_none()
+                {
+                    self.channel_id_to_job_dispatcher.remove(&group_id);
+                    self.on_channel_closed(group_id);
+                }
+            }
+            return Ok(SendTo::RelaySameMessageToRemote(downstream));
+        }
+
+        // Group channel: every channel in the group is closed
+        let downstreams = self
+            .downstream_selector
+            .remove_downstreams_in_channel(m.channel_id);
+        if downstreams.is_empty() {
+            return Err(Error::NoDownstreamsConnected);
+        }
+        if let ChannelKind::Group(group) = &mut self.channel_kind {
+            group.remove_group(m.channel_id);
+        }
+        self.channel_id_to_job_dispatcher.remove(&m.channel_id);
+        self.on_channel_closed(m.channel_id);
+        let mut res = vec![];
+        for downstream in downstreams {
+            let channel_id = match downstream.safe_lock(|d| d.get_channel().clone()).unwrap() {
+                Channel::DowntreamHomUpstreamGroup { channel_id, .. } => channel_id,
+                Channel::DowntreamHomUpstreamExtended { channel_id, .. } => channel_id,
+                Channel::DowntreamNonHomUpstreamExtended { .. } => m.channel_id,
+            };
+            self.channel_id_to_job_dispatcher.remove(&channel_id);
+            self.on_channel_closed(channel_id);
+            let message = CloseChannel {
+                channel_id,
+                reason_code: m.reason_code.clone().into_static(),
+            };
+            res.push(SendTo::RelayNewMessageToRemote(
+                downstream,
+                Mining::CloseChannel(message),
+            ));
+        }
+        Ok(SendTo::Multiple(res))
     }
 
     fn handle_set_extranonce_prefix(
         &mut self,
-        _m: SetExtranoncePrefix,
+        m: SetExtranoncePrefix,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("490")
+        // New prefix for the extended channel that the proxy opened with upstream: every channel
+        // opened by the factory gets its extranonce updated
+        if let ChannelKind::Extended(Some(factory)) = &mut self.channel_kind {
+            if m.channel_id == factory.get_this_channel_id() {
+                let messages =
+                    factory.update_upstream_extranonce_prefix(&m.extranonce_prefix.to_vec())?;
+                let mut res = vec![];
+                for message in messages {
+                    if let Some(downstream) = self
+                        .downstream_selector
+                        .downstream_from_channel_id(message.channel_id)
+                    {
+                        res.push(SendTo::RelayNewMessageToRemote(
+                            downstream,
+                            Mining::SetExtranoncePrefix(message),
+                        ));
+                    }
+                }
+                return Ok(SendTo::Multiple(res));
+            }
+        }
+
+        // Channel opened by the upstream for a single downstream, the prefix is the downstream's
+        let downstream = self
+            .downstream_selector
+            .downstream_from_channel_id(m.channel_id)
+            .ok_or(Error::NoDownstreamsConnected)?;
+        Ok(SendTo::RelaySameMessageToRemote(downstream))
     }
 
     fn handle_submit_shares_success(
         &mut self,
         m: SubmitSharesSuccess,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        let difficulty = self
+            .channel_targets
+            .get(&m.channel_id)
+            .map_or(0.0, |target| target.to_difficulty());
+        self.share_accountant
+            .on_accepted(m.channel_id, m.new_submits_accepted_count, difficulty);
         match &self
             .downstream_selector
             .downstream_from_channel_id(m.channel_id)
@@ -1001,8 +1740,10 @@ impl
 
     fn handle_submit_shares_error(
         &mut self,
-        _m: SubmitSharesError,
+        m: SubmitSharesError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        let reason = std::str::from_utf8(m.error_code.as_ref()).unwrap_or("unknown error code");
+        self.share_accountant.on_rejected(m.channel_id, reason);
         Ok(SendTo::None(None))
     }
 
@@ -1038,6 +1779,16 @@ impl
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
         debug!("Handling new extended mining job: {:?} {}", m, self.id);
 
+        if let Some(JobDispatcher::Extended) = self.channel_id_to_job_dispatcher.get(&m.channel_id)
+        {
+            let downstream = self
+                .downstream_selector
+                .downstream_from_channel_id(m.channel_id)
+                .ok_or(Error::NoDownstreamsConnected)?;
+            self.jobs_relayed += 1;
+            return Ok(SendTo::RelaySameMessageToRemote(downstream));
+        }
+
         let mut res = vec![];
         match &mut self.channel_kind {
             ChannelKind::Group(group) => {
@@ -1060,58 +1811,61 @@ impl
                                 downstream.clone(),
                                 Mining::NewMiningJob(message),
                             ));
+                            self.jobs_relayed += 1;
                         }
                         _ => unreachable!(),
                     }
                 }
             }
             ChannelKind::Extended(Some(factory)) => {
-                if let Ok(messages) = factory.on_new_extended_mining_job(m.clone().as_static()) {
-                    let mut new_p_hash_added = false;
-                    let is_future = m.is_future();
-                    let original_job_id = m.job_id;
-                    if is_future {
-                        self.job_up_to_down_ids.insert(original_job_id, vec![]);
-                    };
-                    for (id, message) in messages {
-                        match &message {
-                            Mining::NewExtendedMiningJob(_) => {
-                                // TODO implement it if support for non HOM downstream is needed
-                                todo!()
-                            }
-                            Mining::NewMiningJob(m) => {
-                                let downstream = self
-                                    .downstream_selector
-                                    .downstream_from_channel_id(id)
-                                    .ok_or(Error::NoDownstreamsConnected)?;
-                                if is_future {
-                                    let ids =
-                                        self.job_up_to_down_ids.get_mut(&original_job_id).unwrap();
-                                    ids.push((downstream.clone(), m.job_id));
-                                };
-                                res.push(SendTo::RelayNewMessageToRemote(
-                                    downstream,
-                                    Mining::NewMiningJob(m.clone()),
-                                ));
-                            }
-                            Mining::SetNewPrevHash(m) => {
-                                if !new_p_hash_added {
-                                    let downstreams =
-                                        self.downstream_selector.get_all_downstreams();
-                                    for downstream in downstreams {
-                                        res.push(SendTo::RelayNewMessageToRemote(
-                                            downstream.clone(),
-                                            Mining::SetNewPrevHash(m.clone()),
-                                        ));
-                                    }
-                                    new_p_hash_added = true;
+                let messages = factory.on_new_extended_mining_job(m.clone().as_static())?;
+                let mut new_p_hash_added = false;
+                let is_future = m.is_future();
+                let original_job_id = m.job_id;
+                if is_future {
+                    self.job_up_to_down_ids.insert(original_job_id, vec![]);
+                };
+                for (id, message) in messages {
+                    match &message {
+                        // The downstreams only open standard channels on the factory, extended
+                        // channels are opened with the upstream and their jobs are relayed as
+                        // they are
+                        Mining::NewExtendedMiningJob(_) => {
+                            error!("Extended job for a channel of the factory, not supported");
+                            return Err(Error::UnexpectedMessage(
+                                const_sv2::MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB,
+                            ));
+                        }
+                        Mining::NewMiningJob(m) => {
+                            let downstream = self
+                                .downstream_selector
+                                .downstream_from_channel_id(id)
+                                .ok_or(Error::NoDownstreamsConnected)?;
+                            if is_future {
+                                let ids =
+                                    self.job_up_to_down_ids.get_mut(&original_job_id).unwrap();
+                                ids.push((downstream.clone(), m.job_id));
+                            };
+                            res.push(SendTo::RelayNewMessageToRemote(
+                                downstream,
+                                Mining::NewMiningJob(m.clone()),
+                            ));
+                            self.jobs_relayed += 1;
+                        }
+                        Mining::SetNewPrevHash(m) => {
+                            if !new_p_hash_added {
+                                let downstreams = self.downstream_selector.get_all_downstreams();
+                                for downstream in downstreams {
+                                    res.push(SendTo::RelayNewMessageToRemote(
+                                        downstream.clone(),
+                                        Mining::SetNewPrevHash(m.clone()),
+                                    ));
                                 }
+                                new_p_hash_added = true;
                             }
-                            _ => todo!(),
                         }
+                        _ => todo!(),
                     }
-                } else {
-                    todo!()
                 }
             }
             ChannelKind::Extended(None) => panic!("Factory not initialized"),
@@ -1156,21 +1910,95 @@ impl
 
     fn handle_set_custom_mining_job_success(
         &mut self,
-        _m: SetCustomMiningJobSuccess,
+        m: SetCustomMiningJobSuccess,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        info!("SET CUSTOM MINIG JOB SUCCESS");
-        Ok(SendTo::None(None))
+        let downstream = self
+            .downstream_selector
+            .downstream_from_channel_id(m.channel_id)
+            .ok_or(Error::NoDownstreamsConnected)?;
+        // The request id was changed when the SetCustomMiningJob was relayed upstream
+        match self.request_id_mapper.remove_checked(m.request_id) {
+            Some(original_request_id) if original_request_id != m.request_id => {
+                let mut m = m.into_static();
+                m.request_id = original_request_id;
+                Ok(SendTo::RelayNewMessageToRemote(
+                    downstream,
+                    Mining::SetCustomMiningJobSuccess(m),
+                ))
+            }
+            _ => Ok(SendTo::RelaySameMessageToRemote(downstream)),
+        }
     }
 
     fn handle_set_custom_mining_job_error(
         &mut self,
-        _m: SetCustomMiningJobError,
+        m: SetCustomMiningJobError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("560")
+        let downstream = self
+            .downstream_selector
+            .downstream_from_channel_id(m.channel_id)
+            .ok_or(Error::NoDownstreamsConnected)?;
+        // The request id was changed when the SetCustomMiningJob was relayed upstream
+        match self.request_id_mapper.remove_checked(m.request_id) {
+            Some(original_request_id) if original_request_id != m.request_id => {
+                let mut m = m.into_static();
+                m.request_id = original_request_id;
+                Ok(SendTo::RelayNewMessageToRemote(
+                    downstream,
+                    Mining::SetCustomMiningJobError(m),
+                ))
+            }
+            _ => Ok(SendTo::RelaySameMessageToRemote(downstream)),
+        }
     }
 
-    fn handle_set_target(&mut self, _m: SetTarget) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("570")
+    fn handle_set_target(&mut self, m: SetTarget) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        if let ChannelKind::Extended(Some(factory)) = &mut self.channel_kind {
+            // The target of the extended channel that the proxy opened with upstream is the
+            // upstream target used by the factory to decide which shares must be relayed.
+            // Downstream targets do not change.
+            if m.channel_id == factory.get_this_channel_id() {
+                let mut new_target: Target = m.maximum_target.clone().into_static().into();
+                factory.set_target(&mut new_target);
+                self.set_channel_target(m.channel_id, &m.maximum_target);
+                self.last_probe_answer = Some((m.channel_id, Instant::now()));
+                return Ok(SendTo::None(None));
+            }
+        }
+
+        // Channel opened by a single downstream, either a standard or an extended one
+        if let Some(downstream) = self
+            .downstream_selector
+            .downstream_from_channel_id(m.channel_id)
+        {
+            self.set_channel_target(m.channel_id, &m.maximum_target);
+            return Ok(SendTo::RelaySameMessageToRemote(downstream));
+        }
+
+        // Group channel: every downstream in the group get the new target on its own channel
+        let downstreams = self
+            .downstream_selector
+            .get_downstreams_in_channel(m.channel_id)
+            .ok_or(Error::NoDownstreamsConnected)?;
+        let target: roles_logic_sv2::utils::Target = m.maximum_target.clone().into();
+        let mut res = vec![];
+        for downstream in downstreams {
+            let channel_id = match downstream.safe_lock(|d| d.get_channel().clone()).unwrap() {
+                Channel::DowntreamHomUpstreamGroup { channel_id, .. } => channel_id,
+                Channel::DowntreamHomUpstreamExtended { channel_id, .. } => channel_id,
+                Channel::DowntreamNonHomUpstreamExtended { .. } => m.channel_id,
+            };
+            self.channel_targets.insert(channel_id, target);
+            let message = SetTarget {
+                channel_id,
+                maximum_target: m.maximum_target.clone().into_static(),
+            };
+            res.push(SendTo::RelayNewMessageToRemote(
+                downstream.clone(),
+                Mining::SetTarget(message),
+            ));
+        }
+        Ok(SendTo::Multiple(res))
     }
 
     fn handle_reconnect(&mut self, _m: Reconnect) -> Result<SendTo<DownstreamMiningNode>, Error> {
@@ -1194,15 +2022,19 @@ pub async fn scan(
             let node = node.clone();
             let cloned = res.clone();
             task::spawn(async move {
-                if let Err(e) = UpstreamMiningNode::setup_flag_and_version(
-                    node.clone(),
-                    None,
-                    min_version,
-                    max_version,
-                )
-                .await
+                if let Err(e) =
+                    UpstreamMiningNode::try_setup(node.clone(), None, min_version, max_version)
+                        .await
                 {
-                    error!("{:?}", e)
+                    error!("{:?}", e);
+                    if !matches!(e, super::error::Error::SetupConnectionError(_)) {
+                        UpstreamMiningNode::reconnect_in_background(
+                            node,
+                            None,
+                            min_version,
+                            max_version,
+                        );
+                    }
                 } else {
                     cloned.safe_lock(|r| r.push(node.clone())).unwrap();
                 }
@@ -1216,11 +2048,11 @@ pub async fn scan(
 }
 
 impl IsUpstream<DownstreamMiningNode, ProxyRemoteSelector> for UpstreamMiningNode {
-    fn get_version(&self) -> u16 {
+    fn get_version(&self) -> ProtocolVersion {
         self.sv2_connection.unwrap().version
     }
 
-    fn get_flags(&self) -> u32 {
+    fn get_flags(&self) -> MiningFlags {
         self.sv2_connection.unwrap().setup_connection_flags
     }
 
@@ -1248,17 +2080,24 @@ impl IsMiningUpstream<DownstreamMiningNode, ProxyRemoteSelector> for UpstreamMin
         self.total_hash_rate += to_add;
     }
     fn get_opened_channels(&mut self) -> &mut Vec<UpstreamChannel> {
-        todo!()
+        &mut self.opened_channels
     }
-    fn update_channels(&mut self, _channel: UpstreamChannel) {
-        todo!()
+    fn update_channels(&mut self, channel: UpstreamChannel) {
+        // A channel recorded without its id could never be removed when closed, the proxy records
+        // its channels with `on_channel_opened`
+        warn!(
+            upstream_id = self.id,
+            "Ignored channel {:?} recorded without a channel id", channel
+        );
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use network_helpers_sv2::test_util::{AUTHORITY_PRIVATE_KEY, AUTHORITY_PUBLIC_KEY};
     use std::net::{IpAddr, Ipv4Addr};
+    use tracing_test::traced_test;
 
     #[test]
     fn new_upstream_minining_node() {
@@ -1282,6 +2121,8 @@ mod tests {
             None,
             100_000.0,
             false,
+            ReconnectBackoff::default(),
+            None,
         );
 
         assert_eq!(actual.id, id);
@@ -1304,4 +2145,1634 @@ mod tests {
         assert!(actual.channel_id_to_job_dispatcher.is_empty());
         assert_eq!(actual.request_id_mapper, RequestIdMapper::new());
     }
+
+    fn new_upstream(channel_kind: super::super::ChannelKind) -> UpstreamMiningNode {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        UpstreamMiningNode::new(
+            0,
+            address,
+            [0; 32],
+            channel_kind,
+            Arc::new(Mutex::new(GroupId::new())),
+            Arc::new(Mutex::new(Id::new())),
+            10.0,
+            None,
+            None,
+            100_000.0,
+            false,
+            ReconnectBackoff::default(),
+            None,
+        )
+    }
+
+    fn new_paired_downstream(id: u32, header_only: bool) -> Arc<Mutex<DownstreamMiningNode>> {
+        let (sender, receiver) = async_channel::bounded(10);
+        let mut downstream = DownstreamMiningNode::new(receiver, sender, id);
+        downstream.status = DownstreamMiningNodeStatus::Paired(
+            roles_logic_sv2::common_properties::CommonDownstreamData {
+                header_only,
+                work_selection: false,
+                version_rolling: false,
+            },
+        );
+        Arc::new(Mutex::new(downstream))
+    }
+
+    #[test]
+    fn open_extended_channel_success_is_relayed_downstream() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let downstream = new_paired_downstream(0, false);
+        let request_id = upstream.request_id_mapper.on_open_channel(7);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(request_id, downstream.clone());
+
+        let channel_id = 3;
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id,
+            channel_id,
+            target: [0; 32].into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 16].try_into().unwrap(),
+        };
+        match upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap()
+        {
+            SendTo::RelayNewMessageToRemote(
+                remote,
+                Mining::OpenExtendedMiningChannelSuccess(m),
+            ) => {
+                assert!(Arc::ptr_eq(&remote, &downstream));
+                assert_eq!(m.request_id, 7);
+                assert_eq!(m.channel_id, channel_id);
+            }
+            _ => panic!("OpenExtendedMiningChannelSuccess not relayed downstream"),
+        }
+
+        let tracked = upstream
+            .downstream_selector
+            .downstream_from_channel_id(channel_id)
+            .unwrap();
+        assert!(Arc::ptr_eq(&tracked, &downstream));
+        assert!(matches!(
+            upstream.channel_id_to_job_dispatcher.get(&channel_id),
+            Some(JobDispatcher::Extended)
+        ));
+        match downstream.safe_lock(|d| d.get_channel().clone()).unwrap() {
+            Channel::DowntreamNonHomUpstreamExtended { extended_ids, .. } => {
+                assert_eq!(extended_ids, vec![channel_id])
+            }
+            _ => panic!("Downstream channel not opened as extended"),
+        }
+    }
+
+    #[test]
+    #[traced_test]
+    fn open_extended_channel_success_emits_channel_id() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let downstream = new_paired_downstream(0, false);
+        let request_id = upstream.request_id_mapper.on_open_channel(7);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(request_id, downstream);
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id,
+            channel_id: 3,
+            target: [0; 32].into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 16].try_into().unwrap(),
+        };
+        upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap();
+
+        assert!(logs_contain("Opened extended channel for downstream"));
+        assert!(logs_contain("upstream_id=0"));
+        assert!(logs_contain("channel_id=3"));
+    }
+
+    #[test]
+    fn extended_job_is_relayed_unmodified_to_downstream_extended_channel() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let downstream = new_paired_downstream(0, false);
+        let request_id = upstream.request_id_mapper.on_open_channel(7);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(request_id, downstream.clone());
+        let channel_id = 3;
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id,
+            channel_id,
+            target: [0; 32].into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 16].try_into().unwrap(),
+        };
+        upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap();
+
+        let job = NewExtendedMiningJob {
+            channel_id,
+            job_id: 1,
+            min_ntime: binary_sv2::Sv2Option::new(None),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1; 32].into()].into(),
+            coinbase_tx_prefix: vec![2; 42].try_into().unwrap(),
+            coinbase_tx_suffix: vec![3; 20].try_into().unwrap(),
+        };
+        match upstream.handle_new_extended_mining_job(job).unwrap() {
+            SendTo::RelaySameMessageToRemote(remote) => {
+                assert!(Arc::ptr_eq(&remote, &downstream))
+            }
+            _ => panic!("NewExtendedMiningJob not relayed unmodified"),
+        }
+    }
+
+    #[test]
+    fn extended_job_for_a_dropped_downstream_extended_channel_is_an_error() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let channel_id = 3;
+        upstream
+            .channel_id_to_job_dispatcher
+            .insert(channel_id, JobDispatcher::Extended);
+
+        let job = NewExtendedMiningJob {
+            channel_id,
+            job_id: 1,
+            min_ntime: binary_sv2::Sv2Option::new(None),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1; 32].into()].into(),
+            coinbase_tx_prefix: vec![2; 42].try_into().unwrap(),
+            coinbase_tx_suffix: vec![3; 20].try_into().unwrap(),
+        };
+        assert!(matches!(
+            upstream.handle_new_extended_mining_job(job),
+            Err(Error::NoDownstreamsConnected)
+        ));
+    }
+
+    #[test]
+    fn extended_job_for_an_extended_channel_of_the_factory_is_an_error() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Extended);
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id: 0,
+            channel_id: 5,
+            target: [255; 32].into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 8].try_into().unwrap(),
+        };
+        upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap();
+        match &mut upstream.channel_kind {
+            ChannelKind::Extended(Some(factory)) => {
+                factory.new_extended_channel(0, 10_000.0, 8).unwrap();
+            }
+            _ => panic!("Factory not initialized"),
+        }
+
+        let job = NewExtendedMiningJob {
+            channel_id: 5,
+            job_id: 1,
+            min_ntime: binary_sv2::Sv2Option::new(None),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1; 32].into()].into(),
+            coinbase_tx_prefix: vec![2; 42].try_into().unwrap(),
+            coinbase_tx_suffix: vec![3; 20].try_into().unwrap(),
+        };
+        assert!(matches!(
+            upstream.handle_new_extended_mining_job(job),
+            Err(Error::UnexpectedMessage(
+                const_sv2::MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB
+            ))
+        ));
+    }
+
+    #[test]
+    fn non_hom_downstream_gets_standard_channel_from_group_upstream() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let downstream = new_paired_downstream(0, false);
+        let (request_id, channel_id, group_channel_id) = (0, 2, 1);
+        upstream.on_standard_channel_request(request_id, 1_000.0);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(request_id, downstream.clone());
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_success(request_id, group_channel_id, channel_id)
+            .unwrap();
+        let success = OpenStandardMiningChannelSuccess {
+            request_id: request_id.into(),
+            channel_id,
+            target: [0; 32].into(),
+            extranonce_prefix: vec![0; 8].try_into().unwrap(),
+            group_channel_id,
+        };
+        match upstream
+            .handle_open_standard_mining_channel_success(success, Some(downstream.clone()))
+            .unwrap()
+        {
+            SendTo::Multiple(messages) => match &messages[0] {
+                SendTo::RelaySameMessageToRemote(remote) => {
+                    assert!(Arc::ptr_eq(remote, &downstream))
+                }
+                _ => panic!("OpenStandardMiningChannelSuccess not relayed downstream"),
+            },
+            _ => panic!("OpenStandardMiningChannelSuccess not relayed downstream"),
+        }
+
+        match downstream.safe_lock(|d| d.get_channel().clone()).unwrap() {
+            Channel::DowntreamHomUpstreamGroup {
+                channel_id: id,
+                group_id,
+                ..
+            } => {
+                assert_eq!(id, channel_id);
+                assert_eq!(group_id, group_channel_id);
+            }
+            _ => panic!("Downstream channel not opened as standard"),
+        }
+        assert!(matches!(
+            upstream.channel_id_to_job_dispatcher.get(&group_channel_id),
+            Some(JobDispatcher::Group(_))
+        ));
+        assert_eq!(upstream.total_hash_rate, 1_000);
+    }
+
+    #[derive(Debug)]
+    struct CountingAccountant {
+        accepted: Arc<Mutex<Vec<(u32, u32, f64)>>>,
+        rejected: Arc<Mutex<Vec<(u32, String)>>>,
+    }
+
+    impl ShareAccountant for CountingAccountant {
+        fn on_accepted(&mut self, channel_id: u32, count: u32, difficulty: f64) {
+            self.accepted
+                .safe_lock(|a| a.push((channel_id, count, difficulty)))
+                .unwrap();
+        }
+        fn on_rejected(&mut self, channel_id: u32, reason: &str) {
+            self.rejected
+                .safe_lock(|r| r.push((channel_id, reason.to_string())))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn share_accountant_tallies_accepted_and_rejected_shares() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let accountant = CountingAccountant {
+            accepted: Arc::new(Mutex::new(vec![])),
+            rejected: Arc::new(Mutex::new(vec![])),
+        };
+        let accepted = accountant.accepted.clone();
+        let rejected = accountant.rejected.clone();
+        upstream.set_share_accountant(Box::new(accountant));
+
+        let downstream = new_paired_downstream(0, false);
+        let request_id = upstream.request_id_mapper.on_open_channel(7);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(request_id, downstream);
+        let channel_id = 3;
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1024.0);
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id,
+            channel_id,
+            target: target.to_le_bytes().into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 16].try_into().unwrap(),
+        };
+        upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap();
+
+        let success = SubmitSharesSuccess {
+            channel_id,
+            last_sequence_number: 1,
+            new_submits_accepted_count: 2,
+            new_shares_sum: 2048,
+        };
+        upstream.handle_submit_shares_success(success).unwrap();
+        let error = SubmitSharesError {
+            channel_id,
+            sequence_number: 2,
+            error_code: "difficulty-too-low"
+                .to_string()
+                .into_bytes()
+                .try_into()
+                .unwrap(),
+        };
+        upstream.handle_submit_shares_error(error).unwrap();
+        // Shares on a channel the proxy does not know the target of
+        let success = SubmitSharesSuccess {
+            channel_id: 9,
+            last_sequence_number: 3,
+            new_submits_accepted_count: 1,
+            new_shares_sum: 1,
+        };
+        upstream.handle_submit_shares_success(success).unwrap();
+
+        assert_eq!(
+            accepted.safe_lock(|a| a.clone()).unwrap(),
+            vec![(channel_id, 2, 1024.0), (9, 1, 0.0)]
+        );
+        assert_eq!(
+            rejected.safe_lock(|r| r.clone()).unwrap(),
+            vec![(channel_id, "difficulty-too-low".to_string())]
+        );
+    }
+
+    fn open_downstream_extended_channel(
+        upstream: &mut UpstreamMiningNode,
+        downstream: Arc<Mutex<DownstreamMiningNode>>,
+        channel_id: u32,
+        target: roles_logic_sv2::utils::Target,
+    ) {
+        let request_id = upstream.request_id_mapper.on_open_channel(channel_id);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(request_id, downstream);
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id,
+            channel_id,
+            target: target.to_le_bytes().into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 16].try_into().unwrap(),
+        };
+        upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap();
+    }
+
+    fn update_channel_error(channel_id: u32, error_code: &str) -> UpdateChannelError<'static> {
+        UpdateChannelError {
+            channel_id,
+            error_code: error_code.to_string().into_bytes().try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn update_channel_error_is_relayed_and_retried_with_clamped_target() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1024.0);
+        let downstream = new_paired_downstream(0, false);
+        open_downstream_extended_channel(&mut upstream, downstream.clone(), 3, target);
+        let other_downstream = new_paired_downstream(1, false);
+        open_downstream_extended_channel(&mut upstream, other_downstream, 4, target);
+
+        upstream.on_update_channel_request(UpdateChannel {
+            channel_id: 3,
+            nominal_hash_rate: 1_000.0,
+            maximum_target: [255; 32].into(),
+        });
+        let error = update_channel_error(3, "max-target-out-of-range");
+        match upstream.handle_update_channel_error(error.clone()).unwrap() {
+            SendTo::Multiple(messages) => {
+                assert_eq!(messages.len(), 2);
+                match &messages[0] {
+                    SendTo::RelaySameMessageToRemote(remote) => {
+                        assert!(Arc::ptr_eq(remote, &downstream))
+                    }
+                    _ => panic!("UpdateChannelError not relayed downstream"),
+                }
+                match &messages[1] {
+                    SendTo::Respond(Mining::UpdateChannel(m)) => {
+                        assert_eq!(m.channel_id, 3);
+                        let retried: roles_logic_sv2::utils::Target =
+                            m.maximum_target.clone().into();
+                        assert_eq!(retried, target);
+                    }
+                    _ => panic!("UpdateChannel not retried"),
+                }
+            }
+            _ => panic!("UpdateChannelError not relayed downstream"),
+        }
+
+        // The request is retried only once
+        match upstream.handle_update_channel_error(error).unwrap() {
+            SendTo::RelaySameMessageToRemote(remote) => assert!(Arc::ptr_eq(&remote, &downstream)),
+            _ => panic!("UpdateChannelError not relayed downstream"),
+        }
+    }
+
+    #[test]
+    fn fatal_update_channel_error_is_only_relayed() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1024.0);
+        let downstream = new_paired_downstream(0, false);
+        open_downstream_extended_channel(&mut upstream, downstream.clone(), 3, target);
+        upstream.on_update_channel_request(UpdateChannel {
+            channel_id: 3,
+            nominal_hash_rate: 1_000.0,
+            maximum_target: [255; 32].into(),
+        });
+
+        let error = update_channel_error(3, "invalid-channel-id");
+        match upstream.handle_update_channel_error(error).unwrap() {
+            SendTo::RelaySameMessageToRemote(remote) => assert!(Arc::ptr_eq(&remote, &downstream)),
+            _ => panic!("UpdateChannelError not relayed downstream"),
+        }
+        // The request is dropped with the fatal error, a later error is never retried
+        let error = update_channel_error(3, "max-target-out-of-range");
+        match upstream.handle_update_channel_error(error).unwrap() {
+            SendTo::RelaySameMessageToRemote(remote) => assert!(Arc::ptr_eq(&remote, &downstream)),
+            _ => panic!("UpdateChannel retried after a fatal error"),
+        }
+        let error = update_channel_error(5, "invalid-channel-id");
+        assert!(matches!(
+            upstream.handle_update_channel_error(error),
+            Err(Error::NoDownstreamsConnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_update_channel_retry_disconnects_upstream() {
+        // Nothing listens on the upstream address
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        upstream.address = address;
+        let (sender, receiver) = async_channel::bounded(10);
+        let mut downstream = DownstreamMiningNode::new(receiver.clone(), sender, 0);
+        downstream.status = DownstreamMiningNodeStatus::Paired(
+            roles_logic_sv2::common_properties::CommonDownstreamData {
+                header_only: false,
+                work_selection: false,
+                version_rolling: false,
+            },
+        );
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1024.0);
+        open_downstream_extended_channel(
+            &mut upstream,
+            Arc::new(Mutex::new(downstream)),
+            3,
+            target,
+        );
+        upstream.on_update_channel_request(UpdateChannel {
+            channel_id: 3,
+            nominal_hash_rate: 1_000.0,
+            maximum_target: [255; 32].into(),
+        });
+        let error = update_channel_error(3, "max-target-out-of-range");
+        let to_send = upstream.handle_update_channel_error(error.clone());
+        assert!(matches!(to_send, Ok(SendTo::Multiple(_))));
+        let upstream = Arc::new(Mutex::new(upstream));
+
+        UpstreamMiningNode::match_next_message(
+            upstream.clone(),
+            to_send,
+            received_frame(Mining::UpdateChannelError(error)),
+        )
+        .await;
+        // The error is relayed and the retry failing leaves the upstream disconnected
+        receiver.try_recv().unwrap();
+        assert!(upstream.safe_lock(|u| u.connection.is_none()).unwrap());
+    }
+
+    #[test]
+    fn open_mining_channel_error_is_routed_to_requesting_downstream() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let downstream = new_paired_downstream(0, true);
+        let request_id = upstream.request_id_mapper.on_open_channel(7);
+        upstream.on_standard_channel_request(request_id, 1_000.0);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(request_id, downstream.clone());
+
+        let error = OpenMiningChannelError::new_max_target_out_of_range(request_id);
+        match upstream.handle_open_mining_channel_error(error).unwrap() {
+            SendTo::RelayNewMessageToRemote(remote, Mining::OpenMiningChannelError(m)) => {
+                assert!(Arc::ptr_eq(&remote, &downstream));
+                assert_eq!(m.request_id, 7);
+                assert_eq!(m.error_code.as_ref(), b"max-target-out-of-range");
+            }
+            _ => panic!("OpenMiningChannelError not relayed downstream"),
+        }
+
+        // Nothing is left of the rejected request
+        assert!(upstream
+            .downstream_selector
+            .remove_request(request_id)
+            .is_none());
+        assert!(upstream
+            .request_id_mapper
+            .get_original(request_id)
+            .is_none());
+        assert!(upstream.requested_hash_rates.is_empty());
+        let error = OpenMiningChannelError::new_max_target_out_of_range(request_id);
+        assert!(matches!(
+            upstream.handle_open_mining_channel_error(error),
+            Err(Error::RequestIdNotMapped(id)) if id == request_id
+        ));
+    }
+
+    #[test]
+    fn set_extranonce_prefix_is_relayed_to_every_factory_channel() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Extended);
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id: 0,
+            channel_id: 5,
+            target: [255; 32].into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 8].try_into().unwrap(),
+        };
+        upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap();
+        let downstream = new_paired_downstream(0, true);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(0, downstream.clone());
+        let old_extranonce = match &upstream.open_standard_channel_down(0, 10_000.0, true, 7)[0] {
+            Mining::OpenStandardMiningChannelSuccess(m) => m.extranonce_prefix.to_vec(),
+            _ => panic!(),
+        };
+
+        let set_extranonce_prefix = SetExtranoncePrefix {
+            channel_id: 5,
+            extranonce_prefix: vec![9; 8].try_into().unwrap(),
+        };
+        let new_extranonce = [&[9; 8], &old_extranonce[8..]].concat();
+        match upstream
+            .handle_set_extranonce_prefix(set_extranonce_prefix)
+            .unwrap()
+        {
+            SendTo::Multiple(messages) => match &messages[..] {
+                [SendTo::RelayNewMessageToRemote(remote, Mining::SetExtranoncePrefix(m))] => {
+                    assert!(Arc::ptr_eq(remote, &downstream));
+                    assert_eq!(m.channel_id, 7);
+                    assert_eq!(m.extranonce_prefix.to_vec(), new_extranonce);
+                }
+                _ => panic!("SetExtranoncePrefix not relayed downstream"),
+            },
+            _ => panic!("SetExtranoncePrefix not relayed downstream"),
+        }
+
+        // The next job of the downstream commits to the new extranonce. Coinbase with a single
+        // input whose script is the 24 bytes extranonce and a single output
+        let coinbase_tx_prefix = [
+            &[1, 0, 0, 0, 1][..],
+            &[0; 32],
+            &[255; 4],
+            &[new_extranonce.len() as u8],
+        ]
+        .concat();
+        let coinbase_tx_suffix = [&[255; 4][..], &[1], &[0; 8], &[0], &[0; 4]].concat();
+        let merkle_path: Vec<Vec<u8>> = vec![vec![1; 32]];
+        let root_for = |extranonce: &[u8]| {
+            roles_logic_sv2::utils::merkle_root_from_path(
+                &coinbase_tx_prefix,
+                &coinbase_tx_suffix,
+                extranonce,
+                &merkle_path,
+            )
+            .unwrap()
+        };
+        let job = NewExtendedMiningJob {
+            channel_id: 5,
+            job_id: 1,
+            min_ntime: binary_sv2::Sv2Option::new(None),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1; 32].into()].into(),
+            coinbase_tx_prefix: coinbase_tx_prefix.clone().try_into().unwrap(),
+            coinbase_tx_suffix: coinbase_tx_suffix.clone().try_into().unwrap(),
+        };
+        match upstream.handle_new_extended_mining_job(job).unwrap() {
+            SendTo::Multiple(messages) => match &messages[..] {
+                [SendTo::RelayNewMessageToRemote(remote, Mining::NewMiningJob(m))] => {
+                    assert!(Arc::ptr_eq(remote, &downstream));
+                    assert_eq!(m.channel_id, 7);
+                    assert_eq!(m.merkle_root.to_vec(), root_for(&new_extranonce));
+                    assert_ne!(m.merkle_root.to_vec(), root_for(&old_extranonce));
+                }
+                _ => panic!("NewMiningJob not sent downstream"),
+            },
+            _ => panic!("NewMiningJob not sent downstream"),
+        }
+    }
+
+    #[test]
+    fn set_extranonce_prefix_for_downstream_channel_is_relayed() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1.0);
+        let downstream = new_paired_downstream(0, false);
+        open_downstream_extended_channel(&mut upstream, downstream.clone(), 3, target);
+
+        let set_extranonce_prefix = SetExtranoncePrefix {
+            channel_id: 3,
+            extranonce_prefix: vec![9; 16].try_into().unwrap(),
+        };
+        match upstream
+            .handle_set_extranonce_prefix(set_extranonce_prefix)
+            .unwrap()
+        {
+            SendTo::RelaySameMessageToRemote(remote) => assert!(Arc::ptr_eq(&remote, &downstream)),
+            _ => panic!("SetExtranoncePrefix not relayed downstream"),
+        }
+    }
+
+    // Frame as read from the upstream connection, `payload` can only be called on those
+    fn received_frame(message: Mining<'static>) -> StdFrame {
+        let frame: StdFrame = PoolMessages::Mining(message).try_into().unwrap();
+        let mut bytes = vec![0; frame.encoded_length()];
+        frame.serialize(&mut bytes).unwrap();
+        StdFrame::from_bytes(bytes.into()).unwrap()
+    }
+
+    #[test]
+    fn relay_only_path_routes_as_message_handlers() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1.0);
+        let downstreams = vec![
+            new_paired_downstream(0, false),
+            new_paired_downstream(1, false),
+        ];
+        open_downstream_extended_channel(&mut upstream, downstreams[0].clone(), 3, target);
+        open_downstream_extended_channel(&mut upstream, downstreams[1].clone(), 4, target);
+
+        for (channel_id, downstream) in [(3, &downstreams[0]), (4, &downstreams[1])] {
+            let job = NewExtendedMiningJob {
+                channel_id,
+                job_id: 1,
+                min_ntime: binary_sv2::Sv2Option::new(None),
+                version: 0x2000_0000,
+                version_rolling_allowed: true,
+                merkle_path: vec![[1; 32].into()].into(),
+                coinbase_tx_prefix: vec![2; 42].try_into().unwrap(),
+                coinbase_tx_suffix: vec![3; 20].try_into().unwrap(),
+            };
+            let mut frame = received_frame(Mining::NewExtendedMiningJob(job.clone()));
+            let message_type = frame.get_header().unwrap().msg_type();
+            let relay_only = upstream
+                .relay_only_downstream(message_type, frame.payload())
+                .unwrap();
+            assert!(Arc::ptr_eq(&relay_only, downstream));
+            match upstream.handle_new_extended_mining_job(job).unwrap() {
+                SendTo::RelaySameMessageToRemote(remote) => {
+                    assert!(Arc::ptr_eq(&remote, &relay_only))
+                }
+                _ => panic!("NewExtendedMiningJob not relayed unmodified"),
+            }
+
+            let set_extranonce_prefix = SetExtranoncePrefix {
+                channel_id,
+                extranonce_prefix: vec![9; 16].try_into().unwrap(),
+            };
+            let mut frame =
+                received_frame(Mining::SetExtranoncePrefix(set_extranonce_prefix.clone()));
+            let message_type = frame.get_header().unwrap().msg_type();
+            let relay_only = upstream
+                .relay_only_downstream(message_type, frame.payload())
+                .unwrap();
+            assert!(Arc::ptr_eq(&relay_only, downstream));
+            match upstream
+                .handle_set_extranonce_prefix(set_extranonce_prefix)
+                .unwrap()
+            {
+                SendTo::RelaySameMessageToRemote(remote) => {
+                    assert!(Arc::ptr_eq(&remote, &relay_only))
+                }
+                _ => panic!("SetExtranoncePrefix not relayed downstream"),
+            }
+        }
+        // Both paths count the relayed jobs
+        assert_eq!(upstream.jobs_relayed, 4);
+
+        // Messages that are not relayed as they are go through the message handlers
+        let close_channel = CloseChannel {
+            channel_id: 3,
+            reason_code: "".to_string().try_into().unwrap(),
+        };
+        let mut frame = received_frame(Mining::CloseChannel(close_channel));
+        let message_type = frame.get_header().unwrap().msg_type();
+        assert!(upstream
+            .relay_only_downstream(message_type, frame.payload())
+            .is_none());
+    }
+
+    #[test]
+    fn stats_track_opened_channels_and_relayed_jobs() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        assert_eq!(
+            upstream.stats(),
+            Stats {
+                total_hash_rate: 0,
+                open_channels: 0,
+                downstream_count: 0,
+                jobs_relayed: 0,
+            }
+        );
+
+        let group_channel_id = 1;
+        for (request_id, channel_id, nominal_hash_rate) in [(0, 2, 1_000.0), (1, 3, 2_000.0)] {
+            let downstream = new_paired_downstream(channel_id, true);
+            upstream.on_standard_channel_request(request_id, nominal_hash_rate);
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_request(request_id, downstream.clone());
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_success(request_id, group_channel_id, channel_id)
+                .unwrap();
+            let success = OpenStandardMiningChannelSuccess {
+                request_id: request_id.into(),
+                channel_id,
+                target: [0; 32].into(),
+                extranonce_prefix: vec![0; 8].try_into().unwrap(),
+                group_channel_id,
+            };
+            upstream
+                .handle_open_standard_mining_channel_success(success, Some(downstream))
+                .unwrap();
+        }
+        // The two standard channels, the group channel that contains them is not counted
+        assert_eq!(
+            upstream.stats(),
+            Stats {
+                total_hash_rate: 3_000,
+                open_channels: 2,
+                downstream_count: 2,
+                jobs_relayed: 0,
+            }
+        );
+
+        let downstream = new_paired_downstream(4, false);
+        let request_id = upstream.request_id_mapper.on_open_channel(7);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(request_id, downstream);
+        let channel_id = 5;
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id,
+            channel_id,
+            target: [0; 32].into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 16].try_into().unwrap(),
+        };
+        upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap();
+        let job = NewExtendedMiningJob {
+            channel_id,
+            job_id: 1,
+            min_ntime: binary_sv2::Sv2Option::new(None),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1; 32].into()].into(),
+            coinbase_tx_prefix: vec![2; 42].try_into().unwrap(),
+            coinbase_tx_suffix: vec![3; 20].try_into().unwrap(),
+        };
+        upstream.handle_new_extended_mining_job(job).unwrap();
+        assert_eq!(
+            upstream.stats(),
+            Stats {
+                total_hash_rate: 3_000,
+                open_channels: 3,
+                downstream_count: 3,
+                jobs_relayed: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn stats_forget_closed_channels_and_are_reset_on_reconnect() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let group_channel_id = 1;
+        for (request_id, channel_id, nominal_hash_rate) in [(0, 2, 1_000.0), (1, 3, 2_000.0)] {
+            let downstream = new_paired_downstream(channel_id, true);
+            upstream.on_standard_channel_request(request_id, nominal_hash_rate);
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_request(request_id, downstream.clone());
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_success(request_id, group_channel_id, channel_id)
+                .unwrap();
+            let success = OpenStandardMiningChannelSuccess {
+                request_id: request_id.into(),
+                channel_id,
+                target: [0; 32].into(),
+                extranonce_prefix: vec![0; 8].try_into().unwrap(),
+                group_channel_id,
+            };
+            upstream
+                .handle_open_standard_mining_channel_success(success, Some(downstream))
+                .unwrap();
+        }
+        let close_channel = |channel_id: u32| CloseChannel {
+            channel_id,
+            reason_code: "".to_string().try_into().unwrap(),
+        };
+
+        upstream.handle_close_channel(close_channel(2)).unwrap();
+        assert_eq!(
+            upstream.stats(),
+            Stats {
+                total_hash_rate: 2_000,
+                open_channels: 1,
+                downstream_count: 1,
+                jobs_relayed: 0,
+            }
+        );
+        // Closing it again does not subtract its hash rate twice
+        assert!(upstream.handle_close_channel(close_channel(2)).is_err());
+        assert_eq!(upstream.stats().total_hash_rate, 2_000);
+
+        // The last channel in the group closes the group too
+        upstream.handle_close_channel(close_channel(3)).unwrap();
+        assert_eq!(upstream.stats().total_hash_rate, 0);
+        assert!(upstream.opened_channels.is_empty());
+
+        // Channels are opened again after a reconnection, the old ones must not be counted
+        let downstream = new_paired_downstream(4, false);
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1.0);
+        upstream.on_standard_channel_request(7, 5_000.0);
+        open_downstream_extended_channel(&mut upstream, downstream, 5, target);
+        assert_eq!(upstream.stats().open_channels, 1);
+        upstream.reset_channels();
+        assert_eq!(
+            upstream.stats(),
+            Stats {
+                total_hash_rate: 0,
+                open_channels: 0,
+                downstream_count: 1,
+                jobs_relayed: 0,
+            }
+        );
+        assert!(upstream.requested_hash_rates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reconnects_with_backoff_until_upstream_accepts() {
+        use codec_sv2::Responder;
+        use roles_logic_sv2::common_messages_sv2::SetupConnectionSuccess;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // Drop the first two connections, then answer the SetupConnection
+        task::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                drop(stream);
+            }
+            let (stream, _) = listener.accept().await.unwrap();
+            let responder = Responder::from_authority_kp(
+                &AUTHORITY_PUBLIC_KEY,
+                AUTHORITY_PRIVATE_KEY,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+            let ConnectionParts {
+                receiver, sender, ..
+            } = Connection::new::<Message>(stream, HandshakeRole::Responder(responder))
+                .await
+                .unwrap();
+            receiver.recv().await.unwrap();
+            let success = PoolMessages::Common(CommonMessages::SetupConnectionSuccess(
+                SetupConnectionSuccess {
+                    used_version: 2,
+                    flags: 0,
+                },
+            ));
+            let frame: StdFrame = success.try_into().unwrap();
+            sender.send(frame.into()).await.unwrap();
+            // Keep the connection open until the test ends
+            receiver.recv().await.ok();
+        });
+
+        let base = Duration::from_millis(100);
+        let mut upstream = UpstreamMiningNode::new(
+            0,
+            address,
+            AUTHORITY_PUBLIC_KEY,
+            super::super::ChannelKind::Group,
+            Arc::new(Mutex::new(GroupId::new())),
+            Arc::new(Mutex::new(Id::new())),
+            10.0,
+            None,
+            None,
+            100_000.0,
+            false,
+            ReconnectBackoff::new(base, Duration::from_secs(1)),
+            None,
+        );
+        let (status_sender, status_receiver) = async_channel::unbounded();
+        upstream.set_status_sender(status_sender);
+        let upstream = Arc::new(Mutex::new(upstream));
+
+        let start = std::time::Instant::now();
+        let mut attempts = vec![];
+        for _ in 0..3 {
+            attempts.push(
+                UpstreamMiningNode::try_setup(upstream.clone(), None, 2, 2)
+                    .await
+                    .is_ok(),
+            );
+        }
+        assert_eq!(attempts, vec![false, false, true]);
+        // No delay before the first attempt, then base and 2 * base
+        assert!(start.elapsed() >= base * 3);
+        // Each attempt after a failure is reported on the status channel with its delay
+        let delays: Vec<_> = std::iter::from_fn(|| status_receiver.try_recv().ok())
+            .map(|status| match status {
+                super::super::status::Status::Reconnecting {
+                    upstream_id: 0,
+                    address: a,
+                    delay,
+                } if a == address => delay,
+                status => panic!("unexpected status {:?}", status),
+            })
+            .collect();
+        assert_eq!(delays, vec![base, base * 2]);
+        // The backoff is only reset once the connection stays up for the minimum uptime
+        assert_eq!(
+            upstream.safe_lock(|u| u.reconnect_backoff.delay()).unwrap(),
+            base * 2
+        );
+        assert!(upstream.safe_lock(|u| u.sv2_connection.is_some()).unwrap());
+    }
+
+    // Upstream that does the handshake, then reads everything the proxy sends without ever
+    // answering. Returns its address and the type and payload of each frame it receives.
+    async fn silent_upstream() -> (SocketAddr, Receiver<(u8, Vec<u8>)>) {
+        use codec_sv2::Responder;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (received_sender, received) = async_channel::unbounded();
+        task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let responder = Responder::from_authority_kp(
+                &AUTHORITY_PUBLIC_KEY,
+                AUTHORITY_PRIVATE_KEY,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+            let ConnectionParts {
+                receiver,
+                sender: _sender,
+                ..
+            } = Connection::new::<Message>(stream, HandshakeRole::Responder(responder))
+                .await
+                .unwrap();
+            while let Ok(frame) = receiver.recv().await {
+                let mut frame: StdFrame = frame.try_into().unwrap();
+                let message_type = frame.get_header().unwrap().msg_type();
+                let payload = frame.payload().to_vec();
+                received_sender.send((message_type, payload)).await.unwrap();
+            }
+        });
+        (address, received)
+    }
+
+    fn upstream_with_health_check(
+        address: SocketAddr,
+        channel_kind: super::super::ChannelKind,
+    ) -> UpstreamMiningNode {
+        let health_check = HealthCheck {
+            interval: Duration::from_millis(100),
+            deadline: Duration::from_millis(100),
+        };
+        let mut upstream = UpstreamMiningNode::new(
+            0,
+            address,
+            AUTHORITY_PUBLIC_KEY,
+            channel_kind,
+            Arc::new(Mutex::new(GroupId::new())),
+            Arc::new(Mutex::new(Id::new())),
+            10.0,
+            None,
+            None,
+            100_000.0,
+            true,
+            ReconnectBackoff::default(),
+            Some(health_check),
+        );
+        upstream.sv2_connection = Some(Sv2MiningConnection {
+            version: 2.into(),
+            setup_connection_flags: MiningFlags::empty(),
+        });
+        upstream
+    }
+
+    // Upstream that does the handshake, then answers every `UpdateChannel` with a `SetTarget` for
+    // `channel_id` that repeats the maximum target of the request
+    async fn answering_upstream(channel_id: u32) -> SocketAddr {
+        use codec_sv2::Responder;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let responder = Responder::from_authority_kp(
+                &AUTHORITY_PUBLIC_KEY,
+                AUTHORITY_PRIVATE_KEY,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+            let ConnectionParts {
+                receiver, sender, ..
+            } = Connection::new::<Message>(stream, HandshakeRole::Responder(responder))
+                .await
+                .unwrap();
+            while let Ok(frame) = receiver.recv().await {
+                let mut frame: StdFrame = frame.try_into().unwrap();
+                let message_type = frame.get_header().unwrap().msg_type();
+                if let Ok(Mining::UpdateChannel(m)) =
+                    Mining::try_from((message_type, frame.payload()))
+                {
+                    let answer = PoolMessages::Mining(Mining::SetTarget(SetTarget {
+                        channel_id,
+                        maximum_target: m.maximum_target.into_static(),
+                    }));
+                    let answer: StdFrame = answer.try_into().unwrap();
+                    if sender.send(answer.into()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        address
+    }
+
+    // Connects to the upstream at `address` with the extended channel 1 of target `target` opened
+    // by the proxy, the channel that gets probed
+    async fn probed_upstream(
+        address: SocketAddr,
+        target: roles_logic_sv2::utils::Target,
+    ) -> Arc<Mutex<UpstreamMiningNode>> {
+        let mut upstream = upstream_with_health_check(address, super::super::ChannelKind::Extended);
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id: 0,
+            channel_id: 1,
+            target: target.to_le_bytes().into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 8].try_into().unwrap(),
+        };
+        upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap();
+        let upstream = Arc::new(Mutex::new(upstream));
+        UpstreamMiningNode::connect(upstream.clone()).await.unwrap();
+        let receiver = upstream
+            .safe_lock(|u| u.connection.clone().unwrap().receiver)
+            .unwrap();
+        UpstreamMiningNode::relay_incoming_messages(upstream.clone(), receiver);
+        upstream
+    }
+
+    async fn wait_for_disconnection(upstream: &Arc<Mutex<UpstreamMiningNode>>) {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while upstream.safe_lock(|u| u.connection.is_some()).unwrap() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the upstream should have been marked unhealthy");
+    }
+
+    #[tokio::test]
+    async fn upstream_that_stops_answering_is_marked_unhealthy() {
+        let (address, received) = silent_upstream().await;
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1.0);
+        let upstream = probed_upstream(address, target).await;
+
+        // The probe repeats the channel's hash rate and target so that it does not change it
+        let (message_type, mut payload) = received.recv().await.unwrap();
+        match Mining::try_from((message_type, payload.as_mut_slice())).unwrap() {
+            Mining::UpdateChannel(m) => {
+                assert_eq!(m.channel_id, 1);
+                assert_eq!(m.nominal_hash_rate, 100_000.0);
+                let maximum_target: roles_logic_sv2::utils::Target = m.maximum_target.into();
+                assert_eq!(maximum_target, target);
+            }
+            _ => panic!("The health probe must be an UpdateChannel"),
+        }
+        wait_for_disconnection(&upstream).await;
+    }
+
+    #[tokio::test]
+    async fn upstream_answering_the_probe_stays_healthy() {
+        let address = answering_upstream(1).await;
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1.0);
+        let upstream = probed_upstream(address, target).await;
+
+        // Several health check intervals
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(upstream.safe_lock(|u| u.connection.is_some()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn set_target_on_another_channel_does_not_answer_the_probe() {
+        let address = answering_upstream(2).await;
+        let target = roles_logic_sv2::utils::Target::from_difficulty(1.0);
+        let upstream = probed_upstream(address, target).await;
+
+        wait_for_disconnection(&upstream).await;
+    }
+
+    #[tokio::test]
+    async fn group_upstream_is_not_probed() {
+        let (address, received) = silent_upstream().await;
+        let upstream = upstream_with_health_check(address, super::super::ChannelKind::Group);
+        assert!(upstream.health_check.is_none());
+        let upstream = Arc::new(Mutex::new(upstream));
+        UpstreamMiningNode::connect(upstream.clone()).await.unwrap();
+        let receiver = upstream
+            .safe_lock(|u| u.connection.clone().unwrap().receiver)
+            .unwrap();
+        UpstreamMiningNode::relay_incoming_messages(upstream.clone(), receiver);
+
+        // Several health check intervals without probes nor disconnection
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(received.is_empty());
+        assert!(upstream.safe_lock(|u| u.connection.is_some()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn pinned_upstream_static_key_is_enforced() {
+        use codec_sv2::Responder;
+        use tokio::net::TcpListener;
+
+        // Upstream with a static key signed by the authority, returns the key and its address
+        let upstream = || async {
+            let responder = Responder::from_authority_kp(
+                &AUTHORITY_PUBLIC_KEY,
+                AUTHORITY_PRIVATE_KEY,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+            let static_key = key_utils::Secp256k1PublicKey(responder.static_public_key());
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            task::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                if let Ok(ConnectionParts {
+                    receiver,
+                    sender: _sender,
+                    ..
+                }) =
+                    Connection::new::<Message>(stream, HandshakeRole::Responder(responder)).await
+                {
+                    receiver.recv().await.ok();
+                }
+            });
+            (static_key, address)
+        };
+
+        let (static_key, address) = upstream().await;
+        assert!(
+            UpstreamMiningNode::connect_(address, AUTHORITY_PUBLIC_KEY, Some(static_key))
+                .await
+                .is_ok()
+        );
+
+        let (other_key, _) = upstream().await;
+        let (_, address) = upstream().await;
+        match UpstreamMiningNode::connect_(address, AUTHORITY_PUBLIC_KEY, Some(other_key)).await {
+            Err(super::super::error::Error::StaticKeyMismatch(a)) => assert_eq!(a, address),
+            other => panic!("expected a static key mismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn set_custom_mining_job_success_is_routed_to_originating_downstream() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let mut downstreams = vec![];
+        for (request_id, channel_id) in [(0, 2), (1, 3)] {
+            let downstream = new_paired_downstream(channel_id, false);
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_request(request_id, downstream.clone());
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_success(request_id, channel_id, channel_id)
+                .unwrap();
+            downstreams.push(downstream);
+        }
+        // The second downstream sent a SetCustomMiningJob with request id 5
+        let request_id = upstream.request_id_mapper.on_open_channel(5);
+
+        let success = SetCustomMiningJobSuccess {
+            channel_id: 3,
+            request_id,
+            job_id: 9,
+        };
+        match upstream
+            .handle_set_custom_mining_job_success(success)
+            .unwrap()
+        {
+            SendTo::RelayNewMessageToRemote(remote, Mining::SetCustomMiningJobSuccess(m)) => {
+                assert!(Arc::ptr_eq(&remote, &downstreams[1]));
+                assert_eq!(m.request_id, 5);
+                assert_eq!(m.job_id, 9);
+            }
+            _ => panic!("SetCustomMiningJobSuccess not routed to the originating downstream"),
+        }
+    }
+
+    fn set_custom_mining_job(channel_id: u32, request_id: u32) -> SetCustomMiningJob<'static> {
+        SetCustomMiningJob {
+            channel_id,
+            request_id,
+            token: vec![1; 8].try_into().unwrap(),
+            version: 0x2000_0000,
+            prev_hash: [2; 32].into(),
+            min_ntime: 0,
+            nbits: 0x1d00_ffff,
+            coinbase_tx_version: 2,
+            coinbase_prefix: vec![3; 4].try_into().unwrap(),
+            coinbase_tx_input_n_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 5_000_000_000,
+            coinbase_tx_outputs: vec![].try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: vec![].into(),
+            extranonce_size: 16,
+        }
+    }
+
+    #[test]
+    fn set_custom_mining_job_goes_from_downstream_to_upstream_and_back() {
+        use roles_logic_sv2::{
+            handlers::mining::ParseDownstreamMiningMessages, routing_logic::MiningRoutingLogic,
+        };
+
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        upstream.sv2_connection = Some(Sv2MiningConnection {
+            version: 2.into(),
+            setup_connection_flags: MiningFlags::REQUIRES_WORK_SELECTION,
+        });
+        let (request_id, group_channel_id, channel_id) = (0, 1, 3);
+        let downstream = new_paired_downstream(0, false);
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_request(request_id, downstream.clone());
+        upstream
+            .downstream_selector
+            .on_open_standard_channel_success(request_id, group_channel_id, channel_id)
+            .unwrap();
+        let upstream = Arc::new(Mutex::new(upstream));
+        downstream
+            .safe_lock(|d| {
+                d.open_channel_for_down_hom_up_group(channel_id, group_channel_id);
+                d.upstream = Some(upstream.clone());
+            })
+            .unwrap();
+
+        // The downstream sends a custom job with request id 5
+        let relayed = match DownstreamMiningNode::handle_message_mining_deserialized(
+            downstream.clone(),
+            Ok(Mining::SetCustomMiningJob(set_custom_mining_job(
+                channel_id, 5,
+            ))),
+            MiningRoutingLogic::None,
+        )
+        .unwrap()
+        {
+            SendTo::RelayNewMessageToRemote(remote, Mining::SetCustomMiningJob(m)) => {
+                assert!(Arc::ptr_eq(&remote, &upstream));
+                m
+            }
+            _ => panic!("SetCustomMiningJob not relayed upstream"),
+        };
+
+        let success = SetCustomMiningJobSuccess {
+            channel_id,
+            request_id: relayed.request_id,
+            job_id: 9,
+        };
+        match UpstreamMiningNode::handle_message_mining_deserialized(
+            upstream,
+            Ok(Mining::SetCustomMiningJobSuccess(success)),
+            MiningRoutingLogic::None,
+        )
+        .unwrap()
+        {
+            SendTo::RelayNewMessageToRemote(remote, Mining::SetCustomMiningJobSuccess(m)) => {
+                assert!(Arc::ptr_eq(&remote, &downstream));
+                assert_eq!(m.request_id, 5);
+                assert_eq!(m.job_id, 9);
+            }
+            _ => panic!("SetCustomMiningJobSuccess not relayed downstream"),
+        }
+    }
+
+    #[test]
+    fn set_custom_mining_job_is_rejected_without_work_selection() {
+        use roles_logic_sv2::{
+            handlers::mining::ParseDownstreamMiningMessages, routing_logic::MiningRoutingLogic,
+        };
+
+        let upstream = Arc::new(Mutex::new(new_upstream(super::super::ChannelKind::Group)));
+        let downstream = new_paired_downstream(0, false);
+        downstream
+            .safe_lock(|d| {
+                d.open_channel_for_down_hom_up_group(3, 1);
+                d.upstream = Some(upstream.clone());
+            })
+            .unwrap();
+
+        assert!(matches!(
+            DownstreamMiningNode::handle_message_mining_deserialized(
+                downstream,
+                Ok(Mining::SetCustomMiningJob(set_custom_mining_job(3, 5))),
+                MiningRoutingLogic::None,
+            ),
+            Err(Error::UnexpectedMessage(
+                const_sv2::MESSAGE_TYPE_SET_CUSTOM_MINING_JOB
+            ))
+        ));
+    }
+
+    #[test]
+    fn work_selection_follows_setup_connection_flags() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        assert!(!upstream.is_work_selection_enabled());
+
+        upstream.sv2_connection = Some(Sv2MiningConnection {
+            version: 2.into(),
+            setup_connection_flags: MiningFlags::REQUIRES_WORK_SELECTION,
+        });
+        assert!(upstream.is_work_selection_enabled());
+
+        upstream.sv2_connection = Some(Sv2MiningConnection {
+            version: 2.into(),
+            setup_connection_flags: MiningFlags::REQUIRES_VERSION_ROLLING,
+        });
+        assert!(!upstream.is_work_selection_enabled());
+    }
+
+    #[test]
+    fn set_target_is_relayed_to_every_channel_in_group() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let group_channel_id = 1;
+        let mut downstreams = vec![];
+        for (request_id, channel_id) in [(0, 2), (1, 3)] {
+            let downstream = new_paired_downstream(channel_id, true);
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_request(request_id, downstream.clone());
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_success(request_id, group_channel_id, channel_id)
+                .unwrap();
+            downstream
+                .safe_lock(|d| d.open_channel_for_down_hom_up_group(channel_id, group_channel_id))
+                .unwrap();
+            downstreams.push((downstream, channel_id));
+        }
+        let maximum_target: binary_sv2::U256 = [1; 32].into();
+
+        let set_target = SetTarget {
+            channel_id: group_channel_id,
+            maximum_target: maximum_target.clone(),
+        };
+        match upstream.handle_set_target(set_target).unwrap() {
+            SendTo::Multiple(sends) => {
+                assert_eq!(sends.len(), downstreams.len());
+                for send in sends {
+                    match send {
+                        SendTo::RelayNewMessageToRemote(remote, Mining::SetTarget(m)) => {
+                            let (_, channel_id) = downstreams
+                                .iter()
+                                .find(|(d, _)| Arc::ptr_eq(d, &remote))
+                                .unwrap();
+                            assert_eq!(m.channel_id, *channel_id);
+                            assert_eq!(m.maximum_target, maximum_target);
+                        }
+                        _ => panic!("SetTarget not relayed downstream"),
+                    }
+                }
+            }
+            _ => panic!("SetTarget not relayed downstream"),
+        }
+
+        let set_target = SetTarget {
+            channel_id: 3,
+            maximum_target,
+        };
+        match upstream.handle_set_target(set_target).unwrap() {
+            SendTo::RelaySameMessageToRemote(remote) => {
+                assert!(Arc::ptr_eq(&remote, &downstreams[1].0))
+            }
+            _ => panic!("SetTarget not relayed to the channel owner"),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn closed_downstream_is_dropped_and_others_still_get_the_message() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let group_channel_id = 1;
+        let mut downstreams = vec![];
+        for (request_id, channel_id) in [(0, 2), (1, 3), (2, 4)] {
+            let (sender, receiver) = async_channel::bounded(10);
+            let mut downstream = DownstreamMiningNode::new(receiver.clone(), sender, channel_id);
+            downstream.status = DownstreamMiningNodeStatus::Paired(
+                roles_logic_sv2::common_properties::CommonDownstreamData {
+                    header_only: true,
+                    work_selection: false,
+                    version_rolling: false,
+                },
+            );
+            let downstream = Arc::new(Mutex::new(downstream));
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_request(request_id, downstream.clone());
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_success(request_id, group_channel_id, channel_id)
+                .unwrap();
+            downstream
+                .safe_lock(|d| d.open_channel_for_down_hom_up_group(channel_id, group_channel_id))
+                .unwrap();
+            downstreams.push((downstream, receiver));
+        }
+        // The connection with the second downstream is gone
+        downstreams[1].1.close();
+
+        let set_target = SetTarget {
+            channel_id: group_channel_id,
+            maximum_target: [1; 32].into(),
+        };
+        let to_send = upstream.handle_set_target(set_target.clone());
+        let upstream = Arc::new(Mutex::new(upstream));
+        UpstreamMiningNode::match_next_message(
+            upstream.clone(),
+            to_send,
+            received_frame(Mining::SetTarget(set_target)),
+        )
+        .await;
+
+        assert!(downstreams[0].1.try_recv().is_ok());
+        assert!(downstreams[2].1.try_recv().is_ok());
+        let remaining = upstream
+            .safe_lock(|u| {
+                u.downstream_selector
+                    .get_downstreams_in_channel(group_channel_id)
+                    .unwrap()
+                    .clone()
+            })
+            .unwrap();
+        assert_eq!(remaining.len(), 2);
+        assert!(!remaining.iter().any(|d| Arc::ptr_eq(d, &downstreams[1].0)));
+        assert!(upstream
+            .safe_lock(|u| u.downstream_selector.downstream_from_channel_id(3))
+            .unwrap()
+            .is_none());
+        assert!(logs_contain("downstream_id=3"));
+        assert!(logs_contain("downstream dropped"));
+    }
+
+    #[test]
+    fn set_target_for_proxy_extended_channel_is_not_relayed() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Extended);
+        let channel_id = 5;
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id: 0,
+            channel_id,
+            target: [0; 32].into(),
+            extranonce_size: 16,
+            extranonce_prefix: vec![0; 8].try_into().unwrap(),
+        };
+        upstream
+            .handle_open_extended_mining_channel_success(success)
+            .unwrap();
+
+        let set_target = SetTarget {
+            channel_id,
+            maximum_target: [255; 32].into(),
+        };
+        assert!(matches!(
+            upstream.handle_set_target(set_target).unwrap(),
+            SendTo::None(None)
+        ));
+
+        let set_target = SetTarget {
+            channel_id: channel_id + 1,
+            maximum_target: [255; 32].into(),
+        };
+        assert!(matches!(
+            upstream.handle_set_target(set_target),
+            Err(Error::NoDownstreamsConnected)
+        ));
+    }
+
+    #[test]
+    fn close_channel_drops_group_dispatcher_with_last_channel() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let group_channel_id = 1;
+        let mut downstreams = vec![];
+        for (request_id, channel_id) in [(0, 2), (1, 3)] {
+            let downstream = new_paired_downstream(channel_id, true);
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_request(request_id, downstream.clone());
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_success(request_id, group_channel_id, channel_id)
+                .unwrap();
+            let success = OpenStandardMiningChannelSuccess {
+                request_id: request_id.into(),
+                channel_id,
+                target: [0; 32].into(),
+                extranonce_prefix: vec![0; 8].try_into().unwrap(),
+                group_channel_id,
+            };
+            upstream
+                .handle_open_standard_mining_channel_success(success, Some(downstream.clone()))
+                .unwrap();
+            downstreams.push(downstream);
+        }
+        assert!(matches!(
+            upstream.channel_id_to_job_dispatcher.get(&group_channel_id),
+            Some(JobDispatcher::Group(_))
+        ));
+
+        let close = CloseChannel {
+            channel_id: 2,
+            reason_code: "".to_string().try_into().unwrap(),
+        };
+        match upstream.handle_close_channel(close).unwrap() {
+            SendTo::RelaySameMessageToRemote(remote) => {
+                assert!(Arc::ptr_eq(&remote, &downstreams[0]))
+            }
+            _ => panic!("CloseChannel not relayed downstream"),
+        }
+        assert!(upstream
+            .downstream_selector
+            .downstream_from_channel_id(2)
+            .is_none());
+        assert!(upstream
+            .channel_id_to_job_dispatcher
+            .contains_key(&group_channel_id));
+
+        let close = CloseChannel {
+            channel_id: 3,
+            reason_code: "".to_string().try_into().unwrap(),
+        };
+        match upstream.handle_close_channel(close).unwrap() {
+            SendTo::RelaySameMessageToRemote(remote) => {
+                assert!(Arc::ptr_eq(&remote, &downstreams[1]))
+            }
+            _ => panic!("CloseChannel not relayed downstream"),
+        }
+        assert!(upstream
+            .downstream_selector
+            .get_downstreams_in_channel(group_channel_id)
+            .is_none());
+        assert!(upstream.channel_id_to_job_dispatcher.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_every_open_channel() {
+        let mut upstream = new_upstream(super::super::ChannelKind::Group);
+        let group_channel_id = 1;
+        for (request_id, channel_id) in [(0, 2), (1, 3)] {
+            let downstream = new_paired_downstream(channel_id, true);
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_request(request_id, downstream.clone());
+            upstream
+                .downstream_selector
+                .on_open_standard_channel_success(request_id, group_channel_id, channel_id)
+                .unwrap();
+            downstream
+                .safe_lock(|d| d.open_channel_for_down_hom_up_group(channel_id, group_channel_id))
+                .unwrap();
+        }
+        let (sender, to_upstream) = async_channel::bounded(10);
+        let (_from_upstream_sender, receiver) = async_channel::bounded(10);
+        upstream.connection = Some(UpstreamMiningConnection { receiver, sender });
+        let upstream = Arc::new(Mutex::new(upstream));
+
+        let closed = task::spawn(async move {
+            let mut closed = vec![];
+            while closed.len() < 2 {
+                let frame: StdFrame = to_upstream.recv().await.unwrap().try_into().unwrap();
+                closed.push(frame.get_header().unwrap().msg_type());
+            }
+            closed
+        });
+        super::super::shutdown_upstreams(vec![upstream.clone()], Duration::from_secs(1)).await;
+
+        assert_eq!(
+            closed.await.unwrap(),
+            vec![const_sv2::MESSAGE_TYPE_CLOSE_CHANNEL; 2]
+        );
+        let sender = upstream
+            .safe_lock(|u| u.connection.as_ref().unwrap().sender.clone())
+            .unwrap();
+        assert!(sender.is_empty());
+    }
 }
@@ -20,10 +20,13 @@
 #![allow(special_module_name)]
 mod lib;
 
-use lib::Config;
+use lib::{status::Status, Config};
 use roles_logic_sv2::utils::{GroupId, Mutex};
-use std::{net::SocketAddr, sync::Arc};
-use tracing::{error, info};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tracing::{error, info, warn};
+
+/// How long the proxy waits for pending upstream messages to be sent before exiting
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 mod args {
     use std::path::PathBuf;
@@ -119,11 +122,19 @@ async fn main() {
             return;
         }
     };
+    if let Err(e) = config.validate() {
+        error!("Invalid config file: {:?}", e);
+        return;
+    }
+
+    let (status_sender, status_receiver) = async_channel::unbounded();
+    tokio::task::spawn(handle_status(status_receiver));
 
     let group_id = Arc::new(Mutex::new(GroupId::new()));
     lib::ROUTING_LOGIC
         .set(Mutex::new(
-            lib::initialize_r_logic(&config.upstreams, group_id, config.clone()).await,
+            lib::initialize_r_logic(&config.upstreams, group_id, config.clone(), status_sender)
+                .await,
         ))
         .expect("BUG: Failed to set ROUTING_LOGIC");
     info!("PROXY INITIALIZING");
@@ -137,5 +148,48 @@ async fn main() {
     );
 
     info!("PROXY INITIALIZED");
-    crate::lib::downstream_mining::listen_for_downstream_mining(socket).await
+    tokio::select! {
+        _ = crate::lib::downstream_mining::listen_for_downstream_mining(
+            socket,
+            config.max_downstream_connections,
+        ) => (),
+        _ = shutdown_signal() => {
+            info!("PROXY SHUTTING DOWN");
+            lib::shutdown(SHUTDOWN_TIMEOUT).await;
+        }
+    }
+}
+
+/// Receive the connection events of the upstreams
+async fn handle_status(receiver: async_channel::Receiver<Status>) {
+    while let Ok(status) = receiver.recv().await {
+        match status {
+            Status::Reconnecting {
+                upstream_id,
+                address,
+                delay,
+            } => warn!(upstream_id, %address, ?delay, "Reconnecting to upstream node"),
+            Status::Reconnected {
+                upstream_id,
+                address,
+            } => info!(upstream_id, %address, "Reconnected to upstream node"),
+        }
+    }
+}
+
+/// Resolve on SIGINT or (on unix) on SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = sigterm.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
 }
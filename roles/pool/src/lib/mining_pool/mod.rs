@@ -7,7 +7,7 @@ use binary_sv2::U256;
 use codec_sv2::{Frame, HandshakeRole, Responder, StandardEitherFrame, StandardSv2Frame};
 use error_handling::handle_result;
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey, SignatureService};
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::noise_connection_tokio::{Connection, ConnectionParts};
 use nohash_hasher::BuildNoHashHasher;
 use roles_logic_sv2::{
     channel_logic::channel_factory::PoolChannelFactory,
@@ -44,16 +44,15 @@ pub type Message = PoolMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
 
+/// Builds the pool outputs of the coinbase. Each output pays its configured `value_sat`, what is
+/// left of the block subsidy plus the fees goes to the first output when the coinbase is built
+/// from a template.
 pub fn get_coinbase_output(config: &Configuration) -> Result<Vec<TxOut>, Error> {
-    let mut result = Vec::new();
-    for coinbase_output_pool in &config.coinbase_outputs {
-        let coinbase_output: CoinbaseOutput_ = coinbase_output_pool.try_into()?;
-        let output_script: Script = coinbase_output.try_into()?;
-        result.push(TxOut {
-            value: 0,
-            script_pubkey: output_script,
-        });
-    }
+    let result = config
+        .coinbase_outputs
+        .iter()
+        .map(TxOut::try_from)
+        .collect::<Result<Vec<TxOut>, Error>>()?;
     match result.is_empty() {
         true => Err(Error::EmptyCoinbaseOutputs),
         _ => Ok(result),
@@ -64,6 +63,22 @@ pub fn get_coinbase_output(config: &Configuration) -> Result<Vec<TxOut>, Error>
 pub struct CoinbaseOutput {
     output_script_type: String,
     output_script_value: String,
+    /// Value paid to the output, in satoshi
+    #[serde(default)]
+    value_sat: u64,
+}
+
+impl TryFrom<&CoinbaseOutput> for TxOut {
+    type Error = Error;
+
+    fn try_from(pool_output: &CoinbaseOutput) -> Result<Self, Self::Error> {
+        let coinbase_output: CoinbaseOutput_ = pool_output.try_into()?;
+        let output_script: Script = coinbase_output.try_into()?;
+        Ok(TxOut {
+            value: pool_output.value_sat,
+            script_pubkey: output_script,
+        })
+    }
 }
 
 impl TryFrom<&CoinbaseOutput> for CoinbaseOutput_ {
@@ -71,7 +86,7 @@ impl TryFrom<&CoinbaseOutput> for CoinbaseOutput_ {
 
     fn try_from(pool_output: &CoinbaseOutput) -> Result<Self, Self::Error> {
         match pool_output.output_script_type.as_str() {
-            "TEST" | "P2PK" | "P2PKH" | "P2WPKH" | "P2SH" | "P2WSH" | "P2TR" => {
+            "TEST" | "P2PK" | "P2PKH" | "P2WPKH" | "P2SH" | "P2WSH" | "P2TR" | "P2A" | "RAW" => {
                 Ok(CoinbaseOutput_ {
                     output_script_type: pool_output.clone().output_script_type,
                     output_script_value: pool_output.clone().output_script_value,
@@ -135,7 +150,7 @@ impl Downstream {
 
         let id = match downstream_data.header_only {
             false => channel_factory.safe_lock(|c| c.new_group_id())?,
-            true => channel_factory.safe_lock(|c| c.new_standard_id_for_hom())?,
+            true => channel_factory.safe_lock(|c| c.new_standard_id_for_hom())??,
         };
 
         let self_ = Arc::new(Mutex::new(Downstream {
@@ -355,13 +370,14 @@ impl Pool {
 
             let responder = Responder::from_authority_kp(
                 &config.authority_public_key.into_bytes(),
-                &config.authority_secret_key.into_bytes(),
+                config.authority_secret_key.into_bytes(),
                 std::time::Duration::from_secs(config.cert_validity_sec),
             );
             match responder {
                 Ok(resp) => {
-                    if let Ok((receiver, sender, _, _)) =
-                        Connection::new(stream, HandshakeRole::Responder(resp)).await
+                    if let Ok(ConnectionParts {
+                        receiver, sender, ..
+                    }) = Connection::new(stream, HandshakeRole::Responder(resp)).await
                     {
                         handle_result!(
                             status_tx,
@@ -742,6 +758,55 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_multiple_coinbase_outputs_from_config() {
+        let config: super::Configuration = toml::from_str(
+            r#"
+            authority_public_key = "9auqWEzQDVyd2oe1JVGFLMLHZtCo2FFqZwtKA5gd9xbuEu7PH72"
+            authority_secret_key = "mkDLTBBRxdBv998612qipDYoTK3YUrqLe8uWw7gu3iXbSrn2n"
+            cert_validity_sec = 3600
+            test_only_listen_adress_plain = "0.0.0.0:34250"
+            listen_address = "0.0.0.0:34254"
+            pool_signature = "Stratum v2 SRI Pool"
+            tp_address = "127.0.0.1:8442"
+
+            [[coinbase_outputs]]
+            output_script_type = "P2WPKH"
+            output_script_value = "036adc3bdf21e6f9a0f0fb0066bf517e5b7909ed1563d6958a10993849a7554075"
+
+            [[coinbase_outputs]]
+            output_script_type = "P2PKH"
+            output_script_value = "0372c47307e5b75ce365daf835f226d246c5a7a92fe24395018d5552123354f086"
+            value_sat = 100000000
+            "#,
+        )
+        .unwrap();
+
+        let outputs = super::get_coinbase_output(&config).unwrap();
+
+        let first: bitcoin::PublicKey =
+            "036adc3bdf21e6f9a0f0fb0066bf517e5b7909ed1563d6958a10993849a7554075"
+                .parse()
+                .unwrap();
+        let second: bitcoin::PublicKey =
+            "0372c47307e5b75ce365daf835f226d246c5a7a92fe24395018d5552123354f086"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            outputs,
+            vec![
+                bitcoin::TxOut {
+                    value: 0,
+                    script_pubkey: bitcoin::Script::new_v0_p2wpkh(&first.wpubkey_hash().unwrap()),
+                },
+                bitcoin::TxOut {
+                    value: 100_000_000,
+                    script_pubkey: bitcoin::Script::new_p2pkh(&second.pubkey_hash()),
+                },
+            ]
+        );
+    }
+
     // copied from roles-logic-sv2::job_creator
     fn coinbase_tx_prefix(coinbase: &Transaction, script_prefix_len: usize) -> B064K<'static> {
         let encoded = coinbase.serialize();
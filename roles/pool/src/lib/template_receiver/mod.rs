@@ -7,7 +7,7 @@ use async_channel::{Receiver, Sender};
 use codec_sv2::{Frame, HandshakeRole, Initiator};
 use error_handling::handle_result;
 use key_utils::Secp256k1PublicKey;
-use network_helpers_sv2::noise_connection_tokio::Connection;
+use network_helpers_sv2::noise_connection_tokio::{Connection, ConnectionParts};
 use roles_logic_sv2::{
     handlers::template_distribution::ParseServerTemplateDistributionMessages,
     parsers::{PoolMessages, TemplateDistribution},
@@ -54,10 +54,13 @@ impl TemplateRx {
             }
             None => Initiator::without_pk(),
         }?;
-        let (mut receiver, mut sender, _, _) =
-            Connection::new(stream, HandshakeRole::Initiator(initiator))
-                .await
-                .unwrap();
+        let ConnectionParts {
+            mut receiver,
+            mut sender,
+            ..
+        } = Connection::new(stream, HandshakeRole::Initiator(initiator))
+            .await
+            .unwrap();
 
         SetupConnectionHandler::setup(&mut receiver, &mut sender, address).await?;
 
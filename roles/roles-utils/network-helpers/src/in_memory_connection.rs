@@ -0,0 +1,157 @@
+//! In-process transport to test roles without opening sockets.
+//!
+//! [`InMemoryConnection::pair`] wires a downstream and an upstream together over an in-memory
+//! byte pipe. Frames go through the same reader and writer tasks and the same noise handshake of
+//! a [`Connection`], so the two ends get the same `Sender`/`Receiver` handles that a role gets
+//! from [`Connection::new`].
+
+use crate::{
+    noise_connection_tokio::{Connection, ConnectionOpts},
+    test_util::{AUTHORITY_PRIVATE_KEY, AUTHORITY_PUBLIC_KEY},
+    Error,
+};
+use async_channel::{Receiver, Sender};
+use binary_sv2::{Deserialize, GetSize, Serialize};
+use codec_sv2::{HandshakeRole, Initiator, Responder, StandardEitherFrame};
+use std::time::Duration;
+
+// Size of the in-memory pipe, in each direction
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// One end of an in-memory connection
+#[derive(Debug)]
+pub struct InMemoryConnection<Message> {
+    pub receiver: Receiver<StandardEitherFrame<Message>>,
+    pub sender: Sender<StandardEitherFrame<Message>>,
+}
+
+impl<Message: Serialize + Deserialize<'static> + GetSize + Send + 'static>
+    InMemoryConnection<Message>
+{
+    /// Runs the noise handshake between two in-memory ends and returns them as
+    /// `(downstream, upstream)`. The downstream end is the handshake initiator.
+    ///
+    /// Dropping the handles of one end closes the pipe, so the other end is disconnected as it
+    /// would be over TCP.
+    pub async fn pair() -> Result<(Self, Self), Error> {
+        let (downstream_stream, upstream_stream) = tokio::io::duplex(PIPE_CAPACITY);
+        let (downstream_reader, downstream_writer) = tokio::io::split(downstream_stream);
+        let (upstream_reader, upstream_writer) = tokio::io::split(upstream_stream);
+
+        let initiator =
+            Initiator::from_raw_k(AUTHORITY_PUBLIC_KEY).map_err(codec_sv2::Error::from)?;
+        let responder = Responder::from_authority_kp(
+            &AUTHORITY_PUBLIC_KEY,
+            AUTHORITY_PRIVATE_KEY,
+            Duration::from_secs(3600),
+        )
+        .map_err(codec_sv2::Error::from)?;
+
+        let (downstream, upstream) = tokio::join!(
+            Connection::from_halves::<Message, _, _>(
+                downstream_reader,
+                downstream_writer,
+                "in-memory upstream".to_string(),
+                HandshakeRole::Initiator(initiator),
+                ConnectionOpts::default(),
+            ),
+            Connection::from_halves::<Message, _, _>(
+                upstream_reader,
+                upstream_writer,
+                "in-memory downstream".to_string(),
+                HandshakeRole::Responder(responder),
+                ConnectionOpts::default(),
+            ),
+        );
+        let downstream = downstream?;
+        let downstream = Self {
+            receiver: downstream.receiver,
+            sender: downstream.sender,
+        };
+        let upstream = upstream?;
+        let upstream = Self {
+            receiver: upstream.receiver,
+            sender: upstream.sender,
+        };
+        Ok((downstream, upstream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec_sv2::{Frame, StandardSv2Frame};
+    use roles_logic_sv2::{
+        common_messages_sv2::{Protocol, SetupConnection, SetupConnectionSuccess},
+        parsers::{CommonMessages, PoolMessages},
+    };
+    use std::convert::TryInto;
+
+    type Message = PoolMessages<'static>;
+
+    async fn recv(connection: &InMemoryConnection<Message>) -> StandardSv2Frame<Message> {
+        connection
+            .receiver
+            .recv()
+            .await
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    fn parse(frame: &mut StandardSv2Frame<Message>) -> CommonMessages<'_> {
+        let message_type = frame.get_header().unwrap().msg_type();
+        match (message_type, frame.payload()).try_into().unwrap() {
+            PoolMessages::Common(message) => message,
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    async fn send(connection: &InMemoryConnection<Message>, message: CommonMessages<'static>) {
+        let frame: StandardSv2Frame<Message> = PoolMessages::Common(message).try_into().unwrap();
+        connection.sender.send(frame.into()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn setup_connection_in_memory() {
+        let (downstream, upstream) = InMemoryConnection::<Message>::pair().await.unwrap();
+
+        let setup_connection = SetupConnection {
+            protocol: Protocol::MiningProtocol,
+            min_version: 2,
+            max_version: 2,
+            flags: 0,
+            endpoint_host: "0.0.0.0".to_string().into_bytes().try_into().unwrap(),
+            endpoint_port: 0,
+            vendor: "vendor".to_string().into_bytes().try_into().unwrap(),
+            hardware_version: "hw".to_string().into_bytes().try_into().unwrap(),
+            firmware: "fw".to_string().into_bytes().try_into().unwrap(),
+            device_id: "device".to_string().into_bytes().try_into().unwrap(),
+        };
+        send(
+            &downstream,
+            CommonMessages::SetupConnection(setup_connection),
+        )
+        .await;
+
+        let mut frame = recv(&upstream).await;
+        match parse(&mut frame) {
+            CommonMessages::SetupConnection(m) => {
+                assert_eq!(m.protocol, Protocol::MiningProtocol);
+                assert_eq!(m.vendor.to_vec(), b"vendor".to_vec());
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+        let success = SetupConnectionSuccess {
+            used_version: 2,
+            flags: 0,
+        };
+        send(&upstream, CommonMessages::SetupConnectionSuccess(success)).await;
+
+        let mut frame = recv(&downstream).await;
+        match parse(&mut frame) {
+            CommonMessages::SetupConnectionSuccess(m) => assert_eq!(m.used_version, 2),
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+}
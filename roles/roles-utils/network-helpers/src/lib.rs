@@ -8,20 +8,29 @@ pub use noise_connection_async_std::{connect, listen, Connection};
 #[cfg(feature = "async_std")]
 pub use plain_connection_async_std::{plain_connect, plain_listen, PlainConnection};
 
+#[cfg(feature = "test-util")]
+pub mod in_memory_connection;
 #[cfg(feature = "tokio")]
 pub mod noise_connection_tokio;
 #[cfg(feature = "tokio")]
 pub mod plain_connection_tokio;
+pub mod reconnect_backoff;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 
 use async_channel::{Receiver, RecvError, SendError, Sender};
-use codec_sv2::{Error as CodecError, HandShakeFrame, HandshakeRole, StandardEitherFrame};
-use const_sv2::{
-    INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE, RESPONDER_EXPECTED_HANDSHAKE_MESSAGE_SIZE,
+use codec_sv2::{
+    h2f,
+    noise_sv2::{handshake, EncryptionAlgorithm},
+    Error as CodecError, HandShakeFrame, HandshakeRole, NoiseCodec, StandardEitherFrame,
 };
 use futures::lock::Mutex;
 use std::{
     convert::TryInto,
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 #[derive(Debug)]
@@ -32,6 +41,81 @@ pub enum Error {
     SendError,
     // This means that a socket that was supposed to be opened have been closed, likley by the peer
     SocketClosed,
+    // The noise handshake did not complete in the time set with `Initiator::with_timeout` or
+    // `Responder::with_timeout`, usually because the peer stalled
+    HandshakeTimeout,
+    // Setting the TCP options of the stream failed
+    SocketOptions(std::io::Error),
+}
+
+/// Reason why the reader or the writer task of a connection terminated
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// Reading from the stream failed, usually because the peer disconnected
+    Read(std::io::Error),
+    /// Writing to the stream failed
+    Write(std::io::Error),
+    /// An incoming frame could not be decoded
+    Decode(CodecError),
+    /// An outgoing frame could not be encoded
+    Encode(CodecError),
+    /// The peer declared a frame bigger than the maximum frame size of the connection, the
+    /// declared size is carried in the error
+    FrameTooLarge(usize),
+}
+
+/// Throughput counters of a connection, updated by its reader and writer tasks. The counters are
+/// atomics so they can be polled at any time without locking the connection.
+///
+/// Bytes are counted as they go over the stream, so they include the frame headers and the
+/// encryption overhead. Handshake frames are counted too.
+#[derive(Debug, Default)]
+pub struct ConnectionMetrics {
+    pub(crate) frames_in: AtomicU64,
+    pub(crate) frames_out: AtomicU64,
+    pub(crate) bytes_in: AtomicU64,
+    pub(crate) bytes_out: AtomicU64,
+}
+
+impl ConnectionMetrics {
+    /// Number of frames decoded from the stream
+    pub fn frames_in(&self) -> u64 {
+        self.frames_in.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames written to the stream
+    pub fn frames_out(&self) -> u64 {
+        self.frames_out.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes read from the stream
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes written to the stream
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+}
+
+/// Parameters negotiated by a noise handshake, reported once the codec is in transport mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeComplete {
+    /// AEAD algorithm used to encrypt the transport messages
+    pub algorithm: EncryptionAlgorithm,
+    /// Serialized x-only static key of the remote peer. Only the initiator learns the remote
+    /// static key so this is `None` on the responder side.
+    pub remote_static_key: Option<[u8; 32]>,
+}
+
+impl HandshakeComplete {
+    fn from_transport_mode(codec: &NoiseCodec) -> Self {
+        Self {
+            algorithm: codec.encryption_algorithm(),
+            remote_static_key: codec.remote_static_key().map(|key| key.serialize()),
+        }
+    }
 }
 
 impl From<CodecError> for Error {
@@ -39,6 +123,11 @@ impl From<CodecError> for Error {
         Error::CodecError(e)
     }
 }
+impl From<codec_sv2::noise_sv2::Error> for Error {
+    fn from(e: codec_sv2::noise_sv2::Error) -> Self {
+        CodecError::from(e).into()
+    }
+}
 impl From<RecvError> for Error {
     fn from(_: RecvError) -> Self {
         Error::RecvError
@@ -50,6 +139,14 @@ impl<T> From<SendError<T>> for Error {
     }
 }
 
+/// Time allowed to the handshake of `role`, enforced around the whole handshake I/O
+fn handshake_timeout(role: &HandshakeRole) -> Option<std::time::Duration> {
+    match role {
+        HandshakeRole::Initiator(initiator) => initiator.handshake_timeout(),
+        HandshakeRole::Responder(responder) => responder.handshake_timeout(),
+    }
+}
+
 trait SetState {
     async fn set_state(self_: Arc<Mutex<Self>>, state: codec_sv2::State);
 }
@@ -63,31 +160,35 @@ async fn initialize_as_downstream<
     role: HandshakeRole,
     sender_outgoing: Sender<StandardEitherFrame<Message>>,
     receiver_incoming: Receiver<StandardEitherFrame<Message>>,
-) -> Result<(), Error> {
-    let mut state = codec_sv2::State::initialized(role);
-
-    // Create and send first handshake message
-    let first_message = state.step_0()?;
-    sender_outgoing.send(first_message.into()).await?;
-
-    // Receive and deserialize second handshake message
-    let second_message = receiver_incoming.recv().await?;
-    let second_message: HandShakeFrame = second_message
-        .try_into()
-        .map_err(|_| Error::HandshakeRemoteInvalidMessage)?;
-    let second_message: [u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE] = second_message
-        .get_payload_when_handshaking()
-        .try_into()
-        .map_err(|_| Error::HandshakeRemoteInvalidMessage)?;
-
-    // Create and send thirth handshake message
-    let transport_mode = state.step_2(second_message)?;
-
-    T::set_state(self_, transport_mode).await;
+) -> Result<HandshakeComplete, Error> {
+    let mut initiator = match role {
+        HandshakeRole::Initiator(initiator) => initiator,
+        HandshakeRole::Responder(_) => return Err(CodecError::InvalidStepForResponder.into()),
+    };
+    let transport_mode = handshake::run_initiator(
+        &mut initiator,
+        |first_message| async move {
+            sender_outgoing.send(h2f(first_message).into()).await?;
+            Ok(())
+        },
+        || async move {
+            let second_message: HandShakeFrame = receiver_incoming
+                .recv()
+                .await?
+                .try_into()
+                .map_err(|_| Error::HandshakeRemoteInvalidMessage)?;
+            handshake::message_from_slice(&second_message.get_payload_when_handshaking())
+                .map_err(Error::from)
+        },
+    )
+    .await?;
+
+    let complete = HandshakeComplete::from_transport_mode(&transport_mode);
+    T::set_state(self_, codec_sv2::State::with_transport_mode(transport_mode)).await;
     while !TRANSPORT_READY.load(std::sync::atomic::Ordering::SeqCst) {
         std::hint::spin_loop()
     }
-    Ok(())
+    Ok(complete)
 }
 
 async fn initialize_as_upstream<'a, Message: Serialize + Deserialize<'a> + GetSize, T: SetState>(
@@ -95,34 +196,40 @@ async fn initialize_as_upstream<'a, Message: Serialize + Deserialize<'a> + GetSi
     role: HandshakeRole,
     sender_outgoing: Sender<StandardEitherFrame<Message>>,
     receiver_incoming: Receiver<StandardEitherFrame<Message>>,
-) -> Result<(), Error> {
-    let mut state = codec_sv2::State::initialized(role);
-
-    // Receive and deserialize first handshake message
-    let first_message: HandShakeFrame = receiver_incoming
-        .recv()
-        .await?
-        .try_into()
-        .map_err(|_| Error::HandshakeRemoteInvalidMessage)?;
-    let first_message: [u8; RESPONDER_EXPECTED_HANDSHAKE_MESSAGE_SIZE] = first_message
-        .get_payload_when_handshaking()
-        .try_into()
-        .map_err(|_| Error::HandshakeRemoteInvalidMessage)?;
-
-    // Create and send second handshake message
-    let (second_message, transport_mode) = state.step_1(first_message)?;
-    HANDSHAKE_READY.store(false, std::sync::atomic::Ordering::SeqCst);
-    sender_outgoing.send(second_message.into()).await?;
+) -> Result<HandshakeComplete, Error> {
+    let mut responder = match role {
+        HandshakeRole::Responder(responder) => responder,
+        HandshakeRole::Initiator(_) => return Err(CodecError::InvalidStepForInitiator.into()),
+    };
+    let transport_mode = handshake::run_responder(
+        &mut responder,
+        |second_message| async move {
+            HANDSHAKE_READY.store(false, std::sync::atomic::Ordering::SeqCst);
+            sender_outgoing.send(h2f(second_message).into()).await?;
+            Ok(())
+        },
+        || async move {
+            let first_message: HandShakeFrame = receiver_incoming
+                .recv()
+                .await?
+                .try_into()
+                .map_err(|_| Error::HandshakeRemoteInvalidMessage)?;
+            handshake::message_from_slice(&first_message.get_payload_when_handshaking())
+                .map_err(Error::from)
+        },
+    )
+    .await?;
 
     // This sets the state to Handshake state - this prompts the task above to move the state
     // to transport mode so that the next incoming message will be decoded correctly
     // It is important to do this directly before sending the fourth message
-    T::set_state(self_, transport_mode).await;
+    let complete = HandshakeComplete::from_transport_mode(&transport_mode);
+    T::set_state(self_, codec_sv2::State::with_transport_mode(transport_mode)).await;
     while !TRANSPORT_READY.load(std::sync::atomic::Ordering::SeqCst) {
         std::hint::spin_loop()
     }
 
-    Ok(())
+    Ok(complete)
 }
 
 static HANDSHAKE_READY: AtomicBool = AtomicBool::new(false);
@@ -145,28 +145,37 @@ impl Connection {
         });
 
         // DO THE NOISE HANDSHAKE
-        match role {
-            HandshakeRole::Initiator(_) => {
-                debug!("Initializing as downstream for - {}", &address);
-                crate::initialize_as_downstream(
-                    connection.clone(),
-                    role,
-                    sender_outgoing.clone(),
-                    receiver_incoming.clone(),
-                )
-                .await?
-            }
-            HandshakeRole::Responder(_) => {
-                debug!("Initializing as upstream for - {}", &address);
-                crate::initialize_as_upstream(
-                    connection.clone(),
-                    role,
-                    sender_outgoing.clone(),
-                    receiver_incoming.clone(),
-                )
-                .await?
+        let timeout = crate::handshake_timeout(&role);
+        let handshake = async {
+            match role {
+                HandshakeRole::Initiator(_) => {
+                    debug!("Initializing as downstream for - {}", &address);
+                    crate::initialize_as_downstream(
+                        connection.clone(),
+                        role,
+                        sender_outgoing.clone(),
+                        receiver_incoming.clone(),
+                    )
+                    .await
+                }
+                HandshakeRole::Responder(_) => {
+                    debug!("Initializing as upstream for - {}", &address);
+                    crate::initialize_as_upstream(
+                        connection.clone(),
+                        role,
+                        sender_outgoing.clone(),
+                        receiver_incoming.clone(),
+                    )
+                    .await
+                }
             }
         };
+        match timeout {
+            Some(timeout) => async_std::future::timeout(timeout, handshake)
+                .await
+                .unwrap_or(Err(Error::HandshakeTimeout))?,
+            None => handshake.await?,
+        };
         debug!("Noise handshake complete - {}", &address);
 
         Ok((receiver_incoming, sender_outgoing))
@@ -186,7 +195,7 @@ pub async fn listen(
         let stream = stream.unwrap();
         let responder = Responder::from_authority_kp(
             &authority_public_key,
-            &authority_private_key,
+            authority_private_key,
             cert_validity,
         )
         .unwrap();
@@ -1,10 +1,15 @@
-use crate::Error;
+use crate::{ConnectionError, ConnectionMetrics, Error, HandshakeComplete};
 use async_channel::{bounded, Receiver, Sender};
 use binary_sv2::{Deserialize, Serialize};
+use const_sv2::{AEAD_MAC_LEN, SV2_FRAME_CHUNK_SIZE};
 use futures::lock::Mutex;
-use std::{sync::Arc, time::Duration};
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     task::{self, AbortHandle},
 };
@@ -34,32 +39,206 @@ impl crate::SetState for Connection {
     }
 }
 
+/// Default capacity of the incoming and outgoing channels of a [`Connection`]
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 10;
+
+// The payload length in the SV2 frame header is an u24
+const MAX_SV2_PAYLOAD_SIZE: usize = (1 << 24) - 1;
+
+/// Default maximum frame size of a [`Connection`], big enough for any encrypted SV2 frame payload
+pub const DEFAULT_MAX_FRAME_SIZE: usize = MAX_SV2_PAYLOAD_SIZE
+    + (MAX_SV2_PAYLOAD_SIZE / (SV2_FRAME_CHUNK_SIZE - AEAD_MAC_LEN) + 1) * AEAD_MAC_LEN;
+
+/// TCP options applied to the stream of a [`Connection`] before the noise handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOpts {
+    /// Set `TCP_NODELAY` so that small frames are not delayed by Nagle's algorithm
+    pub nodelay: bool,
+    /// Enable `SO_KEEPALIVE` and send the first probe after the stream has been idle for the
+    /// given time, so that dead peers are detected without waiting for a write to fail
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for SocketOpts {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+impl SocketOpts {
+    fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        Ok(())
+    }
+}
+
+/// Bounds of a single write of the writer task of a [`Connection`].
+///
+/// The writer never waits for more frames: after the first frame it only takes the frames that
+/// are already queued, so batching does not add latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteBatch {
+    /// Maximum number of frames in a write
+    pub max_frames: usize,
+    /// No more frames are added once the encoded frames reach this size in bytes, so a write
+    /// can go over it by at most one frame
+    pub max_bytes: usize,
+}
+
+/// Options of a [`Connection`]. Start from [`ConnectionOpts::default`] and change the options
+/// that differ from the defaults with the `with_*` methods.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionOpts {
+    socket_opts: SocketOpts,
+    incoming_capacity: usize,
+    outgoing_capacity: usize,
+    max_frame_size: usize,
+    write_batch: Option<WriteBatch>,
+}
+
+impl Default for ConnectionOpts {
+    fn default() -> Self {
+        Self {
+            socket_opts: SocketOpts::default(),
+            incoming_capacity: DEFAULT_CHANNEL_CAPACITY,
+            outgoing_capacity: DEFAULT_CHANNEL_CAPACITY,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            write_batch: None,
+        }
+    }
+}
+
+impl ConnectionOpts {
+    /// TCP options set on the stream before it is split
+    pub fn with_socket_opts(mut self, socket_opts: SocketOpts) -> Self {
+        self.socket_opts = socket_opts;
+        self
+    }
+
+    /// Capacity of the incoming and outgoing channels. When the outgoing channel is full
+    /// senders wait for the stream to be written.
+    pub fn with_channel_capacity(mut self, incoming: usize, outgoing: usize) -> Self {
+        self.incoming_capacity = incoming;
+        self.outgoing_capacity = outgoing;
+        self
+    }
+
+    /// Bounds the declared length of the encrypted payload of an incoming frame (the SV2 payload
+    /// plus its MACs). A frame over the limit is rejected as soon as its header is decrypted,
+    /// before a buffer for the payload is allocated, and the connection is closed with
+    /// [`ConnectionError::FrameTooLarge`].
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Coalesce the frames already queued on the outgoing channel into a single write. Without
+    /// it every frame is written on its own.
+    pub fn with_write_batch(mut self, write_batch: WriteBatch) -> Self {
+        self.write_batch = Some(write_batch);
+        self
+    }
+}
+
+/// Handles of an established [`Connection`]
+#[derive(Debug)]
+pub struct ConnectionParts<Message> {
+    /// Frames decoded from the stream
+    pub receiver: Receiver<StandardEitherFrame<Message>>,
+    /// Frames to encode and write to the stream
+    pub sender: Sender<StandardEitherFrame<Message>>,
+    /// When the connection fails the reader and the writer tasks send the cause here, close
+    /// their channels and terminate
+    pub status: Receiver<ConnectionError>,
+    pub recv_task: AbortHandle,
+    pub send_task: AbortHandle,
+    /// Frames and bytes that go over the stream, the handshake included
+    pub metrics: Arc<ConnectionMetrics>,
+    /// Parameters negotiated by the noise handshake
+    pub handshake: HandshakeComplete,
+}
+
 impl Connection {
+    /// Runs the noise handshake over `stream` as `role` and starts the reader and the writer
+    /// tasks of the connection, with the default [`ConnectionOpts`].
     #[allow(clippy::new_ret_no_self)]
     pub async fn new<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
         stream: TcpStream,
         role: HandshakeRole,
-    ) -> Result<
-        (
-            Receiver<StandardEitherFrame<Message>>,
-            Sender<StandardEitherFrame<Message>>,
-            AbortHandle,
-            AbortHandle,
-        ),
-        Error,
-    > {
+    ) -> Result<ConnectionParts<Message>, Error> {
+        Self::with_opts(stream, role, ConnectionOpts::default()).await
+    }
+
+    /// Like [`Connection::new`] but with the capacity of the incoming and outgoing channels set
+    /// by the caller. When the outgoing channel is full senders wait for the stream to be written.
+    pub async fn with_capacity<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+    >(
+        stream: TcpStream,
+        role: HandshakeRole,
+        incoming_capacity: usize,
+        outgoing_capacity: usize,
+    ) -> Result<ConnectionParts<Message>, Error> {
+        let opts =
+            ConnectionOpts::default().with_channel_capacity(incoming_capacity, outgoing_capacity);
+        Self::with_opts(stream, role, opts).await
+    }
+
+    /// Like [`Connection::new`] but with the options in `opts` instead of the default ones.
+    pub async fn with_opts<'a, Message: Serialize + Deserialize<'a> + GetSize + Send + 'static>(
+        stream: TcpStream,
+        role: HandshakeRole,
+        opts: ConnectionOpts,
+    ) -> Result<ConnectionParts<Message>, Error> {
         let address = stream.peer_addr().map_err(|_| Error::SocketClosed)?;
+        opts.socket_opts
+            .apply(&stream)
+            .map_err(Error::SocketOptions)?;
 
-        let (mut reader, mut writer) = stream.into_split();
+        let (reader, writer) = stream.into_split();
+        Self::from_halves(reader, writer, address.to_string(), role, opts).await
+    }
 
+    /// Runs the reader and the writer tasks of a connection and the noise handshake over any pair
+    /// of byte streams, `address` is only used in the logs and the socket options in `opts` are
+    /// ignored.
+    pub(crate) async fn from_halves<
+        'a,
+        Message: Serialize + Deserialize<'a> + GetSize + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    >(
+        mut reader: R,
+        mut writer: W,
+        address: String,
+        role: HandshakeRole,
+        opts: ConnectionOpts,
+    ) -> Result<ConnectionParts<Message>, Error> {
+        let ConnectionOpts {
+            incoming_capacity,
+            outgoing_capacity,
+            max_frame_size,
+            write_batch,
+            ..
+        } = opts;
         let (sender_incoming, receiver_incoming): (
             Sender<StandardEitherFrame<Message>>,
             Receiver<StandardEitherFrame<Message>>,
-        ) = bounded(10); // TODO caller should provide this param
+        ) = bounded(incoming_capacity);
         let (sender_outgoing, receiver_outgoing): (
             Sender<StandardEitherFrame<Message>>,
             Receiver<StandardEitherFrame<Message>>,
-        ) = bounded(10); // TODO caller should provide this param
+        ) = bounded(outgoing_capacity);
+        // Each task sends at most one error before terminating
+        let (sender_status, receiver_status) = bounded(2);
+        let sender_status_cloned = sender_status.clone();
 
         let state = codec_sv2::State::not_initialized(&role);
 
@@ -68,6 +247,16 @@ impl Connection {
         let cloned1 = connection.clone();
         let cloned2 = connection.clone();
 
+        let metrics = Arc::new(ConnectionMetrics::default());
+        let metrics_reader = metrics.clone();
+        let metrics_writer = metrics.clone();
+
+        // Used by the reader to close the writer when the peer sends a frame that is too large
+        let receiver_outgoing_closer = receiver_outgoing.clone();
+
+        let address_reader = address.clone();
+        let address_writer = address.clone();
+
         // RECEIVE AND PARSE INCOMING MESSAGES FROM TCP STREAM
         let recv_task = task::spawn(async move {
             let mut decoder = StandardNoiseDecoder::<Message>::new();
@@ -75,13 +264,29 @@ impl Connection {
             loop {
                 let writable = decoder.writable();
                 match reader.read_exact(writable).await {
-                    Ok(_) => {
+                    Ok(read) => {
+                        metrics_reader
+                            .bytes_in
+                            .fetch_add(read as u64, Ordering::Relaxed);
                         let mut connection = cloned1.lock().await;
                         let decoded = decoder.next_frame(&mut connection.state);
                         drop(connection);
 
                         match decoded {
+                            Err(codec_sv2::Error::MissingBytes(size)) if size > max_frame_size => {
+                                error!(
+                                    "Shutting down noise stream reader! Frame too large: {} > {} - {}",
+                                    size, max_frame_size, &address_reader
+                                );
+                                let _ =
+                                    sender_status.try_send(ConnectionError::FrameTooLarge(size));
+                                sender_incoming.close();
+                                receiver_outgoing_closer.close();
+                                task::yield_now().await;
+                                break;
+                            }
                             Ok(x) => {
+                                metrics_reader.frames_in.fetch_add(1, Ordering::Relaxed);
                                 if sender_incoming.send(x).await.is_err() {
                                     error!("Shutting down noise stream reader!");
                                     task::yield_now().await;
@@ -92,6 +297,7 @@ impl Connection {
                                 if let codec_sv2::Error::MissingBytes(_) = e {
                                 } else {
                                     error!("Shutting down noise stream reader! {:#?}", e);
+                                    let _ = sender_status.try_send(ConnectionError::Decode(e));
                                     sender_incoming.close();
                                     task::yield_now().await;
                                     break;
@@ -102,10 +308,11 @@ impl Connection {
                     Err(e) => {
                         error!(
                             "Disconnected from client while reading : {} - {}",
-                            e, &address
+                            e, &address_reader
                         );
 
                         //kill thread without a panic - don't need to panic everytime a client disconnects
+                        let _ = sender_status.try_send(ConnectionError::Read(e));
                         sender_incoming.close();
                         task::yield_now().await;
                         break;
@@ -119,6 +326,7 @@ impl Connection {
         // ENCODE AND SEND INCOMING MESSAGES TO TCP STREAM
         let send_task = task::spawn(async move {
             let mut encoder = codec_sv2::NoiseEncoder::<Message>::new();
+            let mut buffer = Vec::new();
 
             loop {
                 let received = receiver_outgoing_cloned.recv().await;
@@ -127,21 +335,56 @@ impl Connection {
                     Ok(frame) => {
                         let mut connection = cloned2.lock().await;
 
-                        let b = encoder.encode(frame, &mut connection.state).unwrap();
+                        buffer.clear();
+                        let mut encoded = encoder
+                            .encode(frame, &mut connection.state)
+                            .map(|b| buffer.extend_from_slice(b.as_ref()));
+                        let mut frames: u64 = 1;
+                        if let Some(batch) = write_batch {
+                            while encoded.is_ok()
+                                && frames < batch.max_frames as u64
+                                && buffer.len() < batch.max_bytes
+                            {
+                                let frame = match receiver_outgoing_cloned.try_recv() {
+                                    Ok(frame) => frame,
+                                    Err(_) => break,
+                                };
+                                encoded = encoder
+                                    .encode(frame, &mut connection.state)
+                                    .map(|b| buffer.extend_from_slice(b.as_ref()));
+                                frames += 1;
+                            }
+                        }
 
                         drop(connection);
 
-                        let b = b.as_ref();
+                        if let Err(e) = encoded {
+                            let _ = writer.shutdown().await;
+                            error!("Disconnecting from client due to error encoding: {:?}", e);
+                            let _ = sender_status_cloned.try_send(ConnectionError::Encode(e));
+                            receiver_outgoing_cloned.close();
+                            task::yield_now().await;
+                            break;
+                        }
 
-                        match (writer).write_all(b).await {
-                            Ok(_) => (),
+                        match (writer).write_all(&buffer).await {
+                            Ok(_) => {
+                                metrics_writer
+                                    .frames_out
+                                    .fetch_add(frames, Ordering::Relaxed);
+                                metrics_writer
+                                    .bytes_out
+                                    .fetch_add(buffer.len() as u64, Ordering::Relaxed);
+                            }
                             Err(e) => {
                                 let _ = writer.shutdown().await;
                                 // Just fail and force to reinitialize everything
                                 error!(
                                     "Disconnecting from client due to error writing: {} - {}",
-                                    e, &address
+                                    e, &address_writer
                                 );
+                                let _ = sender_status_cloned.try_send(ConnectionError::Write(e));
+                                receiver_outgoing_cloned.close();
                                 task::yield_now().await;
                                 break;
                             }
@@ -152,7 +395,7 @@ impl Connection {
                         let _ = writer.shutdown().await;
                         error!(
                             "Disconnecting from client due to error receiving: {} - {}",
-                            e, &address
+                            e, &address_writer
                         );
                         task::yield_now().await;
                         break;
@@ -163,35 +406,58 @@ impl Connection {
         });
 
         // DO THE NOISE HANDSHAKE
-        match role {
-            HandshakeRole::Initiator(_) => {
-                debug!("Initializing as downstream for - {}", &address);
-                crate::initialize_as_downstream(
-                    connection.clone(),
-                    role,
-                    sender_outgoing.clone(),
-                    receiver_incoming.clone(),
-                )
-                .await?
+        let timeout = crate::handshake_timeout(&role);
+        let handshake = async {
+            match role {
+                HandshakeRole::Initiator(_) => {
+                    debug!("Initializing as downstream for - {}", &address);
+                    crate::initialize_as_downstream(
+                        connection.clone(),
+                        role,
+                        sender_outgoing.clone(),
+                        receiver_incoming.clone(),
+                    )
+                    .await
+                }
+                HandshakeRole::Responder(_) => {
+                    debug!("Initializing as upstream for - {}", &address);
+                    crate::initialize_as_upstream(
+                        connection.clone(),
+                        role,
+                        sender_outgoing.clone(),
+                        receiver_incoming.clone(),
+                    )
+                    .await
+                }
+            }
+        };
+        let handshake = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, handshake)
+                .await
+                .unwrap_or(Err(Error::HandshakeTimeout)),
+            None => handshake.await,
+        };
+        let handshake = match handshake {
+            Ok(complete) => {
+                debug!("Noise handshake complete - {}", &address);
+                complete
             }
-            HandshakeRole::Responder(_) => {
-                debug!("Initializing as upstream for - {}", &address);
-                crate::initialize_as_upstream(
-                    connection.clone(),
-                    role,
-                    sender_outgoing.clone(),
-                    receiver_incoming.clone(),
-                )
-                .await?
+            Err(e) => {
+                error!("Noise handshake failed - {} - {:?}", &address, e);
+                recv_task.abort();
+                send_task.abort();
+                return Err(e);
             }
         };
-        debug!("Noise handshake complete - {}", &address);
-        Ok((
-            receiver_incoming,
-            sender_outgoing,
-            recv_task.abort_handle(),
-            send_task.abort_handle(),
-        ))
+        Ok(ConnectionParts {
+            receiver: receiver_incoming,
+            sender: sender_outgoing,
+            status: receiver_status,
+            recv_task: recv_task.abort_handle(),
+            send_task: send_task.abort_handle(),
+            metrics,
+            handshake,
+        })
     }
 }
 
@@ -207,7 +473,7 @@ pub async fn listen(
         if let Ok((stream, _)) = listner.accept().await {
             let responder = Responder::from_authority_kp(
                 &authority_public_key,
-                &authority_private_key,
+                authority_private_key,
                 cert_validity,
             )
             .unwrap();
@@ -226,3 +492,416 @@ pub async fn connect(
     let role = HandshakeRole::Initiator(initiator);
     Ok((stream, role))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{AUTHORITY_PRIVATE_KEY, AUTHORITY_PUBLIC_KEY};
+    use binary_sv2::B064K;
+    use codec_sv2::{Frame, StandardSv2Frame};
+    use const_sv2::ENCRYPTED_SV2_FRAME_HEADER_SIZE;
+    use std::{
+        convert::TryInto,
+        pin::Pin,
+        sync::atomic::AtomicUsize,
+        task::{Context, Poll},
+    };
+
+    type Channels = ConnectionParts<B064K<'static>>;
+
+    // Accept a single connection and complete the handshake as responder
+    async fn listen_once(
+        max_frame_size: usize,
+    ) -> (std::net::SocketAddr, task::JoinHandle<Channels>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let responder = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let responder = Responder::from_authority_kp(
+                &AUTHORITY_PUBLIC_KEY,
+                AUTHORITY_PRIVATE_KEY,
+                Duration::from_secs(60),
+            )
+            .unwrap();
+            Connection::with_opts::<B064K<'static>>(
+                stream,
+                HandshakeRole::Responder(responder),
+                ConnectionOpts::default().with_max_frame_size(max_frame_size),
+            )
+            .await
+            .unwrap()
+        });
+        (address, responder)
+    }
+
+    // Counts the writes that reach the underlying stream
+    struct CountingWriter<W> {
+        inner: W,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(_)) = poll {
+                self.writes.fetch_add(1, Ordering::SeqCst);
+            }
+            poll
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn full_outgoing_channel_blocks_the_sender() {
+        let (address, responder) = listen_once(DEFAULT_MAX_FRAME_SIZE).await;
+        let stream = TcpStream::connect(address).await.unwrap();
+        let initiator = Initiator::from_raw_k(AUTHORITY_PUBLIC_KEY).unwrap();
+        let ConnectionParts {
+            receiver: _receiver,
+            sender,
+            ..
+        } = Connection::with_capacity::<B064K<'static>>(
+            stream,
+            HandshakeRole::Initiator(initiator),
+            DEFAULT_CHANNEL_CAPACITY,
+            2,
+        )
+        .await
+        .unwrap();
+        // The responder never reads its incoming channel, so the stream and then the outgoing
+        // channel of the initiator end up full
+        let _responder = responder.await.unwrap();
+
+        let mut blocked = false;
+        for _ in 0..10_000 {
+            let payload: B064K<'static> = vec![0; 60_000].try_into().unwrap();
+            let frame = StandardSv2Frame::from_message(payload, 0, 0, false).unwrap();
+            match tokio::time::timeout(Duration::from_millis(200), sender.send(frame.into())).await
+            {
+                Ok(result) => assert!(result.is_ok()),
+                Err(_) => {
+                    blocked = true;
+                    break;
+                }
+            }
+        }
+        assert!(blocked);
+        assert_eq!(sender.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn responder_times_out_when_the_initiator_stalls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // Connects but never sends the first handshake message
+        let _stalled = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let responder = Responder::from_authority_kp(
+            &AUTHORITY_PUBLIC_KEY,
+            AUTHORITY_PRIVATE_KEY,
+            Duration::from_secs(60),
+        )
+        .unwrap()
+        .with_timeout(Duration::from_millis(100));
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            Connection::new::<B064K<'static>>(stream, HandshakeRole::Responder(responder)),
+        )
+        .await
+        .expect("the handshake timeout is enforced");
+        assert!(matches!(result, Err(Error::HandshakeTimeout)));
+    }
+
+    #[tokio::test]
+    async fn initiator_times_out_when_the_responder_stalls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // Accepts but never replies to the first handshake message
+        let (_stalled, _) = listener.accept().await.unwrap();
+        let initiator = Initiator::from_raw_k(AUTHORITY_PUBLIC_KEY)
+            .unwrap()
+            .with_timeout(Duration::from_millis(100));
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            Connection::new::<B064K<'static>>(stream, HandshakeRole::Initiator(initiator)),
+        )
+        .await
+        .expect("the handshake timeout is enforced");
+        assert!(matches!(result, Err(Error::HandshakeTimeout)));
+    }
+
+    #[tokio::test]
+    async fn dropped_peer_is_reported_on_the_status_channel() {
+        let (address, responder) = listen_once(DEFAULT_MAX_FRAME_SIZE).await;
+        let stream = TcpStream::connect(address).await.unwrap();
+        let initiator = Initiator::from_raw_k(AUTHORITY_PUBLIC_KEY).unwrap();
+        let ConnectionParts {
+            receiver,
+            sender: _sender,
+            status,
+            ..
+        } = Connection::new::<B064K<'static>>(stream, HandshakeRole::Initiator(initiator))
+            .await
+            .unwrap();
+
+        // Dropping the responder tasks closes its side of the stream
+        let ConnectionParts {
+            recv_task,
+            send_task,
+            ..
+        } = responder.await.unwrap();
+        recv_task.abort();
+        send_task.abort();
+
+        let error = tokio::time::timeout(Duration::from_secs(5), status.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(error, ConnectionError::Read(_)));
+        assert!(receiver.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_opts_are_applied_to_the_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        SocketOpts::default().apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        let opts = SocketOpts {
+            nodelay: false,
+            keepalive: Some(Duration::from_secs(30)),
+        };
+        opts.apply(&stream).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(!stream.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn frame_larger_than_max_frame_size_closes_the_connection() {
+        let (address, responder) = listen_once(1024).await;
+        let stream = TcpStream::connect(address).await.unwrap();
+        let initiator = Initiator::from_raw_k(AUTHORITY_PUBLIC_KEY).unwrap();
+        let ConnectionParts {
+            receiver,
+            sender,
+            status,
+            ..
+        } = Connection::new::<B064K<'static>>(stream, HandshakeRole::Initiator(initiator))
+            .await
+            .unwrap();
+        let ConnectionParts {
+            receiver: responder_receiver,
+            sender: _responder_sender,
+            status: responder_status,
+            ..
+        } = responder.await.unwrap();
+
+        let payload: B064K<'static> = vec![0; 2048].try_into().unwrap();
+        let frame = StandardSv2Frame::from_message(payload, 0, 0, false).unwrap();
+        sender.send(frame.into()).await.unwrap();
+
+        let error = tokio::time::timeout(Duration::from_secs(5), responder_status.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        // The declared size is the encrypted length of the payload, the B064K is prefixed by its
+        // 2 bytes length
+        match error {
+            ConnectionError::FrameTooLarge(size) => assert_eq!(size, 2 + 2048 + AEAD_MAC_LEN),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(responder_receiver.recv().await.is_err());
+
+        // The responder closed the stream, so the initiator is disconnected as well
+        let error = tokio::time::timeout(Duration::from_secs(5), status.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(error, ConnectionError::Read(_)));
+        assert!(receiver.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn metrics_count_frames_and_bytes() {
+        let (address, responder) = listen_once(DEFAULT_MAX_FRAME_SIZE).await;
+        let stream = TcpStream::connect(address).await.unwrap();
+        let initiator = Initiator::from_raw_k(AUTHORITY_PUBLIC_KEY).unwrap();
+        let ConnectionParts {
+            receiver: _receiver,
+            sender,
+            metrics,
+            ..
+        } = Connection::new::<B064K<'static>>(stream, HandshakeRole::Initiator(initiator))
+            .await
+            .unwrap();
+        let ConnectionParts {
+            receiver: responder_receiver,
+            metrics: responder_metrics,
+            ..
+        } = responder.await.unwrap();
+
+        // The counters start after the handshake frames
+        let frames_out = metrics.frames_out();
+        let bytes_out = metrics.bytes_out();
+        let frames_in = responder_metrics.frames_in();
+        let bytes_in = responder_metrics.bytes_in();
+
+        for _ in 0..3 {
+            let payload: B064K<'static> = vec![0; 100].try_into().unwrap();
+            let frame = StandardSv2Frame::from_message(payload, 0, 0, false).unwrap();
+            sender.send(frame.into()).await.unwrap();
+        }
+        for _ in 0..3 {
+            responder_receiver.recv().await.unwrap();
+        }
+
+        // Encrypted header and payload, the B064K is prefixed by its 2 bytes length
+        let frame_size = (ENCRYPTED_SV2_FRAME_HEADER_SIZE + 2 + 100 + AEAD_MAC_LEN) as u64;
+        assert_eq!(metrics.frames_out(), frames_out + 3);
+        assert_eq!(metrics.bytes_out(), bytes_out + 3 * frame_size);
+        assert_eq!(responder_metrics.frames_in(), frames_in + 3);
+        assert_eq!(responder_metrics.bytes_in(), bytes_in + 3 * frame_size);
+        assert_eq!(metrics.bytes_out(), responder_metrics.bytes_in());
+    }
+
+    #[tokio::test]
+    async fn negotiated_parameters_are_returned_with_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (responder_stream, _) = listener.accept().await.unwrap();
+        let responder = Responder::from_authority_kp(
+            &AUTHORITY_PUBLIC_KEY,
+            AUTHORITY_PRIVATE_KEY,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        // The static key signed by the authority in the certificate of the responder
+        let responder_static_key = responder.static_public_key().serialize();
+        let initiator = Initiator::from_raw_k(AUTHORITY_PUBLIC_KEY).unwrap();
+
+        let (initiator, responder) = tokio::join!(
+            Connection::new::<B064K<'static>>(stream, HandshakeRole::Initiator(initiator),),
+            Connection::new::<B064K<'static>>(
+                responder_stream,
+                HandshakeRole::Responder(responder),
+            ),
+        );
+        let handshake = initiator.unwrap().handshake;
+        let responder_handshake = responder.unwrap().handshake;
+
+        assert_eq!(
+            handshake.algorithm,
+            codec_sv2::noise_sv2::EncryptionAlgorithm::ChaChaPoly
+        );
+        assert_eq!(responder_handshake.algorithm, handshake.algorithm);
+        // Only the initiator learns the remote static key during the handshake
+        assert_eq!(handshake.remote_static_key, Some(responder_static_key));
+        assert_eq!(responder_handshake.remote_static_key, None);
+    }
+
+    #[tokio::test]
+    async fn queued_frames_are_written_together() {
+        let (downstream_stream, upstream_stream) = tokio::io::duplex(64 * 1024);
+        let (downstream_reader, downstream_writer) = tokio::io::split(downstream_stream);
+        let (upstream_reader, upstream_writer) = tokio::io::split(upstream_stream);
+        let writes = Arc::new(AtomicUsize::new(0));
+        let downstream_writer = CountingWriter {
+            inner: downstream_writer,
+            writes: writes.clone(),
+        };
+        let initiator = Initiator::from_raw_k(AUTHORITY_PUBLIC_KEY).unwrap();
+        let responder = Responder::from_authority_kp(
+            &AUTHORITY_PUBLIC_KEY,
+            AUTHORITY_PRIVATE_KEY,
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        let batch = WriteBatch {
+            max_frames: 4,
+            max_bytes: DEFAULT_MAX_FRAME_SIZE,
+        };
+
+        let (downstream, upstream) = tokio::join!(
+            Connection::from_halves::<B064K<'static>, _, _>(
+                downstream_reader,
+                downstream_writer,
+                "downstream".to_string(),
+                HandshakeRole::Initiator(initiator),
+                ConnectionOpts::default().with_write_batch(batch),
+            ),
+            Connection::from_halves::<B064K<'static>, _, _>(
+                upstream_reader,
+                upstream_writer,
+                "upstream".to_string(),
+                HandshakeRole::Responder(responder),
+                ConnectionOpts::default(),
+            ),
+        );
+        let ConnectionParts {
+            receiver: _receiver,
+            sender,
+            metrics,
+            ..
+        } = downstream.unwrap();
+        let ConnectionParts {
+            receiver: upstream_receiver,
+            sender: _upstream_sender,
+            ..
+        } = upstream.unwrap();
+        let frames_out = metrics.frames_out();
+
+        // The test runtime is single threaded, so the writer task only runs once all the frames
+        // have been queued
+        let send_frames = |count| {
+            for _ in 0..count {
+                let payload: B064K<'static> = vec![0; 100].try_into().unwrap();
+                let frame = StandardSv2Frame::from_message(payload, 0, 0, false).unwrap();
+                sender.try_send(frame.into()).unwrap();
+            }
+        };
+
+        let writes_before = writes.load(Ordering::SeqCst);
+        send_frames(4);
+        for _ in 0..4 {
+            upstream_receiver.recv().await.unwrap();
+        }
+        assert_eq!(writes.load(Ordering::SeqCst), writes_before + 1);
+
+        // Over `max_frames` the queued frames are split in several writes
+        let writes_before = writes.load(Ordering::SeqCst);
+        send_frames(6);
+        for _ in 0..6 {
+            upstream_receiver.recv().await.unwrap();
+        }
+        assert_eq!(writes.load(Ordering::SeqCst), writes_before + 2);
+        assert_eq!(metrics.frames_out(), frames_out + 10);
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+/// Exponential backoff between two attempts to connect to an upstream. The first attempt is
+/// immediate, after each failure the delay doubles starting from `base`, up to `cap`.
+///
+/// A connection that drops before `min_uptime` counts as a failed attempt, so a peer that accepts
+/// the handshake and closes the connection right away is not retried in a tight loop. Only a
+/// connection that stayed up for at least `min_uptime` resets the backoff.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectBackoff {
+    base: Duration,
+    cap: Duration,
+    min_uptime: Duration,
+    failed_attempts: u32,
+}
+
+impl ReconnectBackoff {
+    pub const DEFAULT_BASE: Duration = Duration::from_secs(1);
+    pub const DEFAULT_CAP: Duration = Duration::from_secs(60);
+    pub const DEFAULT_MIN_UPTIME: Duration = Duration::from_secs(30);
+
+    pub fn new(base: Duration, cap: Duration) -> Self {
+        Self {
+            base,
+            cap,
+            min_uptime: Self::DEFAULT_MIN_UPTIME,
+            failed_attempts: 0,
+        }
+    }
+
+    /// How long a connection must stay up for the backoff to be reset when it drops
+    pub fn with_min_uptime(mut self, min_uptime: Duration) -> Self {
+        self.min_uptime = min_uptime;
+        self
+    }
+
+    /// How long to wait before the next attempt
+    pub fn delay(&self) -> Duration {
+        match self.failed_attempts {
+            0 => Duration::ZERO,
+            n => {
+                let factor = 2_u32.saturating_pow(n - 1);
+                self.base.saturating_mul(factor).min(self.cap)
+            }
+        }
+    }
+
+    /// An attempt to connect failed
+    pub fn on_failure(&mut self) {
+        self.failed_attempts = self.failed_attempts.saturating_add(1);
+    }
+
+    /// An established connection dropped after `uptime`
+    pub fn on_disconnect(&mut self, uptime: Duration) {
+        if uptime >= self.min_uptime {
+            self.failed_attempts = 0;
+        }
+        self.on_failure();
+    }
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_BASE, Self::DEFAULT_CAP)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_backoff_doubles_up_to_cap() {
+        let base = Duration::from_millis(100);
+        let mut backoff = ReconnectBackoff::new(base, Duration::from_millis(350));
+        assert_eq!(backoff.delay(), Duration::ZERO);
+        let mut delays = vec![];
+        for _ in 0..4 {
+            backoff.on_failure();
+            delays.push(backoff.delay());
+        }
+        assert_eq!(
+            delays,
+            vec![
+                base,
+                base * 2,
+                Duration::from_millis(350),
+                Duration::from_millis(350)
+            ]
+        );
+    }
+
+    #[test]
+    fn backoff_is_only_reset_after_the_minimum_uptime() {
+        let base = Duration::from_millis(100);
+        let mut backoff = ReconnectBackoff::new(base, Duration::from_secs(10))
+            .with_min_uptime(Duration::from_secs(1));
+        backoff.on_failure();
+        backoff.on_failure();
+        assert_eq!(backoff.delay(), base * 2);
+
+        // Dropped right after the handshake, the backoff keeps growing
+        backoff.on_disconnect(Duration::from_millis(10));
+        assert_eq!(backoff.delay(), base * 4);
+
+        // Stayed up long enough, the next attempt waits for the base delay only
+        backoff.on_disconnect(Duration::from_secs(1));
+        assert_eq!(backoff.delay(), base);
+    }
+}
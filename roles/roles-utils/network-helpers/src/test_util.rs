@@ -0,0 +1,15 @@
+//! Authority key pair shared by the tests of the roles. The private key is the secret key 1, it
+//! must never be used outside of tests.
+
+/// x-only public key of [`AUTHORITY_PRIVATE_KEY`]
+pub const AUTHORITY_PUBLIC_KEY: [u8; 32] = [
+    0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87, 0x0b, 0x07,
+    0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16, 0xf8, 0x17, 0x98,
+];
+
+/// The secret key 1
+pub const AUTHORITY_PRIVATE_KEY: [u8; 32] = {
+    let mut key = [0; 32];
+    key[31] = 1;
+    key
+};
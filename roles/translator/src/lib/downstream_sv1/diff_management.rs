@@ -1,12 +1,13 @@
 use super::{Downstream, DownstreamMessages, SetDownstreamTarget};
 
 use super::super::error::{Error, ProxyResult};
-use roles_logic_sv2::utils::Mutex;
-use std::{ops::Div, sync::Arc};
+use roles_logic_sv2::{
+    sv1_sv2_conversions::target_to_sv1_difficulty,
+    utils::{Mutex, Target},
+};
+use std::sync::Arc;
 use v1::json_rpc;
 
-use stratum_common::bitcoin::util::uint::Uint256;
-
 impl Downstream {
     /// initializes the timestamp and resets the number of submits for a connection.
     /// Should only be called once for the lifetime of a connection since `try_update_difficulty_settings()`
@@ -105,9 +106,8 @@ impl Downstream {
                 Err(v) => return Err(Error::TargetError(v)),
             };
             tracing::debug!("New target from hashrate: {:?}", new_target.inner_as_ref());
-            let message = Self::get_set_difficulty(new_target.to_vec())?;
             // send mining.set_difficulty to miner
-            Downstream::send_message_downstream(self_.clone(), message).await?;
+            Self::send_set_difficulty(self_.clone(), new_target.to_vec()).await?;
             let update_target_msg = SetDownstreamTarget {
                 channel_id,
                 new_target: new_target.into(),
@@ -149,6 +149,21 @@ impl Downstream {
         Ok(())
     }
 
+    /// Sends the `mining.set_difficulty` for `target` to the Downstream role. The shares received
+    /// from now on are recorded at this difficulty.
+    pub(super) async fn send_set_difficulty(
+        self_: Arc<Mutex<Self>>,
+        target: Vec<u8>,
+    ) -> ProxyResult<'static, ()> {
+        let difficulty = Self::difficulty_from_target(target.clone())?;
+        let message = Self::get_set_difficulty(target)?;
+        self_
+            .safe_lock(|d| d.current_difficulty = difficulty)
+            .map_err(|_e| Error::PoisonLock)?;
+        Self::send_message_downstream(self_, message).await?;
+        Ok(())
+    }
+
     /// Converts target received by the `SetTarget` SV2 message from the Upstream role into the
     /// difficulty for the Downstream role and creates the SV1 `mining.set_difficulty` message to
     /// be sent to the Downstream role.
@@ -164,32 +179,11 @@ impl Downstream {
     /// Converts target received by the `SetTarget` SV2 message from the Upstream role into the
     /// difficulty for the Downstream role sent via the SV1 `mining.set_difficulty` message.
     #[allow(clippy::result_large_err)]
-    pub(super) fn difficulty_from_target(mut target: Vec<u8>) -> ProxyResult<'static, f64> {
-        // reverse because target is LE and this function relies on BE
-        target.reverse();
-        let target = target.as_slice();
+    pub(super) fn difficulty_from_target(target: Vec<u8>) -> ProxyResult<'static, f64> {
+        // Shorter targets are accepted, the missing most significant bytes are zero
+        let target = Target::try_from(target.as_slice())?.to_le_bytes();
         tracing::debug!("Target: {:?}", target);
-
-        // If received target is 0, return 0
-        if Downstream::is_zero(target) {
-            return Ok(0.0);
-        }
-        let target = Uint256::from_be_slice(target)?;
-        let pdiff: [u8; 32] = [
-            0, 0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
-            255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
-        ];
-        let pdiff = Uint256::from_be_bytes(pdiff);
-
-        if pdiff > target {
-            let diff = pdiff.div(target);
-            Ok(diff.low_u64() as f64)
-        } else {
-            let diff = target.div(pdiff);
-            let diff = diff.low_u64() as f64;
-            // TODO still results in a difficulty that is too low
-            Ok(1.0 / diff)
-        }
+        Ok(target_to_sv1_difficulty(&target))
     }
 
     /// This function updates the miner hashrate and resets difficulty management params. To calculate hashrate it calculates the realized shares per minute from the number of shares submitted
@@ -289,17 +283,6 @@ impl Downstream {
             })
             .map_err(|_e| Error::PoisonLock)?
     }
-
-    /// Helper function to check if target is set to zero for some reason (typically happens when
-    /// Downstream role first connects).
-    /// https://stackoverflow.com/questions/65367552/checking-a-vecu8-to-see-if-its-all-zero
-    fn is_zero(buf: &[u8]) -> bool {
-        let (prefix, aligned, suffix) = unsafe { buf.align_to::<u128>() };
-
-        prefix.iter().all(|&x| x == 0)
-            && suffix.iter().all(|&x| x == 0)
-            && aligned.iter().all(|&x| x == 0)
-    }
 }
 
 #[cfg(test)]
@@ -491,6 +474,70 @@ mod test {
         let actual_0s = trailing_0s(initial_target.inner_as_ref().to_vec());
         assert!(expected_0s.abs_diff(actual_0s) <= 1);
     }
+    #[tokio::test]
+    async fn test_burst_of_fast_shares_increases_difficulty() {
+        let start_hashrate = 1_000_000_000_000.0;
+        let downstream_conf = DownstreamDifficultyConfig {
+            min_individual_miner_hashrate: start_hashrate,
+            shares_per_minute: 10.0,
+            submits_since_last_update: 0,
+            timestamp_of_last_update: 0,
+        };
+        let upstream_config = UpstreamDifficultyConfig {
+            channel_diff_update_interval: 60,
+            channel_nominal_hashrate: 0.0,
+            timestamp_of_last_update: 0,
+            should_aggregate: false,
+        };
+        let (tx_sv1_submit, _rx_sv1_submit) = unbounded();
+        let (tx_outgoing, rx_outgoing) = unbounded();
+        let downstream = Downstream::new(
+            1,
+            vec![],
+            vec![],
+            None,
+            None,
+            tx_sv1_submit,
+            tx_outgoing,
+            false,
+            0,
+            downstream_conf,
+            Arc::new(Mutex::new(upstream_config)),
+        );
+        let downstream = Arc::new(Mutex::new(downstream));
+        let initial_target = Downstream::hash_rate_to_target(downstream.clone()).unwrap();
+        let initial_difficulty = Downstream::difficulty_from_target(initial_target).unwrap();
+
+        // 10 times the expected shares in the last minute
+        downstream
+            .safe_lock(|d| {
+                d.difficulty_mgmt.timestamp_of_last_update = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+                    - 60;
+            })
+            .unwrap();
+        for _ in 0..100 {
+            Downstream::save_share(downstream.clone()).unwrap();
+        }
+        Downstream::try_update_difficulty_settings(downstream.clone())
+            .await
+            .unwrap();
+
+        let new_hashrate = downstream
+            .safe_lock(|d| d.difficulty_mgmt.min_individual_miner_hashrate)
+            .unwrap();
+        assert!(new_hashrate > start_hashrate);
+        let set_difficulty = match rx_outgoing.try_recv().unwrap() {
+            v1::Message::Notification(n) => {
+                v1::methods::server_to_client::SetDifficulty::try_from(n).unwrap()
+            }
+            m => panic!("Expected mining.set_difficulty, got {:?}", m),
+        };
+        assert!(set_difficulty.value > initial_difficulty);
+    }
+
     fn trailing_0s(mut v: Vec<u8>) -> usize {
         let mut ret = 0;
         while v.pop() == Some(0) {
@@ -15,10 +15,15 @@ use error_handling::handle_result;
 use futures::FutureExt;
 use tokio::sync::broadcast;
 
-use super::{kill, DownstreamMessages, SubmitShareWithChannelId, SUBSCRIBE_TIMEOUT_SECS};
+use super::super::utils::clamp_extranonce2_size;
+use super::{
+    kill, CloseDownstreamChannel, DownstreamListenerConfig, DownstreamMessages, ShareStats,
+    SubmitShareWithChannelId, SUBSCRIBE_TIMEOUT_SECS,
+};
 
 use roles_logic_sv2::{
     common_properties::{IsDownstream, IsMiningDownstream},
+    mining_sv2::SetExtranoncePrefix,
     utils::Mutex,
 };
 
@@ -26,7 +31,7 @@ use crate::error::Error;
 use futures::select;
 use tokio_util::codec::{FramedRead, LinesCodec};
 
-use std::{net::SocketAddr, sync::Arc};
+use std::sync::Arc;
 use tracing::{debug, info, warn};
 use v1::{
     client_to_server::{self, Submit},
@@ -60,8 +65,17 @@ pub struct Downstream {
     /// True if this is the first job received from `Upstream`.
     first_job_received: bool,
     extranonce2_len: usize,
+    /// Extranonce2 space of the channel reserved to the miner. When `extranonce2_len` is smaller
+    /// the miner gets `extranonce1` padded with zeros up to it.
+    max_extranonce2_len: usize,
+    /// Smallest `extranonce2_size` that the miner accepts
+    min_extranonce2_len: usize,
     pub(super) difficulty_mgmt: DownstreamDifficultyConfig,
     pub(super) upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    /// Difficulty of the last `mining.set_difficulty` sent, 0 until the first one is sent
+    pub(super) current_difficulty: f64,
+    /// Shares submitted so far, recorded against `current_difficulty`
+    share_stats: ShareStats,
 }
 
 impl Downstream {
@@ -89,8 +103,12 @@ impl Downstream {
             tx_outgoing,
             first_job_received,
             extranonce2_len,
+            max_extranonce2_len: extranonce2_len,
+            min_extranonce2_len: 0,
             difficulty_mgmt,
             upstream_difficulty_config,
+            current_difficulty: 0.0,
+            share_stats: ShareStats::default(),
         }
     }
     /// Instantiate a new `Downstream`.
@@ -100,10 +118,12 @@ impl Downstream {
         connection_id: u32,
         tx_sv1_bridge: Sender<DownstreamMessages>,
         mut rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
+        mut rx_set_extranonce: broadcast::Receiver<Vec<SetExtranoncePrefix<'static>>>,
         tx_status: status::Sender,
         extranonce1: Vec<u8>,
         last_notify: Option<server_to_client::Notify<'static>>,
         extranonce2_len: usize,
+        min_extranonce2_len: usize,
         host: String,
         difficulty_config: DownstreamDifficultyConfig,
         upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
@@ -115,6 +135,7 @@ impl Downstream {
         let (tx_outgoing, receiver_outgoing) = bounded(10);
 
         let socket_writer_clone = socket_writer.clone();
+        let tx_sv1_bridge_reader = tx_sv1_bridge.clone();
         // Used to send SV1 `mining.notify` messages to the Downstreams
         let _socket_writer_notify = socket_writer;
 
@@ -129,8 +150,12 @@ impl Downstream {
             tx_outgoing,
             first_job_received: false,
             extranonce2_len,
+            max_extranonce2_len: extranonce2_len,
+            min_extranonce2_len,
             difficulty_mgmt: difficulty_config,
             upstream_difficulty_config,
+            current_difficulty: 0.0,
+            share_stats: ShareStats::default(),
         }));
         let self_ = downstream.clone();
 
@@ -166,18 +191,9 @@ impl Downstream {
                         match res {
                             Some(Ok(incoming)) => {
                                 debug!("Receiving from Mining Device {}: {:?}", &host_, &incoming);
-                                let incoming: json_rpc::Message = handle_result!(tx_status_reader, serde_json::from_str(&incoming));
-                                // Handle what to do with message
-                                // if let json_rpc::Message
-
-                                // if message is Submit Shares update difficulty management
-                                if let v1::Message::StandardRequest(standard_req) = incoming.clone() {
-                                    if let Ok(Submit{..}) = standard_req.try_into() {
-                                        handle_result!(tx_status_reader, Self::save_share(self_.clone()));
-                                    }
-                                }
-
-                                let res = Self::handle_incoming_sv1(self_.clone(), incoming).await;
+                                // A line can carry a single message or a batch of messages
+                                let incoming = handle_result!(tx_status_reader, json_rpc::Message::from_batch(&incoming));
+                                let res = Self::handle_incoming_batch(self_.clone(), incoming).await;
                                 handle_result!(tx_status_reader, res);
                             }
                             Some(Err(_)) => {
@@ -198,6 +214,26 @@ impl Downstream {
                     }
                 };
             }
+            if let Ok(stats) = self_.safe_lock(|d| d.share_stats()) {
+                info!(
+                    "Downstream {}: {} shares submitted, {:.1}% accepted, {} accepted difficulty, last difficulty {}",
+                    connection_id,
+                    stats.submitted,
+                    stats.accept_rate() * 100.0,
+                    stats.accepted_difficulty,
+                    stats.current_difficulty
+                );
+            }
+            // The miner is gone, let the Bridge close the channel opened for it
+            let close = DownstreamMessages::CloseDownstreamChannel(CloseDownstreamChannel {
+                channel_id: connection_id,
+            });
+            if tx_sv1_bridge_reader.send(close).await.is_err() {
+                debug!(
+                    "Downstream: Bridge is gone, can not close channel {}",
+                    connection_id
+                );
+            }
             kill(&tx_shutdown_clone).await;
             warn!("Downstream: Shutting down sv1 downstream reader");
         });
@@ -263,11 +299,9 @@ impl Downstream {
                         tx_status_notify,
                         Self::init_difficulty_management(downstream.clone(), &target).await
                     );
-                    let message =
-                        handle_result!(tx_status_notify, Self::get_set_difficulty(target));
                     handle_result!(
                         tx_status_notify,
-                        Downstream::send_message_downstream(downstream.clone(), message).await
+                        Self::send_set_difficulty(downstream.clone(), target).await
                     );
 
                     let sv1_mining_notify_msg = last_notify.clone().unwrap();
@@ -295,6 +329,10 @@ impl Downstream {
                             let message: json_rpc::Message = sv1_mining_notify_msg.into();
                             handle_result!(tx_status_notify, Downstream::send_message_downstream(downstream.clone(), message).await);
                         },
+                        res = rx_set_extranonce.recv().fuse() => {
+                            let prefixes = handle_result!(tx_status_notify, res);
+                            handle_result!(tx_status_notify, Self::on_set_extranonce_prefix(downstream.clone(), prefixes).await);
+                        },
                         _ = rx_shutdown.recv().fuse() => {
                                 break;
                             }
@@ -323,16 +361,20 @@ impl Downstream {
     /// Accept connections from one or more SV1 Downstream roles (SV1 Mining Devices) and create a
     /// new `Downstream` for each connection.
     pub fn accept_connections(
-        downstream_addr: SocketAddr,
+        config: DownstreamListenerConfig,
         tx_sv1_submit: Sender<DownstreamMessages>,
         tx_mining_notify: broadcast::Sender<server_to_client::Notify<'static>>,
         tx_status: status::Sender,
         bridge: Arc<Mutex<crate::proxy::Bridge>>,
-        downstream_difficulty_config: DownstreamDifficultyConfig,
-        upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
     ) {
+        let DownstreamListenerConfig {
+            address,
+            difficulty_config: downstream_difficulty_config,
+            upstream_difficulty_config,
+            min_extranonce2_len,
+        } = config;
         task::spawn(async move {
-            let downstream_listener = TcpListener::bind(downstream_addr).await.unwrap();
+            let downstream_listener = TcpListener::bind(address).await.unwrap();
             let mut downstream_incoming = downstream_listener.incoming();
 
             while let Some(stream) = downstream_incoming.next().await {
@@ -351,10 +393,12 @@ impl Downstream {
                             opened.channel_id,
                             tx_sv1_submit.clone(),
                             tx_mining_notify.subscribe(),
+                            opened.rx_set_extranonce,
                             tx_status.listener_to_connection(),
                             opened.extranonce,
                             opened.last_notify,
                             opened.extranonce2_len as usize,
+                            min_extranonce2_len,
                             host,
                             downstream_difficulty_config.clone(),
                             upstream_difficulty_config.clone(),
@@ -369,6 +413,23 @@ impl Downstream {
         });
     }
 
+    /// Handles the messages received in a single line, in order. Stops at the first error.
+    async fn handle_incoming_batch(
+        self_: Arc<Mutex<Self>>,
+        messages: Vec<json_rpc::Message>,
+    ) -> Result<(), super::super::error::Error<'static>> {
+        for message in messages {
+            // if message is Submit Shares update difficulty management
+            if let v1::Message::StandardRequest(standard_req) = message.clone() {
+                if let Ok(Submit { .. }) = standard_req.try_into() {
+                    Self::save_share(self_.clone())?;
+                }
+            }
+            Self::handle_incoming_sv1(self_.clone(), message).await?;
+        }
+        Ok(())
+    }
+
     /// As SV1 messages come in, determines if the message response needs to be translated to SV2
     /// and sent to the `Upstream`, or if a direct response can be sent back by the `Translator`
     /// (SV1 and SV2 protocol messages are NOT 1-to-1).
@@ -376,9 +437,32 @@ impl Downstream {
         self_: Arc<Mutex<Self>>,
         message_sv1: json_rpc::Message,
     ) -> Result<(), super::super::error::Error<'static>> {
+        let is_submit = matches!(
+            &message_sv1,
+            json_rpc::Message::StandardRequest(r) if r.method == "mining.submit"
+        );
+        let subscribe_id = match &message_sv1 {
+            json_rpc::Message::StandardRequest(r) if r.method == "mining.subscribe" => Some(r.id),
+            _ => None,
+        };
         // `handle_message` in `IsServer` trait + calls `handle_request`
         // TODO: Map err from V1Error to Error::V1Error
-        let response = self_.safe_lock(|s| s.handle_message(message_sv1)).unwrap();
+        let response = self_
+            .safe_lock(|s| {
+                if let Some(error) = subscribe_id.and_then(|id| s.unsatisfiable_subscribe(id)) {
+                    return Ok(Some(error));
+                }
+                let response = s.handle_message(message_sv1);
+                if is_submit {
+                    let accepted = matches!(
+                        &response,
+                        Ok(Some(r)) if r.result.as_bool() == Some(true)
+                    );
+                    s.record_share(accepted);
+                }
+                response
+            })
+            .unwrap();
         match response {
             Ok(res) => {
                 if let Some(r) = res {
@@ -401,6 +485,109 @@ impl Downstream {
         }
     }
 
+    /// Error response to a `mining.subscribe` when the extranonce2 space of the channel is smaller
+    /// than the `extranonce2_size` the miner needs, `None` when the subscribe can be satisfied.
+    fn unsatisfiable_subscribe(&self, id: u64) -> Option<json_rpc::Response> {
+        clamp_extranonce2_size(
+            self.extranonce2_len,
+            self.min_extranonce2_len,
+            self.max_extranonce2_len,
+        )
+        .is_none()
+        .then(|| {
+            warn!(
+                "Down: Rejecting mining.subscribe of {}, the channel has {} bytes of extranonce2 and the miner needs {}",
+                self.connection_id, self.max_extranonce2_len, self.min_extranonce2_len
+            );
+            json_rpc::Response {
+                id,
+                error: Some(json_rpc::JsonRpcError {
+                    code: 20,
+                    message: format!(
+                        "extranonce2_size of at least {} bytes not available",
+                        self.min_extranonce2_len
+                    ),
+                    data: None,
+                }),
+                result: serde_json::Value::Null,
+            }
+        })
+    }
+
+    /// Records a `mining.submit` at the current difficulty
+    fn record_share(&mut self, accepted: bool) {
+        self.share_stats.submitted += 1;
+        if accepted {
+            self.share_stats.accepted += 1;
+            self.share_stats.accepted_difficulty += self.current_difficulty;
+        }
+    }
+
+    /// Shares submitted since the Downstream role connected and the difficulty they are currently
+    /// submitted at. Logged when the Downstream role disconnects.
+    pub fn share_stats(&self) -> ShareStats {
+        ShareStats {
+            current_difficulty: self.current_difficulty,
+            ..self.share_stats
+        }
+    }
+
+    /// Updates `extranonce1` and `extranonce2_size` with the values of a SV1
+    /// `mining.set_extranonce` and returns the message to be sent to the Downstream role. The new
+    /// values must fill the same extranonce space of the channel, so the sum of the `extranonce1`
+    /// length and `extranonce2_size` can not change.
+    #[allow(clippy::result_large_err)]
+    pub fn on_set_extranonce(
+        &mut self,
+        msg: server_to_client::SetExtranonce<'static>,
+    ) -> ProxyResult<'static, json_rpc::Message> {
+        let extranonce1_len = msg.extra_nonce1.len();
+        let channel_extranonce_len = self.extranonce1.len() + self.max_extranonce2_len;
+        if extranonce1_len + msg.extra_nonce2_size != channel_extranonce_len {
+            return Err(Error::InvalidExtranonce(format!(
+                "extranonce1 of {} bytes and extranonce2_size {} do not match the channel extranonce of {} bytes",
+                extranonce1_len,
+                msg.extra_nonce2_size,
+                channel_extranonce_len
+            )));
+        }
+        if msg.extra_nonce2_size < self.min_extranonce2_len {
+            return Err(Error::InvalidExtranonce(format!(
+                "extranonce2_size {} is smaller than the {} bytes the miner needs",
+                msg.extra_nonce2_size, self.min_extranonce2_len
+            )));
+        }
+        self.max_extranonce2_len = msg.extra_nonce2_size;
+        Ok(self.update_extranonce(msg.extra_nonce1, msg.extra_nonce2_size)?)
+    }
+
+    /// Picks the extranonce prefix of this Downstream's channel among the ones sent by the
+    /// `Bridge` after the Upstream changed its extranonce prefix, applies it with
+    /// [`Downstream::on_set_extranonce`] and sends the `mining.set_extranonce` to the Downstream
+    /// role.
+    async fn on_set_extranonce_prefix(
+        self_: Arc<Mutex<Self>>,
+        prefixes: Vec<SetExtranoncePrefix<'static>>,
+    ) -> ProxyResult<'static, ()> {
+        let message = self_.safe_lock(|d| {
+            prefixes
+                .into_iter()
+                .find(|prefix| prefix.channel_id == d.connection_id)
+                .map(|prefix| {
+                    let set_extranonce = server_to_client::SetExtranonce {
+                        extra_nonce1: prefix.extranonce_prefix.to_vec().try_into()?,
+                        extra_nonce2_size: d.max_extranonce2_len,
+                    };
+                    d.on_set_extranonce(set_extranonce)
+                })
+                .transpose()
+        })??;
+        if let Some(message) = message {
+            Self::send_message_downstream(self_, message).await?;
+        }
+        Ok(())
+    }
+
     /// Send SV1 response message that is generated by `Downstream` (as opposed to being received
     /// by `Bridge`) to be written to the SV1 Downstream role.
     pub(super) async fn send_message_downstream(
@@ -460,6 +647,25 @@ impl IsServer<'static> for Downstream {
         )
     }
 
+    /// Handle the `mining.suggest_difficulty` message. Before the first job is sent to the miner
+    /// the suggested difficulty replaces the configured miner hashrate, so the first
+    /// `mining.set_difficulty` uses it. Suggestions received later are ignored, from then on the
+    /// difficulty is managed by the proxy.
+    fn handle_suggest_difficulty(
+        &mut self,
+        request: &client_to_server::SuggestDifficulty,
+    ) -> Option<f64> {
+        debug!("Down: Handling mining.suggest_difficulty: {:?}", &request);
+        if self.first_job_received || !request.value.is_normal() || request.value < 0.0 {
+            return None;
+        }
+        // a share of difficulty 1 takes 2^32 hashes on average
+        let hashrate =
+            request.value * 2_f64.powi(32) * self.difficulty_mgmt.shares_per_minute as f64 / 60.0;
+        self.difficulty_mgmt.min_individual_miner_hashrate = hashrate as f32;
+        Some(request.value)
+    }
+
     /// Handle the response to a `mining.subscribe` message received from the client.
     /// The subscription messages are erroneous and just used to conform the SV1 protocol spec.
     /// Because no one unsubscribed in practice, they just unplug their machine.
@@ -494,14 +700,33 @@ impl IsServer<'static> for Downstream {
         info!("Down: Submitting Share");
         debug!("Down: Handling mining.submit: {:?}", &request);
 
-        // TODO: Check if receiving valid shares by adding diff field to Downstream
+        let version_bits_allowed = match (&request.version_bits, &self.version_rolling_mask) {
+            (Some(version_bits), Some(mask)) => mask.check_mask(version_bits),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        if !version_bits_allowed {
+            warn!(
+                "Down: Rejecting mining.submit, version bits {:?} are outside of the negotiated mask {:?}",
+                request.version_bits, self.version_rolling_mask
+            );
+            return false;
+        }
 
         if self.first_job_received {
+            // The zeros that pad the extranonce1 sent to the miner belong to the extranonce2 of
+            // the channel
+            let mut share = request.clone();
+            if self.extranonce2_len < self.max_extranonce2_len {
+                let mut extranonce2 = vec![0; self.max_extranonce2_len - self.extranonce2_len];
+                extranonce2.extend_from_slice(request.extra_nonce2.as_ref());
+                share.extra_nonce2 = extranonce2.try_into().unwrap();
+            }
             let to_send = SubmitShareWithChannelId {
                 channel_id: self.connection_id,
-                share: request.clone(),
+                share,
                 extranonce: self.extranonce1.clone(),
-                extranonce2_len: self.extranonce2_len,
+                extranonce2_len: self.max_extranonce2_len,
                 version_rolling_mask: self.version_rolling_mask.clone(),
             };
             self.tx_sv1_bridge
@@ -525,22 +750,46 @@ impl IsServer<'static> for Downstream {
     }
 
     /// Sets the `extranonce1` field sent in the SV1 `mining.notify` message to the value specified
-    /// by the SV2 `OpenExtendedMiningChannelSuccess` message sent from the Upstream role.
-    fn set_extranonce1(
-        &mut self,
-        _extranonce1: Option<Extranonce<'static>>,
-    ) -> Extranonce<'static> {
-        self.extranonce1.clone().try_into().unwrap()
+    /// by the SV2 `OpenExtendedMiningChannelSuccess` message sent from the Upstream role, or to
+    /// `extranonce1` when the Upstream role changes it.
+    fn set_extranonce1(&mut self, extranonce1: Option<Extranonce<'static>>) -> Extranonce<'static> {
+        if let Some(extranonce1) = extranonce1 {
+            self.extranonce1 = extranonce1.into();
+        }
+        self.extranonce1()
     }
 
-    /// Returns the `Downstream`'s `extranonce1` value.
+    /// Returns the `Downstream`'s `extranonce1` value, padded with zeros when the miner uses a
+    /// smaller extranonce2 than the one of the channel.
     fn extranonce1(&self) -> Extranonce<'static> {
-        self.extranonce1.clone().try_into().unwrap()
+        let mut extranonce1 = self.extranonce1.clone();
+        extranonce1.resize(
+            extranonce1.len() + self.max_extranonce2_len - self.extranonce2_len,
+            0,
+        );
+        extranonce1.try_into().unwrap()
     }
 
-    /// Sets the `extranonce2_size` field sent in the SV1 `mining.notify` message to the value
-    /// specified by the SV2 `OpenExtendedMiningChannelSuccess` message sent from the Upstream role.
-    fn set_extranonce2_size(&mut self, _extra_nonce2_size: Option<usize>) -> usize {
+    /// Sets the `extranonce2_size` field sent to the miner to the requested size clamped into the
+    /// extranonce2 space of the channel, see [`clamp_extranonce2_size`]. A request that can not be
+    /// satisfied leaves the size unchanged. Returns the size the miner has to use.
+    fn set_extranonce2_size(&mut self, extra_nonce2_size: Option<usize>) -> usize {
+        if let Some(extra_nonce2_size) = extra_nonce2_size {
+            match clamp_extranonce2_size(
+                extra_nonce2_size,
+                self.min_extranonce2_len,
+                self.max_extranonce2_len,
+            ) {
+                Some(extranonce2_len) => self.extranonce2_len = extranonce2_len,
+                None => warn!(
+                    "Down: Can not use an extranonce2_size of {} for {}, the channel has {} bytes and the miner needs {}",
+                    extra_nonce2_size,
+                    self.connection_id,
+                    self.max_extranonce2_len,
+                    self.min_extranonce2_len
+                ),
+            }
+        }
         self.extranonce2_len
     }
 
@@ -583,6 +832,49 @@ impl IsDownstream for Downstream {
 mod tests {
     use super::*;
 
+    fn difficulty_configs() -> (DownstreamDifficultyConfig, UpstreamDifficultyConfig) {
+        let downstream_conf = DownstreamDifficultyConfig {
+            min_individual_miner_hashrate: 0.0,
+            shares_per_minute: 10.0,
+            submits_since_last_update: 0,
+            timestamp_of_last_update: 0,
+        };
+        let upstream_config = UpstreamDifficultyConfig {
+            channel_diff_update_interval: 60,
+            channel_nominal_hashrate: 0.0,
+            timestamp_of_last_update: 0,
+            should_aggregate: false,
+        };
+        (downstream_conf, upstream_config)
+    }
+
+    /// `Downstream` of the channel 1 with an 8 bytes extranonce1 and extranonce2, "user"
+    /// authorized, the version rolling mask 0x1fffe000 negotiated and the first job received.
+    /// Returned with the receivers of the messages it sends to the `Bridge` and to the miner.
+    fn test_downstream() -> (
+        Downstream,
+        Receiver<DownstreamMessages>,
+        Receiver<json_rpc::Message>,
+    ) {
+        let (downstream_conf, upstream_config) = difficulty_configs();
+        let (tx_sv1_submit, rx_sv1_submit) = async_channel::unbounded();
+        let (tx_outgoing, rx_outgoing) = async_channel::unbounded();
+        let downstream = Downstream::new(
+            1,
+            vec!["user".to_string()],
+            vec![1; 8],
+            Some(HexU32Be(0x1fff_e000)),
+            None,
+            tx_sv1_submit,
+            tx_outgoing,
+            true,
+            8,
+            downstream_conf,
+            Arc::new(Mutex::new(upstream_config)),
+        );
+        (downstream, rx_sv1_submit, rx_outgoing)
+    }
+
     #[test]
     fn gets_difficulty_from_target() {
         let target = vec![
@@ -593,4 +885,338 @@ mod tests {
         let expect = 512.0;
         assert_eq!(actual, expect);
     }
+
+    #[test]
+    fn dropped_connection_closes_the_downstream_channel() {
+        let (downstream_conf, upstream_config) = difficulty_configs();
+        let (tx_sv1_bridge, rx_sv1_bridge) = async_channel::unbounded();
+        let (tx_status, _rx_status) = async_channel::unbounded();
+        let (_tx_sv1_notify, rx_sv1_notify) = broadcast::channel(10);
+        let (_tx_set_extranonce, rx_set_extranonce) = broadcast::channel(10);
+
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let miner = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            Downstream::new_downstream(
+                stream,
+                7,
+                tx_sv1_bridge,
+                rx_sv1_notify,
+                rx_set_extranonce,
+                status::Sender::Downstream(tx_status),
+                vec![1; 8],
+                None,
+                8,
+                8,
+                "127.0.0.1".to_string(),
+                downstream_conf,
+                Arc::new(Mutex::new(upstream_config)),
+            )
+            .await;
+
+            drop(miner);
+            let message =
+                async_std::future::timeout(std::time::Duration::from_secs(5), rx_sv1_bridge.recv())
+                    .await
+                    .expect("the channel is not closed when the miner disconnects")
+                    .unwrap();
+            match message {
+                DownstreamMessages::CloseDownstreamChannel(close) => {
+                    assert_eq!(close.channel_id, 7)
+                }
+                m => panic!("Expected CloseDownstreamChannel, got {:?}", m),
+            }
+        });
+    }
+
+    #[test]
+    fn set_extranonce_updates_extranonce1() {
+        let (mut downstream, _rx_sv1_submit, _rx_outgoing) = test_downstream();
+
+        let set_extranonce = server_to_client::SetExtranonce {
+            extra_nonce1: vec![2; 10].try_into().unwrap(),
+            extra_nonce2_size: 6,
+        };
+        let message = downstream.on_set_extranonce(set_extranonce).unwrap();
+        match message {
+            json_rpc::Message::Notification(n) => assert_eq!(n.method, "mining.set_extranonce"),
+            m => panic!("Expected mining.set_extranonce, got {:?}", m),
+        }
+        let extranonce1: Vec<u8> = downstream.extranonce1().into();
+        assert_eq!(extranonce1, vec![2; 10]);
+        assert_eq!(downstream.extranonce2_size(), 6);
+
+        // the channel extranonce is 16 bytes, 10 + 8 does not fit
+        let set_extranonce = server_to_client::SetExtranonce {
+            extra_nonce1: vec![3; 10].try_into().unwrap(),
+            extra_nonce2_size: 8,
+        };
+        assert!(downstream.on_set_extranonce(set_extranonce).is_err());
+        let extranonce1: Vec<u8> = downstream.extranonce1().into();
+        assert_eq!(extranonce1, vec![2; 10]);
+    }
+
+    #[test]
+    fn set_extranonce_prefix_of_the_channel_is_sent_to_the_miner() {
+        let (downstream, _rx_sv1_submit, rx_outgoing) = test_downstream();
+        let downstream = Arc::new(Mutex::new(downstream));
+
+        let prefixes = vec![
+            SetExtranoncePrefix {
+                channel_id: 2,
+                extranonce_prefix: vec![3; 8].try_into().unwrap(),
+            },
+            SetExtranoncePrefix {
+                channel_id: 1,
+                extranonce_prefix: vec![2; 8].try_into().unwrap(),
+            },
+        ];
+        async_std::task::block_on(Downstream::on_set_extranonce_prefix(
+            downstream.clone(),
+            prefixes,
+        ))
+        .unwrap();
+        match rx_outgoing.try_recv().unwrap() {
+            json_rpc::Message::Notification(n) => assert_eq!(n.method, "mining.set_extranonce"),
+            m => panic!("Expected mining.set_extranonce, got {:?}", m),
+        }
+        let extranonce1: Vec<u8> = downstream.safe_lock(|d| d.extranonce1()).unwrap().into();
+        assert_eq!(extranonce1, vec![2; 8]);
+
+        // The prefixes of the other channels are ignored
+        let prefixes = vec![SetExtranoncePrefix {
+            channel_id: 2,
+            extranonce_prefix: vec![4; 8].try_into().unwrap(),
+        }];
+        async_std::task::block_on(Downstream::on_set_extranonce_prefix(
+            downstream.clone(),
+            prefixes,
+        ))
+        .unwrap();
+        assert!(rx_outgoing.try_recv().is_err());
+    }
+
+    #[test]
+    fn smaller_extranonce2_size_pads_extranonce1() {
+        let (mut downstream, rx_sv1_submit, _rx_outgoing) = test_downstream();
+        downstream.min_extranonce2_len = 4;
+
+        // below the minimum, the miner gets the smallest size it accepts
+        assert_eq!(downstream.set_extranonce2_size(Some(2)), 4);
+        let extranonce1: Vec<u8> = downstream.extranonce1().into();
+        assert_eq!(extranonce1, [vec![1; 8], vec![0; 4]].concat());
+
+        // above the channel space, the miner gets all of it
+        assert_eq!(downstream.set_extranonce2_size(Some(16)), 8);
+        let extranonce1: Vec<u8> = downstream.extranonce1().into();
+        assert_eq!(extranonce1, vec![1; 8]);
+
+        assert_eq!(downstream.set_extranonce2_size(Some(6)), 6);
+        let submit = client_to_server::Submit {
+            user_name: "user".to_string(),
+            job_id: "0".to_string(),
+            extra_nonce2: vec![7; 6].try_into().unwrap(),
+            time: HexU32Be(1),
+            nonce: HexU32Be(1),
+            version_bits: None,
+            id: 2,
+        };
+        assert!(downstream.handle_submit(&submit));
+        match rx_sv1_submit.try_recv().unwrap() {
+            DownstreamMessages::SubmitShares(share) => {
+                let extranonce2: Vec<u8> = share.share.extra_nonce2.into();
+                assert_eq!(extranonce2, [vec![0; 2], vec![7; 6]].concat());
+                assert_eq!(share.extranonce2_len, 8);
+            }
+            m => panic!("Expected a share, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn subscribe_that_can_not_be_satisfied_gets_an_error() {
+        let (downstream, _rx_sv1_submit, rx_outgoing) = test_downstream();
+        let downstream = Arc::new(Mutex::new(downstream));
+        let subscribe = || -> json_rpc::Message {
+            json_rpc::StandardRequest {
+                id: 1,
+                method: "mining.subscribe".to_string(),
+                params: serde_json::json!(["cpuminer/1.0"]),
+            }
+            .into()
+        };
+
+        async_std::task::block_on(Downstream::handle_incoming_sv1(
+            downstream.clone(),
+            subscribe(),
+        ))
+        .unwrap();
+        assert!(matches!(
+            rx_outgoing.try_recv().unwrap(),
+            json_rpc::Message::OkResponse(_)
+        ));
+
+        // the miner needs more than the 8 bytes of extranonce2 of the channel
+        downstream
+            .safe_lock(|d| d.min_extranonce2_len = 12)
+            .unwrap();
+        async_std::task::block_on(Downstream::handle_incoming_sv1(
+            downstream.clone(),
+            subscribe(),
+        ))
+        .unwrap();
+        match rx_outgoing.try_recv().unwrap() {
+            json_rpc::Message::ErrorResponse(r) => {
+                assert_eq!(r.id, 1);
+                assert!(r.error.is_some());
+            }
+            m => panic!("Expected an error response, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn suggested_difficulty_is_used_for_first_set_difficulty() {
+        let (mut downstream, _rx_sv1_submit, _rx_outgoing) = test_downstream();
+        downstream.first_job_received = false;
+        let downstream = Arc::new(Mutex::new(downstream));
+        let suggest: json_rpc::Message = client_to_server::SuggestDifficulty {
+            id: 1,
+            value: 1024.0,
+        }
+        .into();
+        let response = downstream
+            .safe_lock(|d| d.handle_message(suggest))
+            .unwrap()
+            .unwrap();
+        assert!(response.is_none());
+
+        let target = Downstream::hash_rate_to_target(downstream.clone()).unwrap();
+        let difficulty = match Downstream::get_set_difficulty(target).unwrap() {
+            json_rpc::Message::Notification(n) => {
+                server_to_client::SetDifficulty::try_from(n).unwrap().value
+            }
+            m => panic!("Expected mining.set_difficulty, got {:?}", m),
+        };
+        // difficulty_from_target truncates to an integer
+        assert!((difficulty - 1024.0).abs() <= 1.0, "{}", difficulty);
+    }
+
+    #[test]
+    fn submit_with_version_bits_outside_of_mask_is_rejected() {
+        let (downstream, rx_sv1_submit, _rx_outgoing) = test_downstream();
+        let submit = |version_bits| client_to_server::Submit {
+            user_name: "user".to_string(),
+            job_id: "0".to_string(),
+            extra_nonce2: vec![0; 8].try_into().unwrap(),
+            time: HexU32Be(1),
+            nonce: HexU32Be(1),
+            version_bits,
+            id: 2,
+        };
+
+        assert!(downstream.handle_submit(&submit(Some(HexU32Be(0x0000_e000)))));
+        assert!(rx_sv1_submit.try_recv().is_ok());
+
+        assert!(!downstream.handle_submit(&submit(Some(HexU32Be(0x2000_0000)))));
+        assert!(rx_sv1_submit.try_recv().is_err());
+    }
+
+    #[test]
+    fn set_version_mask_narrows_allowed_version_bits() {
+        let (downstream, rx_sv1_submit, _rx_outgoing) = test_downstream();
+        let downstream = Arc::new(Mutex::new(downstream));
+        let submit = |version_bits| -> json_rpc::Message {
+            client_to_server::Submit {
+                user_name: "user".to_string(),
+                job_id: "0".to_string(),
+                extra_nonce2: vec![0; 8].try_into().unwrap(),
+                time: HexU32Be(1),
+                nonce: HexU32Be(1),
+                version_bits,
+                id: 2,
+            }
+            .into()
+        };
+        let submit_is_accepted = |version_bits| match downstream
+            .safe_lock(|d| d.handle_message(submit(version_bits)))
+            .unwrap()
+        {
+            Ok(Some(response)) => response.result.as_bool().unwrap(),
+            Err(v1::error::Error::InvalidSubmission) => false,
+            r => panic!("Expected a mining.submit response, got {:?}", r),
+        };
+
+        assert!(submit_is_accepted(Some(HexU32Be(0x1000_0000))));
+        assert!(rx_sv1_submit.try_recv().is_ok());
+
+        let message = downstream
+            .safe_lock(|d| d.update_version_rolling_mask(HexU32Be(0x0fff_e000)))
+            .unwrap();
+        match message {
+            json_rpc::Message::Notification(n) => {
+                assert_eq!(n.method, "mining.set_version_mask");
+                assert_eq!(n.params, serde_json::json!(["0fffe000"]));
+            }
+            m => panic!("Expected mining.set_version_mask, got {:?}", m),
+        }
+
+        // bit 28 was allowed by the negotiated mask but is not by the new one
+        assert!(!submit_is_accepted(Some(HexU32Be(0x1000_0000))));
+        assert!(rx_sv1_submit.try_recv().is_err());
+        assert!(submit_is_accepted(Some(HexU32Be(0x0000_e000))));
+        assert!(rx_sv1_submit.try_recv().is_ok());
+    }
+
+    #[test]
+    fn share_stats_record_submits_at_current_difficulty() {
+        let (downstream, _rx_sv1_submit, rx_outgoing) = test_downstream();
+        let downstream = Arc::new(Mutex::new(downstream));
+        let submit = |version_bits| -> json_rpc::Message {
+            client_to_server::Submit {
+                user_name: "user".to_string(),
+                job_id: "0".to_string(),
+                extra_nonce2: vec![0; 8].try_into().unwrap(),
+                time: HexU32Be(1),
+                nonce: HexU32Be(1),
+                version_bits,
+                id: 2,
+            }
+            .into()
+        };
+
+        // difficulty 512, see gets_difficulty_from_target
+        let target = vec![
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 255, 127,
+            0, 0, 0, 0, 0,
+        ];
+        async_std::task::block_on(Downstream::send_set_difficulty(downstream.clone(), target))
+            .unwrap();
+        match rx_outgoing.try_recv().unwrap() {
+            json_rpc::Message::Notification(n) => assert_eq!(n.method, "mining.set_difficulty"),
+            m => panic!("Expected mining.set_difficulty, got {:?}", m),
+        }
+
+        for _ in 0..3 {
+            async_std::task::block_on(Downstream::handle_incoming_sv1(
+                downstream.clone(),
+                submit(Some(HexU32Be(0x0000_e000))),
+            ))
+            .unwrap();
+        }
+        // version bits outside of the mask, the share is rejected
+        assert!(async_std::task::block_on(Downstream::handle_incoming_sv1(
+            downstream.clone(),
+            submit(Some(HexU32Be(0x2000_0000))),
+        ))
+        .is_err());
+
+        let stats = downstream.safe_lock(|d| d.share_stats()).unwrap();
+        assert_eq!(stats.submitted, 4);
+        assert_eq!(stats.accepted, 3);
+        assert_eq!(stats.current_difficulty, 512.0);
+        assert_eq!(stats.accepted_difficulty, 3.0 * 512.0);
+        assert_eq!(stats.accept_rate(), 0.75);
+    }
 }
@@ -1,4 +1,6 @@
-use roles_logic_sv2::mining_sv2::Target;
+use crate::proxy_config::{DownstreamDifficultyConfig, UpstreamDifficultyConfig};
+use roles_logic_sv2::{mining_sv2::Target, utils::Mutex};
+use std::{net::SocketAddr, sync::Arc};
 use v1::{client_to_server::Submit, utils::HexU32Be};
 pub mod diff_management;
 pub mod downstream;
@@ -11,11 +13,24 @@ pub use downstream::Downstream;
 /// `mining.subscribe` messages that init connections and take up compute
 const SUBSCRIBE_TIMEOUT_SECS: u64 = 10;
 
+/// Settings of the listener that accepts the SV1 Downstream roles, see
+/// [`Downstream::accept_connections`]
+#[derive(Debug, Clone)]
+pub struct DownstreamListenerConfig {
+    /// Address the SV1 Downstream roles connect to
+    pub address: SocketAddr,
+    pub difficulty_config: DownstreamDifficultyConfig,
+    pub upstream_difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
+    /// Smallest `extranonce2_size` that the miners accept
+    pub min_extranonce2_len: usize,
+}
+
 /// enum of messages sent to the Bridge
 #[derive(Debug)]
 pub enum DownstreamMessages {
     SubmitShares(SubmitShareWithChannelId),
     SetDownstreamTarget(SetDownstreamTarget),
+    CloseDownstreamChannel(CloseDownstreamChannel),
 }
 
 /// wrapper around a `mining.submit` with extra channel informationfor the Bridge to
@@ -37,6 +52,36 @@ pub struct SetDownstreamTarget {
     pub new_target: Target,
 }
 
+/// message for notifying the bridge that a downstream disconnected so the Bridge can close the
+/// channel opened for it
+#[derive(Debug)]
+pub struct CloseDownstreamChannel {
+    pub channel_id: u32,
+}
+
+/// Shares submitted by a Downstream role since it connected, see [`Downstream::share_stats`]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ShareStats {
+    /// Number of `mining.submit` received
+    pub submitted: u64,
+    /// Number of `mining.submit` accepted by the translator
+    pub accepted: u64,
+    /// Sum of the difficulties the accepted shares were submitted at
+    pub accepted_difficulty: f64,
+    /// Difficulty of the last `mining.set_difficulty` sent to the Downstream role
+    pub current_difficulty: f64,
+}
+
+impl ShareStats {
+    /// Ratio of accepted to submitted shares, 0 when no share has been submitted
+    pub fn accept_rate(&self) -> f64 {
+        if self.submitted == 0 {
+            return 0.0;
+        }
+        self.accepted as f64 / self.submitted as f64
+    }
+}
+
 /// This is just a wrapper function to send a message on the Downstream task shutdown channel
 /// it does not matter what message is sent because the receiving ends should shutdown on any message
 pub async fn kill(sender: &async_channel::Sender<bool>) {
@@ -1,5 +1,7 @@
 use roles_logic_sv2::{
-    mining_sv2::{ExtendedExtranonce, NewExtendedMiningJob, SetCustomMiningJob},
+    mining_sv2::{
+        ExtendedExtranonce, NewExtendedMiningJob, SetCustomMiningJob, SetExtranoncePrefix,
+    },
     parsers::Mining,
 };
 use std::{fmt, sync::PoisonError};
@@ -14,12 +16,15 @@ pub enum ChannelSendError<'a> {
     SubmitSharesExtended(
         async_channel::SendError<roles_logic_sv2::mining_sv2::SubmitSharesExtended<'a>>,
     ),
-    SetNewPrevHash(async_channel::SendError<roles_logic_sv2::mining_sv2::SetNewPrevHash<'a>>),
-    NewExtendedMiningJob(async_channel::SendError<NewExtendedMiningJob<'a>>),
+    SetNewPrevHash(
+        async_channel::SendError<(roles_logic_sv2::mining_sv2::SetNewPrevHash<'a>, u32)>,
+    ),
+    NewExtendedMiningJob(async_channel::SendError<(NewExtendedMiningJob<'a>, u32)>),
     Notify(tokio::sync::broadcast::error::SendError<Notify<'a>>),
     V1Message(async_channel::SendError<v1::Message>),
     General(String),
     Extranonce(async_channel::SendError<(ExtendedExtranonce, u32)>),
+    SetExtranoncePrefix(async_channel::SendError<SetExtranoncePrefix<'a>>),
     SetCustomMiningJob(
         async_channel::SendError<roles_logic_sv2::mining_sv2::SetCustomMiningJob<'a>>,
     ),
@@ -55,6 +60,8 @@ pub enum Error<'a> {
     /// Errors from `roles_logic_sv2` crate.
     RolesSv2Logic(roles_logic_sv2::errors::Error),
     UpstreamIncoming(roles_logic_sv2::errors::Error),
+    /// A frame received from the Upstream is not a valid SV2 frame
+    MalformedSv2Frame(String),
     /// SV1 protocol library error
     V1Protocol(v1::error::Error<'a>),
     #[allow(dead_code)]
@@ -94,6 +101,7 @@ impl<'a> fmt::Display for Error<'a> {
             V1Protocol(ref e) => write!(f, "V1 Protocol Error: `{:?}`", e),
             SubprotocolMining(ref e) => write!(f, "Subprotocol Mining Error: `{:?}`", e),
             UpstreamIncoming(ref e) => write!(f, "Upstream parse incoming error: `{:?}`", e),
+            MalformedSv2Frame(ref e) => write!(f, "Malformed SV2 frame from upstream: `{}`", e),
             PoisonLock => write!(f, "Poison Lock error"),
             ChannelErrorReceiver(ref e) => write!(f, "Channel receive error: `{:?}`", e),
             TokioChannelErrorRecv(ref e) => write!(f, "Channel receive error: `{:?}`", e),
@@ -201,10 +209,12 @@ impl<'a> From<async_channel::SendError<roles_logic_sv2::mining_sv2::SubmitShares
     }
 }
 
-impl<'a> From<async_channel::SendError<roles_logic_sv2::mining_sv2::SetNewPrevHash<'a>>>
+impl<'a> From<async_channel::SendError<(roles_logic_sv2::mining_sv2::SetNewPrevHash<'a>, u32)>>
     for Error<'a>
 {
-    fn from(e: async_channel::SendError<roles_logic_sv2::mining_sv2::SetNewPrevHash<'a>>) -> Self {
+    fn from(
+        e: async_channel::SendError<(roles_logic_sv2::mining_sv2::SetNewPrevHash<'a>, u32)>,
+    ) -> Self {
         Error::ChannelErrorSender(ChannelSendError::SetNewPrevHash(e))
     }
 }
@@ -227,8 +237,14 @@ impl<'a> From<async_channel::SendError<(ExtendedExtranonce, u32)>> for Error<'a>
     }
 }
 
-impl<'a> From<async_channel::SendError<NewExtendedMiningJob<'a>>> for Error<'a> {
-    fn from(e: async_channel::SendError<NewExtendedMiningJob<'a>>) -> Self {
+impl<'a> From<async_channel::SendError<SetExtranoncePrefix<'a>>> for Error<'a> {
+    fn from(e: async_channel::SendError<SetExtranoncePrefix<'a>>) -> Self {
+        Error::ChannelErrorSender(ChannelSendError::SetExtranoncePrefix(e))
+    }
+}
+
+impl<'a> From<async_channel::SendError<(NewExtendedMiningJob<'a>, u32)>> for Error<'a> {
+    fn from(e: async_channel::SendError<(NewExtendedMiningJob<'a>, u32)>) -> Self {
         Error::ChannelErrorSender(ChannelSendError::NewExtendedMiningJob(e))
     }
 }
@@ -3,7 +3,8 @@ use async_std::task;
 use roles_logic_sv2::{
     channel_logic::channel_factory::{ExtendedChannelKind, ProxyExtendedChannelFactory, Share},
     mining_sv2::{
-        ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash, SubmitSharesExtended, Target,
+        ExtendedExtranonce, NewExtendedMiningJob, SetExtranoncePrefix, SetNewPrevHash,
+        SubmitSharesExtended, Target,
     },
     parsers::Mining,
     utils::{GroupId, Mutex},
@@ -13,16 +14,19 @@ use tokio::sync::broadcast;
 use v1::{client_to_server::Submit, server_to_client, utils::HexU32Be};
 
 use super::super::{
-    downstream_sv1::{DownstreamMessages, SetDownstreamTarget, SubmitShareWithChannelId},
+    downstream_sv1::{
+        CloseDownstreamChannel, DownstreamMessages, SetDownstreamTarget, SubmitShareWithChannelId,
+    },
     error::{
         Error::{self, PoisonLock},
         ProxyResult,
     },
     status,
 };
+use super::next_mining_notify::NextMiningNotify;
 use error_handling::handle_result;
 use roles_logic_sv2::{channel_logic::channel_factory::OnNewShare, Error as RolesLogicError};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 /// Bridge between the SV2 `Upstream` and SV1 `Downstream` responsible for the following messaging
 /// translation:
@@ -37,11 +41,18 @@ pub struct Bridge {
     tx_sv2_submit_shares_ext: Sender<SubmitSharesExtended<'static>>,
     /// Receives a SV2 `SetNewPrevHash` message from the `Upstream` to be translated (along with a
     /// SV2 `NewExtendedMiningJob` message) to a SV1 `mining.submit` for the `Downstream`.
-    rx_sv2_set_new_prev_hash: Receiver<SetNewPrevHash<'static>>,
+    /// Each message comes with its prev hash epoch.
+    rx_sv2_set_new_prev_hash: Receiver<(SetNewPrevHash<'static>, u32)>,
     /// Receives a SV2 `NewExtendedMiningJob` message from the `Upstream` to be translated (along
     /// with a SV2 `SetNewPrevHash` message) to a SV1 `mining.submit` to be sent to the
-    /// `Downstream`.
-    rx_sv2_new_ext_mining_job: Receiver<NewExtendedMiningJob<'static>>,
+    /// `Downstream`. Each message comes with the epoch of the prev hash it is for.
+    rx_sv2_new_ext_mining_job: Receiver<(NewExtendedMiningJob<'static>, u32)>,
+    /// Receives a SV2 `SetExtranoncePrefix` message from the `Upstream` when the upstream part of
+    /// the extranonce changes.
+    rx_sv2_set_extranonce_prefix: Receiver<SetExtranoncePrefix<'static>>,
+    /// Sends the new extranonce prefix of every channel to the `Downstream`s after a
+    /// `SetExtranoncePrefix`, each `Downstream` picks the one of its channel.
+    tx_sv1_set_extranonce: broadcast::Sender<Vec<SetExtranoncePrefix<'static>>>,
     /// Sends SV1 `mining.notify` message (translated from the SV2 `SetNewPrevHash` and
     /// `NewExtendedMiningJob` messages stored in the `NextMiningNotify`) to the `Downstream`.
     tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
@@ -62,34 +73,54 @@ pub struct Bridge {
     pub(self) channel_factory: ProxyExtendedChannelFactory,
     future_jobs: Vec<NewExtendedMiningJob<'static>>,
     last_p_hash: Option<SetNewPrevHash<'static>>,
+    /// Epoch of `last_p_hash`, 0 before the first one
+    p_hash_epoch: u32,
+    /// `SetNewPrevHash` received before its future job, with its epoch
+    pending_p_hash: Option<(SetNewPrevHash<'static>, u32)>,
+    /// Jobs received before the `SetNewPrevHash` they depend on, with their epoch, at most
+    /// `MAX_PENDING_JOBS`
+    pending_jobs: Vec<(NewExtendedMiningJob<'static>, u32)>,
+    /// Decides if the next `mining.notify` has `clean_jobs` set
+    next_mining_notify: NextMiningNotify,
     target: Arc<Mutex<Vec<u8>>>,
     last_job_id: u32,
+    /// Sequence number of the next `SubmitSharesExtended` sent upstream.
+    next_sequence_number: u32,
 }
 
+/// Maximum number of jobs kept in `Bridge::pending_jobs`, the oldest one is dropped when a new one
+/// does not fit
+const MAX_PENDING_JOBS: usize = 64;
+
 impl Bridge {
     #[allow(clippy::too_many_arguments)]
     /// Instantiate a new `Bridge`.
     pub fn new(
         rx_sv1_downstream: Receiver<DownstreamMessages>,
         tx_sv2_submit_shares_ext: Sender<SubmitSharesExtended<'static>>,
-        rx_sv2_set_new_prev_hash: Receiver<SetNewPrevHash<'static>>,
-        rx_sv2_new_ext_mining_job: Receiver<NewExtendedMiningJob<'static>>,
+        rx_sv2_set_new_prev_hash: Receiver<(SetNewPrevHash<'static>, u32)>,
+        rx_sv2_new_ext_mining_job: Receiver<(NewExtendedMiningJob<'static>, u32)>,
+        rx_sv2_set_extranonce_prefix: Receiver<SetExtranoncePrefix<'static>>,
         tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
         tx_status: status::Sender,
         extranonces: ExtendedExtranonce,
         target: Arc<Mutex<Vec<u8>>>,
         up_id: u32,
+        force_clean_jobs_every: Option<u32>,
     ) -> Arc<Mutex<Self>> {
         let ids = Arc::new(Mutex::new(GroupId::new()));
         let share_per_min = 1.0;
         let upstream_target: [u8; 32] =
             target.safe_lock(|t| t.clone()).unwrap().try_into().unwrap();
         let upstream_target: Target = upstream_target.into();
+        let (tx_sv1_set_extranonce, _) = broadcast::channel(10);
         Arc::new(Mutex::new(Self {
             rx_sv1_downstream,
             tx_sv2_submit_shares_ext,
             rx_sv2_set_new_prev_hash,
             rx_sv2_new_ext_mining_job,
+            rx_sv2_set_extranonce_prefix,
+            tx_sv1_set_extranonce,
             tx_sv1_notify,
             tx_status,
             last_notify: None,
@@ -105,8 +136,13 @@ impl Bridge {
             ),
             future_jobs: vec![],
             last_p_hash: None,
+            p_hash_epoch: 0,
+            pending_p_hash: None,
+            pending_jobs: vec![],
+            next_mining_notify: NextMiningNotify::with_forced_clean_jobs(force_clean_jobs_every),
             target,
             last_job_id: 0,
+            next_sequence_number: 0,
         }))
     }
 
@@ -131,6 +167,7 @@ impl Bridge {
                                 extranonce,
                                 target: self.target.clone(),
                                 extranonce2_len,
+                                rx_set_extranonce: self.tx_sv1_set_extranonce.subscribe(),
                             });
                         }
                         Mining::OpenMiningChannelError(_) => todo!(),
@@ -156,9 +193,45 @@ impl Bridge {
     pub fn start(self_: Arc<Mutex<Self>>) {
         Self::handle_new_prev_hash(self_.clone());
         Self::handle_new_extended_mining_job(self_.clone());
+        Self::handle_set_extranonce_prefix(self_.clone());
         Self::handle_downstream_messages(self_);
     }
 
+    /// Receives a SV2 `SetExtranoncePrefix` message from the `Upstream` and replaces the upstream
+    /// part of the extranonce of every channel, see [`Bridge::on_set_extranonce_prefix`].
+    fn handle_set_extranonce_prefix(self_: Arc<Mutex<Self>>) {
+        let (rx_sv2_set_extranonce_prefix, tx_status) = self_
+            .safe_lock(|s| (s.rx_sv2_set_extranonce_prefix.clone(), s.tx_status.clone()))
+            .unwrap();
+        task::spawn(async move {
+            loop {
+                let m = handle_result!(tx_status, rx_sv2_set_extranonce_prefix.recv().await);
+                let res = self_
+                    .safe_lock(|s| s.on_set_extranonce_prefix(m))
+                    .map_err(|_| PoisonLock);
+                handle_result!(tx_status, handle_result!(tx_status, res));
+            }
+        });
+    }
+
+    /// Updates the channel factory with the new upstream extranonce prefix and sends the new
+    /// extranonce prefix of each channel to the `Downstream`s, that forward it to the miners with a
+    /// SV1 `mining.set_extranonce`.
+    #[allow(clippy::result_large_err)]
+    fn on_set_extranonce_prefix(
+        &mut self,
+        m: SetExtranoncePrefix<'static>,
+    ) -> ProxyResult<'static, ()> {
+        let prefixes = self
+            .channel_factory
+            .update_upstream_extranonce_prefix(&m.extranonce_prefix.to_vec())?;
+        // Fails only when no Downstream is connected
+        if self.tx_sv1_set_extranonce.send(prefixes).is_err() {
+            debug!("No Downstream to send the new extranonce prefix to");
+        }
+        Ok(())
+    }
+
     /// Receives a `DownstreamMessages` message from the `Downstream`, handles based on the
     /// variant received.
     fn handle_downstream_messages(self_: Arc<Mutex<Self>>) {
@@ -182,6 +255,12 @@ impl Bridge {
                             Self::handle_update_downstream_target(self_.clone(), new_target)
                         );
                     }
+                    DownstreamMessages::CloseDownstreamChannel(close) => {
+                        handle_result!(
+                            tx_status,
+                            Self::handle_close_downstream_channel(self_.clone(), close)
+                        );
+                    }
                 };
             }
         });
@@ -200,6 +279,27 @@ impl Bridge {
             .map_err(|_| PoisonLock)?;
         Ok(())
     }
+    /// receives a `CloseDownstreamChannel` and removes the channel from the channel factory, so
+    /// that it stops accepting shares for it. The channel only exists in the proxy, the upstream
+    /// keeps seeing the single extended channel opened by the proxy.
+    #[allow(clippy::result_large_err)]
+    fn handle_close_downstream_channel(
+        self_: Arc<Mutex<Self>>,
+        close: CloseDownstreamChannel,
+    ) -> ProxyResult<'static, ()> {
+        let closed = self_
+            .safe_lock(|b| b.channel_factory.close_channel(close.channel_id))
+            .map_err(|_| PoisonLock)?;
+        if closed {
+            info!(
+                "Closed channel {} of disconnected downstream",
+                close.channel_id
+            );
+        } else {
+            debug!("Downstream channel {} is already closed", close.channel_id);
+        }
+        Ok(())
+    }
     /// receives a `SubmitShareWithChannelId` and validates the shares and sends to `Upstream` if
     /// the share meets the upstream target
     async fn handle_submit_shares(
@@ -272,7 +372,7 @@ impl Bridge {
     /// Translates a SV1 `mining.submit` message to a SV2 `SubmitSharesExtended` message.
     #[allow(clippy::result_large_err)]
     fn translate_submit(
-        &self,
+        &mut self,
         channel_id: u32,
         sv1_submit: Submit,
         version_rolling_mask: Option<HexU32Be>,
@@ -289,10 +389,11 @@ impl Bridge {
         };
         let mining_device_extranonce: Vec<u8> = sv1_submit.extra_nonce2.into();
         let extranonce2 = mining_device_extranonce;
+        let sequence_number = self.next_sequence_number;
+        self.next_sequence_number = self.next_sequence_number.wrapping_add(1);
         Ok(SubmitSharesExtended {
             channel_id,
-            // I put 0 below cause sequence_number is not what should be TODO
-            sequence_number: 0,
+            sequence_number,
             job_id: sv1_submit.job_id.parse::<u32>()?,
             nonce: sv1_submit.nonce.0,
             ntime: sv1_submit.time.0,
@@ -304,59 +405,101 @@ impl Bridge {
     async fn handle_new_prev_hash_(
         self_: Arc<Mutex<Self>>,
         sv2_set_new_prev_hash: SetNewPrevHash<'static>,
+        epoch: u32,
         tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
     ) -> Result<(), Error<'static>> {
-        while !crate::upstream_sv2::upstream::IS_NEW_JOB_HANDLED
-            .load(std::sync::atomic::Ordering::SeqCst)
-        {
-            tokio::task::yield_now().await;
-        }
         self_
-            .safe_lock(|s| s.last_p_hash = Some(sv2_set_new_prev_hash.clone()))
-            .map_err(|_| PoisonLock)?;
+            .safe_lock(|s| s.on_new_prev_hash(sv2_set_new_prev_hash, epoch, &tx_sv1_notify))
+            .map_err(|_| PoisonLock)?
+    }
 
-        let on_new_prev_hash_res = self_
-            .safe_lock(|s| {
-                s.channel_factory
-                    .on_new_prev_hash(sv2_set_new_prev_hash.clone())
-            })
-            .map_err(|_| PoisonLock)?;
-        on_new_prev_hash_res?;
+    /// A `SetNewPrevHash` is only applied once its future job has been received, otherwise it is
+    /// kept in `pending_p_hash` until [`Bridge::on_new_extended_mining_job`] gets the job.
+    #[allow(clippy::result_large_err)]
+    fn on_new_prev_hash(
+        &mut self,
+        sv2_set_new_prev_hash: SetNewPrevHash<'static>,
+        epoch: u32,
+        tx_sv1_notify: &broadcast::Sender<server_to_client::Notify<'static>>,
+    ) -> Result<(), Error<'static>> {
+        if let Some((pending, pending_epoch)) = self.pending_p_hash.take() {
+            warn!(
+                "No future job {} received for prev hash of epoch {}",
+                pending.job_id, pending_epoch
+            );
+            self.apply_new_prev_hash(pending, pending_epoch, tx_sv1_notify)?;
+        }
+        if self
+            .future_jobs
+            .iter()
+            .any(|job| job.job_id == sv2_set_new_prev_hash.job_id)
+        {
+            self.apply_new_prev_hash(sv2_set_new_prev_hash, epoch, tx_sv1_notify)
+        } else {
+            debug!(
+                "Waiting for future job {} of prev hash of epoch {}",
+                sv2_set_new_prev_hash.job_id, epoch
+            );
+            self.pending_p_hash = Some((sv2_set_new_prev_hash, epoch));
+            Ok(())
+        }
+    }
 
-        let mut future_jobs = self_
-            .safe_lock(|s| {
-                let future_jobs = s.future_jobs.clone();
-                s.future_jobs = vec![];
-                future_jobs
-            })
-            .map_err(|_| PoisonLock)?;
+    /// Makes `sv2_set_new_prev_hash` the current prev hash, notifies its future job and then
+    /// handles the jobs that were waiting for it.
+    #[allow(clippy::result_large_err)]
+    fn apply_new_prev_hash(
+        &mut self,
+        sv2_set_new_prev_hash: SetNewPrevHash<'static>,
+        epoch: u32,
+        tx_sv1_notify: &broadcast::Sender<server_to_client::Notify<'static>>,
+    ) -> Result<(), Error<'static>> {
+        self.last_p_hash = Some(sv2_set_new_prev_hash.clone());
+        self.p_hash_epoch = epoch;
+        self.next_mining_notify.on_new_prev_hash();
+        self.channel_factory
+            .on_new_prev_hash(sv2_set_new_prev_hash.clone())?;
 
+        let mut future_jobs = std::mem::take(&mut self.future_jobs);
         let mut match_a_future_job = false;
         while let Some(job) = future_jobs.pop() {
             if job.job_id == sv2_set_new_prev_hash.job_id {
-                let j_id = job.job_id;
-                // Create the mining.notify to be sent to the Downstream.
-                let notify = crate::proxy::next_mining_notify::create_notify(
-                    sv2_set_new_prev_hash.clone(),
-                    job,
-                    true,
-                );
-
-                // Get the sender to send the mining.notify to the Downstream
-                tx_sv1_notify.send(notify.clone())?;
+                self.send_notify(sv2_set_new_prev_hash.clone(), job, tx_sv1_notify)?;
                 match_a_future_job = true;
-                self_
-                    .safe_lock(|s| {
-                        s.last_notify = Some(notify);
-                        s.last_job_id = j_id;
-                    })
-                    .map_err(|_| PoisonLock)?;
                 break;
             }
         }
         if !match_a_future_job {
             debug!("No future jobs for {:?}", sv2_set_new_prev_hash);
         }
+
+        // Jobs for an older prev hash are dropped, the ones for this prev hash are handled and the
+        // ones for a later prev hash keep waiting
+        let pending_jobs = std::mem::take(&mut self.pending_jobs);
+        for (job, job_epoch) in pending_jobs {
+            self.on_new_extended_mining_job(job, job_epoch, tx_sv1_notify)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the `mining.notify` for `job` and `prev_hash` and sends it to the Downstreams
+    #[allow(clippy::result_large_err)]
+    fn send_notify(
+        &mut self,
+        prev_hash: SetNewPrevHash<'static>,
+        job: NewExtendedMiningJob<'static>,
+        tx_sv1_notify: &broadcast::Sender<server_to_client::Notify<'static>>,
+    ) -> Result<(), Error<'static>> {
+        let j_id = job.job_id;
+        let full_extranonce_len = self.channel_factory.extranonce_size();
+        // Create the mining.notify to be sent to the Downstream.
+        let notify = self
+            .next_mining_notify
+            .create_notify(prev_hash, job, full_extranonce_len)?;
+        // Get the sender to send the mining.notify to the Downstream
+        tx_sv1_notify.send(notify.clone())?;
+        self.last_notify = Some(notify);
+        self.last_job_id = j_id;
         Ok(())
     }
 
@@ -364,8 +507,8 @@ impl Bridge {
     /// `mining.notify` message (in conjunction with a previously received SV2
     /// `NewExtendedMiningJob` message) which is sent to the `Downstream`. The protocol requires
     /// that before every received `SetNewPrevHash`, a `NewExtendedMiningJob` with a
-    /// corresponding `job_id` has already been received. If this is not the case, an error has
-    /// occurred on the Upstream pool role and the connection will close.
+    /// corresponding `job_id` has already been received. The job is received by another task, so
+    /// when the `SetNewPrevHash` is handled first it waits for the job before being applied.
     fn handle_new_prev_hash(self_: Arc<Mutex<Self>>) {
         let (tx_sv1_notify, rx_sv2_set_new_prev_hash, tx_status) = self_
             .safe_lock(|s| {
@@ -380,7 +523,7 @@ impl Bridge {
         task::spawn(async move {
             loop {
                 // Receive `SetNewPrevHash` from `Upstream`
-                let sv2_set_new_prev_hash: SetNewPrevHash =
+                let (sv2_set_new_prev_hash, epoch): (SetNewPrevHash, u32) =
                     handle_result!(tx_status, rx_sv2_set_new_prev_hash.clone().recv().await);
                 debug!(
                     "handle_new_prev_hash job_id: {:?}",
@@ -391,6 +534,7 @@ impl Bridge {
                     Self::handle_new_prev_hash_(
                         self_.clone(),
                         sv2_set_new_prev_hash,
+                        epoch,
                         tx_sv1_notify.clone(),
                     )
                     .await
@@ -402,52 +546,95 @@ impl Bridge {
     async fn handle_new_extended_mining_job_(
         self_: Arc<Mutex<Self>>,
         sv2_new_extended_mining_job: NewExtendedMiningJob<'static>,
+        epoch: u32,
         tx_sv1_notify: broadcast::Sender<server_to_client::Notify<'static>>,
     ) -> Result<(), Error<'static>> {
-        // convert to non segwit jobs so we dont have to depend if miner's support segwit or not
         self_
             .safe_lock(|s| {
-                s.channel_factory
-                    .on_new_extended_mining_job(sv2_new_extended_mining_job.as_static().clone())
+                s.on_new_extended_mining_job(sv2_new_extended_mining_job, epoch, &tx_sv1_notify)
             })
-            .map_err(|_| PoisonLock)??;
+            .map_err(|_| PoisonLock)?
+    }
+
+    /// A job is only handled once the prev hash it comes after has been applied: the one of its
+    /// epoch for a non future job, the previous one for a future job. A job that comes early is
+    /// kept in `pending_jobs` and a job for an older prev hash is dropped.
+    ///
+    /// The jobs are received in the order the upstream sent them, so a non future job of the epoch
+    /// of `pending_p_hash`, or any job of a later epoch, means that the future job of
+    /// `pending_p_hash` will never come: the prev hash is applied without it.
+    #[allow(clippy::result_large_err)]
+    fn on_new_extended_mining_job(
+        &mut self,
+        sv2_new_extended_mining_job: NewExtendedMiningJob<'static>,
+        epoch: u32,
+        tx_sv1_notify: &broadcast::Sender<server_to_client::Notify<'static>>,
+    ) -> Result<(), Error<'static>> {
+        if let Some((pending, pending_epoch)) = self.pending_p_hash.take() {
+            if epoch > pending_epoch
+                || (epoch == pending_epoch && !sv2_new_extended_mining_job.is_future())
+            {
+                warn!(
+                    "No future job {} received for prev hash of epoch {}",
+                    pending.job_id, pending_epoch
+                );
+                self.apply_new_prev_hash(pending, pending_epoch, tx_sv1_notify)?;
+            } else {
+                self.pending_p_hash = Some((pending, pending_epoch));
+            }
+        }
+        let after_p_hash_epoch = match sv2_new_extended_mining_job.is_future() {
+            true => epoch.wrapping_sub(1),
+            false => epoch,
+        };
+        if after_p_hash_epoch < self.p_hash_epoch {
+            debug!(
+                "Dropping job {} of old prev hash epoch {}",
+                sv2_new_extended_mining_job.job_id, epoch
+            );
+            return Ok(());
+        }
+        if after_p_hash_epoch > self.p_hash_epoch {
+            if self.pending_jobs.len() >= MAX_PENDING_JOBS {
+                let (dropped, dropped_epoch) = self.pending_jobs.remove(0);
+                warn!(
+                    "Too many jobs waiting for their prev hash, dropping job {} of epoch {}",
+                    dropped.job_id, dropped_epoch
+                );
+            }
+            self.pending_jobs.push((sv2_new_extended_mining_job, epoch));
+            return Ok(());
+        }
+
+        // convert to non segwit jobs so we dont have to depend if miner's support segwit or not
+        self.channel_factory
+            .on_new_extended_mining_job(sv2_new_extended_mining_job.as_static().clone())?;
 
         // If future_job=true, this job is meant for a future SetNewPrevHash that the proxy
         // has yet to receive. Insert this new job into the job_mapper .
         if sv2_new_extended_mining_job.is_future() {
-            self_
-                .safe_lock(|s| s.future_jobs.push(sv2_new_extended_mining_job.clone()))
-                .map_err(|_| PoisonLock)?;
-            Ok(())
+            let job_id = sv2_new_extended_mining_job.job_id;
+            self.future_jobs.push(sv2_new_extended_mining_job);
+            // The prev hash of this job may have been waiting for it
+            match self.pending_p_hash.take() {
+                Some((p_hash, p_hash_epoch))
+                    if p_hash_epoch == epoch && p_hash.job_id == job_id =>
+                {
+                    self.apply_new_prev_hash(p_hash, p_hash_epoch, tx_sv1_notify)
+                }
+                pending => {
+                    self.pending_p_hash = pending;
+                    Ok(())
+                }
+            }
 
         // If future_job=false, this job is meant for the current SetNewPrevHash.
         } else {
-            let last_p_hash_option = self_
-                .safe_lock(|s| s.last_p_hash.clone())
-                .map_err(|_| PoisonLock)?;
-
             // last_p_hash is an Option<SetNewPrevHash> so we need to map to the correct error type to be handled
-            let last_p_hash = last_p_hash_option.ok_or(Error::RolesSv2Logic(
+            let last_p_hash = self.last_p_hash.clone().ok_or(Error::RolesSv2Logic(
                 RolesLogicError::JobIsNotFutureButPrevHashNotPresent,
             ))?;
-
-            let j_id = sv2_new_extended_mining_job.job_id;
-            // Create the mining.notify to be sent to the Downstream.
-            // clean_jobs must be false because it's not a NewPrevHash template
-            let notify = crate::proxy::next_mining_notify::create_notify(
-                last_p_hash,
-                sv2_new_extended_mining_job.clone(),
-                false,
-            );
-            // Get the sender to send the mining.notify to the Downstream
-            tx_sv1_notify.send(notify.clone())?;
-            self_
-                .safe_lock(|s| {
-                    s.last_notify = Some(notify);
-                    s.last_job_id = j_id;
-                })
-                .map_err(|_| PoisonLock)?;
-            Ok(())
+            self.send_notify(last_p_hash, sv2_new_extended_mining_job, tx_sv1_notify)
         }
     }
 
@@ -473,7 +660,7 @@ impl Bridge {
         task::spawn(async move {
             loop {
                 // Receive `NewExtendedMiningJob` from `Upstream`
-                let sv2_new_extended_mining_job: NewExtendedMiningJob = handle_result!(
+                let (sv2_new_extended_mining_job, epoch): (NewExtendedMiningJob, u32) = handle_result!(
                     tx_status.clone(),
                     rx_sv2_new_ext_mining_job.clone().recv().await
                 );
@@ -486,12 +673,11 @@ impl Bridge {
                     Self::handle_new_extended_mining_job_(
                         self_.clone(),
                         sv2_new_extended_mining_job,
+                        epoch,
                         tx_sv1_notify.clone(),
                     )
                     .await
                 );
-                crate::upstream_sv2::upstream::IS_NEW_JOB_HANDLED
-                    .store(true, std::sync::atomic::Ordering::SeqCst);
             }
         });
     }
@@ -502,6 +688,9 @@ pub struct OpenSv1Downstream {
     pub extranonce: Vec<u8>,
     pub target: Arc<Mutex<Vec<u8>>>,
     pub extranonce2_len: u16,
+    /// Receives the new extranonce prefixes of the channels, see
+    /// [`Bridge::on_set_extranonce_prefix`]
+    pub rx_set_extranonce: broadcast::Receiver<Vec<SetExtranoncePrefix<'static>>>,
 }
 
 #[cfg(test)]
@@ -517,8 +706,9 @@ mod test {
         pub struct BridgeInterface {
             pub tx_sv1_submit: Sender<DownstreamMessages>,
             pub rx_sv2_submit_shares_ext: Receiver<SubmitSharesExtended<'static>>,
-            pub tx_sv2_set_new_prev_hash: Sender<SetNewPrevHash<'static>>,
-            pub tx_sv2_new_ext_mining_job: Sender<NewExtendedMiningJob<'static>>,
+            pub tx_sv2_set_new_prev_hash: Sender<(SetNewPrevHash<'static>, u32)>,
+            pub tx_sv2_new_ext_mining_job: Sender<(NewExtendedMiningJob<'static>, u32)>,
+            pub tx_sv2_set_extranonce_prefix: Sender<SetExtranoncePrefix<'static>>,
             pub rx_sv1_notify: broadcast::Receiver<server_to_client::Notify<'static>>,
         }
 
@@ -529,7 +719,8 @@ mod test {
             let (tx_sv2_submit_shares_ext, rx_sv2_submit_shares_ext) = bounded(1);
             let (tx_sv2_set_new_prev_hash, rx_sv2_set_new_prev_hash) = bounded(1);
             let (tx_sv2_new_ext_mining_job, rx_sv2_new_ext_mining_job) = bounded(1);
-            let (tx_sv1_notify, rx_sv1_notify) = broadcast::channel(1);
+            let (tx_sv2_set_extranonce_prefix, rx_sv2_set_extranonce_prefix) = bounded(1);
+            let (tx_sv1_notify, rx_sv1_notify) = broadcast::channel(10);
             let (tx_status, _rx_status) = bounded(1);
             let upstream_target = vec![
                 0, 0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -540,6 +731,7 @@ mod test {
                 rx_sv2_submit_shares_ext,
                 tx_sv2_set_new_prev_hash,
                 tx_sv2_new_ext_mining_job,
+                tx_sv2_set_extranonce_prefix,
                 rx_sv1_notify,
             };
 
@@ -548,15 +740,94 @@ mod test {
                 tx_sv2_submit_shares_ext,
                 rx_sv2_set_new_prev_hash,
                 rx_sv2_new_ext_mining_job,
+                rx_sv2_set_extranonce_prefix,
                 tx_sv1_notify,
                 status::Sender::Bridge(tx_status),
                 extranonces,
                 Arc::new(Mutex::new(upstream_target)),
                 1,
+                None,
             );
             (b, interface)
         }
 
+        /// Serialized coinbase with a 16 bytes script_sig, that is the space for the extranonce
+        /// in jobs built with `coinbase_tx_prefix: tx[0..42]` and `coinbase_tx_suffix: tx[58..]`.
+        pub fn coinbase_tx() -> Vec<u8> {
+            use stratum_common::{
+                bitcoin,
+                bitcoin::{blockdata::witness::Witness, hashes::Hash},
+            };
+
+            let out_id = bitcoin::hashes::sha256d::Hash::from_slice(&[
+                0_u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0,
+            ])
+            .unwrap();
+            let p_out = bitcoin::OutPoint {
+                txid: bitcoin::Txid::from_hash(out_id),
+                vout: 0xffff_ffff,
+            };
+            let in_ = bitcoin::TxIn {
+                previous_output: p_out,
+                script_sig: vec![89_u8; 16].into(),
+                sequence: bitcoin::Sequence(0),
+                witness: Witness::from_vec(vec![]),
+            };
+            let tx = bitcoin::Transaction {
+                version: 1,
+                lock_time: bitcoin::PackedLockTime(0),
+                input: vec![in_],
+                output: vec![],
+            };
+            tx.serialize()
+        }
+
+        /// Opens a channel on the bridge factory and sends it a prev hash and an extended job with
+        /// the given `version`, so that shares for job 0 can be translated.
+        pub fn set_up_job(
+            bridge: &mut Bridge,
+            channel_id: u32,
+            version: u32,
+        ) -> NewExtendedMiningJob<'static> {
+            let tx = coinbase_tx();
+            let _down = bridge
+                .channel_factory
+                .add_standard_channel(0, 10_000_000_000.0, true, 1)
+                .unwrap();
+            let prev_hash = SetNewPrevHash {
+                channel_id,
+                job_id: 0,
+                prev_hash: [
+                    3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
+                    3, 3, 3, 3, 3, 3,
+                ]
+                .into(),
+                min_ntime: 989898,
+                nbits: 9,
+            };
+            bridge.channel_factory.on_new_prev_hash(prev_hash).unwrap();
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32;
+            let new_mining_job = NewExtendedMiningJob {
+                channel_id,
+                job_id: 0,
+                min_ntime: binary_sv2::Sv2Option::new(Some(now)),
+                version,
+                version_rolling_allowed: false,
+                merkle_path: vec![].into(),
+                coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
+                coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
+            };
+            bridge
+                .channel_factory
+                .on_new_extended_mining_job(new_mining_job.clone())
+                .unwrap();
+            new_mining_job
+        }
+
         pub fn create_sv1_submit(job_id: u32) -> Submit<'static> {
             Submit {
                 user_name: "test_user".to_string(),
@@ -572,72 +843,13 @@ mod test {
 
     #[test]
     fn test_version_bits_insert() {
-        use stratum_common::{
-            bitcoin,
-            bitcoin::{blockdata::witness::Witness, hashes::Hash},
-        };
-
         let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
         let (bridge, _) = test_utils::create_bridge(extranonces);
         bridge
             .safe_lock(|bridge| {
                 let channel_id = 1;
-                let out_id = bitcoin::hashes::sha256d::Hash::from_slice(&[
-                    0_u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                    0, 0, 0, 0, 0, 0, 0,
-                ])
-                .unwrap();
-                let p_out = bitcoin::OutPoint {
-                    txid: bitcoin::Txid::from_hash(out_id),
-                    vout: 0xffff_ffff,
-                };
-                let in_ = bitcoin::TxIn {
-                    previous_output: p_out,
-                    script_sig: vec![89_u8; 16].into(),
-                    sequence: bitcoin::Sequence(0),
-                    witness: Witness::from_vec(vec![]).into(),
-                };
-                let tx = bitcoin::Transaction {
-                    version: 1,
-                    lock_time: bitcoin::PackedLockTime(0),
-                    input: vec![in_],
-                    output: vec![],
-                };
-                let tx = tx.serialize();
-                let _down = bridge
-                    .channel_factory
-                    .add_standard_channel(0, 10_000_000_000.0, true, 1)
-                    .unwrap();
-                let prev_hash = SetNewPrevHash {
-                    channel_id,
-                    job_id: 0,
-                    prev_hash: [
-                        3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
-                        3, 3, 3, 3, 3, 3, 3,
-                    ]
-                    .into(),
-                    min_ntime: 989898,
-                    nbits: 9,
-                };
-                bridge.channel_factory.on_new_prev_hash(prev_hash).unwrap();
-                let now = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as u32;
-                let new_mining_job = NewExtendedMiningJob {
-                    channel_id,
-                    job_id: 0,
-                    min_ntime: binary_sv2::Sv2Option::new(Some(now)),
-                    version: 0b0000_0000_0000_0000,
-                    version_rolling_allowed: false,
-                    merkle_path: vec![].into(),
-                    coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
-                    coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
-                };
-                bridge
-                    .channel_factory
-                    .on_new_extended_mining_job(new_mining_job.clone())
-                    .unwrap();
+                let new_mining_job =
+                    test_utils::set_up_job(bridge, channel_id, 0b0000_0000_0000_0000);
 
                 // pass sv1_submit into Bridge::translate_submit
                 let sv1_submit = test_utils::create_sv1_submit(0);
@@ -652,4 +864,368 @@ mod test {
             })
             .unwrap();
     }
+
+    #[test]
+    fn test_translate_submit_fields() {
+        let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
+        let (bridge, _) = test_utils::create_bridge(extranonces);
+        bridge
+            .safe_lock(|bridge| {
+                let channel_id = 1;
+                let job_version = 0x2000_0000;
+                test_utils::set_up_job(bridge, channel_id, job_version);
+
+                let mask = HexU32Be(0x1fff_e000);
+                let mut sv1_submit = test_utils::create_sv1_submit(0);
+                sv1_submit.extra_nonce2 = v1::utils::Extranonce::try_from(vec![7; 8]).unwrap();
+                sv1_submit.time = HexU32Be(0x6500_0000);
+                sv1_submit.nonce = HexU32Be(0xdead_beef);
+                // bits outside of the mask must be ignored
+                sv1_submit.version_bits = Some(HexU32Be(0xffff_ffff));
+
+                let first = bridge
+                    .translate_submit(channel_id, sv1_submit.clone(), Some(mask.clone()))
+                    .unwrap();
+                assert_eq!(first.channel_id, channel_id);
+                assert_eq!(first.sequence_number, 0);
+                assert_eq!(first.job_id, 0);
+                assert_eq!(first.nonce, 0xdead_beef);
+                assert_eq!(first.ntime, 0x6500_0000);
+                assert_eq!(first.version, 0x3fff_e000);
+                assert_eq!(first.extranonce.to_vec(), vec![7; 8]);
+
+                let second = bridge
+                    .translate_submit(channel_id, sv1_submit.clone(), Some(mask))
+                    .unwrap();
+                assert_eq!(second.sequence_number, 1);
+
+                // version bits without a negotiated mask are not a valid submission
+                assert!(bridge
+                    .translate_submit(channel_id, sv1_submit, None)
+                    .is_err());
+            })
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_future_job_and_new_prev_hash_are_notified() {
+        let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
+        let (bridge, mut interface) = test_utils::create_bridge(extranonces);
+        let tx_sv1_notify = bridge.safe_lock(|b| b.tx_sv1_notify.clone()).unwrap();
+
+        let tx = test_utils::coinbase_tx();
+        let job = NewExtendedMiningJob {
+            channel_id: 1,
+            job_id: 5,
+            min_ntime: binary_sv2::Sv2Option::new(None),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1_u8; 32].into()].into(),
+            coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
+            coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
+        };
+        let prev_hash = SetNewPrevHash {
+            channel_id: 1,
+            job_id: 5,
+            prev_hash: [3_u8; 32].into(),
+            min_ntime: 0x6500_0000,
+            nbits: 0x1703_4219,
+        };
+
+        Bridge::handle_new_extended_mining_job_(bridge.clone(), job, 1, tx_sv1_notify.clone())
+            .await
+            .unwrap();
+        // a future job is only notified together with its prev hash
+        assert!(interface.rx_sv1_notify.try_recv().is_err());
+        Bridge::handle_new_prev_hash_(bridge.clone(), prev_hash, 1, tx_sv1_notify.clone())
+            .await
+            .unwrap();
+
+        let notify = interface.rx_sv1_notify.try_recv().unwrap();
+        assert_eq!(notify.job_id, "5");
+        assert_eq!(notify.prev_hash.0.to_vec(), vec![3_u8; 32]);
+        let coin_base1: Vec<u8> = notify.coin_base1.into();
+        let coin_base2: Vec<u8> = notify.coin_base2.into();
+        assert_eq!(coin_base1, tx[0..42].to_vec());
+        assert_eq!(coin_base2, tx[58..].to_vec());
+        assert_eq!(notify.merkle_branch.len(), 1);
+        assert_eq!(notify.merkle_branch[0].0.to_vec(), vec![1_u8; 32]);
+        assert_eq!(notify.version.0, 0x2000_0000);
+        assert_eq!(notify.bits.0, 0x1703_4219);
+        assert_eq!(notify.time.0, 0x6500_0000);
+        assert!(notify.clean_jobs);
+        assert_eq!(bridge.safe_lock(|b| b.last_job_id).unwrap(), 5);
+
+        // A new job for the same prev hash does not invalidate the current ones
+        let job = NewExtendedMiningJob {
+            channel_id: 1,
+            job_id: 6,
+            min_ntime: binary_sv2::Sv2Option::new(Some(0x6500_0001)),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1_u8; 32].into()].into(),
+            coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
+            coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
+        };
+        Bridge::handle_new_extended_mining_job_(bridge.clone(), job, 1, tx_sv1_notify)
+            .await
+            .unwrap();
+        let notify = interface.rx_sv1_notify.try_recv().unwrap();
+        assert_eq!(notify.job_id, "6");
+        assert!(!notify.clean_jobs);
+    }
+
+    #[tokio::test]
+    async fn test_forced_clean_jobs_every_third_notify() {
+        let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
+        let (bridge, mut interface) = test_utils::create_bridge(extranonces);
+        bridge
+            .safe_lock(|b| b.next_mining_notify = NextMiningNotify::with_forced_clean_jobs(Some(3)))
+            .unwrap();
+        let tx_sv1_notify = bridge.safe_lock(|b| b.tx_sv1_notify.clone()).unwrap();
+
+        let tx = test_utils::coinbase_tx();
+        let new_job = |job_id, is_future| NewExtendedMiningJob {
+            channel_id: 1,
+            job_id,
+            min_ntime: binary_sv2::Sv2Option::new(match is_future {
+                true => None,
+                false => Some(0x6500_0000),
+            }),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1_u8; 32].into()].into(),
+            coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
+            coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
+        };
+        let prev_hash = SetNewPrevHash {
+            channel_id: 1,
+            job_id: 1,
+            prev_hash: [3_u8; 32].into(),
+            min_ntime: 0x6500_0000,
+            nbits: 0x1703_4219,
+        };
+
+        // The first notify cleans the jobs because of the new prev hash
+        Bridge::handle_new_extended_mining_job_(
+            bridge.clone(),
+            new_job(1, true),
+            1,
+            tx_sv1_notify.clone(),
+        )
+        .await
+        .unwrap();
+        Bridge::handle_new_prev_hash_(bridge.clone(), prev_hash, 1, tx_sv1_notify.clone())
+            .await
+            .unwrap();
+        let notify = interface.rx_sv1_notify.try_recv().unwrap();
+        assert!(notify.clean_jobs);
+
+        // All the other jobs have the same prev hash, only every third notify cleans the jobs
+        for job_id in 2..=9 {
+            Bridge::handle_new_extended_mining_job_(
+                bridge.clone(),
+                new_job(job_id, false),
+                1,
+                tx_sv1_notify.clone(),
+            )
+            .await
+            .unwrap();
+            let notify = interface.rx_sv1_notify.try_recv().unwrap();
+            assert_eq!(notify.job_id, job_id.to_string());
+            assert_eq!(notify.clean_jobs, job_id % 3 == 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prev_hash_and_job_out_of_order_are_notified_together() {
+        let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
+        let (bridge, mut interface) = test_utils::create_bridge(extranonces);
+        let tx_sv1_notify = bridge.safe_lock(|b| b.tx_sv1_notify.clone()).unwrap();
+
+        let tx = test_utils::coinbase_tx();
+        let new_job = |job_id, is_future| NewExtendedMiningJob {
+            channel_id: 1,
+            job_id,
+            min_ntime: binary_sv2::Sv2Option::new(match is_future {
+                true => None,
+                false => Some(0x6500_0000),
+            }),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1_u8; 32].into()].into(),
+            coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
+            coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
+        };
+        let new_prev_hash = |job_id, prev_hash| SetNewPrevHash {
+            channel_id: 1,
+            job_id,
+            prev_hash: [prev_hash; 32].into(),
+            min_ntime: 0x6500_0000,
+            nbits: 0x1703_4219,
+        };
+
+        // The upstream sent the future job 1 and then its prev hash, the prev hash is handled
+        // first and waits for the job
+        Bridge::handle_new_prev_hash_(
+            bridge.clone(),
+            new_prev_hash(1, 3),
+            1,
+            tx_sv1_notify.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(interface.rx_sv1_notify.try_recv().is_err());
+        Bridge::handle_new_extended_mining_job_(
+            bridge.clone(),
+            new_job(1, true),
+            1,
+            tx_sv1_notify.clone(),
+        )
+        .await
+        .unwrap();
+        let notify = interface.rx_sv1_notify.try_recv().unwrap();
+        assert_eq!(notify.job_id, "1");
+        assert_eq!(notify.prev_hash.0.to_vec(), vec![3_u8; 32]);
+        assert!(notify.clean_jobs);
+
+        // The upstream sent the future job 2, its prev hash and the job 3 for that prev hash. The
+        // job 3 is handled first, it must not be notified with the old prev hash.
+        Bridge::handle_new_extended_mining_job_(
+            bridge.clone(),
+            new_job(3, false),
+            2,
+            tx_sv1_notify.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(interface.rx_sv1_notify.try_recv().is_err());
+        Bridge::handle_new_extended_mining_job_(
+            bridge.clone(),
+            new_job(2, true),
+            2,
+            tx_sv1_notify.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(interface.rx_sv1_notify.try_recv().is_err());
+        Bridge::handle_new_prev_hash_(
+            bridge.clone(),
+            new_prev_hash(2, 4),
+            2,
+            tx_sv1_notify.clone(),
+        )
+        .await
+        .unwrap();
+
+        let notify = interface.rx_sv1_notify.try_recv().unwrap();
+        assert_eq!(notify.job_id, "2");
+        assert_eq!(notify.prev_hash.0.to_vec(), vec![4_u8; 32]);
+        assert!(notify.clean_jobs);
+        let notify = interface.rx_sv1_notify.try_recv().unwrap();
+        assert_eq!(notify.job_id, "3");
+        assert_eq!(notify.prev_hash.0.to_vec(), vec![4_u8; 32]);
+        assert!(!notify.clean_jobs);
+        assert!(interface.rx_sv1_notify.try_recv().is_err());
+        assert_eq!(bridge.safe_lock(|b| b.last_job_id).unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_prev_hash_without_future_job_is_applied_by_the_next_job() {
+        let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
+        let (bridge, mut interface) = test_utils::create_bridge(extranonces);
+        let tx_sv1_notify = bridge.safe_lock(|b| b.tx_sv1_notify.clone()).unwrap();
+
+        let tx = test_utils::coinbase_tx();
+        let new_job = |job_id, is_future| NewExtendedMiningJob {
+            channel_id: 1,
+            job_id,
+            min_ntime: binary_sv2::Sv2Option::new(match is_future {
+                true => None,
+                false => Some(0x6500_0000),
+            }),
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: vec![[1_u8; 32].into()].into(),
+            coinbase_tx_prefix: tx[0..42].to_vec().try_into().unwrap(),
+            coinbase_tx_suffix: tx[58..].to_vec().try_into().unwrap(),
+        };
+        let prev_hash = SetNewPrevHash {
+            channel_id: 1,
+            job_id: 1,
+            prev_hash: [3; 32].into(),
+            min_ntime: 0x6500_0000,
+            nbits: 0x1703_4219,
+        };
+
+        // The future job 1 of the prev hash never comes, the job 2 of the same epoch proves it
+        Bridge::handle_new_prev_hash_(bridge.clone(), prev_hash, 1, tx_sv1_notify.clone())
+            .await
+            .unwrap();
+        assert!(interface.rx_sv1_notify.try_recv().is_err());
+        Bridge::handle_new_extended_mining_job_(
+            bridge.clone(),
+            new_job(2, false),
+            1,
+            tx_sv1_notify.clone(),
+        )
+        .await
+        .unwrap();
+        let notify = interface.rx_sv1_notify.try_recv().unwrap();
+        assert_eq!(notify.job_id, "2");
+        assert_eq!(notify.prev_hash.0.to_vec(), vec![3_u8; 32]);
+        assert!(notify.clean_jobs);
+        assert!(bridge.safe_lock(|b| b.pending_p_hash.is_none()).unwrap());
+
+        // Jobs of a prev hash that does not come are kept up to the cap
+        for job_id in 0..MAX_PENDING_JOBS as u32 + 10 {
+            Bridge::handle_new_extended_mining_job_(
+                bridge.clone(),
+                new_job(job_id + 3, true),
+                3,
+                tx_sv1_notify.clone(),
+            )
+            .await
+            .unwrap();
+        }
+        let pending_jobs = bridge.safe_lock(|b| b.pending_jobs.clone()).unwrap();
+        assert_eq!(pending_jobs.len(), MAX_PENDING_JOBS);
+        assert_eq!(pending_jobs[0].0.job_id, 13);
+        assert!(interface.rx_sv1_notify.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_set_extranonce_prefix_is_sent_to_the_downstreams() {
+        let extranonces = ExtendedExtranonce::new(0..6, 6..8, 8..16);
+        let (bridge, _interface) = test_utils::create_bridge(extranonces);
+        let mut opened = bridge
+            .safe_lock(|b| b.on_new_sv1_connection(10_000.0))
+            .unwrap()
+            .unwrap();
+
+        let set_extranonce_prefix = SetExtranoncePrefix {
+            channel_id: 1,
+            extranonce_prefix: vec![9; 6].try_into().unwrap(),
+        };
+        bridge
+            .safe_lock(|b| b.on_set_extranonce_prefix(set_extranonce_prefix))
+            .unwrap()
+            .unwrap();
+
+        let prefixes = opened.rx_set_extranonce.try_recv().unwrap();
+        assert_eq!(prefixes.len(), 1);
+        assert_eq!(prefixes[0].channel_id, opened.channel_id);
+        let new_extranonce = [&[9; 6], &opened.extranonce[6..]].concat();
+        assert_eq!(prefixes[0].extranonce_prefix.to_vec(), new_extranonce);
+
+        // The upstream part of the extranonce must keep its length
+        let set_extranonce_prefix = SetExtranoncePrefix {
+            channel_id: 1,
+            extranonce_prefix: vec![9; 4].try_into().unwrap(),
+        };
+        assert!(bridge
+            .safe_lock(|b| b.on_set_extranonce_prefix(set_extranonce_prefix))
+            .unwrap()
+            .is_err());
+    }
 }
@@ -8,18 +8,87 @@ use v1::{
     utils::{HexU32Be, MerkleNode, PrevHash},
 };
 
+use super::super::error::ProxyResult;
+
+/// Remembers if a SV2 `SetNewPrevHash` has been received since the last SV1 `mining.notify` was
+/// created, to know if the next `mining.notify` must tell the miners to drop their current jobs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NextMiningNotify {
+    new_prev_hash_pending: bool,
+    /// Some SV1 firmware misbehaves when `clean_jobs` is never set, when this is `Some(n)` every
+    /// `n`th `mining.notify` has `clean_jobs` set whatever the prev hash.
+    force_clean_jobs_every: Option<u32>,
+    /// `mining.notify` created since the last one forced to clean the jobs
+    notifies_since_forced_clean: u32,
+}
+
+impl NextMiningNotify {
+    /// Like [`NextMiningNotify::default`] but `clean_jobs` is also set on every
+    /// `force_clean_jobs_every`th `mining.notify`
+    pub fn with_forced_clean_jobs(force_clean_jobs_every: Option<u32>) -> Self {
+        Self {
+            force_clean_jobs_every,
+            ..Default::default()
+        }
+    }
+
+    /// Called for each `SetNewPrevHash` received from the Upstream
+    pub fn on_new_prev_hash(&mut self) {
+        self.new_prev_hash_pending = true;
+    }
+
+    /// `true` when the latest `SetNewPrevHash` has been received after the last `mining.notify`
+    /// was created: a new job with the same prev hash does not invalidate the jobs the miners are
+    /// working on. Also `true` when the next `mining.notify` is forced to clean the jobs.
+    pub fn should_clean_jobs(&self) -> bool {
+        self.new_prev_hash_pending || self.is_forced_clean()
+    }
+
+    fn is_forced_clean(&self) -> bool {
+        match self.force_clean_jobs_every {
+            Some(every) => self.notifies_since_forced_clean + 1 >= every,
+            None => false,
+        }
+    }
+
+    /// Like [`create_notify`] but `clean_jobs` is given by [`Self::should_clean_jobs`]
+    #[allow(clippy::result_large_err)]
+    pub fn create_notify(
+        &mut self,
+        new_prev_hash: SetNewPrevHash<'static>,
+        new_job: NewExtendedMiningJob<'static>,
+        full_extranonce_len: usize,
+    ) -> ProxyResult<'static, server_to_client::Notify<'static>> {
+        let notify = create_notify(
+            new_prev_hash,
+            new_job,
+            self.should_clean_jobs(),
+            full_extranonce_len,
+        )?;
+        if self.is_forced_clean() {
+            self.notifies_since_forced_clean = 0;
+        } else {
+            self.notifies_since_forced_clean += 1;
+        }
+        self.new_prev_hash_pending = false;
+        Ok(notify)
+    }
+}
+
 /// Creates a new SV1 `mining.notify` message if both SV2 `SetNewPrevHash` and
 /// `NewExtendedMiningJob` messages have been received. If one of these messages is still being
 /// waited on, the function returns `None`.
 /// If clean_jobs = false, it means a new job is created, with the same PrevHash
+/// `full_extranonce_len` is the size of the extranonce space in the job's coinbase, it is used to
+/// strip the segwit data from the coinbase.
+#[allow(clippy::result_large_err)]
 pub fn create_notify(
     new_prev_hash: SetNewPrevHash<'static>,
     new_job: NewExtendedMiningJob<'static>,
     clean_jobs: bool,
-) -> server_to_client::Notify<'static> {
-    // TODO 32 must be changed!
-    let new_job = extended_job_to_non_segwit(new_job, 32)
-        .expect("failed to convert extended job to non segwit");
+    full_extranonce_len: usize,
+) -> ProxyResult<'static, server_to_client::Notify<'static>> {
+    let new_job = extended_job_to_non_segwit(new_job, full_extranonce_len)?;
     // Make sure that SetNewPrevHash + NewExtendedMiningJob is matching (not future)
     let job_id = new_job.job_id.to_string();
 
@@ -54,5 +123,5 @@ pub fn create_notify(
         clean_jobs,
     };
     debug!("\nNextMiningNotify: {:?}\n", notify_response);
-    notify_response
+    Ok(notify_response)
 }
@@ -11,6 +11,9 @@ pub struct ProxyConfig {
     pub max_supported_version: u16,
     pub min_supported_version: u16,
     pub min_extranonce2_size: u16,
+    /// Set `clean_jobs` on every Nth `mining.notify`, for SV1 firmware that misbehaves when it
+    /// is never set
+    pub force_clean_jobs_every: Option<u32>,
     pub downstream_difficulty_config: DownstreamDifficultyConfig,
     pub upstream_difficulty_config: UpstreamDifficultyConfig,
 }
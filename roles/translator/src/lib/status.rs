@@ -136,6 +136,10 @@ pub async fn handle_error(
         Error::UpstreamIncoming(_) => {
             send_status(sender, e, error_handling::ErrorBranch::Break).await
         }
+        // The stream can not be trusted after a corrupt frame, so the connection is closed
+        Error::MalformedSv2Frame(_) => {
+            send_status(sender, e, error_handling::ErrorBranch::Break).await
+        }
         // SV1 protocol library error
         Error::V1Protocol(_) => send_status(sender, e, error_handling::ErrorBranch::Break).await,
         Error::SubprotocolMining(_) => {
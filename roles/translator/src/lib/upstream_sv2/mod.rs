@@ -1,5 +1,8 @@
 use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
-use roles_logic_sv2::parsers::PoolMessages;
+use roles_logic_sv2::{
+    common_messages_sv2::{MiningFlags, ProtocolVersion},
+    parsers::PoolMessages,
+};
 
 pub mod diff_management;
 pub mod upstream;
@@ -13,8 +16,8 @@ pub type EitherFrame = StandardEitherFrame<Message>;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Sv2MiningConnection {
-    _version: u16,
-    _setup_connection_flags: u32,
+    _version: ProtocolVersion,
+    _setup_connection_flags: MiningFlags,
     #[allow(dead_code)]
-    setup_connection_success_flags: u32,
+    setup_connection_success_flags: MiningFlags,
 }
@@ -1,7 +1,7 @@
 use crate::{
     downstream_sv1::Downstream,
     error::{
-        Error::{CodecNoise, InvalidExtranonce, PoisonLock, UpstreamIncoming},
+        Error::{CodecNoise, InvalidExtranonce, MalformedSv2Frame, PoisonLock, UpstreamIncoming},
         ProxyResult,
     },
     proxy_config::UpstreamDifficultyConfig,
@@ -13,10 +13,11 @@ use async_std::{net::TcpStream, task};
 use binary_sv2::u256_from_int;
 use codec_sv2::{Frame, HandshakeRole, Initiator};
 use error_handling::handle_result;
+use framing_sv2::header::Header;
 use key_utils::Secp256k1PublicKey;
 use network_helpers_sv2::Connection;
 use roles_logic_sv2::{
-    common_messages_sv2::{Protocol, SetupConnection},
+    common_messages_sv2::{MiningFlags, Protocol, ProtocolVersion, SetupConnection},
     common_properties::{IsMiningUpstream, IsUpstream},
     handlers::{
         common::{ParseUpstreamCommonMessages, SendTo as SendToCommon},
@@ -24,26 +25,20 @@ use roles_logic_sv2::{
     },
     mining_sv2::{
         ExtendedExtranonce, Extranonce, NewExtendedMiningJob, OpenExtendedMiningChannel,
-        SetNewPrevHash, SubmitSharesExtended,
+        SetExtranoncePrefix, SetNewPrevHash, SubmitSharesExtended,
     },
-    parsers::Mining,
+    parsers::{JobDeclaration, JobDeclarationTypes, Mining},
     routing_logic::{CommonRoutingLogic, MiningRoutingLogic, NoRouting},
     selectors::NullDownstreamMiningSelector,
     utils::Mutex,
     Error as RolesLogicError,
     Error::NoUpstreamsConnected,
 };
-use std::{
-    net::SocketAddr,
-    sync::{atomic::AtomicBool, Arc},
-    thread::sleep,
-    time::Duration,
-};
+use std::{net::SocketAddr, sync::Arc, thread::sleep, time::Duration};
 use tracing::{error, info, warn};
 
 use stratum_common::bitcoin::BlockHash;
 
-pub static IS_NEW_JOB_HANDLED: AtomicBool = AtomicBool::new(true);
 /// Represents the currently active `prevhash` of the mining job being worked on OR being submitted
 /// from the Downstream role.
 #[derive(Debug, Clone)]
@@ -73,14 +68,19 @@ pub struct Upstream {
     rx_sv2_submit_shares_ext: Receiver<SubmitSharesExtended<'static>>,
     /// Sends SV2 `SetNewPrevHash` messages to be translated (along with SV2 `NewExtendedMiningJob`
     /// messages) into SV1 `mining.notify` messages. Received and translated by the `Bridge`.
-    tx_sv2_set_new_prev_hash: Sender<SetNewPrevHash<'static>>,
+    /// Each message is sent with its prev hash epoch, see [`Upstream::parse_incoming`].
+    tx_sv2_set_new_prev_hash: Sender<(SetNewPrevHash<'static>, u32)>,
     /// Sends SV2 `NewExtendedMiningJob` messages to be translated (along with SV2 `SetNewPrevHash`
     /// messages) into SV1 `mining.notify` messages. Received and translated by the `Bridge`.
-    tx_sv2_new_ext_mining_job: Sender<NewExtendedMiningJob<'static>>,
+    /// Each message is sent with the epoch of the prev hash it is for.
+    tx_sv2_new_ext_mining_job: Sender<(NewExtendedMiningJob<'static>, u32)>,
     /// Sends the extranonce1 and the channel id received in the SV2 `OpenExtendedMiningChannelSuccess` message to be
     /// used by the `Downstream` and sent to the Downstream role in a SV2 `mining.subscribe`
     /// response message. Passed to the `Downstream` on connection creation.
     tx_sv2_extranonce: Sender<(ExtendedExtranonce, u32)>,
+    /// Sends the SV2 `SetExtranoncePrefix` messages received for the channel to the `Bridge`,
+    /// that updates the `extranonce1` of every Downstream role.
+    tx_sv2_set_extranonce_prefix: Sender<SetExtranoncePrefix<'static>>,
     /// This allows the upstream threads to be able to communicate back to the main thread its
     /// current status.
     tx_status: status::Sender,
@@ -117,10 +117,11 @@ impl Upstream {
         address: SocketAddr,
         authority_public_key: Secp256k1PublicKey,
         rx_sv2_submit_shares_ext: Receiver<SubmitSharesExtended<'static>>,
-        tx_sv2_set_new_prev_hash: Sender<SetNewPrevHash<'static>>,
-        tx_sv2_new_ext_mining_job: Sender<NewExtendedMiningJob<'static>>,
+        tx_sv2_set_new_prev_hash: Sender<(SetNewPrevHash<'static>, u32)>,
+        tx_sv2_new_ext_mining_job: Sender<(NewExtendedMiningJob<'static>, u32)>,
         min_extranonce_size: u16,
         tx_sv2_extranonce: Sender<(ExtendedExtranonce, u32)>,
+        tx_sv2_set_extranonce_prefix: Sender<SetExtranoncePrefix<'static>>,
         tx_status: status::Sender,
         target: Arc<Mutex<Vec<u8>>>,
         difficulty_config: Arc<Mutex<UpstreamDifficultyConfig>>,
@@ -168,6 +169,7 @@ impl Upstream {
             min_extranonce_size,
             upstream_extranonce1_size: 16, // 16 is the default since that is the only value the pool supports currently
             tx_sv2_extranonce,
+            tx_sv2_set_extranonce_prefix,
             tx_status,
             target,
             difficulty_config,
@@ -195,7 +197,7 @@ impl Upstream {
         // Wait for the SV2 Upstream to respond with either a `SetupConnectionSuccess` or a
         // `SetupConnectionError` inside a SV2 binary message frame
         let mut incoming: StdFrame = match connection.receiver.recv().await {
-            Ok(frame) => frame.try_into()?,
+            Ok(frame) => Self::sv2_frame(frame)?,
             Err(e) => {
                 error!("Upstream connection closed: {}", e);
                 return Err(CodecNoise(
@@ -204,14 +206,8 @@ impl Upstream {
             }
         };
 
-        // Gets the binary frame message type from the message header
-        let message_type = if let Some(header) = incoming.get_header() {
-            header.msg_type()
-        } else {
-            return Err(framing_sv2::Error::ExpectedHandshakeFrame.into());
-        };
-        // Gets the message payload
-        let payload = incoming.payload();
+        // Gets the binary frame message type from the message header and the message payload
+        let (message_type, payload) = Self::message_type_and_payload(&mut incoming)?;
 
         // Handle the incoming message (should be either `SetupConnectionSuccess` or
         // `SetupConnectionError`)
@@ -256,6 +252,11 @@ impl Upstream {
 
     /// Parses the incoming SV2 message from the Upstream role and routes the message to the
     /// appropriate handler.
+    ///
+    /// `SetNewPrevHash` and `NewExtendedMiningJob` are sent to the `Bridge` over different
+    /// channels, so they are tagged with a prev hash epoch that keeps the order in which the
+    /// Upstream sent them: the n-th `SetNewPrevHash` has epoch n, a future job has the epoch of the
+    /// next `SetNewPrevHash` and a non future job the epoch of the last one.
     #[allow(clippy::result_large_err)]
     pub fn parse_incoming(self_: Arc<Mutex<Self>>) -> ProxyResult<'static, ()> {
         let clone = self_.clone();
@@ -264,6 +265,7 @@ impl Upstream {
             tx_sv2_extranonce,
             tx_sv2_new_ext_mining_job,
             tx_sv2_set_new_prev_hash,
+            tx_sv2_set_extranonce_prefix,
             recv,
             tx_status,
         ) = clone
@@ -273,6 +275,7 @@ impl Upstream {
                     s.tx_sv2_extranonce.clone(),
                     s.tx_sv2_new_ext_mining_job.clone(),
                     s.tx_sv2_set_new_prev_hash.clone(),
+                    s.tx_sv2_set_extranonce_prefix.clone(),
                     s.connection.receiver.clone(),
                     s.tx_status.clone(),
                 )
@@ -291,22 +294,23 @@ impl Upstream {
         }
 
         task::spawn(async move {
+            // Number of `SetNewPrevHash` received so far
+            let mut prev_hash_epoch: u32 = 0;
             loop {
                 // Waiting to receive a message from the SV2 Upstream role
                 let incoming = handle_result!(tx_status, recv.recv().await);
-                let mut incoming: StdFrame = handle_result!(tx_status, incoming.try_into());
+                let mut incoming: StdFrame = handle_result!(tx_status, Self::sv2_frame(incoming));
                 // On message receive, get the message type from the message header and get the
                 // message payload
-                let message_type =
-                    incoming
-                        .get_header()
-                        .ok_or(super::super::error::Error::FramingSv2(
-                            framing_sv2::Error::ExpectedSv2Frame,
-                        ));
+                let (message_type, payload) =
+                    handle_result!(tx_status, Self::message_type_and_payload(&mut incoming));
 
-                let message_type = handle_result!(tx_status, message_type).msg_type();
-
-                let payload = incoming.payload();
+                if handle_result!(
+                    tx_status,
+                    Self::handle_job_declaration(message_type, payload)
+                ) {
+                    continue;
+                }
 
                 // Since this is not communicating with an SV2 proxy, but instead a custom SV1
                 // proxy where the routing logic is handled via the `Upstream`'s communication
@@ -364,18 +368,25 @@ impl Upstream {
                                 // range 1 is the extranonce1 added by the tproxy
                                 // range 2 is the extranonce2 used by the miner for rolling
                                 // range 0 + range 1 is the extranonce1 sent to the miner
-                                let tproxy_e1_len = super::super::utils::proxy_extranonce1_len(
-                                    m.extranonce_size as usize,
-                                    miner_extranonce2_size,
+                                let tproxy_e1_len = handle_result!(
+                                    tx_status,
+                                    super::super::utils::proxy_extranonce1_len(
+                                        m.extranonce_size as usize,
+                                        miner_extranonce2_size,
+                                    )
+                                    .ok_or_else(|| InvalidExtranonce(format!(
+                                        "Channel extranonce of {} bytes, the miners need at least {}",
+                                        m.extranonce_size, miner_extranonce2_size
+                                    )))
                                 );
-                                let range_0 = 0..prefix_len; // upstream extranonce1
+                                // range 0 is 0..prefix_len, the upstream extranonce1
                                 let range_1 = prefix_len..prefix_len + tproxy_e1_len; // downstream extranonce1
                                 let range_2 = prefix_len + tproxy_e1_len
                                     ..prefix_len + m.extranonce_size as usize; // extranonce2
-                                let extended = handle_result!(tx_status, ExtendedExtranonce::from_upstream_extranonce(
-                                    extranonce_prefix.clone(), range_0.clone(), range_1.clone(), range_2.clone(),
-                                ).ok_or_else(|| InvalidExtranonce(format!("Impossible to create a valid extended extranonce from {:?} {:?} {:?} {:?}",
-                                    extranonce_prefix,range_0,range_1,range_2))));
+                                let extended = handle_result!(tx_status, ExtendedExtranonce::from_ranges(
+                                    extranonce_prefix.clone(), range_1.clone(), range_2.clone(),
+                                ).map_err(|e| InvalidExtranonce(format!("Impossible to create a valid extended extranonce from {:?} {:?} {:?}: {:?}",
+                                    extranonce_prefix,range_1,range_2,e))));
                                 handle_result!(
                                     tx_status,
                                     tx_sv2_extranonce.send((extended, m.channel_id)).await
@@ -389,10 +400,27 @@ impl Upstream {
                                     })
                                     .map_err(|_e| PoisonLock);
                                 handle_result!(tx_status, res);
-                                handle_result!(tx_status, tx_sv2_new_ext_mining_job.send(m).await);
+                                let epoch = match m.is_future() {
+                                    true => prev_hash_epoch.wrapping_add(1),
+                                    false => prev_hash_epoch,
+                                };
+                                handle_result!(
+                                    tx_status,
+                                    tx_sv2_new_ext_mining_job.send((m, epoch)).await
+                                );
                             }
                             Mining::SetNewPrevHash(m) => {
-                                handle_result!(tx_status, tx_sv2_set_new_prev_hash.send(m).await);
+                                prev_hash_epoch = prev_hash_epoch.wrapping_add(1);
+                                handle_result!(
+                                    tx_status,
+                                    tx_sv2_set_new_prev_hash.send((m, prev_hash_epoch)).await
+                                );
+                            }
+                            Mining::SetExtranoncePrefix(m) => {
+                                handle_result!(
+                                    tx_status,
+                                    tx_sv2_set_extranonce_prefix.send(m).await
+                                );
                             }
                             Mining::CloseChannel(_m) => {
                                 error!("Received Mining::CloseChannel msg from upstream!");
@@ -436,6 +464,64 @@ impl Upstream {
 
         Ok(())
     }
+    /// Converts a frame received from the Upstream role, a handshake frame after the handshake is
+    /// reported as [`MalformedSv2Frame`].
+    #[allow(clippy::result_large_err)]
+    fn sv2_frame(frame: EitherFrame) -> ProxyResult<'static, StdFrame> {
+        frame
+            .try_into()
+            .map_err(|e: framing_sv2::Error| MalformedSv2Frame(format!("{:?}", e)))
+    }
+
+    /// Message type and payload of a frame received from the Upstream role. A frame whose payload
+    /// is not as long as its header declares is reported as [`MalformedSv2Frame`] before any
+    /// parser reads it.
+    #[allow(clippy::result_large_err)]
+    fn message_type_and_payload(frame: &mut StdFrame) -> ProxyResult<'static, (u8, &mut [u8])> {
+        let header = frame
+            .get_header()
+            .ok_or_else(|| MalformedSv2Frame("Frame without header".to_string()))?;
+        let payload_len = frame.encoded_length().saturating_sub(Header::SIZE);
+        if payload_len != header.len() {
+            return Err(MalformedSv2Frame(format!(
+                "Payload of {} bytes but the header declares {}",
+                payload_len,
+                header.len()
+            )));
+        }
+        Ok((header.msg_type(), frame.payload()))
+    }
+
+    /// Handles the job declaration messages sent by the Upstream role. The translator does not
+    /// declare jobs, so there is no request these messages can answer: they are logged and
+    /// dropped instead of being parsed as mining messages, which would shut the Upstream down.
+    /// Returns `false` if the message is not a job declaration message.
+    #[allow(clippy::result_large_err)]
+    fn handle_job_declaration(message_type: u8, payload: &mut [u8]) -> ProxyResult<'static, bool> {
+        if JobDeclarationTypes::try_from(message_type).is_err() {
+            return Ok(false);
+        }
+        match JobDeclaration::try_from((message_type, payload))? {
+            JobDeclaration::AllocateMiningJobTokenSuccess(m) => warn!(
+                "Received AllocateMiningJobTokenSuccess for request {} but no token was requested",
+                m.request_id
+            ),
+            JobDeclaration::DeclareMiningJobSuccess(m) => warn!(
+                "Received DeclareMiningJobSuccess for request {} but no job was declared",
+                m.request_id
+            ),
+            JobDeclaration::DeclareMiningJobError(m) => warn!(
+                "Received DeclareMiningJobError for request {} but no job was declared",
+                m.request_id
+            ),
+            _ => warn!(
+                "Received unexpected job declaration message {:#x} from upstream",
+                message_type
+            ),
+        }
+        Ok(true)
+    }
+
     #[allow(clippy::result_large_err)]
     fn get_job_id(
         self_: &Arc<Mutex<Self>>,
@@ -547,11 +633,11 @@ impl Upstream {
 }
 
 impl IsUpstream<Downstream, NullDownstreamMiningSelector> for Upstream {
-    fn get_version(&self) -> u16 {
+    fn get_version(&self) -> ProtocolVersion {
         todo!()
     }
 
-    fn get_flags(&self) -> u32 {
+    fn get_flags(&self) -> MiningFlags {
         todo!()
     }
 
@@ -652,7 +738,11 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         let tproxy_e1_len = super::super::utils::proxy_extranonce1_len(
             m.extranonce_size as usize,
             self.min_extranonce_size.into(),
-        ) as u16;
+        )
+        .ok_or(RolesLogicError::InvalidExtranonceSize(
+            self.min_extranonce_size,
+            m.extranonce_size,
+        ))? as u16;
         if self.min_extranonce_size + tproxy_e1_len < m.extranonce_size {
             return Err(RolesLogicError::InvalidExtranonceSize(
                 self.min_extranonce_size,
@@ -698,12 +788,23 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         Ok(SendTo::None(Some(Mining::CloseChannel(m.as_static()))))
     }
 
-    /// Handles the SV2 `SetExtranoncePrefix` message (TODO).
+    /// Handles the SV2 `SetExtranoncePrefix` message. The new prefix replaces the upstream part
+    /// of the extranonce, so it must have the same length as the one of the opened channel.
     fn handle_set_extranonce_prefix(
         &mut self,
-        _: roles_logic_sv2::mining_sv2::SetExtranoncePrefix,
+        m: roles_logic_sv2::mining_sv2::SetExtranoncePrefix,
     ) -> Result<roles_logic_sv2::handlers::mining::SendTo<Downstream>, RolesLogicError> {
-        todo!()
+        if m.extranonce_prefix.len() != self.upstream_extranonce1_size {
+            return Err(RolesLogicError::InvalidExtranonceSize(
+                self.upstream_extranonce1_size as u16,
+                m.extranonce_prefix.len() as u16,
+            ));
+        }
+        info!("Up: Updating the extranonce prefix of the channel");
+        self.extranonce_prefix = Some(m.extranonce_prefix.to_vec());
+        Ok(SendTo::None(Some(Mining::SetExtranoncePrefix(
+            m.into_static(),
+        ))))
     }
 
     /// Handles the SV2 `SubmitSharesSuccess` message.
@@ -742,7 +843,6 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         if self.is_work_selection_enabled() {
             Ok(SendTo::None(None))
         } else {
-            IS_NEW_JOB_HANDLED.store(false, std::sync::atomic::Ordering::SeqCst);
             if !m.version_rolling_allowed {
                 warn!("VERSION ROLLING NOT ALLOWED IS A TODO");
                 // todo!()
@@ -809,3 +909,70 @@ impl ParseUpstreamMiningMessages<Downstream, NullDownstreamMiningSelector, NoRou
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use roles_logic_sv2::{
+        job_declaration_sv2::DeclareMiningJobSuccess, mining_sv2::SetTarget, parsers::PoolMessages,
+    };
+
+    // Frame as read from the upstream connection
+    fn received_frame(message: Message) -> StdFrame {
+        let frame: StdFrame = message.try_into().unwrap();
+        let mut bytes = vec![0; frame.encoded_length()];
+        frame.serialize(&mut bytes).unwrap();
+        StdFrame::from_bytes(bytes.into()).unwrap()
+    }
+
+    #[test]
+    fn job_declaration_messages_are_handled_apart_from_mining_messages() {
+        let success = DeclareMiningJobSuccess {
+            request_id: 1,
+            new_mining_job_token: vec![1; 8].try_into().unwrap(),
+        };
+        let mut frame = received_frame(PoolMessages::JobDeclaration(
+            JobDeclaration::DeclareMiningJobSuccess(success),
+        ));
+        let message_type = frame.get_header().unwrap().msg_type();
+        assert!(Upstream::handle_job_declaration(message_type, frame.payload()).unwrap());
+
+        let set_target = SetTarget {
+            channel_id: 1,
+            maximum_target: [1; 32].into(),
+        };
+        let mut frame = received_frame(PoolMessages::Mining(Mining::SetTarget(set_target)));
+        let message_type = frame.get_header().unwrap().msg_type();
+        assert!(!Upstream::handle_job_declaration(message_type, frame.payload()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_is_reported() {
+        let set_target = SetTarget {
+            channel_id: 1,
+            maximum_target: [1; 32].into(),
+        };
+        let frame: StdFrame = PoolMessages::Mining(Mining::SetTarget(set_target))
+            .try_into()
+            .unwrap();
+        let mut bytes = vec![0; frame.encoded_length()];
+        frame.serialize(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 2);
+        let mut frame = StdFrame::from_bytes_unchecked(bytes.into());
+
+        let e = match Upstream::message_type_and_payload(&mut frame) {
+            Err(e @ MalformedSv2Frame(_)) => e,
+            _ => panic!("a truncated frame must be rejected"),
+        };
+
+        // The error closes the upstream connection
+        let (tx_status, rx_status) = async_channel::bounded(1);
+        let branch = status::handle_error(&status::Sender::Upstream(tx_status), e).await;
+        assert!(matches!(branch, error_handling::ErrorBranch::Break));
+        let status = rx_status.try_recv().unwrap();
+        assert!(matches!(
+            status.state,
+            status::State::UpstreamShutdown(MalformedSv2Frame(_))
+        ));
+    }
+}
@@ -1,9 +1,37 @@
 /// currently the pool only supports 16 bytes exactly for its channels
 /// to use but that may change
+/// `None` when the channel does not leave `downstream_extranonce2_len` bytes to the miners
 pub fn proxy_extranonce1_len(
     channel_extranonce2_size: usize,
     downstream_extranonce2_len: usize,
-) -> usize {
+) -> Option<usize> {
     // full_extranonce_len - pool_extranonce1_len - miner_extranonce2 = tproxy_extranonce1_len
-    channel_extranonce2_size - downstream_extranonce2_len
+    channel_extranonce2_size.checked_sub(downstream_extranonce2_len)
+}
+
+/// `extranonce2_size` to use for a miner that requests `requested` bytes: the request is clamped
+/// into `[min, max]`, where `min` is the smallest size the miner accepts and `max` the extranonce
+/// space of the channel reserved to the miner. `None` when the channel space is smaller than `min`.
+pub fn clamp_extranonce2_size(requested: usize, min: usize, max: usize) -> Option<usize> {
+    match min <= max {
+        true => Some(requested.clamp(min, max)),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extranonce2_size_is_clamped_into_the_channel_space() {
+        // within range
+        assert_eq!(clamp_extranonce2_size(6, 4, 8), Some(6));
+        // below min
+        assert_eq!(clamp_extranonce2_size(2, 4, 8), Some(4));
+        // above max
+        assert_eq!(clamp_extranonce2_size(16, 4, 8), Some(8));
+        // the channel space can not satisfy the miner
+        assert_eq!(clamp_extranonce2_size(4, 8, 4), None);
+    }
 }
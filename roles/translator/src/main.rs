@@ -69,6 +69,10 @@ async fn main() {
     // passed to the `Downstream` upon a Downstream role connection
     // (Sender<ExtendedExtranonce>, Receiver<ExtendedExtranonce>)
     let (tx_sv2_extranonce, rx_sv2_extranonce) = bounded(1);
+
+    // Sender/Receiver to send a SV2 `SetExtranoncePrefix` message from the `Upstream` to the
+    // `Bridge`
+    let (tx_sv2_set_extranonce_prefix, rx_sv2_set_extranonce_prefix) = bounded(10);
     let target = Arc::new(Mutex::new(vec![0; 32]));
 
     // Sender/Receiver to send SV1 `mining.notify` message from the `Bridge` to the `Downstream`
@@ -95,6 +99,7 @@ async fn main() {
         tx_sv2_new_ext_mining_job,
         proxy_config.min_extranonce2_size,
         tx_sv2_extranonce,
+        tx_sv2_set_extranonce_prefix,
         status::Sender::Upstream(tx_status.clone()),
         target.clone(),
         diff_config.clone(),
@@ -158,11 +163,13 @@ async fn main() {
             tx_sv2_submit_shares_ext,
             rx_sv2_set_new_prev_hash,
             rx_sv2_new_ext_mining_job,
+            rx_sv2_set_extranonce_prefix,
             tx_sv1_notify.clone(),
             status::Sender::Bridge(tx_status.clone()),
             extended_extranonce,
             target,
             up_id,
+            proxy_config.force_clean_jobs_every,
         );
         proxy::Bridge::start(b.clone());
 
@@ -172,15 +179,20 @@ async fn main() {
             proxy_config.downstream_port,
         );
 
+        let downstream_config = downstream_sv1::DownstreamListenerConfig {
+            address: downstream_addr,
+            difficulty_config: proxy_config.downstream_difficulty_config,
+            upstream_difficulty_config: diff_config,
+            min_extranonce2_len: proxy_config.min_extranonce2_size as usize,
+        };
+
         // Accept connections from one or more SV1 Downstream roles (SV1 Mining Devices)
         downstream_sv1::Downstream::accept_connections(
-            downstream_addr,
+            downstream_config,
             tx_sv1_bridge,
             tx_sv1_notify,
             status::Sender::DownstreamListener(tx_status.clone()),
             b,
-            proxy_config.downstream_difficulty_config,
-            diff_config,
         );
     }); // End of init task
 
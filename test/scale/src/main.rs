@@ -11,7 +11,8 @@ use codec_sv2::{HandshakeRole, Initiator, Responder, StandardEitherFrame, Standa
 use std::time::Duration;
 
 use network_helpers::{
-    noise_connection_tokio::Connection, plain_connection_tokio::PlainConnection,
+    noise_connection_tokio::{Connection, ConnectionParts},
+    plain_connection_tokio::PlainConnection,
 };
 
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
@@ -72,10 +73,12 @@ async fn setup_driver(
         let k: Secp256k1PublicKey = AUTHORITY_PUBLIC_K.to_string().try_into().unwrap();
         let initiator = Initiator::from_raw_k(k.into_bytes()).unwrap();
 
-        (_, server_sender, _, _) =
-            Connection::new(server_stream, HandshakeRole::Initiator(initiator))
-                .await
-                .unwrap();
+        ConnectionParts {
+            sender: server_sender,
+            ..
+        } = Connection::new(server_stream, HandshakeRole::Initiator(initiator))
+            .await
+            .unwrap();
     } else {
         (_server_receiver, server_sender) = PlainConnection::new(server_stream).await;
     }
@@ -171,11 +174,14 @@ async fn create_proxy(
         let k_priv: Secp256k1SecretKey = AUTHORITY_PRIVATE_K.to_string().try_into().unwrap();
         let responder = Responder::from_authority_kp(
             &k_pub.into_bytes(),
-            &k_priv.into_bytes(),
+            k_priv.into_bytes(),
             Duration::from_secs(3600),
         )
         .unwrap();
-        (cli_receiver, _, _, _) = Connection::new(cli_stream, HandshakeRole::Responder(responder))
+        ConnectionParts {
+            receiver: cli_receiver,
+            ..
+        } = Connection::new(cli_stream, HandshakeRole::Responder(responder))
             .await
             .unwrap();
     } else {
@@ -193,10 +199,12 @@ async fn create_proxy(
 
         if encrypt {
             let initiator = Initiator::from_raw_k(k_pub.into_bytes()).unwrap();
-            (_, server_sender, _, _) =
-                Connection::new(server_stream, HandshakeRole::Initiator(initiator))
-                    .await
-                    .unwrap();
+            ConnectionParts {
+                sender: server_sender,
+                ..
+            } = Connection::new(server_stream, HandshakeRole::Initiator(initiator))
+                .await
+                .unwrap();
         } else {
             (_server_receiver, server_sender) = PlainConnection::new(server_stream).await;
         }
@@ -0,0 +1,171 @@
+//! On disk formats for keys, so that roles can read them from a path in their config instead of
+//! embedding them.
+
+use crate::Error;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use secp256k1::{Keypair, Secp256k1, SecretKey, XOnlyPublicKey};
+use std::{fs, io::Write, path::Path};
+
+const BEGIN: &str = "-----BEGIN SV2 KEYPAIR-----";
+const END: &str = "-----END SV2 KEYPAIR-----";
+const VERSION_LABEL: &str = "Version: ";
+const PUBLIC_LABEL: &str = "Public: ";
+const SECRET_LABEL: &str = "Secret: ";
+const VERSION: u16 = 1;
+
+/// Keypair stored in a PEM like text file:
+///
+/// ```text
+/// -----BEGIN SV2 KEYPAIR-----
+/// Version: 1
+/// Public: <base64 of the 32 bytes x-only public key>
+/// Secret: <base64 of the 32 bytes secret key>
+/// -----END SV2 KEYPAIR-----
+/// ```
+pub struct KeypairFile;
+
+impl KeypairFile {
+    /// Write `keypair` to `path`, on unix the file is only readable by its owner
+    pub fn save<P: AsRef<Path>>(keypair: &Keypair, path: P) -> Result<(), Error> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        file.write_all(Self::encode(keypair).as_bytes())?;
+        Ok(())
+    }
+
+    /// Read a keypair written by [`KeypairFile::save`]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Keypair, Error> {
+        Self::decode(&fs::read_to_string(path)?)
+    }
+
+    pub fn encode(keypair: &Keypair) -> String {
+        let public = keypair.x_only_public_key().0.serialize();
+        let secret = keypair.secret_bytes();
+        format!(
+            "{BEGIN}\n{VERSION_LABEL}{VERSION}\n{PUBLIC_LABEL}{}\n{SECRET_LABEL}{}\n{END}\n",
+            STANDARD.encode(public),
+            STANDARD.encode(secret)
+        )
+    }
+
+    /// Parse the content of a keypair file, the public key must be the one of the secret key
+    pub fn decode(content: &str) -> Result<Keypair, Error> {
+        let mut lines = content.lines().map(str::trim).filter(|l| !l.is_empty());
+        let mut next_line = |expected: &str| {
+            lines
+                .next()
+                .ok_or_else(|| Error::KeyFileFormat(format!("missing {}", expected.trim())))
+        };
+
+        if next_line(BEGIN)? != BEGIN {
+            return Err(Error::KeyFileFormat("missing begin line".to_string()));
+        }
+        let version = field(next_line(VERSION_LABEL)?, VERSION_LABEL)?
+            .parse::<u16>()
+            .map_err(|_| Error::KeyFileFormat("invalid version".to_string()))?;
+        if version != VERSION {
+            return Err(Error::KeyVersion(version));
+        }
+        let public = STANDARD.decode(field(next_line(PUBLIC_LABEL)?, PUBLIC_LABEL)?)?;
+        let secret = STANDARD.decode(field(next_line(SECRET_LABEL)?, SECRET_LABEL)?)?;
+        if next_line(END)? != END {
+            return Err(Error::KeyFileFormat("missing end line".to_string()));
+        }
+        if lines.next().is_some() {
+            return Err(Error::KeyFileFormat("data after end line".to_string()));
+        }
+
+        if public.len() != 32 || secret.len() != 32 {
+            return Err(Error::KeyLength);
+        }
+        let public = XOnlyPublicKey::from_slice(&public)?;
+        let keypair = Keypair::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&secret)?);
+        if keypair.x_only_public_key().0 != public {
+            return Err(Error::KeyFileFormat(
+                "public key does not match the secret key".to_string(),
+            ));
+        }
+        Ok(keypair)
+    }
+}
+
+fn field<'a>(line: &'a str, label: &str) -> Result<&'a str, Error> {
+    line.strip_prefix(label)
+        .ok_or_else(|| Error::KeyFileFormat(format!("expected {}", label.trim())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use secp256k1::rand;
+
+    fn keypair() -> Keypair {
+        Keypair::new(&Secp256k1::new(), &mut rand::thread_rng())
+    }
+
+    #[test]
+    fn keypair_file_round_trip() {
+        let keypair = keypair();
+        let path = std::env::temp_dir().join(format!("sv2-keypair-{}", std::process::id()));
+
+        KeypairFile::save(&keypair, &path).unwrap();
+        let loaded = KeypairFile::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap(), keypair);
+        assert_eq!(
+            KeypairFile::decode(&KeypairFile::encode(&keypair)).unwrap(),
+            keypair
+        );
+    }
+
+    #[test]
+    fn truncated_keypair_file_is_a_parse_error() {
+        let encoded = KeypairFile::encode(&keypair());
+
+        // The end line is missing
+        let truncated = &encoded[..encoded.find(END).unwrap()];
+        assert!(matches!(
+            KeypairFile::decode(truncated),
+            Err(Error::KeyFileFormat(_))
+        ));
+
+        // The secret key is cut in the middle
+        let truncated = &encoded[..encoded.find(SECRET_LABEL).unwrap() + SECRET_LABEL.len() + 20];
+        assert!(matches!(
+            KeypairFile::decode(truncated),
+            Err(Error::KeyFileFormat(_))
+        ));
+
+        // Both keys are complete but one byte of the secret key is missing
+        let short_secret = format!(
+            "{BEGIN}\n{VERSION_LABEL}{VERSION}\n{PUBLIC_LABEL}{}\n{SECRET_LABEL}{}\n{END}\n",
+            STANDARD.encode([2; 32]),
+            STANDARD.encode([1; 31])
+        );
+        assert!(matches!(
+            KeypairFile::decode(&short_secret),
+            Err(Error::KeyLength)
+        ));
+    }
+
+    #[test]
+    fn keypair_file_with_mismatched_keys_is_rejected() {
+        let encoded = KeypairFile::encode(&keypair());
+        let other = KeypairFile::encode(&keypair());
+        let public_line = |s: &str| s.lines().nth(2).unwrap().to_string();
+        let mismatched = encoded.replace(&public_line(&encoded), &public_line(&other));
+
+        assert!(matches!(
+            KeypairFile::decode(&mismatched),
+            Err(Error::KeyFileFormat(_))
+        ));
+        assert!(matches!(
+            KeypairFile::decode(&encoded.replace("Version: 1", "Version: 2")),
+            Err(Error::KeyVersion(2))
+        ));
+    }
+}
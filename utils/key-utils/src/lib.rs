@@ -7,6 +7,8 @@ use secp256k1::{
 use serde::{Deserialize, Serialize};
 use std::{fmt::Display, str::FromStr};
 
+pub mod formats;
+
 #[derive(Debug)]
 pub enum Error {
     Bs58Decode(Bs58DecodeError),
@@ -14,6 +16,10 @@ pub enum Error {
     KeyVersion(u16),
     KeyLength,
     Custom(String),
+    Io(std::io::Error),
+    Base64Decode(base64::DecodeError),
+    // The content of a key file does not follow the expected layout
+    KeyFileFormat(String),
 }
 
 impl Display for Error {
@@ -26,6 +32,9 @@ impl Display for Error {
             }
             Self::KeyLength => write!(f, "Bad key length"),
             Self::Custom(error) => write!(f, "Custom error: {error}"),
+            Self::Io(error) => write!(f, "IO error: {error}"),
+            Self::Base64Decode(error) => write!(f, "Base64 decode error: {error}"),
+            Self::KeyFileFormat(error) => write!(f, "Invalid key file: {error}"),
         }
     }
 }
@@ -38,6 +47,18 @@ impl From<Bs58DecodeError> for Error {
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Self {
+        Error::Base64Decode(e)
+    }
+}
+
 impl From<secp256k1::Error> for Error {
     fn from(e: secp256k1::Error) -> Self {
         Error::Secp256k1(e)
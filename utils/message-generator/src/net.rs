@@ -4,7 +4,8 @@ use binary_sv2::{Deserialize, GetSize, Serialize};
 use codec_sv2::{HandshakeRole, Initiator, Responder, StandardEitherFrame as EitherFrame};
 use key_utils::{Secp256k1PublicKey, Secp256k1SecretKey};
 use network_helpers_sv2::{
-    noise_connection_tokio::Connection, plain_connection_tokio::PlainConnection,
+    noise_connection_tokio::{Connection, ConnectionParts},
+    plain_connection_tokio::PlainConnection,
 };
 use std::{net::SocketAddr, time::Duration};
 use tokio::{
@@ -37,11 +38,15 @@ pub async fn setup_as_upstream<
         Some((publ, secret)) => {
             let responder = Responder::from_authority_kp(
                 &publ.into_bytes(),
-                &secret.into_bytes(),
+                secret.into_bytes(),
                 std::time::Duration::from_secs(6000),
             )
             .unwrap();
-            let (recv, sender, _, _) = Connection::new(stream, HandshakeRole::Responder(responder))
+            let ConnectionParts {
+                receiver: recv,
+                sender,
+                ..
+            } = Connection::new(stream, HandshakeRole::Responder(responder))
                 .await
                 .unwrap();
             (recv, sender)
@@ -61,7 +66,11 @@ pub async fn setup_as_downstream<
     match key {
         Some(publ) => {
             let initiator = Initiator::from_raw_k(publ.into_bytes()).unwrap();
-            let (recv, sender, _, _) = Connection::new(stream, HandshakeRole::Initiator(initiator))
+            let ConnectionParts {
+                receiver: recv,
+                sender,
+                ..
+            } = Connection::new(stream, HandshakeRole::Initiator(initiator))
                 .await
                 .unwrap();
             (recv, sender)